@@ -0,0 +1,60 @@
+// Tako -- Take container image.
+// Copyright 2018 Arian van Putten, Ruud van Asseldonk, Tako Marks.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Build script that records what `tako --version --format json` reports.
+//!
+//! Sets `TAKO_GIT_COMMIT` to the commit that Tako is built from, or to the empty
+//! string when building outside of a git checkout, and `TAKO_FEATURES` to the
+//! enabled Cargo features, separated by commas.
+
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::process::Command;
+
+fn git_commit() -> String {
+    let output = match Command::new("git").arg("rev-parse").arg("HEAD").output() {
+        Ok(output) => output,
+        Err(..) => return String::new(),
+    };
+    if !output.status.success() {
+        return String::new()
+    }
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+fn features() -> String {
+    let prefix = "CARGO_FEATURE_";
+    let mut features: Vec<String> = env::vars()
+        .map(|(k, _)| k)
+        .filter(|k| k.starts_with(prefix))
+        .map(|k| k[prefix.len()..].to_lowercase().replace('_', "-"))
+        .collect();
+    features.sort();
+    features.join(",")
+}
+
+fn main() {
+    println!("cargo:rustc-env=TAKO_GIT_COMMIT={}", git_commit());
+    println!("cargo:rustc-env=TAKO_FEATURES={}", features());
+
+    // Rebuild when the checked out commit changes. Outside of a git checkout
+    // these files do not exist, and Cargo falls back to its default of
+    // rerunning when any file in the package changes.
+    for path in &[".git/HEAD", ".git/packed-refs"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+    let mut head = String::new();
+    if let Ok(mut f) = fs::File::open(".git/HEAD") {
+        if f.read_to_string(&mut head).is_ok() && head.starts_with("ref: ") {
+            println!("cargo:rerun-if-changed=.git/{}", head[5..].trim());
+        }
+    }
+}
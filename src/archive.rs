@@ -0,0 +1,271 @@
+// Tako -- Take container image.
+// Copyright 2018 Arian van Putten, Ruud van Asseldonk, Tako Marks.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Signed archives of configs, for `fetch --config-archive`.
+//!
+//! A config archive is a tar file of configs in a signed envelope: a short text
+//! header with the signatures, followed by the tar file. The signatures cover
+//! the tar file, and are verified with the same code as manifest signatures.
+//! Only uncompressed ustar archives are supported, Tako has no decompressor.
+//! See docs/config-archive-format.md for the format.
+
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::str;
+
+use base64;
+use ring::signature::Ed25519KeyPair;
+
+use config::PublicKey;
+use error::{Error, Result};
+use manifest;
+
+/// The first line of a config archive, which includes the format version.
+const MAGIC: &'static [u8] = b"Tako Config Archive 1";
+
+/// Tar files consist of blocks of this many bytes.
+const BLOCK_LEN: usize = 512;
+
+/// A file in the archive, with its path inside the archive.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Member {
+    pub path: String,
+    pub contents: Vec<u8>,
+}
+
+/// Parse an octal number field of a tar header, which may be terminated by a
+/// space or nul.
+fn parse_octal(field: &[u8]) -> Result<usize> {
+    let msg = "Invalid number in tar header.";
+    let digits = str::from_utf8(field).map_err(|_| Error::InvalidArchive(msg))?;
+    let digits = digits.trim_matches(|c| c == ' ' || c == '\0');
+    if digits.is_empty() {
+        return Ok(0)
+    }
+    usize::from_str_radix(digits, 8).map_err(|_| Error::InvalidArchive(msg))
+}
+
+/// Return the part of a tar header field before the first nul.
+fn until_nul(field: &[u8]) -> &[u8] {
+    match field.iter().position(|&b| b == 0) {
+        Some(n) => &field[..n],
+        None => field,
+    }
+}
+
+/// Read the regular files from an uncompressed ustar archive.
+///
+/// Directories, links, and other special files are skipped. Extended headers
+/// are not supported, so paths are at most 255 bytes.
+pub fn read_tar(bytes: &[u8]) -> Result<Vec<Member>> {
+    if bytes.starts_with(&[0x1f, 0x8b]) {
+        let msg = "The archive is compressed with gzip. Only uncompressed tar files are supported.";
+        return Err(Error::InvalidArchive(msg))
+    }
+
+    let mut members = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        if bytes.len() < offset + BLOCK_LEN {
+            return Err(Error::InvalidArchive("Unexpected end of tar file."))
+        }
+        let header = &bytes[offset..offset + BLOCK_LEN];
+        offset += BLOCK_LEN;
+
+        // The archive ends with zero blocks.
+        if header.iter().all(|&b| b == 0) {
+            break
+        }
+        if &header[257..262] != b"ustar" {
+            return Err(Error::InvalidArchive("The archive is not a ustar tar file."))
+        }
+
+        let size = parse_octal(&header[124..136])?;
+        // The contents are padded to a whole number of blocks.
+        let padded_size = size + (BLOCK_LEN - size % BLOCK_LEN) % BLOCK_LEN;
+        if bytes.len() < offset + size {
+            return Err(Error::InvalidArchive("Unexpected end of tar file."))
+        }
+        let contents = &bytes[offset..offset + size];
+        offset += padded_size;
+
+        match header[156] {
+            b'0' | b'\0' => {}
+            b'x' | b'L' => {
+                let msg = "The archive uses extended headers, which are not supported. \
+                           Paths must be at most 255 bytes.";
+                return Err(Error::InvalidArchive(msg))
+            }
+            _ => continue,
+        }
+
+        let name = until_nul(&header[0..100]);
+        let prefix = until_nul(&header[345..500]);
+        let mut path = Vec::with_capacity(prefix.len() + 1 + name.len());
+        if !prefix.is_empty() {
+            path.extend_from_slice(prefix);
+            path.push(b'/');
+        }
+        path.extend_from_slice(name);
+        if path.starts_with(b"./") {
+            path.drain(..2);
+        }
+
+        let msg = "The archive contains a path that is not UTF-8.";
+        let path = String::from_utf8(path).map_err(|_| Error::InvalidArchive(msg))?;
+
+        members.push(Member {
+            path: path,
+            contents: contents.to_vec(),
+        });
+    }
+
+    Ok(members)
+}
+
+/// Verify the envelope of a config archive, and return the tar file inside.
+pub fn verify<'a>(bytes: &'a [u8], public_key: &PublicKey) -> Result<&'a [u8]> {
+    let mut lines = bytes.split(|&b| b == b'\n');
+    let mut header_len = 0;
+
+    match lines.next() {
+        Some(line) if line == MAGIC => header_len += line.len() + 1,
+        _ => return Err(Error::InvalidArchive("Expected 'Tako Config Archive 1' header.")),
+    }
+
+    let mut signatures = Vec::new();
+    loop {
+        let line = lines.next().ok_or(Error::InvalidArchive("Unexpected end of archive header."))?;
+        header_len += line.len() + 1;
+        if line == b"" {
+            break
+        }
+        // Tako ignores header fields that it does not recognize.
+        if line.starts_with(b"Signature=") {
+            signatures.push(manifest::parse_signature(&line[b"Signature=".len()..])?);
+        }
+    }
+
+    let tar = &bytes[header_len..];
+    if signatures.is_empty() {
+        return Err(Error::InvalidArchiveSignature("The archive is not signed."))
+    }
+    if !manifest::is_signed_by_any(tar, &signatures, Some(public_key)) {
+        return Err(Error::InvalidArchiveSignature("The signature does not match the archive key."))
+    }
+
+    Ok(tar)
+}
+
+/// Wrap a tar file in an envelope that is signed with every key pair, the
+/// inverse of `verify`.
+pub fn sign(tar: &[u8], key_pairs: &[Ed25519KeyPair]) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.push(b'\n');
+    for key_pair in key_pairs {
+        out.extend_from_slice(b"PublicKey=");
+        out.extend_from_slice(PublicKey::from_pair(key_pair).to_base64().as_bytes());
+        out.push(b'\n');
+        out.extend_from_slice(b"Signature=");
+        out.extend_from_slice(base64::encode(key_pair.sign(tar).as_ref()).as_bytes());
+        out.push(b'\n');
+    }
+    out.push(b'\n');
+    out.extend_from_slice(tar);
+    out
+}
+
+/// Load a config archive, verify it against the key, and return the configs
+/// in it: the files with extension `.tako`, ordered by path.
+pub fn load_configs(path: &Path, public_key: &PublicKey) -> Result<Vec<(String, Vec<String>)>> {
+    let mut bytes = Vec::new();
+    fs::File::open(path)?.read_to_end(&mut bytes)?;
+    let tar = verify(&bytes[..], public_key)?;
+
+    let mut configs = Vec::new();
+    for member in read_tar(tar)? {
+        if !member.path.ends_with(".tako") {
+            continue
+        }
+        let msg = "The archive contains a config that is not UTF-8.";
+        let contents = String::from_utf8(member.contents).map_err(|_| Error::InvalidArchive(msg))?;
+        let lines = contents.lines().map(String::from).collect();
+        configs.push((member.path, lines));
+    }
+    configs.sort();
+
+    Ok(configs)
+}
+
+#[cfg(test)]
+mod test {
+    use ring::signature::Ed25519KeyPair;
+    use ring::test::rand::FixedSliceRandom;
+    use untrusted::Input;
+
+    use config::PublicKey;
+    use super::{Member, read_tar, sign, verify};
+
+    fn key_pair(seed: &[u8; 32]) -> Ed25519KeyPair {
+        let rng = FixedSliceRandom { bytes: &seed[..] };
+        let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        Ed25519KeyPair::from_pkcs8(Input::from(&pkcs8_bytes)).unwrap()
+    }
+
+    /// Return a ustar header block for a file.
+    fn tar_header(path: &str, typeflag: u8, size: usize) -> Vec<u8> {
+        let mut header = vec![0_u8; 512];
+        header[..path.len()].copy_from_slice(path.as_bytes());
+        let size = format!("{:011o}\0", size);
+        header[124..136].copy_from_slice(size.as_bytes());
+        header[156] = typeflag;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header
+    }
+
+    fn get_test_tar() -> Vec<u8> {
+        let config = b"Origin=https://images.example.com/app-foo\n";
+        let mut tar = tar_header("./etc/", b'5', 0);
+        tar.extend(tar_header("./etc/app-foo.tako", b'0', config.len()));
+        tar.extend_from_slice(config);
+        tar.extend(vec![0_u8; 512 - config.len()]);
+        tar.extend(vec![0_u8; 1024]);
+        tar
+    }
+
+    #[test]
+    fn read_tar_returns_regular_files() {
+        let members = read_tar(&get_test_tar()).unwrap();
+        assert_eq!(members, vec![Member {
+            path: "etc/app-foo.tako".to_string(),
+            contents: b"Origin=https://images.example.com/app-foo\n".to_vec(),
+        }]);
+
+        assert!(read_tar(&get_test_tar()[..1024]).is_err());
+        assert!(read_tar(&[0x1f, 0x8b, 0x08, 0x00]).is_err());
+    }
+
+    #[test]
+    fn sign_then_verify_is_identity() {
+        let pair = key_pair(b"test-key-very-security-such-safe");
+        let public_key = PublicKey::from_pair(&pair);
+        let tar = get_test_tar();
+        let signed = sign(&tar, &[pair]);
+        assert_eq!(verify(&signed, &public_key).unwrap(), &tar[..]);
+
+        // A different key, or a modified tar file, does not verify.
+        let other_key = PublicKey::from_pair(&key_pair(b"second-key-for-rotation-testing!"));
+        assert!(verify(&signed, &other_key).is_err());
+        let mut tampered = signed.clone();
+        let n = tampered.len() - 1500;
+        tampered[n] ^= 1;
+        assert!(verify(&tampered, &public_key).is_err());
+        assert!(verify(&tar, &public_key).is_err());
+    }
+}
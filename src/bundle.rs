@@ -0,0 +1,310 @@
+// Tako -- Take container image.
+// Copyright 2018 Arian van Putten, Ruud van Asseldonk, Tako Marks.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Bundles carry a manifest and an image to hosts without access to the origin.
+//!
+//! `tako export` writes a bundle with the image that fetch would select, and
+//! `tako import` installs it. A bundle is a short text header, followed by the
+//! signed manifest as served by the origin, followed by the image. The header
+//! itself is not signed, the import verifies the manifest against a key that
+//! the operator provides, and the image against its digest in the manifest.
+//! See docs/bundle-format.md for the format.
+
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use cli;
+use config;
+use config::{Config, RestartBackend};
+use error::{Error, Result};
+use fetch;
+use manifest;
+use manifest::{Entry, Manifest};
+use state::State;
+use util;
+use version::Version;
+
+/// The first line of a bundle, which includes the format version.
+const MAGIC: &'static str = "Tako Bundle 1";
+
+/// Header lines longer than this are not a bundle header.
+const MAX_HEADER_LINE_LEN: u64 = 1024;
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Header {
+    /// The version of the image in the bundle.
+    pub version: Version,
+
+    /// The length of the manifest in bytes, the image follows it.
+    pub manifest_len: u64,
+}
+
+impl Header {
+    /// Parse the `Key=Value` lines of the header, after the magic line.
+    pub fn parse<I, S>(lines: I) -> Result<Header>
+    where I: IntoIterator<Item = S>,
+          S: AsRef<str> {
+        let mut version = None;
+        let mut manifest_len = None;
+
+        config::for_each_pair(lines, |_lineno, key, value| {
+            match key {
+                "Version" => version = Some(Version::from(value)),
+                "Manifest" => match value.parse() {
+                    Ok(n) => manifest_len = Some(n),
+                    Err(..) => return Err(Error::InvalidBundle("Manifest length is not a number.")),
+                },
+                // Ignore unknown keys, so later versions can add to the header.
+                _ => {}
+            }
+            Ok(())
+        })?;
+
+        let header = Header {
+            version: match version {
+                Some(v) => v,
+                None => return Err(Error::InvalidBundle("Header lacks a 'Version=' line.")),
+            },
+            manifest_len: match manifest_len {
+                Some(n) => n,
+                None => return Err(Error::InvalidBundle("Header lacks a 'Manifest=' line.")),
+            },
+        };
+
+        Ok(header)
+    }
+
+    /// Print the header including the magic line and the blank line that
+    /// ends it, the inverse of `read`.
+    pub fn serialize(&self) -> String {
+        format!(
+            "{}\nVersion={}\nManifest={}\n\n",
+            MAGIC, self.version.as_str(), self.manifest_len,
+        )
+    }
+
+    /// Read the header, leaving the reader at the start of the manifest.
+    pub fn read<R: BufRead>(reader: &mut R) -> Result<Header> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = Vec::new();
+            reader.by_ref().take(MAX_HEADER_LINE_LEN).read_until(b'\n', &mut line)?;
+            if line.pop() != Some(b'\n') {
+                return Err(Error::InvalidBundle("The header is truncated."))
+            }
+            let line = match String::from_utf8(line) {
+                Ok(l) => l,
+                Err(..) => return Err(Error::InvalidBundle("The header is not valid UTF-8.")),
+            };
+            if line.is_empty() {
+                break
+            }
+            lines.push(line);
+        }
+
+        if lines.first().map(|l| &l[..]) != Some(MAGIC) {
+            return Err(Error::InvalidBundle("The file does not start with 'Tako Bundle 1'."))
+        }
+
+        Header::parse(&lines[1..])
+    }
+}
+
+/// Download and verify the image that fetch would select for a config, and
+/// write it to a bundle together with its manifest. Returns the entry.
+///
+/// The bundle is written to a temporary file first, and only moved into place
+/// once the digest of the image is verified.
+pub fn export(export: &cli::Export) -> Result<Entry> {
+    let fetch = cli::Fetch::new(vec![export.config_fname.clone()]);
+    let config = fetch::load_config(&export.config_fname)?;
+    let mut curl_handle = fetch::new_curl_handle(&fetch, &config, None)?;
+
+    let (manifest_bytes, manifest) = fetch::download_manifest(&fetch, &config, &mut curl_handle)?;
+    fetch::check_name(&config, &manifest)?;
+    let candidate = manifest.select_candidate(
+        &config.version,
+        config.version_lower_bound.as_ref(),
+        config.version_upper_bound.as_ref(),
+    )?;
+
+    let uri = fetch::image_uri(&config, &manifest, &fetch::store_path(candidate));
+    println!("Exporting {} from {} ...", candidate.version.as_str(), uri);
+
+    let header = Header {
+        version: candidate.version.clone(),
+        manifest_len: manifest_bytes.len() as u64,
+    };
+
+    let mut tmp_path = export.output_path.clone().into_os_string();
+    tmp_path.push(".new");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    // Delete the bundle if the download or verification fails.
+    let guard = util::FileGuard::new(&tmp_path);
+    let mut f = BufWriter::new(fs::File::create(&tmp_path)?);
+    f.write_all(header.serialize().as_bytes())?;
+    f.write_all(&manifest_bytes[..])?;
+
+    let actual_digest = if manifest.image_base().is_some() {
+        let mut image_handle = fetch::new_image_handle(&fetch, &config, &curl_handle)?;
+        fetch::download_digest_to(&uri, &mut f, &mut image_handle)?
+    } else {
+        fetch::set_image_timeout(&fetch, &config, &mut curl_handle);
+        fetch::download_digest_to(&uri, &mut f, &mut curl_handle)?
+    };
+    // Flush explicitly, dropping the writer would ignore errors.
+    f.flush()?;
+
+    if actual_digest != candidate.digest {
+        return Err(Error::InvalidDigest)
+    }
+
+    guard.move_readonly(&export.output_path)?;
+
+    Ok(candidate.clone())
+}
+
+/// Verify a bundle, store its manifest and image in the destination, and
+/// point `latest` at the image.
+pub fn import(import: &cli::Import) -> Result<fetch::Outcome> {
+    let mut reader = io::BufReader::new(fs::File::open(&import.bundle_path)?);
+    let header = Header::read(&mut reader)?;
+
+    let mut manifest_bytes = Vec::new();
+    reader.by_ref().take(header.manifest_len).read_to_end(&mut manifest_bytes)?;
+    if manifest_bytes.len() as u64 != header.manifest_len {
+        return Err(Error::InvalidBundle("The bundle ends inside the manifest."))
+    }
+
+    // Only continue past this point if the signature is valid, so we never
+    // write an untrusted manifest or image.
+    let manifest = Manifest::parse(&manifest_bytes[..], &import.public_key)?;
+    let candidate = match manifest.entry(&header.version) {
+        Some(entry) => entry.clone(),
+        None => return Err(Error::InvalidBundle("The manifest does not list the version of the bundle.")),
+    };
+
+    // There is no config on the offline host, only what the command line
+    // provides. Without units, installing only changes `latest` and the state.
+    let config = Config {
+        origin: import.bundle_path.to_string_lossy().into_owned(),
+        unix_socket_path: None,
+        public_key: Some(import.public_key.clone()),
+        version: candidate.version.clone(),
+        version_lower_bound: None,
+        version_upper_bound: None,
+        destination: util::resolve_dir(&import.destination)?,
+        manifest_timeout: None,
+        image_timeout: None,
+        restart_units: Vec::new(),
+        stop_units: Vec::new(),
+        restart_backend: RestartBackend::Systemd,
+        pinned_certs: Vec::new(),
+        ca_cert: None,
+        ca_path: None,
+        requires: Vec::new(),
+        verify_cmd: None,
+        name: None,
+        manifest_name: None,
+        index: None,
+        index_key: None,
+        fsync: true,
+    };
+
+    // The same checks as for a fetched manifest, see `fetch::fetch_manifest`.
+    if let Some(bytes) = Manifest::load_local_bytes(&config.destination)? {
+        let local_manifest = Manifest::parse(&bytes[..], &import.public_key)?;
+        if !manifest.is_successor_of(&local_manifest) {
+            let msg = "The bundle manifest is not a superset of the local manifest. Rejecting bundle.";
+            return Err(Error::OperationError(msg))
+        }
+    }
+    if let Some(state) = State::load_local(&config.destination)? {
+        if candidate.version < state.version {
+            return Err(Error::Downgrade(state.version, candidate.version))
+        }
+    }
+
+    store_image(&mut reader, &config.destination, &candidate)?;
+    manifest::store_local(&config.destination, &manifest_bytes[..])?;
+
+    let fetch = cli::Fetch::new(Vec::new());
+    fetch::install(&fetch, &config, &candidate)
+}
+
+/// Copy the image from the rest of the bundle into the store, and verify it.
+fn store_image<R: Read>(reader: &mut R, destination: &Path, candidate: &Entry) -> Result<()> {
+    let store_dir = destination.join("store");
+    if !store_dir.is_dir() {
+        fs::create_dir(&store_dir)?;
+    }
+
+    let target_fname = destination.join(fetch::store_path(candidate));
+    let tmp_fname = target_fname.with_extension("new");
+
+    // In case of error, delete the temp file.
+    let guard = util::FileGuard::new(&tmp_fname);
+    {
+        let mut f = BufWriter::new(fs::File::create(&tmp_fname)?);
+        io::copy(reader, &mut f)?;
+        f.flush()?;
+    }
+
+    if util::sha256sum(&tmp_fname)? != candidate.digest {
+        return Err(Error::InvalidDigest)
+    }
+
+    guard.move_readonly(&target_fname)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use version::Version;
+    use super::Header;
+
+    #[test]
+    fn serialize_then_read_is_identity() {
+        let header = Header {
+            version: Version::from("1.2.0"),
+            manifest_len: 417,
+        };
+        let mut bundle = header.serialize().into_bytes();
+        bundle.extend_from_slice(b"Tako Manifest 1.0\n");
+        let mut reader = &bundle[..];
+        assert_eq!(Header::read(&mut reader).unwrap(), header);
+
+        // The reader is left at the start of the manifest.
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "Tako Manifest 1.0\n");
+    }
+
+    #[test]
+    fn read_ignores_unknown_keys() {
+        let bundle = b"Tako Bundle 1\nCreated=2018\nVersion=1.0\nManifest=12\n\n";
+        let header = Header::read(&mut &bundle[..]).unwrap();
+        assert_eq!(header.version, Version::from("1.0"));
+        assert_eq!(header.manifest_len, 12);
+    }
+
+    #[test]
+    fn read_rejects_invalid_header() {
+        assert!(Header::read(&mut &b"Tako Bundle 2\nVersion=1.0\nManifest=12\n\n"[..]).is_err());
+        assert!(Header::read(&mut &b"Tako Bundle 1\nVersion=1.0\nManifest=12\n"[..]).is_err());
+        assert!(Header::read(&mut &b"Tako Bundle 1\nVersion=1.0\nManifest=x\n\n"[..]).is_err());
+        assert!(Header::read(&mut &b"Tako Bundle 1\nManifest=12\n\n"[..]).is_err());
+        assert!(Header::read(&mut &b"Tako Bundle 1\nVersion=1.0\n\n"[..]).is_err());
+        assert!(Header::read(&mut &b"Tako Manifest 1.0\n\n"[..]).is_err());
+    }
+}
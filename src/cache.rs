@@ -0,0 +1,232 @@
+// Tako -- Take container image.
+// Copyright 2018 Arian van Putten, Ruud van Asseldonk, Tako Marks.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! On-disk cache of manifests, to make conditional requests across runs.
+//!
+//! The cache directory holds one file per manifest uri, named after the SHA256
+//! of the uri. The file starts with `Key=Value` lines with the validators of
+//! the response, the time of the download, and the SHA256 of the manifest, then
+//! a blank line, and then the manifest exactly as downloaded. A file whose
+//! manifest does not match the digest is discarded, so a damaged cache costs a
+//! download. The signature of the manifest is verified again when it is reused,
+//! so the cache directory need not be trusted.
+
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::str;
+
+use ring;
+
+use config;
+use curl::Validators;
+use error::{Error, Result};
+use util;
+use util::Sha256;
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct CachedManifest {
+    /// The validators of the response that the manifest came from.
+    pub validators: Validators,
+
+    /// Unix time at which the manifest was downloaded.
+    pub fetched_at: u64,
+
+    /// The manifest, exactly as it was downloaded.
+    pub manifest_bytes: Vec<u8>,
+}
+
+impl CachedManifest {
+    pub fn parse(bytes: &[u8]) -> Result<CachedManifest> {
+        let msg = "Cache file does not contain a blank line before the manifest.";
+        let split = bytes
+            .windows(2)
+            .position(|w| w == b"\n\n")
+            .ok_or(Error::InvalidConfig(0, msg))?;
+        let msg = "Cache file header is not valid UTF-8.";
+        let header = str::from_utf8(&bytes[..split]).map_err(|_| Error::InvalidConfig(0, msg))?;
+
+        let mut validators = Validators::default();
+        let mut fetched_at = None;
+        let mut digest = None;
+
+        config::for_each_pair(header.lines(), |lineno, key, value| {
+            match key {
+                "ETag" => validators.etag = Some(String::from(value)),
+                "LastModified" => validators.last_modified = Some(String::from(value)),
+                "FetchedAt" => {
+                    let msg = "Invalid time. Expected seconds since the unix epoch.";
+                    fetched_at = Some(value.parse().map_err(|_| Error::InvalidConfig(lineno, msg))?);
+                }
+                "Digest" => {
+                    let msg = "Invalid digest. Expected 64 lowercase hexadecimal characters.";
+                    let sha256 = Sha256::from_hex(value.as_bytes());
+                    digest = Some(sha256.ok_or(Error::InvalidConfig(lineno, msg))?);
+                }
+                _ => {
+                    let msg = "Unknown key. Expected one of \
+                        'ETag', 'LastModified', 'FetchedAt', or 'Digest'.";
+                    return Err(Error::InvalidConfig(lineno, msg))
+                }
+            }
+            Ok(())
+        })?;
+
+        let cached = CachedManifest {
+            validators: validators,
+            fetched_at: match fetched_at {
+                Some(t) => t,
+                None => return Err(Error::IncompleteConfig(
+                    "FetchedAt not set in cache file. Expected 'FetchedAt='-line."
+                )),
+            },
+            manifest_bytes: bytes[split + 2..].to_vec(),
+        };
+
+        match digest {
+            Some(ref d) if *d == sha256(&cached.manifest_bytes[..]) => Ok(cached),
+            Some(..) => Err(Error::InvalidDigest),
+            None => Err(Error::IncompleteConfig(
+                "Digest not set in cache file. Expected 'Digest='-line."
+            )),
+        }
+    }
+
+    /// Format the cache file, the inverse of `parse`.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = String::new();
+        if let Some(ref etag) = self.validators.etag {
+            out.push_str("ETag=");
+            out.push_str(etag);
+            out.push('\n');
+        }
+        if let Some(ref last_modified) = self.validators.last_modified {
+            out.push_str("LastModified=");
+            out.push_str(last_modified);
+            out.push('\n');
+        }
+        out.push_str(&format!("FetchedAt={}\nDigest=", self.fetched_at));
+        util::append_hex(&mut out, sha256(&self.manifest_bytes[..]).as_ref());
+        out.push_str("\n\n");
+
+        let mut bytes = out.into_bytes();
+        bytes.extend_from_slice(&self.manifest_bytes[..]);
+        bytes
+    }
+}
+
+fn sha256(bytes: &[u8]) -> Sha256 {
+    let digest = ring::digest::digest(&ring::digest::SHA256, bytes);
+    Sha256::copy_from_slice(digest.as_ref())
+}
+
+/// Return the path of the cache file for a manifest uri.
+///
+/// For `http+unix://` origins, the uri does not identify the server, so the
+/// socket path is part of the key.
+fn cache_path(cache_dir: &Path, uri: &str, socket_path: Option<&Path>) -> PathBuf {
+    let mut key = String::from(uri);
+    if let Some(socket_path) = socket_path {
+        key.push('\n');
+        key.push_str(&socket_path.to_string_lossy());
+    }
+    let digest = ring::digest::digest(&ring::digest::SHA256, key.as_bytes());
+    let mut fname = String::new();
+    util::append_hex(&mut fname, digest.as_ref());
+
+    let mut path = PathBuf::from(cache_dir);
+    path.push(fname);
+    path
+}
+
+/// Load the cached manifest for the uri, if there is one.
+///
+/// A cache file that cannot be parsed, or whose manifest does not match its
+/// digest, is deleted and treated as missing.
+pub fn load(cache_dir: &Path, uri: &str, socket_path: Option<&Path>) -> Result<Option<CachedManifest>> {
+    let path = cache_path(cache_dir, uri, socket_path);
+    let mut bytes = Vec::new();
+    match fs::File::open(&path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+        Ok(mut f) => { f.read_to_end(&mut bytes)?; }
+    }
+    match CachedManifest::parse(&bytes[..]) {
+        Ok(cached) => Ok(Some(cached)),
+        Err(..) => {
+            fs::remove_file(&path)?;
+            Ok(None)
+        }
+    }
+}
+
+/// Store the manifest in the cache. Writes first and then swaps the file.
+pub fn store(cache_dir: &Path, uri: &str, socket_path: Option<&Path>, cached: &CachedManifest) -> Result<()> {
+    fs::create_dir_all(cache_dir)?;
+    let path = cache_path(cache_dir, uri, socket_path);
+    let path_tmp = path.with_extension("new");
+
+    // Delete the file if the write fails.
+    let guard = util::FileGuard::new(&path_tmp);
+    fs::File::create(&path_tmp)?.write_all(&cached.serialize()[..])?;
+    guard.move_readonly(&path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use curl::Validators;
+    use super::{CachedManifest, cache_path};
+
+    #[test]
+    fn serialize_then_parse_is_identity() {
+        let cached = CachedManifest {
+            validators: Validators {
+                etag: Some("\"5b1c-1a\"".to_string()),
+                last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+            },
+            fetched_at: 1_500_000_000,
+            manifest_bytes: b"Tako Manifest 1\n\n1.0.0 abc\n\nsig\n".to_vec(),
+        };
+        let bytes = cached.serialize();
+        assert_eq!(CachedManifest::parse(&bytes[..]).unwrap(), cached);
+    }
+
+    #[test]
+    fn parse_rejects_incomplete_cache_file() {
+        assert!(CachedManifest::parse(b"FetchedAt=1\nTako Manifest 1\n").is_err());
+        assert!(CachedManifest::parse(b"ETag=\"x\"\n\nTako Manifest 1\n").is_err());
+        assert!(CachedManifest::parse(b"FetchedAt=1\n\nTako Manifest 1\n").is_err());
+    }
+
+    #[test]
+    fn parse_rejects_damaged_manifest() {
+        let cached = CachedManifest {
+            validators: Validators::default(),
+            fetched_at: 1,
+            manifest_bytes: b"Tako Manifest 1\n\n1.0.0 abc\n\nsig\n".to_vec(),
+        };
+        let mut bytes = cached.serialize();
+        let n = bytes.len();
+        bytes[n - 2] = b'S';
+        assert!(CachedManifest::parse(&bytes[..]).is_err());
+    }
+
+    #[test]
+    fn cache_path_depends_on_socket_path() {
+        let dir = Path::new("/var/cache/tako");
+        let uri = "http://localhost/app";
+        let a = cache_path(dir, uri, None);
+        let b = cache_path(dir, uri, Some(Path::new("/run/a.sock")));
+        assert!(a.starts_with(dir));
+        assert!(a != b);
+    }
+}
@@ -17,9 +17,15 @@
 
 use std::env;
 use std::fmt;
+use std::os::raw;
 use std::path::PathBuf;
 use std::vec;
 
+use config;
+use config::PublicKey;
+use exit_code;
+use util;
+use util::Sha256;
 use version::Version;
 
 const USAGE: &'static str = "
@@ -28,74 +34,932 @@ Tako -- Take container image.
 Usage:
   tako <command> [<args>...]
   tako -h | --help
+  tako help [<command> | exit-codes]
   tako --version
 
 Commands:
-  fetch      Download or update an image.
-  store      Add a new image version to a server directory.
-  gen-key    Generate a key pair for signing manifests.
+  fetch         Download or update an image.
+  store         Add a new image version to a server directory.
+  seed          Add a directory of image versions to a server directory.
+  gen-key       Generate a key pair for signing manifests.
+  import-key    Convert an existing Ed25519 key to a Tako key pair.
+  print-config  Print the effective configuration of a config file.
+  keys          Print the public keys that configs trust.
+  doctor        Diagnose problems in a server directory.
+  digest        Print the digest of a file, as Tako computes it.
+  export        Pack the newest image and its manifest into a bundle.
+  import        Install an image from a bundle made with 'tako export'.
+  rekey-all     Sign every manifest in a directory tree with a new key.
+  canonicalize  Print the bytes of a manifest that signatures cover.
+  index         Sign an index that pins the manifests of several images.
+  sign-archive  Sign a tar file of configs for 'fetch --config-archive'.
+  verify        Check images in a destination against the stored manifest.
 
 Options:
-  -h --help  Show this screen, or help about a command.
-  --version  Show version.
+  -h --help     Show this screen, or help about a command.
+  --version     Show version. With '--format json', print the version, the git
+                commit, and the enabled features as a json object.
 
-See 'tako <command> --help' for information on a specific command.
+See 'tako <command> --help' for information on a specific command, and
+'tako help exit-codes' for the meaning of the exit status.
 ";
 
 const USAGE_FETCH: &'static str = "
 tako fetch -- Download or update an image.
 
 Usage:
-  tako fetch [--init] [--] <config>...
+  tako fetch [--init] [--allow-downgrade] [--fallback-to-older]
+             [--expected-version <version>]
+             [--accept-expired-cert] [--cacert <file>] [--capath <dir>]
+             [--tofu] [--detect-rollback <mode>]
+             [--verify-active [--verify-grace <dur>]] [--force-restart]
+             [--no-restart-on-first-install] [--allow-missing-restart-unit]
+             [--ignore-restart-units-without-systemd] [--repair]
+             [--no-download-if-present]
+             [--policy <file>]
+             [-q] [--format <fmt>] [--no-color] [--metrics-file <file>]
+             [--digest-file <file>]
+             [--manifest-timeout <dur>] [--image-timeout <dur>]
+             [--deadline <dur>]
+             [--cache-dir <dir> [--cache-ttl <dur>] [--no-cache]]
+             [--preserve-temp] [--no-fsync] [--write-buffer-size <size>]
+             [--] <config>...
+  tako fetch [<options>] --set <key=value>...
+  tako fetch [<options>] --config-archive <file> --archive-key <key>
+  tako fetch --print-url [--expected-version <version>] [--] <config>
+  tako fetch --print-url [--expected-version <version>] --set <key=value>...
+  tako fetch --report-only [<options>] [--] <config>...
+  tako fetch --manifest-only [--out <file>] [--accept-expired-cert] [--tofu]
+             [--cacert <file>] [--capath <dir>]
+             [--cache-dir <dir> [--cache-ttl <dur>] [--no-cache]]
+             [--key <key> | --key-file <file> [--force]] [--] <config>
 
 Options:
-  --init    Download images only if none exists already.
+  --init                 Download images only if none exists already.
+  --allow-downgrade      Install the selected version even if it is older than
+                         the installed version.
+  --fallback-to-older    If the image of the newest compatible version cannot
+                         be downloaded or does not match its digest, try the
+                         next older compatible version, down to the installed
+                         version. Skipped versions are reported on stderr.
+  --expected-version <version>
+                         Fail without installing anything, unless the
+                         selected version is exactly this version. Unlike
+                         'Version=', this does not affect which version is
+                         selected, it only asserts the outcome.
+  --verify-active        After starting or restarting a unit, check with
+                         'systemctl is-active' that it stays active during
+                         the grace period, and fail if it does not.
+  --verify-grace <dur>   Grace period for --verify-active. Defaults to 10s.
+  --force-restart        Restart the units also when the selected version has
+                         the same image as the installed version, for
+                         instance after a re-tag. By default, such a version
+                         is recorded as installed without restarting units.
+  --no-restart-on-first-install
+                         Do not stop, start, or restart units when the
+                         destination has no image yet, so they can be started
+                         by other means. Updates restart them as usual.
+  --allow-missing-restart-unit
+                         Skip a 'RestartUnit=' that does not exist on this
+                         host, with a warning, rather than failing. A unit
+                         that exists but fails to restart still fails. For
+                         configs that are shared by hosts with different
+                         units.
+  --ignore-restart-units-without-systemd
+                         Skip the units in 'RestartUnit=' and 'StopUnit=',
+                         with a warning, if systemd, or the init system from
+                         'RestartBackend=', does not run on this host, such as
+                         in a minimal container, rather than failing.
+  --repair               If the destination drifted from what Tako installed,
+                         because the installed image no longer matches its
+                         digest, or 'latest' was changed by hand, download the
+                         image again, point 'latest' at it, and restart the
+                         units. Without it, drift fails the fetch.
+  --no-download-if-present
+                         If the selected image is the installed one, per
+                         the state in the destination, and 'latest' points
+                         at it, trust it without hashing it again, so a
+                         fetch without update costs only the manifest.
+                         Ignored with --repair, which always verifies.
+  --policy <file>        Policy file that bounds what configs may do, see
+                         below. Defaults to /etc/tako/policy, if it exists.
+  -q --quiet             Print only errors, and no summary at the end.
+  --format <fmt>         Format of the summary at the end, 'text' (default),
+                         'json', or 'env'. With 'json' and 'env', progress is
+                         not printed. The 'env' format prints shell variable
+                         assignments TAKO_UPDATED, TAKO_VERSION, TAKO_DIGEST,
+                         and TAKO_DESTINATION, and requires a single config.
+                         The 'json' format includes the size, duration, and
+                         speed of every download, and the time until name
+                         resolution, connect, and TLS handshake completed.
+  --no-color             Do not color the summary. By default it is colored
+                         if stdout is a terminal, and NO_COLOR is not set.
+  --metrics-file <file>  After fetching, update this file with metrics about
+                         the run in Prometheus text format, for the textfile
+                         collector of node_exporter.
+  --digest-file <file>   After fetching, write the digest of the installed
+                         image to this file, as 'sha256:<hex>'. If the fetch
+                         fails or selects no version, remove the file.
+                         Requires a single config.
+  --accept-expired-cert  Do not verify the certificate chain of the origin.
+                         Only allowed if the config pins the public key of
+                         the origin with 'PinnedCert='.
+  --cacert <file>        Verify the certificate chain of the origin against
+                         the CA certificates in this PEM file, instead of
+                         the system trust store. Overrides 'CaCert=' in the
+                         config.
+  --capath <dir>         Verify the certificate chain of the origin against
+                         the CA certificates in this directory, prepared
+                         with 'c_rehash'. Overrides 'CaPath=' in the config.
+  --tofu                 Trust on first use: for configs without
+                         'PublicKey=', trust the key in the manifest on the
+                         first fetch, and require that key afterwards. The
+                         fingerprint is recorded in 'trusted-key' in the
+                         destination directory.
+  --detect-rollback <mode>
+                         Record the highest version that the origin has
+                         listed in 'highest-version' in the destination
+                         directory, and detect when a later manifest lists
+                         no version that high. With mode 'warn', print a
+                         warning and continue, with 'refuse', fail.
+  --manifest-timeout <dur>
+                         Maximum time to download the manifest. Overrides
+                         'ManifestTimeout=' in the config. Defaults to 30s.
+  --image-timeout <dur>  Maximum time to download an image. Overrides
+                         'ImageTimeout=' in the config. Defaults to no limit.
+                         Downloads that receive no data for 60s are aborted
+                         regardless. Durations are seconds, or a number
+                         with suffix 's', 'm', or 'h'. Zero means no limit.
+  --deadline <dur>       Maximum time for the entire run. Configs that are
+                         not done when it passes fail, and nothing is
+                         installed for them. Exits with status 2 if the
+                         deadline caused a failure.
+  --cache-dir <dir>      Keep downloaded manifests in this directory, and
+                         on later runs only download the manifest if it
+                         changed, using a conditional request.
+  --cache-ttl <dur>      Download a cached manifest unconditionally when it
+                         is older than this. Defaults to 1h.
+  --no-cache             Do not reuse cached manifests, but do update the
+                         cache.
+  --preserve-temp        When a downloaded image does not match its digest,
+                         keep it as 'store/<digest>.rejected' in the
+                         destination, rather than deleting it. When the
+                         manifest fails verification, keep it as
+                         'manifest.rejected'. Prints where it was kept.
+  --no-fsync             Do not flush images, manifests, and state to disk
+                         before renaming them into place. Faster, but after
+                         a power loss or kernel crash, the destination may
+                         hold empty or partial files. A config can opt out
+                         on its own with 'Fsync=false'.
+  --write-buffer-size <size>
+                         Size of the buffer between the download and the
+                         image file, in bytes, or with suffix 'K', 'M', or
+                         'G'. Defaults to 1M. A larger buffer makes fewer
+                         write calls, the download speed that '--format json'
+                         reports shows whether it helps.
+  --set <key=value>      Set a config key, as in a config file. Repeat the
+                         flag for every key. The keys form a single config,
+                         which replaces the config files. Errors on 'line n'
+                         refer to the n-th --set.
+  --config-archive <file>
+                         Fetch every config with extension '.tako' in this
+                         signed archive, made with 'tako sign-archive',
+                         rather than config files. 'Requires=' refers to
+                         configs in the archive. See
+                         docs/config-archive-format.md.
+  --archive-key <key>    Public key that the config archive must be signed
+                         with. Required with --config-archive.
+  --manifest-only        Download and verify the manifest, and write it to
+                         stdout. Does not touch the destination directory.
+  --out <file>           With --manifest-only, write the manifest to this
+                         file rather than stdout.
+  --key <key>            With --manifest-only, verify the manifest against
+                         this public key rather than 'PublicKey=' of the
+                         config, e.g. to test a new key during a rotation.
+  --key-file <file>      Like --key, but read the key from a file.
+  --force                Allow --key or --key-file to replace a key that the
+                         config sets. Without it, that is an error.
+  --print-url            Print the uri of the manifest, and of the images
+                         with a placeholder for the digest, as fetch would
+                         construct them, and exit without downloading. The
+                         image uri is unresolved: the manifest can move the
+                         images with 'ImageBase='. With --expected-version,
+                         download and verify the manifest, and print the
+                         uri of the image of that version instead.
+  --report-only          Download and verify the manifest, select a version,
+                         and report whether it is installed already, without
+                         downloading the image or writing to the destination.
+                         Exits with status 3 if any config has an update.
+                         The manifest can move images with 'ImageBase='.
 
 Arguments:
-  <config>  Path to a config file that determines what to fetch.
+  <config>               Path to a config file that determines what to fetch.
+
+The policy file has the same syntax as a config. Every 'AllowUnit=<pattern>'
+line allows units whose name matches the pattern, where '*' matches any part
+of a name, e.g. 'AllowUnit=app-*.service'. If the policy has such lines, a
+config with a 'RestartUnit=' or 'StopUnit=' that matches none of them fails
+before anything is downloaded. Without policy file, any unit is allowed.
 ";
 
 const USAGE_STORE: &'static str = "
 tako store -- Add a new image version to a server directory.
 
 Usage:
-  tako store [-k <key> | -f <file>] --output <dir> [--] <image> <version>
+  tako store [-k <key> | -f <file> | --key-dir <dir> | --key-env <var>]...
+             --output <dir> [--] <image> <version>
+  tako store --config <file> [<options>] [--] <image> <version>
+  tako store [<options>] --annotate <key>=<value>... [--] <version>
+  tako store [<options>] --notes <text> [--] [<image>] <version>
 
 Options:
   -k --key <key>        Secret key to sign the manifest with. Can alternatively
                         be read from the TAKO_SECRET_KEY environment variable.
-  -f --key-file <file>  File to read the secret key from.
-  -o --output <dir>     Server directory.
+                        Repeat --key or --key-file to sign with multiple keys,
+                        e.g. during a key rotation. Keys from --key come first.
+  -f --key-file <file>  File to read the secret key from, or '-' to read it
+                        from stdin, which keeps it out of the process table.
+  --key-dir <dir>       Directory to read secret keys from, one per file, such
+                        as a mounted Kubernetes secret. Reads the files without
+                        extension or with extension '.key', in order of their
+                        names, and skips other files and hidden files.
+  --key-env <var>       Environment variable to read the secret key from,
+                        instead of TAKO_SECRET_KEY, e.g. when several signing
+                        tools share an environment.
+  -o --output <dir>     Server directory, or an sftp://user@host/path url to
+                        upload to a server directory on a remote host.
+  -c --config <file>    Publish config to read the server directory and key
+                        file from. Options on the command line take precedence,
+                        and so does TAKO_SECRET_KEY.
+  --expect-digest <hex>
+                        Refuse to store the image unless its SHA256 digest
+                        matches this lowercase hexadecimal digest.
+  --image-base <url>    Record in the manifest that fetchers should download
+                        images from <url>/store/<digest>, rather than from the
+                        origin. The manifest keeps its previous setting if
+                        this is omitted.
+  --name <name>         Record the name of the image in the manifest, so
+                        fetchers with 'Name=' in their config can check that
+                        their origin serves the right image. The manifest
+                        keeps its previous name if this is omitted.
+  --embed-public-key    Write the public keys in the manifest, so fetchers can
+                        trust the key on first use with 'fetch --tofu'. Raises
+                        the manifest version to 1.1, which Tako before 1.1
+                        cannot read. The manifest keeps the keys from then on.
+  --image-ext <ext>     Store images as store/<digest><ext>, e.g. with '.img',
+                        for static hosts that pick the content type by
+                        extension. Recorded in the manifest, so fetchers
+                        download from the right url. Cannot change once the
+                        manifest has versions.
+  --manifest-name <name>
+                        File name of the manifest in the server directory,
+                        instead of 'manifest'. Fetchers need the same
+                        'ManifestName=' in their config.
+  --owner <user>        Change the owner of the image and the manifest to this
+                        user, by name or uid. Usually requires root. Not
+                        supported for sftp urls.
+  --group <group>       Change the group of the image and the manifest to this
+                        group, by name or gid.
+  --max-versions <n>    After storing the image, remove the oldest versions from
+                        the manifest until at most <n> remain, and delete their
+                        images from the store, unless a remaining version has
+                        the same image. Fails if the stored version would be
+                        removed. Not supported for sftp urls.
+  --delta               Also store a delta from the image of the next older
+                        version to this image, and record it in the manifest.
+                        Fetchers that have the older version installed then
+                        download only the delta. Skipped if the delta is not
+                        smaller than the image. Not supported for sftp urls.
+  --annotate <key>=<value>
+                        Set an annotation on an existing version, and sign the
+                        manifest again, without storing an image. Fails if the
+                        version does not exist, or if its image in the store
+                        no longer matches its digest. Can be repeated.
+  --notes <text>        Set release notes on the version, as the 'notes'
+                        annotation. With an image, on the version that is
+                        stored, otherwise on an existing version, like
+                        --annotate. The notes cannot contain newlines.
 
 Arguments:
-  <image>               Path to image file to be stored.
+  <image>               Path to image file to be stored, or an http:// or
+                        https:// url to download it from. Omitted with
+                        --annotate.
   <version>             Version to store the image under.
+
+The publish config has the same syntax as a fetch config. It accepts the keys
+'Output=<dir>', 'KeyFile=<file>', 'ImageBase=<url>', 'Name=<name>', and
+'MaxVersions=<n>'. All are optional.
+";
+
+const USAGE_SEED: &'static str = "
+tako seed -- Add a directory of image versions to a server directory.
+
+Usage:
+  tako seed [-k <key> | -f <file>] --output <dir> --from <dir>
+
+Options:
+  -k --key <key>        Secret key to sign the manifest with. Can alternatively
+                        be read from the TAKO_SECRET_KEY environment variable.
+  -f --key-file <file>  File to read the secret key from.
+  -o --output <dir>     Server directory.
+  --from <dir>          Directory with one subdirectory per version, named
+                        after the version, that contains the image file.
+
+Versions that conflict with a version in the manifest are reported and
+skipped. Versions that are in the manifest already with the same image are
+not an error, so seeding again from the same directory is safe.
 ";
 
 const USAGE_GEN_KEY: &'static str = "
 tako gen-key -- Generate a key pair for signing manifests.
 
 Usage:
-  tako gen-key
+  tako gen-key [--secret-out <file>] [--public-out <file>]
+
+Options:
+  --secret-out <file>  Write the secret key to a new file that only the owner
+                       can read, rather than printing it. Fails if the file
+                       exists already.
+  --public-out <file>  Write the public key to a file, rather than printing it.
+
+Without options, prints the secret key and the public key.
+";
+
+const USAGE_IMPORT_KEY: &'static str = "
+tako import-key -- Convert an existing Ed25519 key to a Tako key pair.
+
+Usage:
+  tako import-key --from <format> [--] <file>
+
+Options:
+  --from <format>  Format of the key: 'openssh' for an unencrypted OpenSSH
+                   private key, 'seed-hex' for the 32-byte seed as 64
+                   hexadecimal characters, or 'pem' for an unencrypted
+                   PKCS#8 private key as written by OpenSSL.
+
+Arguments:
+  <file>           File to read the key from, or '-' for stdin.
+
+Prints the secret key in the format that 'tako store' and TAKO_SECRET_KEY
+expect, and the public key for 'PublicKey=', like 'tako gen-key' does.
+Fails if the input is not a valid Ed25519 key.
+";
+
+const USAGE_PRINT_CONFIG: &'static str = "
+tako print-config -- Print the effective configuration of a config file.
+
+Usage:
+  tako print-config [--] <config>
+
+Arguments:
+  <config>  Path to the config file to print.
+
+The config is printed in canonical 'Key=Value' format, with one line per key.
+If the config is invalid, every invalid line and every missing key is
+reported on stderr, rather than only the first mistake.
+";
+
+const USAGE_KEYS: &'static str = "
+tako keys -- Print the public keys that configs trust.
+
+Usage:
+  tako keys [--] <config>...
+
+Arguments:
+  <config>  Path to a fetch config file.
+
+Prints one line per config: the path, a colon, the SHA256 fingerprint of the
+key as 'sha256:<hex>', and the base64-encoded key from 'PublicKey='. Configs
+without 'PublicKey=' are reported on stderr. Exits with a nonzero status if a
+config cannot be read or parsed.
+";
+
+const USAGE_DOCTOR: &'static str = "
+tako doctor -- Diagnose problems in a server directory.
+
+Usage:
+  tako doctor --output <dir> [--key <key>] [--manifest-name <name>]
+              [--fix [--assume-yes]]
+
+Options:
+  -o --output <dir>  Server directory to check.
+  -k --key <key>     Public key to verify the manifest signature with. If not
+                     provided, the signature is not checked.
+  --manifest-name <name>
+                     File name of the manifest in the server directory,
+                     instead of 'manifest'.
+  --fix              Remove files in the store that the manifest does not
+                     reference, after asking for confirmation. Refuses when
+                     there is no manifest, or when it does not verify with
+                     --key.
+  -y --assume-yes    Do not ask for confirmation. Without it, --fix fails
+                     when stdin is not a terminal.
+
+Reports images in the manifest that are missing or damaged, and files in the
+store that no manifest entry references. Exits with a nonzero status if the
+signature is invalid or if an image is damaged.
+";
+
+const USAGE_DIGEST: &'static str = "
+tako digest -- Print the digest of a file, as Tako computes it.
+
+Usage:
+  tako digest [--algo <algo>] [--] <file>
+
+Options:
+  --algo <algo>  Digest algorithm, 'sha256' (default) or 'sha512'.
+
+Arguments:
+  <file>         Path to the file to compute the digest of.
+
+Prints the digest as the algorithm name, a colon, and the hexadecimal digest,
+e.g. 'sha256:9641a49d...'. The sha256 digest is what 'tako store' records in
+the manifest.
+";
+
+const USAGE_EXPORT: &'static str = "
+tako export -- Pack the newest image and its manifest into a bundle.
+
+Usage:
+  tako export --output <file> [--] <config>
+
+Options:
+  -o --output <file>  Path to write the bundle to.
+
+Arguments:
+  <config>            Config file that points at the origin.
+
+Downloads and verifies the manifest and the image that 'tako fetch' would
+select, and writes both to a single file. The bundle is written only if the
+signature and the digest are valid. The destination of the config is not
+touched. Carry the bundle to a host without access to the origin, and install
+it there with 'tako import'. See docs/bundle-format.md for the format.
+";
+
+const USAGE_IMPORT: &'static str = "
+tako import -- Install an image from a bundle made with 'tako export'.
+
+Usage:
+  tako import --destination <dir> --key <key> [--] <bundle>
+
+Options:
+  -d --destination <dir>  Destination directory to install the image into.
+  -k --key <key>          Public key that the manifest must be signed with.
+
+Arguments:
+  <bundle>                Bundle file written by 'tako export'.
+
+Verifies the manifest in the bundle against the key, and the image against its
+digest in the manifest. Then stores both in the destination, and points
+'latest' at the image, as 'tako fetch' would. Like fetch, it refuses a manifest
+that lacks entries of the local manifest, and a version older than the
+installed version.
+";
+
+const USAGE_REKEY_ALL: &'static str = "
+tako rekey-all -- Sign every manifest in a directory tree with a new key.
+
+Usage:
+  tako rekey-all --root <dir> --old-key <key> [--new-key <key> | --new-key-file <file>]
+                 [--manifest-name <name>] [--assume-yes]
+
+Options:
+  --root <dir>            Directory to search for server directories. Every
+                          file named 'manifest' below it is a manifest, apart
+                          from the 'store' directory next to a manifest.
+  --manifest-name <name>  File name of the manifests, instead of 'manifest'.
+  --old-key <key>         Public key that the manifests are signed with now.
+  --new-key <key>         Secret key to sign the manifests with. Can
+                          alternatively be read from the TAKO_SECRET_KEY
+                          environment variable.
+  --new-key-file <file>   File to read the new secret key from.
+  -y --assume-yes         Do not ask for confirmation. Without it, the command
+                          fails when stdin is not a terminal.
+
+Verifies every manifest against the old key, and replaces it with a manifest
+that is signed with only the new key. Manifests that verify with the new key
+already are skipped, so after a partial failure, running the command again is
+safe. A manifest that fails to verify is reported, and the others are still
+re-signed. Lists the manifests and asks for confirmation before changing any.
+Exits with a failure status if any manifest failed.
+";
+
+const USAGE_CANONICALIZE: &'static str = "
+tako canonicalize -- Print the bytes of a manifest that signatures cover.
+
+Usage:
+  tako canonicalize --output <dir> [--key <key>]... [--manifest-name <name>]
+
+Options:
+  -o --output <dir>     Server directory with the manifest.
+  -k --key <key>        Public key that will sign the manifest, base64-encoded.
+                        Can be repeated. Defaults to the keys in the manifest.
+  --manifest-name <name>
+                        File name of the manifest in the server directory,
+                        instead of 'manifest'.
+
+Prints the manifest as 'tako store' writes it, up to and including the blank
+line before the signatures. These are the bytes that every signature covers, so
+external signing or attestation tools can sign the same bytes, and append the
+base64-encoded signatures, one per line. The bytes include a 'PublicKey=' line
+per key, in order, so they depend on the keys. The signatures of the manifest
+are not checked.
+";
+
+const USAGE_INDEX: &'static str = "
+tako index -- Sign an index that pins the manifests of several images.
+
+Usage:
+  tako index [--key <key> | --key-file <file>] --output <file> [--] (<dir> <version>)...
+
+Options:
+  -k --key <key>        Secret key to sign the index with. Can alternatively
+                        be read from the TAKO_SECRET_KEY environment variable.
+  -f --key-file <file>  File to read the secret key from.
+  -o --output <file>    File to write the index to.
+
+Arguments:
+  <dir>                 Server directory of an image. Its manifest must set
+                        'Name=', see 'tako store --name'.
+  <version>             Version of the image in the release.
+
+Writes an index that lists, for every image, the version and the SHA256 digest
+of its current manifest. A config with 'Index=' fetches exactly that manifest
+and version, and a fetch of configs that require each other installs none of
+them unless all match the index. Run this after storing the images, a manifest
+that changes afterwards no longer matches. See docs/index-format.md.
+";
+
+const USAGE_SIGN_ARCHIVE: &'static str = "
+tako sign-archive -- Sign a tar file of configs for 'fetch --config-archive'.
+
+Usage:
+  tako sign-archive [--key <key> | --key-file <file>] --output <file> [--] <tar>
+
+Options:
+  -k --key <key>        Secret key to sign the archive with. Can alternatively
+                        be read from the TAKO_SECRET_KEY environment variable.
+  -f --key-file <file>  File to read the secret key from.
+  -o --output <file>    File to write the signed archive to.
+
+Arguments:
+  <tar>                 Uncompressed tar file with the configs, such as from
+                        'tar -cf configs.tar *.tako'.
+
+Writes the tar file in a signed envelope. 'tako fetch --config-archive' reads
+the configs with extension '.tako' from it, after verifying the signature. See
+docs/config-archive-format.md.
+";
+
+const USAGE_VERIFY: &'static str = "
+tako verify -- Check images in a destination against the stored manifest.
+
+Usage:
+  tako verify [--] <config> <version>
+  tako verify --all [--] <config>
+
+Options:
+  --all       Verify every version in the manifest that has an image in the
+              store of the destination.
+
+Arguments:
+  <config>    Config of the destination to verify.
+  <version>   Version to verify. Its image must be in the store.
+
+Verifies the signature of the manifest in the destination against the public
+key of the config, or the key trusted with 'fetch --tofu', and then the image
+of every version against its digest in the manifest. Downloads nothing. Prints
+a line per verified version, and exits with a nonzero status if the signature
+or a digest does not match.
 ";
 
+/// The name that stands in for a config filename, for the config from `--set`.
+pub const ARGS_CONFIG: &'static str = "<args>";
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+    Env,
+}
+
+/// What `fetch --detect-rollback` does when the origin lists fewer versions.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RollbackMode {
+    Warn,
+    Refuse,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Fetch {
+    /// Config files to fetch, or `ARGS_CONFIG` for the config from `--set`.
+    pub config_fnames: Vec<String>,
+
+    /// `Key=Value` lines of the config given with `--set`, if any.
+    pub config_args: Vec<String>,
+
+    /// The signed archive to read the configs from, and the key that it must
+    /// be signed with, from `--config-archive` and `--archive-key`.
+    pub config_archive: Option<PathBuf>,
+    pub archive_key: Option<PublicKey>,
+
+    /// Path and lines of every config in the archive, once it is verified.
+    /// The paths are the archive path joined with the path in the archive.
+    pub archived_configs: Vec<(String, Vec<String>)>,
+
+    pub init: bool,
+    pub allow_downgrade: bool,
+    pub fallback_to_older: bool,
+    pub expected_version: Option<Version>,
+    pub accept_expired_cert: bool,
+
+    /// CA certificates from `--cacert` and `--capath`, which take precedence
+    /// over `CaCert=` and `CaPath=` in the config.
+    pub ca_cert: Option<PathBuf>,
+    pub ca_path: Option<PathBuf>,
+    pub tofu: bool,
+    pub verify_active: bool,
+    pub force_restart: bool,
+    pub no_restart_on_first_install: bool,
+    pub allow_missing_restart_unit: bool,
+    pub ignore_restart_units_without_systemd: bool,
+    pub repair: bool,
+    pub no_download_if_present: bool,
+
+    /// The policy file from `--policy`, otherwise the default path is used.
+    pub policy_path: Option<PathBuf>,
+    pub verify_grace: Option<u64>,
+    pub detect_rollback: Option<RollbackMode>,
+    pub quiet: bool,
+    pub format: Format,
+    pub no_color: bool,
+    pub metrics_path: Option<PathBuf>,
+    pub digest_path: Option<PathBuf>,
+    pub manifest_timeout: Option<u64>,
+    pub image_timeout: Option<u64>,
+    pub deadline: Option<u64>,
+    pub cache_dir: Option<PathBuf>,
+    pub cache_ttl: Option<u64>,
+    pub no_cache: bool,
+    pub preserve_temp: bool,
+    pub no_fsync: bool,
+    pub write_buffer_size: Option<usize>,
+    pub manifest_only: bool,
+    pub out_path: Option<PathBuf>,
+
+    /// Key from `--key` that replaces the key of the config.
+    pub public_key: Option<PublicKey>,
+
+    /// File with a key that replaces the key of the config, from `--key-file`.
+    pub public_key_path: Option<PathBuf>,
+    pub force: bool,
+    pub print_url: bool,
+    pub report_only: bool,
+}
+
+impl Fetch {
+    /// Return a fetch of the given configs with all options at their defaults.
+    pub fn new(config_fnames: Vec<String>) -> Fetch {
+        Fetch {
+            config_fnames: config_fnames,
+            config_args: Vec::new(),
+            config_archive: None,
+            archive_key: None,
+            archived_configs: Vec::new(),
+            init: false,
+            allow_downgrade: false,
+            fallback_to_older: false,
+            expected_version: None,
+            accept_expired_cert: false,
+            ca_cert: None,
+            ca_path: None,
+            tofu: false,
+            verify_active: false,
+            force_restart: false,
+            no_restart_on_first_install: false,
+            allow_missing_restart_unit: false,
+            ignore_restart_units_without_systemd: false,
+            repair: false,
+            no_download_if_present: false,
+            policy_path: None,
+            verify_grace: None,
+            detect_rollback: None,
+            quiet: false,
+            format: Format::Text,
+            no_color: false,
+            metrics_path: None,
+            digest_path: None,
+            manifest_timeout: None,
+            image_timeout: None,
+            deadline: None,
+            cache_dir: None,
+            cache_ttl: None,
+            no_cache: false,
+            preserve_temp: false,
+            no_fsync: false,
+            write_buffer_size: None,
+            manifest_only: false,
+            out_path: None,
+            public_key: None,
+            public_key_path: None,
+            force: false,
+            print_url: false,
+            report_only: false,
+        }
+    }
+
+    /// Return whether to print progress messages for humans.
+    pub fn prints_progress(&self) -> bool {
+        !self.quiet && self.format == Format::Text
+    }
+
+    /// Return whether to color output to the given file descriptor.
+    ///
+    /// Only text output is colored, the other formats are for machines.
+    pub fn uses_color(&self, fd: raw::c_int) -> bool {
+        !self.no_color && self.format == Format::Text && util::is_color_terminal(fd)
+    }
+}
+
 #[derive(Debug, Eq, PartialEq)]
 pub struct Store {
+    pub secret_keys: Vec<String>,
+
+    /// Key files from `--key-file`, `-` for stdin, read at store time.
+    pub secret_key_paths: Vec<PathBuf>,
+    pub secret_key_dirs: Vec<PathBuf>,
+
+    /// Names of environment variables with secret keys, from `--key-env`.
+    pub secret_key_envs: Vec<String>,
+    pub config_path: Option<PathBuf>,
+    pub output_path: Option<PathBuf>,
+    pub version: Version,
+
+    /// The image to store, or None to only set annotations.
+    pub image_path: Option<PathBuf>,
+
+    /// Annotations to set on the version, from `--annotate` and `--notes`.
+    pub annotations: Vec<(String, String)>,
+    pub expect_digest: Option<Sha256>,
+    pub image_base: Option<String>,
+    pub name: Option<String>,
+
+    /// Whether to write the public keys in the manifest, from `--embed-public-key`.
+    pub embed_public_key: bool,
+    pub image_ext: Option<String>,
+    pub manifest_name: Option<String>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+
+    /// Keep at most this many versions, prune the oldest ones.
+    pub max_versions: Option<usize>,
+
+    /// Whether to store a delta from the previous version, from `--delta`.
+    pub delta: bool,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Seed {
     pub secret_key: Option<String>,
     pub secret_key_path: Option<PathBuf>,
     pub output_path: PathBuf,
-    pub version: Version,
-    pub image_path: PathBuf,
+    pub source_path: PathBuf,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct GenKey {
+    /// File to write the secret key to, from `--secret-out`.
+    pub secret_out: Option<PathBuf>,
+
+    /// File to write the public key to, from `--public-out`.
+    pub public_out: Option<PathBuf>,
+}
+
+/// The formats that `import-key --from` accepts.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum KeyFormat {
+    OpenSsh,
+    SeedHex,
+    Pem,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct ImportKey {
+    pub format: KeyFormat,
+
+    /// File to read the key from, `-` for stdin.
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Doctor {
+    pub output_path: PathBuf,
+    pub public_key: Option<PublicKey>,
+    pub manifest_name: Option<String>,
+    pub fix: bool,
+    pub assume_yes: bool,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Export {
+    pub config_fname: String,
+    pub output_path: PathBuf,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Import {
+    pub destination: PathBuf,
+    pub public_key: PublicKey,
+    pub bundle_path: PathBuf,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct RekeyAll {
+    pub root: PathBuf,
+    pub old_key: PublicKey,
+    pub secret_key: Option<String>,
+    pub secret_key_path: Option<PathBuf>,
+    pub manifest_name: Option<String>,
+    pub assume_yes: bool,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Canonicalize {
+    pub output_path: PathBuf,
+
+    /// The keys for the `PublicKey=` lines, or empty for the keys in the manifest.
+    pub public_keys: Vec<PublicKey>,
+    pub manifest_name: Option<String>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Index {
+    pub secret_key: Option<String>,
+    pub secret_key_path: Option<PathBuf>,
+    pub output_path: PathBuf,
+
+    /// The server directory and version of every image, in order.
+    pub images: Vec<(PathBuf, Version)>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct SignArchive {
+    pub secret_key: Option<String>,
+    pub secret_key_path: Option<PathBuf>,
+    pub output_path: PathBuf,
+    pub tar_path: PathBuf,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Verify {
+    pub config_fname: String,
+
+    /// The version to verify, or None to verify all versions, for `--all`.
+    pub version: Option<Version>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DigestAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+impl DigestAlgorithm {
+    /// Return the name of the algorithm, as accepted by `--algo`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DigestAlgorithm::Sha256 => "sha256",
+            DigestAlgorithm::Sha512 => "sha512",
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Digest {
+    pub algorithm: DigestAlgorithm,
+    pub path: PathBuf,
 }
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum Cmd {
-    Fetch(Vec<String>),
-    Init(Vec<String>),
+    Fetch(Fetch),
     Store(Store),
-    GenKey,
+    Seed(Seed),
+    GenKey(GenKey),
+    ImportKey(ImportKey),
+    PrintConfig(String),
+    Keys(Vec<String>),
+    Doctor(Doctor),
+    Digest(Digest),
+    Export(Export),
+    Import(Import),
+    RekeyAll(RekeyAll),
+    Canonicalize(Canonicalize),
+    Index(Index),
+    SignArchive(SignArchive),
+    Verify(Verify),
     Help(String),
-    Version,
+    Version(Format),
 }
 
 pub fn print_usage(cmd: String) {
@@ -104,14 +968,70 @@ pub fn print_usage(cmd: String) {
         "tako" => print!("{}", &USAGE[1..]),
         "fetch" => print!("{}", &USAGE_FETCH[1..]),
         "store" => print!("{}", &USAGE_STORE[1..]),
+        "seed" => print!("{}", &USAGE_SEED[1..]),
         "gen-key" => print!("{}", &USAGE_GEN_KEY[1..]),
+        "import-key" => print!("{}", &USAGE_IMPORT_KEY[1..]),
+        "print-config" => print!("{}", &USAGE_PRINT_CONFIG[1..]),
+        "keys" => print!("{}", &USAGE_KEYS[1..]),
+        "doctor" => print!("{}", &USAGE_DOCTOR[1..]),
+        "digest" => print!("{}", &USAGE_DIGEST[1..]),
+        "export" => print!("{}", &USAGE_EXPORT[1..]),
+        "import" => print!("{}", &USAGE_IMPORT[1..]),
+        "rekey-all" => print!("{}", &USAGE_REKEY_ALL[1..]),
+        "canonicalize" => print!("{}", &USAGE_CANONICALIZE[1..]),
+        "index" => print!("{}", &USAGE_INDEX[1..]),
+        "sign-archive" => print!("{}", &USAGE_SIGN_ARCHIVE[1..]),
+        "verify" => print!("{}", &USAGE_VERIFY[1..]),
+        "exit-codes" => print_exit_codes(),
         _ => println!("'{}' is not a Tako command. See 'tako --help'.", cmd),
     }
 }
 
-pub fn print_version() {
-    println!("0.0.0");
-    // TODO: Licenses and stuff.
+/// Print the exit codes and their meaning, wrapped to 80 columns.
+fn print_exit_codes() {
+    println!("Exit codes:\n");
+    for &(code, meaning) in exit_code::EXIT_CODES {
+        let mut line = format!("  {:<3}", code);
+        for word in meaning.split_whitespace() {
+            if line.len() + 1 + word.len() > 80 {
+                println!("{}", line);
+                line = String::from("     ");
+            } else if line.len() > 5 {
+                line.push(' ');
+            }
+            line.push_str(word);
+        }
+        println!("{}", line);
+    }
+}
+
+/// Print the version, in the `Text` or `Json` format.
+pub fn print_version(format: Format) {
+    let version = env!("CARGO_PKG_VERSION");
+    if format != Format::Json {
+        println!("{}", version);
+        return
+    }
+
+    // The build script sets these, see build.rs.
+    let commit = env!("TAKO_GIT_COMMIT");
+    let features = env!("TAKO_FEATURES");
+
+    let mut out = String::from("{\"version\":");
+    util::append_json_string(&mut out, version);
+    out.push_str(",\"commit\":");
+    if commit.is_empty() {
+        out.push_str("null");
+    } else {
+        util::append_json_string(&mut out, commit);
+    }
+    out.push_str(",\"features\":[");
+    for (i, feature) in features.split(',').filter(|f| !f.is_empty()).enumerate() {
+        if i > 0 { out.push(','); }
+        util::append_json_string(&mut out, feature);
+    }
+    out.push_str("]}");
+    println!("{}", out);
 }
 
 enum Arg<T> {
@@ -200,7 +1120,8 @@ impl Iterator for ArgIter {
             return Some(Arg::Long(flag))
         }
 
-        if arg.starts_with("-") {
+        // A lone dash is not a flag, by convention it stands for stdin.
+        if arg.starts_with("-") && arg.len() > 1 {
             let mut flag = String::from(&arg[1..]);
             if flag.len() > 1 {
                 self.leftover = Some(flag.split_off(1));
@@ -227,57 +1148,457 @@ pub fn parse(argv: Vec<String>) -> Result<Cmd, String> {
     match arg.as_ref() {
         Arg::Plain("fetch") => parse_fetch(args),
         Arg::Plain("store") => parse_store(args),
+        Arg::Plain("seed") => parse_seed(args),
         Arg::Plain("gen-key") => parse_gen_key(args),
-        Arg::Long("version") => drain(args).and(Ok(Cmd::Version)),
-        Arg::Short("h") | Arg::Long("help") => parse_help(args),
+        Arg::Plain("import-key") => parse_import_key(args),
+        Arg::Plain("print-config") => parse_print_config(args),
+        Arg::Plain("keys") => parse_keys(args),
+        Arg::Plain("doctor") => parse_doctor(args),
+        Arg::Plain("digest") => parse_digest(args),
+        Arg::Plain("export") => parse_export(args),
+        Arg::Plain("import") => parse_import(args),
+        Arg::Plain("rekey-all") => parse_rekey_all(args),
+        Arg::Plain("canonicalize") => parse_canonicalize(args),
+        Arg::Plain("index") => parse_index(args),
+        Arg::Plain("sign-archive") => parse_sign_archive(args),
+        Arg::Plain("verify") => parse_verify(args),
+        Arg::Long("version") => parse_version(args),
+        Arg::Plain("help") | Arg::Short("h") | Arg::Long("help") => parse_help(args),
         _ => return unexpected(arg),
     }
 }
 
 fn parse_fetch(mut args: ArgIter) -> Result<Cmd, String> {
     let mut fnames = Vec::new();
+    let mut config_args = Vec::new();
+    let mut config_archive = None;
+    let mut archive_key = None;
     let mut is_init = false;
+    let mut allow_downgrade = false;
+    let mut fallback_to_older = false;
+    let mut expected_version = None;
+    let mut accept_expired_cert = false;
+    let mut ca_cert = None;
+    let mut ca_path = None;
+    let mut tofu = false;
+    let mut verify_active = false;
+    let mut force_restart = false;
+    let mut no_restart_on_first_install = false;
+    let mut allow_missing_restart_unit = false;
+    let mut ignore_restart_units_without_systemd = false;
+    let mut repair = false;
+    let mut no_download_if_present = false;
+    let mut policy_path = None;
+    let mut verify_grace = None;
+    let mut detect_rollback = None;
+    let mut quiet = false;
+    let mut format = Format::Text;
+    let mut no_color = false;
+    let mut metrics_path = None;
+    let mut digest_path = None;
+    let mut manifest_timeout = None;
+    let mut image_timeout = None;
+    let mut deadline = None;
+    let mut cache_dir = None;
+    let mut cache_ttl = None;
+    let mut no_cache = false;
+    let mut preserve_temp = false;
+    let mut no_fsync = false;
+    let mut write_buffer_size = None;
+    let mut manifest_only = false;
+    let mut out_path = None;
+    let mut public_key = None;
+    let mut public_key_path = None;
+    let mut force = false;
+    let mut print_url = false;
+    let mut report_only = false;
     while let Some(arg) = args.next() {
         match arg.as_ref() {
             Arg::Plain(..) => fnames.push(arg.into_string()),
             Arg::Long("init") => is_init = true,
+            Arg::Long("allow-downgrade") => allow_downgrade = true,
+            Arg::Long("fallback-to-older") => fallback_to_older = true,
+            Arg::Long("expected-version") => {
+                let msg = "Expected a version after --expected-version.";
+                expected_version = Some(Version::new(expect_plain(&mut args, msg)?));
+            }
+            Arg::Long("accept-expired-cert") => accept_expired_cert = true,
+            Arg::Long("cacert") => {
+                let msg = "Expected a PEM file after --cacert.";
+                ca_cert = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Long("capath") => {
+                let msg = "Expected a directory after --capath.";
+                ca_path = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Long("tofu") => tofu = true,
+            Arg::Long("verify-active") => verify_active = true,
+            Arg::Long("force-restart") => force_restart = true,
+            Arg::Long("no-restart-on-first-install") => no_restart_on_first_install = true,
+            Arg::Long("allow-missing-restart-unit") => allow_missing_restart_unit = true,
+            Arg::Long("ignore-restart-units-without-systemd") => ignore_restart_units_without_systemd = true,
+            Arg::Long("repair") => repair = true,
+            Arg::Long("no-download-if-present") => no_download_if_present = true,
+            Arg::Long("policy") => {
+                let msg = "Expected policy file path after --policy.";
+                policy_path = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Long("verify-grace") => {
+                let msg = "Expected a duration like '10s' after --verify-grace.";
+                verify_grace = Some(expect_duration(&mut args, msg)?);
+            }
+            Arg::Long("detect-rollback") => {
+                let msg = "Expected 'warn' or 'refuse' after --detect-rollback.";
+                detect_rollback = match &expect_plain(&mut args, msg)?[..] {
+                    "warn" => Some(RollbackMode::Warn),
+                    "refuse" => Some(RollbackMode::Refuse),
+                    _ => return Err(msg.to_string()),
+                };
+            }
+            Arg::Short("q") | Arg::Long("quiet") => quiet = true,
+            Arg::Long("no-color") => no_color = true,
+            Arg::Long("metrics-file") => {
+                let msg = "Expected metrics file path after --metrics-file.";
+                metrics_path = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Long("digest-file") => {
+                let msg = "Expected digest file path after --digest-file.";
+                digest_path = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Long("manifest-timeout") => {
+                let msg = "Expected a duration like '30s' after --manifest-timeout.";
+                manifest_timeout = Some(expect_duration(&mut args, msg)?);
+            }
+            Arg::Long("image-timeout") => {
+                let msg = "Expected a duration like '30m' after --image-timeout.";
+                image_timeout = Some(expect_duration(&mut args, msg)?);
+            }
+            Arg::Long("deadline") => {
+                let msg = "Expected a duration like '5m' after --deadline.";
+                deadline = Some(expect_duration(&mut args, msg)?);
+            }
+            Arg::Long("cache-dir") => {
+                let msg = "Expected cache directory after --cache-dir.";
+                cache_dir = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Long("cache-ttl") => {
+                let msg = "Expected a duration like '1h' after --cache-ttl.";
+                cache_ttl = Some(expect_duration(&mut args, msg)?);
+            }
+            Arg::Long("no-cache") => no_cache = true,
+            Arg::Long("preserve-temp") => preserve_temp = true,
+            Arg::Long("no-fsync") => no_fsync = true,
+            Arg::Long("write-buffer-size") => {
+                let msg = "Expected a size like '1M' after --write-buffer-size.";
+                let size = expect_plain(&mut args, msg)?;
+                write_buffer_size = Some(util::parse_size(&size).ok_or(msg.to_string())?);
+            }
+            Arg::Long("set") => {
+                let msg = "Expected a config line like 'Origin=https://...' after --set.";
+                config_args.push(expect_plain(&mut args, msg)?);
+            }
+            Arg::Long("config-archive") => {
+                let msg = "Expected config archive path after --config-archive.";
+                config_archive = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Long("archive-key") => {
+                let msg = "Expected base64-encoded public key after --archive-key.";
+                let key = expect_plain(&mut args, msg)?;
+                archive_key = Some(PublicKey::from_base64(&key).ok_or(msg.to_string())?);
+            }
+            Arg::Long("manifest-only") => manifest_only = true,
+            Arg::Long("print-url") => print_url = true,
+            Arg::Long("report-only") => report_only = true,
+            Arg::Long("out") => {
+                let msg = "Expected manifest output path after --out.";
+                out_path = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Long("key") => {
+                let msg = "Expected base64-encoded public key after --key.";
+                let key = expect_plain(&mut args, msg)?;
+                public_key = Some(PublicKey::from_base64(&key).ok_or(msg.to_string())?);
+            }
+            Arg::Long("key-file") => {
+                let msg = "Expected key path after --key-file.";
+                public_key_path = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Long("force") => force = true,
+            Arg::Long("format") => {
+                let msg = "Expected 'text', 'json', or 'env' after --format.";
+                format = match &expect_plain(&mut args, msg)?[..] {
+                    "text" => Format::Text,
+                    "json" => Format::Json,
+                    "env" => Format::Env,
+                    _ => return Err(msg.to_string()),
+                };
+            }
             Arg::Short("h") | Arg::Long("help") => return drain_help(args, "fetch"),
             _ => return unexpected(arg),
         }
     }
 
-    if fnames.len() == 0 {
-        return Err("Expected at least one fetch config filename.".to_string())
+    // The configs come from the archive, once it is verified, see `main`.
+    if config_archive.is_some() {
+        if !fnames.is_empty() || !config_args.is_empty() {
+            return Err("--config-archive cannot be combined with config filenames or --set.".to_string())
+        }
+        if archive_key.is_none() {
+            return Err("--config-archive requires --archive-key.".to_string())
+        }
+        if manifest_only || print_url || format == Format::Env || digest_path.is_some() {
+            let msg = "--config-archive cannot be combined with --manifest-only, --print-url, \
+                       '--format env', or --digest-file.";
+            return Err(msg.to_string())
+        }
+    } else if archive_key.is_some() {
+        return Err("--archive-key requires --config-archive.".to_string())
     }
 
-    if is_init {
-        Ok(Cmd::Init(fnames))
-    } else {
-        Ok(Cmd::Fetch(fnames))
+    match (fnames.len(), config_args.len()) {
+        (0, 0) if config_archive.is_some() => {}
+        (0, 0) => return Err("Expected at least one fetch config filename.".to_string()),
+        (0, _) => fnames.push(ARGS_CONFIG.to_string()),
+        (_, 0) => {}
+        _ => return Err("Config filenames cannot be combined with --set.".to_string()),
     }
-}
 
-fn parse_store(mut args: ArgIter) -> Result<Cmd, String> {
-    let mut output_path = None;
-    let mut secret_key = None;
-    let mut secret_key_path = None;
-    let mut image_path = None;
-    let mut version = None;
+    // With multiple configs, it would be ambiguous which one the variables
+    // refer to.
+    if format == Format::Env && fnames.len() > 1 {
+        return Err("The 'env' format requires exactly one config.".to_string())
+    }
+    if digest_path.is_some() && fnames.len() > 1 {
+        return Err("--digest-file requires exactly one config.".to_string())
+    }
 
-    while let Some(arg) = args.next() {
-        match arg.as_ref() {
-            Arg::Short("k") | Arg::Long("key") => {
-                let msg = "Expected secret key after --key.";
-                secret_key = Some(expect_plain(&mut args, msg)?);
-            }
-            Arg::Short("f") | Arg::Long("key-file") => {
-                let msg = "Expected key path after --key-file.";
-                secret_key_path = Some(expect_plain(&mut args, msg)?);
+    if manifest_only && (
+        fnames.len() > 1 || is_init || format != Format::Text ||
+        metrics_path.is_some() || digest_path.is_some() || expected_version.is_some() ||
+        preserve_temp
+    ) {
+        let msg = "--manifest-only requires exactly one config, and cannot be combined \
+                   with --init, --format, --metrics-file, --digest-file, --expected-version, \
+                   or --preserve-temp.";
+        return Err(msg.to_string())
+    }
+
+    if print_url && (fnames.len() > 1 || manifest_only) {
+        let msg = "--print-url requires exactly one config, and cannot be combined \
+                   with --manifest-only.";
+        return Err(msg.to_string())
+    }
+
+    if report_only && (
+        manifest_only || print_url || is_init || format != Format::Text ||
+        metrics_path.is_some() || digest_path.is_some() || repair
+    ) {
+        let msg = "--report-only cannot be combined with --manifest-only, --print-url, \
+                   --init, --format, --metrics-file, --digest-file, or --repair.";
+        return Err(msg.to_string())
+    }
+
+    if fallback_to_older && expected_version.is_some() {
+        return Err("--fallback-to-older cannot be combined with --expected-version.".to_string())
+    }
+
+    if verify_grace.is_some() && !verify_active {
+        return Err("--verify-grace requires --verify-active.".to_string())
+    }
+
+    if (cache_ttl.is_some() || no_cache) && cache_dir.is_none() {
+        return Err("--cache-ttl and --no-cache require --cache-dir.".to_string())
+    }
+
+    if out_path.is_some() && !manifest_only {
+        return Err("--out is only supported with --manifest-only.".to_string())
+    }
+
+    if (public_key.is_some() || public_key_path.is_some()) && !manifest_only {
+        return Err("--key and --key-file are only supported with --manifest-only.".to_string())
+    }
+    if public_key.is_some() && public_key_path.is_some() {
+        return Err("--key cannot be combined with --key-file.".to_string())
+    }
+    if force && public_key.is_none() && public_key_path.is_none() {
+        return Err("--force requires --key or --key-file.".to_string())
+    }
+
+    let fetch = Fetch {
+        config_fnames: fnames,
+        config_args: config_args,
+        config_archive: config_archive.map(PathBuf::from),
+        archive_key: archive_key,
+        archived_configs: Vec::new(),
+        init: is_init,
+        allow_downgrade: allow_downgrade,
+        fallback_to_older: fallback_to_older,
+        expected_version: expected_version,
+        accept_expired_cert: accept_expired_cert,
+        ca_cert: ca_cert.map(PathBuf::from),
+        ca_path: ca_path.map(PathBuf::from),
+        tofu: tofu,
+        verify_active: verify_active,
+        force_restart: force_restart,
+        no_restart_on_first_install: no_restart_on_first_install,
+        allow_missing_restart_unit: allow_missing_restart_unit,
+        ignore_restart_units_without_systemd: ignore_restart_units_without_systemd,
+        repair: repair,
+        no_download_if_present: no_download_if_present,
+        policy_path: policy_path.map(PathBuf::from),
+        verify_grace: verify_grace,
+        detect_rollback: detect_rollback,
+        quiet: quiet,
+        format: format,
+        no_color: no_color,
+        metrics_path: metrics_path.map(PathBuf::from),
+        digest_path: digest_path.map(PathBuf::from),
+        manifest_timeout: manifest_timeout,
+        image_timeout: image_timeout,
+        deadline: deadline,
+        cache_dir: cache_dir.map(PathBuf::from),
+        cache_ttl: cache_ttl,
+        no_cache: no_cache,
+        preserve_temp: preserve_temp,
+        no_fsync: no_fsync,
+        write_buffer_size: write_buffer_size,
+        manifest_only: manifest_only,
+        out_path: out_path.map(PathBuf::from),
+        public_key: public_key,
+        public_key_path: public_key_path.map(PathBuf::from),
+        force: force,
+        print_url: print_url,
+        report_only: report_only,
+    };
+
+    Ok(Cmd::Fetch(fetch))
+}
+
+fn parse_store(mut args: ArgIter) -> Result<Cmd, String> {
+    let mut output_path = None;
+    let mut secret_keys = Vec::new();
+    let mut secret_key_paths = Vec::new();
+    let mut secret_key_dirs = Vec::new();
+    let mut secret_key_envs = Vec::new();
+    let mut config_path = None;
+    let mut expect_digest = None;
+    let mut image_base = None;
+    let mut name = None;
+    let mut embed_public_key = false;
+    let mut image_ext = None;
+    let mut manifest_name = None;
+    let mut owner = None;
+    let mut group = None;
+    let mut max_versions = None;
+    let mut delta = false;
+    let mut annotations = Vec::new();
+    let mut has_annotate = false;
+    let mut image_path = None;
+    let mut version = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            Arg::Short("k") | Arg::Long("key") => {
+                let msg = "Expected secret key after --key.";
+                secret_keys.push(expect_plain(&mut args, msg)?);
+            }
+            Arg::Short("f") | Arg::Long("key-file") => {
+                let msg = "Expected key path after --key-file.";
+                let path = expect_plain(&mut args, msg)?;
+                if path == "-" && secret_key_paths.iter().any(|p: &PathBuf| p.to_str() == Some("-")) {
+                    return Err("Stdin can be read only once, pass '--key-file -' once.".to_string())
+                }
+                secret_key_paths.push(PathBuf::from(path));
+            }
+            Arg::Long("key-dir") => {
+                let msg = "Expected key directory after --key-dir.";
+                secret_key_dirs.push(PathBuf::from(expect_plain(&mut args, msg)?));
+            }
+            Arg::Long("key-env") => {
+                let msg = "Expected environment variable name after --key-env.";
+                let var = expect_plain(&mut args, msg)?;
+                if var.is_empty() || var.contains('=') {
+                    return Err(msg.to_string())
+                }
+                secret_key_envs.push(var);
             }
             Arg::Short("o") | Arg::Long("output") => {
                 let msg = "Expected server directory after --output.";
                 output_path = Some(expect_plain(&mut args, msg)?);
             }
+            Arg::Short("c") | Arg::Long("config") => {
+                let msg = "Expected publish config path after --config.";
+                config_path = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Long("expect-digest") => {
+                let msg = "Expected 64 lowercase hexadecimal characters after --expect-digest.";
+                let hex = expect_plain(&mut args, msg)?;
+                expect_digest = Some(Sha256::from_hex(hex.as_bytes()).ok_or(msg.to_string())?);
+            }
+            Arg::Long("image-base") => {
+                let msg = "Expected an http:// or https:// url after --image-base.";
+                image_base = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Long("name") => {
+                let msg = "Expected an image name without whitespace after --name.";
+                let value = expect_plain(&mut args, msg)?;
+                if value.is_empty() || value.contains(char::is_whitespace) {
+                    return Err(msg.to_string())
+                }
+                name = Some(value);
+            }
+            Arg::Long("embed-public-key") => embed_public_key = true,
+            Arg::Long("image-ext") => {
+                let msg = "Expected an extension such as '.img' after --image-ext.";
+                image_ext = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Long("manifest-name") => {
+                let msg = "Expected a file name without slashes after --manifest-name.";
+                let value = expect_plain(&mut args, msg)?;
+                if !config::is_manifest_name(&value) {
+                    return Err(msg.to_string())
+                }
+                manifest_name = Some(value);
+            }
+            Arg::Long("owner") => {
+                let msg = "Expected a user name or uid after --owner.";
+                owner = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Long("group") => {
+                let msg = "Expected a group name or gid after --group.";
+                group = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Long("max-versions") => {
+                let msg = "Expected a number of at least 1 after --max-versions.";
+                max_versions = match expect_plain(&mut args, msg)?.parse() {
+                    Ok(n) if n >= 1 => Some(n),
+                    _ => return Err(msg.to_string()),
+                };
+            }
+            Arg::Long("delta") => delta = true,
+            Arg::Long("annotate") => {
+                let msg = "Expected '<key>=<value>' after --annotate. \
+                           The key cannot contain whitespace, and the value \
+                           cannot contain newlines.";
+                let annotation = expect_plain(&mut args, msg)?;
+                let (key, value) = match annotation.find('=') {
+                    Some(i) => (&annotation[..i], &annotation[i + 1..]),
+                    None => return Err(msg.to_string()),
+                };
+                if key.is_empty() || key.contains(char::is_whitespace) || value.contains('\n') {
+                    return Err(msg.to_string())
+                }
+                annotations.push((key.to_string(), value.to_string()));
+                has_annotate = true;
+            }
+            Arg::Long("notes") => {
+                let msg = "Expected release notes after --notes. \
+                           The notes cannot contain newlines.";
+                let notes = expect_plain(&mut args, msg)?;
+                if notes.contains('\n') {
+                    return Err(msg.to_string())
+                }
+                annotations.push(("notes".to_string(), notes));
+            }
             Arg::Short("h") | Arg::Long("help") => {
                 return drain_help(args, "store")
             }
@@ -291,6 +1612,107 @@ fn parse_store(mut args: ArgIter) -> Result<Cmd, String> {
         }
     }
 
+    // If none of --key, --key-file, --key-dir, or --key-env are provided,
+    // check the TAKO_SECRET_KEY environment variable. If that is not set
+    // either, the publish config might still provide a key file; we find out
+    // when we read it.
+    if secret_keys.is_empty() && secret_key_paths.is_empty() &&
+        secret_key_dirs.is_empty() && secret_key_envs.is_empty() {
+        match env::var("TAKO_SECRET_KEY") {
+            Ok(v) => secret_keys.push(v),
+            Err(..) if config_path.is_some() => {}
+            Err(..) => {
+                let msg = "Secret key not provided. Pass it via --key, \
+                           read if from a key file with --key-file, \
+                           or from a directory with --key-dir, \
+                           or from a variable with --key-env, \
+                           or set the TAKO_SECRET_KEY environment variable.";
+                return Err(msg.to_string())
+            }
+        }
+    }
+
+    if output_path.is_none() && config_path.is_none() {
+        let msg = "Server directory not provided. Pass it via --output.";
+        return Err(msg.to_string())
+    }
+
+    // With --annotate, the only argument is the version. With --notes, there
+    // may be an image too, then the notes go on the version that is stored.
+    if has_annotate || (!annotations.is_empty() && version.is_none()) {
+        if version.is_some() {
+            let msg = "An image cannot be combined with --annotate, pass only the version.";
+            return Err(msg.to_string())
+        }
+        version = image_path.take();
+    }
+
+    let msg = "Image path not provided. See 'tako store --help' for usage.";
+    if annotations.is_empty() && image_path.is_none() {
+        return Err(msg.to_string())
+    }
+
+    let msg = "Version not provided. See 'tako store --help' for usage.";
+    let version = version.ok_or(msg.to_string())?;
+
+    if delta && image_path.is_none() {
+        return Err("--delta requires an image.".to_string())
+    }
+
+    let store = Store {
+        secret_keys: secret_keys,
+        secret_key_paths: secret_key_paths,
+        secret_key_dirs: secret_key_dirs,
+        secret_key_envs: secret_key_envs,
+        config_path: config_path.map(PathBuf::from),
+        output_path: output_path.map(PathBuf::from),
+        version: Version::new(version),
+        image_path: image_path.map(PathBuf::from),
+        annotations: annotations,
+        expect_digest: expect_digest,
+        image_base: image_base,
+        name: name,
+        embed_public_key: embed_public_key,
+        image_ext: image_ext,
+        manifest_name: manifest_name,
+        owner: owner,
+        group: group,
+        max_versions: max_versions,
+        delta: delta,
+    };
+
+    Ok(Cmd::Store(store))
+}
+
+fn parse_seed(mut args: ArgIter) -> Result<Cmd, String> {
+    let mut output_path = None;
+    let mut source_path = None;
+    let mut secret_key = None;
+    let mut secret_key_path = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            Arg::Short("k") | Arg::Long("key") => {
+                let msg = "Expected secret key after --key.";
+                secret_key = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Short("f") | Arg::Long("key-file") => {
+                let msg = "Expected key path after --key-file.";
+                secret_key_path = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Short("o") | Arg::Long("output") => {
+                let msg = "Expected server directory after --output.";
+                output_path = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Long("from") => {
+                let msg = "Expected source directory after --from.";
+                source_path = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Short("h") | Arg::Long("help") => return drain_help(args, "seed"),
+            _ => return unexpected(arg),
+        }
+    }
+
     // If --key nor --key-file are provided, check the TAKO_SECRET_KEY
     // environment variable.
     if secret_key.is_none() && secret_key_path.is_none() {
@@ -308,31 +1730,541 @@ fn parse_store(mut args: ArgIter) -> Result<Cmd, String> {
     let msg = "Server directory not provided. Pass it via --output.";
     let output_path = output_path.ok_or(msg.to_string())?;
 
-    let msg = "Image path not provided. See 'tako store --help' for usage.";
-    let image_path = image_path.ok_or(msg.to_string())?;
+    let msg = "Source directory not provided. Pass it via --from.";
+    let source_path = source_path.ok_or(msg.to_string())?;
+
+    let seed = Seed {
+        secret_key: secret_key,
+        secret_key_path: secret_key_path.map(PathBuf::from),
+        output_path: PathBuf::from(output_path),
+        source_path: PathBuf::from(source_path),
+    };
+
+    Ok(Cmd::Seed(seed))
+}
+
+fn parse_gen_key(mut args: ArgIter) -> Result<Cmd, String> {
+    let mut secret_out = None;
+    let mut public_out = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            Arg::Long("secret-out") => {
+                let msg = "Expected a file path after --secret-out.";
+                secret_out = Some(PathBuf::from(expect_plain(&mut args, msg)?));
+            }
+            Arg::Long("public-out") => {
+                let msg = "Expected a file path after --public-out.";
+                public_out = Some(PathBuf::from(expect_plain(&mut args, msg)?));
+            }
+            Arg::Short("h") | Arg::Long("help") => return drain_help(args, "gen-key"),
+            _ => return unexpected(arg),
+        }
+    }
+
+    if secret_out.is_some() && secret_out == public_out {
+        return Err("--secret-out and --public-out must be different files.".to_string())
+    }
+
+    let gen_key = GenKey {
+        secret_out: secret_out,
+        public_out: public_out,
+    };
+
+    Ok(Cmd::GenKey(gen_key))
+}
+
+fn parse_print_config(mut args: ArgIter) -> Result<Cmd, String> {
+    let mut fname = None;
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            Arg::Short("h") | Arg::Long("help") => return drain_help(args, "print-config"),
+            Arg::Plain(..) if fname.is_none() => fname = Some(arg.into_string()),
+            _ => return unexpected(arg),
+        }
+    }
+
+    let msg = "Expected a config filename. See 'tako print-config --help'.";
+    fname.ok_or(msg.to_string()).map(Cmd::PrintConfig)
+}
+
+fn parse_keys(mut args: ArgIter) -> Result<Cmd, String> {
+    let mut fnames = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            Arg::Short("h") | Arg::Long("help") => return drain_help(args, "keys"),
+            Arg::Plain(..) => fnames.push(arg.into_string()),
+            _ => return unexpected(arg),
+        }
+    }
+
+    if fnames.is_empty() {
+        return Err("Expected at least one config filename. See 'tako keys --help'.".to_string())
+    }
+
+    Ok(Cmd::Keys(fnames))
+}
+
+fn parse_doctor(mut args: ArgIter) -> Result<Cmd, String> {
+    let mut output_path = None;
+    let mut public_key = None;
+    let mut manifest_name = None;
+    let mut fix = false;
+    let mut assume_yes = false;
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            Arg::Short("o") | Arg::Long("output") => {
+                let msg = "Expected server directory after --output.";
+                output_path = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Short("k") | Arg::Long("key") => {
+                let msg = "Expected base64-encoded public key after --key.";
+                let key = expect_plain(&mut args, msg)?;
+                public_key = Some(PublicKey::from_base64(&key).ok_or(msg.to_string())?);
+            }
+            Arg::Long("manifest-name") => {
+                let msg = "Expected a file name without slashes after --manifest-name.";
+                let value = expect_plain(&mut args, msg)?;
+                if !config::is_manifest_name(&value) {
+                    return Err(msg.to_string())
+                }
+                manifest_name = Some(value);
+            }
+            Arg::Long("fix") => fix = true,
+            Arg::Short("y") | Arg::Long("assume-yes") => assume_yes = true,
+            Arg::Short("h") | Arg::Long("help") => return drain_help(args, "doctor"),
+            _ => return unexpected(arg),
+        }
+    }
+
+    let msg = "Server directory not provided. Pass it via --output.";
+    let output_path = output_path.ok_or(msg.to_string())?;
+
+    if assume_yes && !fix {
+        return Err("--assume-yes requires --fix.".to_string())
+    }
+
+    let doctor = Doctor {
+        output_path: PathBuf::from(output_path),
+        public_key: public_key,
+        manifest_name: manifest_name,
+        fix: fix,
+        assume_yes: assume_yes,
+    };
+
+    Ok(Cmd::Doctor(doctor))
+}
+
+fn parse_export(mut args: ArgIter) -> Result<Cmd, String> {
+    let mut output_path = None;
+    let mut fname = None;
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            Arg::Short("o") | Arg::Long("output") => {
+                let msg = "Expected bundle path after --output.";
+                output_path = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Short("h") | Arg::Long("help") => return drain_help(args, "export"),
+            Arg::Plain(..) if fname.is_none() => fname = Some(arg.into_string()),
+            _ => return unexpected(arg),
+        }
+    }
+
+    let msg = "Expected a config filename. See 'tako export --help'.";
+    let fname = fname.ok_or(msg.to_string())?;
+    let msg = "Bundle path not provided. Pass it via --output.";
+    let output_path = output_path.ok_or(msg.to_string())?;
+
+    let export = Export {
+        config_fname: fname,
+        output_path: PathBuf::from(output_path),
+    };
+
+    Ok(Cmd::Export(export))
+}
+
+fn parse_import(mut args: ArgIter) -> Result<Cmd, String> {
+    let mut destination = None;
+    let mut public_key = None;
+    let mut bundle_path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            Arg::Short("d") | Arg::Long("destination") => {
+                let msg = "Expected destination directory after --destination.";
+                destination = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Short("k") | Arg::Long("key") => {
+                let msg = "Expected base64-encoded public key after --key.";
+                let key = expect_plain(&mut args, msg)?;
+                public_key = Some(PublicKey::from_base64(&key).ok_or(msg.to_string())?);
+            }
+            Arg::Short("h") | Arg::Long("help") => return drain_help(args, "import"),
+            Arg::Plain(..) if bundle_path.is_none() => bundle_path = Some(arg.into_string()),
+            _ => return unexpected(arg),
+        }
+    }
+
+    let msg = "Expected a bundle filename. See 'tako import --help'.";
+    let bundle_path = bundle_path.ok_or(msg.to_string())?;
+    let msg = "Destination directory not provided. Pass it via --destination.";
+    let destination = destination.ok_or(msg.to_string())?;
+    // Unlike fetch, there is no config to hold the key, and no origin to
+    // trust on first use, so the key is required.
+    let msg = "Public key not provided. Pass it via --key.";
+    let public_key = public_key.ok_or(msg.to_string())?;
+
+    let import = Import {
+        destination: PathBuf::from(destination),
+        public_key: public_key,
+        bundle_path: PathBuf::from(bundle_path),
+    };
+
+    Ok(Cmd::Import(import))
+}
+
+fn parse_rekey_all(mut args: ArgIter) -> Result<Cmd, String> {
+    let mut root = None;
+    let mut old_key = None;
+    let mut secret_key = None;
+    let mut secret_key_path = None;
+    let mut manifest_name = None;
+    let mut assume_yes = false;
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            Arg::Long("root") => {
+                let msg = "Expected directory after --root.";
+                root = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Long("old-key") => {
+                let msg = "Expected base64-encoded public key after --old-key.";
+                let key = expect_plain(&mut args, msg)?;
+                old_key = Some(PublicKey::from_base64(&key).ok_or(msg.to_string())?);
+            }
+            Arg::Long("new-key") => {
+                let msg = "Expected secret key after --new-key.";
+                secret_key = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Long("new-key-file") => {
+                let msg = "Expected key path after --new-key-file.";
+                secret_key_path = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Long("manifest-name") => {
+                let msg = "Expected a file name without slashes after --manifest-name.";
+                let value = expect_plain(&mut args, msg)?;
+                if !config::is_manifest_name(&value) {
+                    return Err(msg.to_string())
+                }
+                manifest_name = Some(value);
+            }
+            Arg::Short("y") | Arg::Long("assume-yes") => assume_yes = true,
+            Arg::Short("h") | Arg::Long("help") => return drain_help(args, "rekey-all"),
+            _ => return unexpected(arg),
+        }
+    }
+
+    let msg = "Directory not provided. Pass it via --root.";
+    let root = root.ok_or(msg.to_string())?;
+    let msg = "Old public key not provided. Pass it via --old-key.";
+    let old_key = old_key.ok_or(msg.to_string())?;
+
+    if secret_key.is_some() && secret_key_path.is_some() {
+        return Err("--new-key cannot be combined with --new-key-file.".to_string())
+    }
+    if secret_key.is_none() && secret_key_path.is_none() {
+        match env::var("TAKO_SECRET_KEY") {
+            Ok(v) => secret_key = Some(v),
+            Err(..) => {
+                let msg = "New secret key not provided. Pass it via --new-key, \
+                           read it from a key file with --new-key-file, \
+                           or set the TAKO_SECRET_KEY environment variable.";
+                return Err(msg.to_string())
+            }
+        }
+    }
+
+    let rekey = RekeyAll {
+        root: PathBuf::from(root),
+        old_key: old_key,
+        secret_key: secret_key,
+        secret_key_path: secret_key_path.map(PathBuf::from),
+        manifest_name: manifest_name,
+        assume_yes: assume_yes,
+    };
+
+    Ok(Cmd::RekeyAll(rekey))
+}
+
+fn parse_import_key(mut args: ArgIter) -> Result<Cmd, String> {
+    let mut format = None;
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            Arg::Long("from") => {
+                let msg = "Expected 'openssh', 'seed-hex', or 'pem' after --from.";
+                format = match &expect_plain(&mut args, msg)?[..] {
+                    "openssh" => Some(KeyFormat::OpenSsh),
+                    "seed-hex" => Some(KeyFormat::SeedHex),
+                    "pem" => Some(KeyFormat::Pem),
+                    _ => return Err(msg.to_string()),
+                };
+            }
+            Arg::Short("h") | Arg::Long("help") => return drain_help(args, "import-key"),
+            Arg::Plain(..) if path.is_none() => path = Some(arg.into_string()),
+            _ => return unexpected(arg),
+        }
+    }
+
+    let msg = "Key format not provided. Pass it via --from.";
+    let format = format.ok_or(msg.to_string())?;
+    let msg = "Expected a filename, or '-' for stdin. See 'tako import-key --help'.";
+    let path = path.ok_or(msg.to_string())?;
+
+    let import = ImportKey {
+        format: format,
+        path: PathBuf::from(path),
+    };
+
+    Ok(Cmd::ImportKey(import))
+}
+
+fn parse_canonicalize(mut args: ArgIter) -> Result<Cmd, String> {
+    let mut output_path = None;
+    let mut public_keys = Vec::new();
+    let mut manifest_name = None;
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            Arg::Short("o") | Arg::Long("output") => {
+                let msg = "Expected server directory after --output.";
+                output_path = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Short("k") | Arg::Long("key") => {
+                let msg = "Expected base64-encoded public key after --key.";
+                let key = expect_plain(&mut args, msg)?;
+                public_keys.push(PublicKey::from_base64(&key).ok_or(msg.to_string())?);
+            }
+            Arg::Long("manifest-name") => {
+                let msg = "Expected a file name without slashes after --manifest-name.";
+                let value = expect_plain(&mut args, msg)?;
+                if !config::is_manifest_name(&value) {
+                    return Err(msg.to_string())
+                }
+                manifest_name = Some(value);
+            }
+            Arg::Short("h") | Arg::Long("help") => return drain_help(args, "canonicalize"),
+            _ => return unexpected(arg),
+        }
+    }
+
+    let msg = "Server directory not provided. Pass it via --output.";
+    let output_path = output_path.ok_or(msg.to_string())?;
+
+    let canonicalize = Canonicalize {
+        output_path: PathBuf::from(output_path),
+        public_keys: public_keys,
+        manifest_name: manifest_name,
+    };
+
+    Ok(Cmd::Canonicalize(canonicalize))
+}
+
+fn parse_index(mut args: ArgIter) -> Result<Cmd, String> {
+    let mut secret_key = None;
+    let mut secret_key_path = None;
+    let mut output_path = None;
+    let mut plain = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            Arg::Short("k") | Arg::Long("key") => {
+                let msg = "Expected secret key after --key.";
+                secret_key = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Short("f") | Arg::Long("key-file") => {
+                let msg = "Expected key path after --key-file.";
+                secret_key_path = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Short("o") | Arg::Long("output") => {
+                let msg = "Expected index file after --output.";
+                output_path = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Short("h") | Arg::Long("help") => return drain_help(args, "index"),
+            Arg::Plain(..) => plain.push(arg.into_string()),
+            _ => return unexpected(arg),
+        }
+    }
+
+    if secret_key.is_some() && secret_key_path.is_some() {
+        return Err("Expected only one of --key and --key-file.".to_string())
+    }
+    if secret_key.is_none() && secret_key_path.is_none() {
+        match env::var("TAKO_SECRET_KEY") {
+            Ok(v) => secret_key = Some(v),
+            Err(..) => {
+                let msg = "Secret key not provided. Pass it via --key, \
+                           read if from a key file with --key-file, \
+                           or set the TAKO_SECRET_KEY environment variable.";
+                return Err(msg.to_string())
+            }
+        }
+    }
+
+    let msg = "Index file not provided. Pass it via --output.";
+    let output_path = output_path.ok_or(msg.to_string())?;
+
+    if plain.is_empty() || plain.len() % 2 != 0 {
+        let msg = "Expected a server directory and a version for every image. See 'tako index --help'.";
+        return Err(msg.to_string())
+    }
+    let images = plain
+        .chunks(2)
+        .map(|pair| (PathBuf::from(&pair[0]), Version::from(&pair[1][..])))
+        .collect();
+
+    let index = Index {
+        secret_key: secret_key,
+        secret_key_path: secret_key_path.map(PathBuf::from),
+        output_path: PathBuf::from(output_path),
+        images: images,
+    };
+
+    Ok(Cmd::Index(index))
+}
+
+fn parse_sign_archive(mut args: ArgIter) -> Result<Cmd, String> {
+    let mut secret_key = None;
+    let mut secret_key_path = None;
+    let mut output_path = None;
+    let mut tar_path = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            Arg::Short("k") | Arg::Long("key") => {
+                let msg = "Expected secret key after --key.";
+                secret_key = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Short("f") | Arg::Long("key-file") => {
+                let msg = "Expected key path after --key-file.";
+                secret_key_path = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Short("o") | Arg::Long("output") => {
+                let msg = "Expected signed archive path after --output.";
+                output_path = Some(expect_plain(&mut args, msg)?);
+            }
+            Arg::Short("h") | Arg::Long("help") => return drain_help(args, "sign-archive"),
+            Arg::Plain(..) if tar_path.is_none() => tar_path = Some(arg.into_string()),
+            _ => return unexpected(arg),
+        }
+    }
+
+    if secret_key.is_some() && secret_key_path.is_some() {
+        return Err("Expected only one of --key and --key-file.".to_string())
+    }
+    if secret_key.is_none() && secret_key_path.is_none() {
+        match env::var("TAKO_SECRET_KEY") {
+            Ok(v) => secret_key = Some(v),
+            Err(..) => {
+                let msg = "Secret key not provided. Pass it via --key, \
+                           read if from a key file with --key-file, \
+                           or set the TAKO_SECRET_KEY environment variable.";
+                return Err(msg.to_string())
+            }
+        }
+    }
+
+    let msg = "Signed archive path not provided. Pass it via --output.";
+    let output_path = output_path.ok_or(msg.to_string())?;
+    let msg = "Expected a tar file. See 'tako sign-archive --help'.";
+    let tar_path = tar_path.ok_or(msg.to_string())?;
+
+    let sign = SignArchive {
+        secret_key: secret_key,
+        secret_key_path: secret_key_path.map(PathBuf::from),
+        output_path: PathBuf::from(output_path),
+        tar_path: PathBuf::from(tar_path),
+    };
+
+    Ok(Cmd::SignArchive(sign))
+}
+
+fn parse_verify(mut args: ArgIter) -> Result<Cmd, String> {
+    let mut all = false;
+    let mut plain = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            Arg::Long("all") => all = true,
+            Arg::Short("h") | Arg::Long("help") => return drain_help(args, "verify"),
+            Arg::Plain(..) if plain.len() < 2 => plain.push(arg.into_string()),
+            _ => return unexpected(arg),
+        }
+    }
+
+    let mut plain = plain.into_iter();
+    let msg = "Expected a config. See 'tako verify --help'.";
+    let config_fname = plain.next().ok_or(msg.to_string())?;
+    let version = plain.next().map(Version::new);
+
+    match (all, &version) {
+        (true, &Some(..)) => return Err("Expected a version or --all, not both.".to_string()),
+        (false, &None) => return Err("Expected a version, or --all. See 'tako verify --help'.".to_string()),
+        _ => {}
+    }
+
+    let verify = Verify {
+        config_fname: config_fname,
+        version: version,
+    };
+
+    Ok(Cmd::Verify(verify))
+}
+
+fn parse_digest(mut args: ArgIter) -> Result<Cmd, String> {
+    let mut algorithm = DigestAlgorithm::Sha256;
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            Arg::Long("algo") => {
+                let msg = "Expected 'sha256' or 'sha512' after --algo.";
+                algorithm = match &expect_plain(&mut args, msg)?[..] {
+                    "sha256" => DigestAlgorithm::Sha256,
+                    "sha512" => DigestAlgorithm::Sha512,
+                    _ => return Err(msg.to_string()),
+                };
+            }
+            Arg::Short("h") | Arg::Long("help") => return drain_help(args, "digest"),
+            Arg::Plain(..) if path.is_none() => path = Some(arg.into_string()),
+            _ => return unexpected(arg),
+        }
+    }
 
-    let msg = "Version not provided. See 'tako store --help' for usage.";
-    let version = version.ok_or(msg.to_string())?;
+    let msg = "Expected a filename. See 'tako digest --help'.";
+    let path = path.ok_or(msg.to_string())?;
 
-    let store = Store {
-        secret_key: secret_key,
-        secret_key_path: secret_key_path.map(PathBuf::from),
-        output_path: PathBuf::from(output_path),
-        version: Version::new(version),
-        image_path: PathBuf::from(image_path),
+    let digest = Digest {
+        algorithm: algorithm,
+        path: PathBuf::from(path),
     };
 
-    Ok(Cmd::Store(store))
+    Ok(Cmd::Digest(digest))
 }
 
-fn parse_gen_key(mut args: ArgIter) -> Result<Cmd, String> {
+fn parse_version(mut args: ArgIter) -> Result<Cmd, String> {
+    let mut format = Format::Text;
+
     while let Some(arg) = args.next() {
         match arg.as_ref() {
-            Arg::Short("h") | Arg::Long("help") => return drain_help(args, "gen-key"),
+            Arg::Long("format") => {
+                let msg = "Expected 'text' or 'json' after --format.";
+                format = match &expect_plain(&mut args, msg)?[..] {
+                    "text" => Format::Text,
+                    "json" => Format::Json,
+                    _ => return Err(msg.to_string()),
+                };
+            }
             _ => return unexpected(arg),
         }
     }
-    Ok(Cmd::GenKey)
+
+    Ok(Cmd::Version(format))
 }
 
 fn parse_help(mut args: ArgIter) -> Result<Cmd, String> {
@@ -355,6 +2287,11 @@ fn expect_plain(args: &mut ArgIter, msg: &'static str) -> Result<String, String>
     }
 }
 
+fn expect_duration(args: &mut ArgIter, msg: &'static str) -> Result<u64, String> {
+    let duration = expect_plain(args, msg)?;
+    util::parse_duration(&duration).ok_or(msg.to_string())
+}
+
 fn drain(args: ArgIter) -> Result<(), String> {
     for arg in args {
         return unexpected::<()>(arg);
@@ -370,7 +2307,9 @@ fn unexpected<T>(arg: Arg<String>) -> Result<T, String> {
 #[cfg(test)]
 mod test {
     use std::path::PathBuf;
-    use super::{Cmd, Store, parse};
+    use config::PublicKey;
+    use util::Sha256;
+    use super::{Canonicalize, Cmd, Digest, DigestAlgorithm, Doctor, Export, Fetch, Format, GenKey, Import, ImportKey, Index, KeyFormat, RekeyAll, RollbackMode, Seed, SignArchive, Store, Verify, parse};
     use version::Version;
 
     fn parse_slice(args: &[&'static str]) -> Result<Cmd, String> {
@@ -378,11 +2317,28 @@ mod test {
         parse(argv)
     }
 
+    /// Return fetch options for the given configs, with all flags off.
+    fn fetch_default(fnames: &[&'static str]) -> Fetch {
+        Fetch::new(fnames.iter().map(|s| String::from(*s)).collect())
+    }
+
     #[test]
     fn parse_parses_help() {
         let expected = Ok(Cmd::Help("tako".to_string()));
         assert_eq!(parse_slice(&["tako", "-h"]), expected);
         assert_eq!(parse_slice(&["tako", "--help"]), expected);
+        assert_eq!(parse_slice(&["tako", "help"]), expected);
+    }
+
+    #[test]
+    fn parse_parses_version() {
+        assert_eq!(parse_slice(&["tako", "--version"]), Ok(Cmd::Version(Format::Text)));
+        assert_eq!(
+            parse_slice(&["tako", "--version", "--format", "json"]),
+            Ok(Cmd::Version(Format::Json))
+        );
+        assert!(parse_slice(&["tako", "--version", "--format", "env"]).is_err());
+        assert!(parse_slice(&["tako", "--version", "fetch"]).is_err());
     }
 
     #[test]
@@ -404,16 +2360,25 @@ mod test {
         assert_eq!(parse_slice(&["tako", "--help", "gen-key"]), gen_key);
         assert_eq!(parse_slice(&["tako", "gen-key", "-h"]), gen_key);
         assert_eq!(parse_slice(&["tako", "gen-key", "--help"]), gen_key);
+
+        let print_config = Ok(Cmd::Help("print-config".to_string()));
+        assert_eq!(parse_slice(&["tako", "-h", "print-config"]), print_config);
+        assert_eq!(parse_slice(&["tako", "print-config", "--help"]), print_config);
+        assert_eq!(parse_slice(&["tako", "help", "print-config"]), print_config);
+
+        let exit_codes = Ok(Cmd::Help("exit-codes".to_string()));
+        assert_eq!(parse_slice(&["tako", "help", "exit-codes"]), exit_codes);
+        assert_eq!(parse_slice(&["tako", "--help", "exit-codes"]), exit_codes);
     }
 
     #[test]
     fn parse_parses_fetch() {
-        let fetch = Ok(Cmd::Fetch(vec!["foo".to_string(), "bar".to_string()]));
+        let fetch = Ok(Cmd::Fetch(fetch_default(&["foo", "bar"])));
         assert_eq!(parse_slice(&["tako", "fetch", "foo", "bar"]), fetch);
         assert_eq!(parse_slice(&["tako", "fetch", "--", "foo", "bar"]), fetch);
         assert_eq!(parse_slice(&["tako", "fetch", "foo", "--", "bar"]), fetch);
 
-        let fetch = Ok(Cmd::Fetch(vec!["foo".to_string(), "--bar".to_string()]));
+        let fetch = Ok(Cmd::Fetch(fetch_default(&["foo", "--bar"])));
         assert_eq!(parse_slice(&["tako", "fetch", "foo", "--", "--bar"]), fetch);
         assert_eq!(parse_slice(&["tako", "fetch", "--", "foo", "--bar"]), fetch);
 
@@ -427,20 +2392,712 @@ mod test {
 
     #[test]
     fn parse_parses_fetch_init() {
-        let init = Ok(Cmd::Init(vec!["foo".to_string(), "bar".to_string()]));
+        let init = Ok(Cmd::Fetch(Fetch {
+            init: true,
+            .. fetch_default(&["foo", "bar"])
+        }));
         assert_eq!(parse_slice(&["tako", "fetch", "--init", "foo", "bar"]), init);
         assert_eq!(parse_slice(&["tako", "fetch", "foo", "--init", "bar"]), init);
         assert_eq!(parse_slice(&["tako", "fetch", "foo", "bar", "--init"]), init);
     }
 
     #[test]
-    fn parse_parses_store() {
-        let store = Store {
+    fn parse_parses_fetch_allow_downgrade() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            allow_downgrade: true,
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--allow-downgrade", "foo"]), fetch);
+        assert_eq!(parse_slice(&["tako", "fetch", "foo", "--allow-downgrade"]), fetch);
+    }
+
+    #[test]
+    fn parse_parses_fetch_accept_expired_cert() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            accept_expired_cert: true,
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--accept-expired-cert", "foo"]), fetch);
+        assert_eq!(parse_slice(&["tako", "fetch", "foo", "--accept-expired-cert"]), fetch);
+    }
+
+    #[test]
+    fn parse_parses_fetch_cacert_and_capath() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            ca_cert: Some(PathBuf::from("/etc/tako/ca.pem")),
+            ca_path: Some(PathBuf::from("/etc/tako/ca.d")),
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(
+            parse_slice(&["tako", "fetch", "--cacert", "/etc/tako/ca.pem", "--capath", "/etc/tako/ca.d", "foo"]),
+            fetch,
+        );
+        assert!(parse_slice(&["tako", "fetch", "foo", "--cacert"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_fetch_tofu() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            tofu: true,
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--tofu", "foo"]), fetch);
+    }
+
+    #[test]
+    fn parse_parses_fetch_output_options() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            quiet: true,
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "-q", "foo"]), fetch);
+        assert_eq!(parse_slice(&["tako", "fetch", "foo", "--quiet"]), fetch);
+
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            format: Format::Json,
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--format", "json", "foo"]), fetch);
+        assert_eq!(parse_slice(&["tako", "fetch", "foo", "--format=json"]), fetch);
+
+        // Unknown or missing format.
+        assert!(parse_slice(&["tako", "fetch", "--format", "yaml", "foo"]).is_err());
+        assert!(parse_slice(&["tako", "fetch", "foo", "--format"]).is_err());
+
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            no_color: true,
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--no-color", "foo"]), fetch);
+
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            metrics_path: Some(PathBuf::from("tako.prom")),
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--metrics-file", "tako.prom", "foo"]), fetch);
+        assert!(parse_slice(&["tako", "fetch", "foo", "--metrics-file"]).is_err());
+
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            digest_path: Some(PathBuf::from("foo.digest")),
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--digest-file", "foo.digest", "foo"]), fetch);
+        assert!(parse_slice(&["tako", "fetch", "--digest-file", "foo.digest", "foo", "bar"]).is_err());
+
+        // The env format is only allowed for a single config.
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            format: Format::Env,
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--format=env", "foo"]), fetch);
+        assert!(parse_slice(&["tako", "fetch", "--format=env", "foo", "bar"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_fetch_timeouts() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            manifest_timeout: Some(10),
+            image_timeout: Some(7200),
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(
+            &["tako", "fetch", "--manifest-timeout", "10", "--image-timeout=2h", "foo"]
+        ), fetch);
+
+        assert!(parse_slice(&["tako", "fetch", "--manifest-timeout", "soon", "foo"]).is_err());
+        assert!(parse_slice(&["tako", "fetch", "foo", "--image-timeout"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_fetch_detect_rollback() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            detect_rollback: Some(RollbackMode::Refuse),
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--detect-rollback", "refuse", "foo"]), fetch);
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            detect_rollback: Some(RollbackMode::Warn),
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--detect-rollback=warn", "foo"]), fetch);
+        assert!(parse_slice(&["tako", "fetch", "--detect-rollback", "foo"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_fetch_deadline() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            deadline: Some(300),
+            .. fetch_default(&["foo", "bar"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--deadline", "5m", "foo", "bar"]), fetch);
+        assert!(parse_slice(&["tako", "fetch", "--deadline", "later", "foo"]).is_err());
+        assert!(parse_slice(&["tako", "fetch", "--deadline", "18446744073709551615", "foo"]).is_err());
+        assert!(parse_slice(&["tako", "fetch", "foo", "--deadline"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_fetch_config_args() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            config_args: vec!["Origin=https://example.com".to_string(), "Version=*".to_string()],
+            .. fetch_default(&["<args>"])
+        }));
+        assert_eq!(parse_slice(
+            &["tako", "fetch", "--set", "Origin=https://example.com", "--set=Version=*"]
+        ), fetch);
+
+        assert!(parse_slice(&["tako", "fetch", "--set", "Version=*", "foo"]).is_err());
+        assert!(parse_slice(&["tako", "fetch", "--set"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_fetch_config_archive() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            config_archive: Some(PathBuf::from("/usr/share/tako/configs.tar")),
+            archive_key: PublicKey::from_base64("l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k="),
+            .. fetch_default(&[])
+        }));
+        assert_eq!(parse_slice(&[
+            "tako", "fetch", "--config-archive", "/usr/share/tako/configs.tar",
+            "--archive-key", "l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=",
+        ]), fetch);
+
+        assert!(parse_slice(&["tako", "fetch", "--config-archive", "configs.tar"]).is_err());
+        assert!(parse_slice(&[
+            "tako", "fetch", "--archive-key", "l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=", "foo",
+        ]).is_err());
+        assert!(parse_slice(&[
+            "tako", "fetch", "--config-archive", "configs.tar",
+            "--archive-key", "l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=", "foo",
+        ]).is_err());
+        assert!(parse_slice(&[
+            "tako", "fetch", "--config-archive", "configs.tar",
+            "--archive-key", "l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=", "--format", "env",
+        ]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_fetch_fallback_to_older() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            fallback_to_older: true,
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--fallback-to-older", "foo"]), fetch);
+        assert!(parse_slice(
+            &["tako", "fetch", "--fallback-to-older", "--expected-version", "1.0.0", "foo"]
+        ).is_err());
+    }
+
+    #[test]
+    fn parse_parses_fetch_expected_version() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            expected_version: Some(Version::from("1.2.0")),
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--expected-version", "1.2.0", "foo"]), fetch);
+        assert_eq!(parse_slice(&["tako", "fetch", "foo", "--expected-version=1.2.0"]), fetch);
+
+        assert!(parse_slice(&["tako", "fetch", "foo", "--expected-version"]).is_err());
+        assert!(parse_slice(
+            &["tako", "fetch", "--manifest-only", "--expected-version", "1.2.0", "foo"]
+        ).is_err());
+    }
+
+    #[test]
+    fn parse_parses_fetch_verify_active() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            verify_active: true,
+            verify_grace: Some(30),
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--verify-active", "--verify-grace", "30s", "foo"]), fetch);
+        assert!(parse_slice(&["tako", "fetch", "--verify-grace", "30s", "foo"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_fetch_force_restart() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            force_restart: true,
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--force-restart", "foo"]), fetch);
+    }
+
+    #[test]
+    fn parse_parses_fetch_no_restart_on_first_install() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            no_restart_on_first_install: true,
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--no-restart-on-first-install", "foo"]), fetch);
+    }
+
+    #[test]
+    fn parse_parses_fetch_allow_missing_restart_unit() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            allow_missing_restart_unit: true,
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--allow-missing-restart-unit", "foo"]), fetch);
+    }
+
+    #[test]
+    fn parse_parses_fetch_ignore_restart_units_without_systemd() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            ignore_restart_units_without_systemd: true,
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--ignore-restart-units-without-systemd", "foo"]), fetch);
+    }
+
+    #[test]
+    fn parse_parses_fetch_repair() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            repair: true,
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--repair", "foo"]), fetch);
+    }
+
+    #[test]
+    fn parse_parses_fetch_no_download_if_present() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            no_download_if_present: true,
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--no-download-if-present", "foo"]), fetch);
+    }
+
+    #[test]
+    fn parse_parses_fetch_print_url() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            print_url: true,
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--print-url", "foo"]), fetch);
+        assert!(parse_slice(&["tako", "fetch", "--print-url", "foo", "bar"]).is_err());
+        assert!(parse_slice(&["tako", "fetch", "--print-url", "--manifest-only", "foo"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_fetch_report_only() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            report_only: true,
+            .. fetch_default(&["foo", "bar"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--report-only", "foo", "bar"]), fetch);
+        assert!(parse_slice(&["tako", "fetch", "--report-only", "--init", "foo"]).is_err());
+        assert!(parse_slice(&["tako", "fetch", "--report-only", "--format", "json", "foo"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_fetch_policy() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            policy_path: Some(PathBuf::from("/etc/tako/strict")),
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--policy", "/etc/tako/strict", "foo"]), fetch);
+        assert!(parse_slice(&["tako", "fetch", "foo", "--policy"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_fetch_cache_options() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            cache_dir: Some(PathBuf::from("/var/cache/tako")),
+            cache_ttl: Some(600),
+            no_cache: true,
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(
+            &["tako", "fetch", "--cache-dir", "/var/cache/tako", "--cache-ttl=10m", "--no-cache", "foo"]
+        ), fetch);
+
+        assert!(parse_slice(&["tako", "fetch", "--no-cache", "foo"]).is_err());
+        assert!(parse_slice(&["tako", "fetch", "--cache-ttl", "1h", "foo"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_fetch_manifest_only_key() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            manifest_only: true,
+            public_key: PublicKey::from_base64("l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k="),
+            force: true,
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&[
+            "tako", "fetch", "--manifest-only", "--force",
+            "--key", "l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=", "foo",
+        ]), fetch);
+
+        assert!(parse_slice(&["tako", "fetch", "--key-file", "new.pub", "foo"]).is_err());
+        assert!(parse_slice(&["tako", "fetch", "--manifest-only", "--force", "foo"]).is_err());
+        assert!(parse_slice(&["tako", "fetch", "--manifest-only", "--key", "not-a-key", "foo"]).is_err());
+        assert!(parse_slice(&[
+            "tako", "fetch", "--manifest-only", "--key-file", "new.pub",
+            "--key", "l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=", "foo",
+        ]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_fetch_no_fsync() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            no_fsync: true,
+            .. fetch_default(&["foo", "bar"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--no-fsync", "foo", "bar"]), fetch);
+    }
+
+    #[test]
+    fn parse_parses_fetch_write_buffer_size() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            write_buffer_size: Some(256 * 1024),
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--write-buffer-size", "256K", "foo"]), fetch);
+        assert!(parse_slice(&["tako", "fetch", "--write-buffer-size", "0", "foo"]).is_err());
+        assert!(parse_slice(&["tako", "fetch", "--write-buffer-size", "1MiB", "foo"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_fetch_preserve_temp() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            preserve_temp: true,
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--preserve-temp", "foo"]), fetch);
+        assert!(parse_slice(&["tako", "fetch", "--manifest-only", "--preserve-temp", "foo"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_fetch_manifest_only() {
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            manifest_only: true,
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(&["tako", "fetch", "--manifest-only", "foo"]), fetch);
+
+        let fetch = Ok(Cmd::Fetch(Fetch {
+            manifest_only: true,
+            out_path: Some(PathBuf::from("manifest.txt")),
+            .. fetch_default(&["foo"])
+        }));
+        assert_eq!(parse_slice(
+            &["tako", "fetch", "--manifest-only", "--out", "manifest.txt", "foo"]
+        ), fetch);
+        assert_eq!(parse_slice(
+            &["tako", "fetch", "foo", "--out=manifest.txt", "--manifest-only"]
+        ), fetch);
+
+        // --out without --manifest-only, or --manifest-only with other modes.
+        assert!(parse_slice(&["tako", "fetch", "--out", "manifest.txt", "foo"]).is_err());
+        assert!(parse_slice(&["tako", "fetch", "--manifest-only", "foo", "bar"]).is_err());
+        assert!(parse_slice(&["tako", "fetch", "--manifest-only", "--init", "foo"]).is_err());
+        assert!(parse_slice(&["tako", "fetch", "--manifest-only", "--format=json", "foo"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_print_config() {
+        let print_config = Ok(Cmd::PrintConfig("foo".to_string()));
+        assert_eq!(parse_slice(&["tako", "print-config", "foo"]), print_config);
+        assert_eq!(parse_slice(&["tako", "print-config", "--", "foo"]), print_config);
+
+        // Exactly one config must be provided.
+        assert!(parse_slice(&["tako", "print-config"]).is_err());
+        assert!(parse_slice(&["tako", "print-config", "foo", "bar"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_keys() {
+        let keys = Ok(Cmd::Keys(vec!["foo".to_string(), "bar".to_string()]));
+        assert_eq!(parse_slice(&["tako", "keys", "foo", "bar"]), keys);
+        assert_eq!(parse_slice(&["tako", "keys", "--", "foo", "bar"]), keys);
+
+        assert!(parse_slice(&["tako", "keys"]).is_err());
+        assert!(parse_slice(&["tako", "keys", "--foo", "bar"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_seed() {
+        let seed = Ok(Cmd::Seed(Seed {
+            secret_key: None,
+            secret_key_path: Some(PathBuf::from("key")),
+            output_path: PathBuf::from("/srv"),
+            source_path: PathBuf::from("pile"),
+        }));
+        assert_eq!(parse_slice(&["tako", "seed", "-f", "key", "-o", "/srv", "--from", "pile"]), seed);
+        assert_eq!(parse_slice(&["tako", "seed", "--from=pile", "--output=/srv", "-fkey"]), seed);
+
+        // Both directories are required.
+        assert!(parse_slice(&["tako", "seed", "-f", "key", "-o", "/srv"]).is_err());
+        assert!(parse_slice(&["tako", "seed", "-f", "key", "--from", "pile"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_doctor() {
+        let doctor = Ok(Cmd::Doctor(Doctor {
+            output_path: PathBuf::from("/srv"),
+            public_key: None,
+            manifest_name: None,
+            fix: false,
+            assume_yes: false,
+        }));
+        assert_eq!(parse_slice(&["tako", "doctor", "--output", "/srv"]), doctor);
+        assert_eq!(parse_slice(&["tako", "doctor", "-o/srv"]), doctor);
+
+        let doctor = Ok(Cmd::Doctor(Doctor {
+            output_path: PathBuf::from("/srv"),
+            public_key: PublicKey::from_base64("l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k="),
+            manifest_name: Some("app.manifest".to_string()),
+            fix: true,
+            assume_yes: true,
+        }));
+        assert_eq!(parse_slice(&[
+            "tako", "doctor", "--fix", "-y",
+            "-k", "l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=",
+            "-o", "/srv", "--manifest-name", "app.manifest",
+        ]), doctor);
+        assert!(parse_slice(&["tako", "doctor", "-k", "not-a-key", "-o", "/srv"]).is_err());
+        assert!(parse_slice(&["tako", "doctor", "-o", "/srv", "--manifest-name", "a/b"]).is_err());
+        assert!(parse_slice(&["tako", "doctor", "-y", "-o", "/srv"]).is_err());
+
+        // The server directory is required, and there are no plain arguments.
+        assert!(parse_slice(&["tako", "doctor"]).is_err());
+        assert!(parse_slice(&["tako", "doctor", "-o", "/srv", "foo"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_gen_key() {
+        let gen_key = GenKey {
+            secret_out: None,
+            public_out: None,
+        };
+        assert_eq!(parse_slice(&["tako", "gen-key"]), Ok(Cmd::GenKey(gen_key)));
+
+        let gen_key = GenKey {
+            secret_out: Some(PathBuf::from("app.key")),
+            public_out: Some(PathBuf::from("app.pub")),
+        };
+        assert_eq!(
+            parse_slice(&["tako", "gen-key", "--secret-out", "app.key", "--public-out=app.pub"]),
+            Ok(Cmd::GenKey(gen_key))
+        );
+        assert!(parse_slice(&["tako", "gen-key", "--secret-out", "k", "--public-out", "k"]).is_err());
+        assert!(parse_slice(&["tako", "gen-key", "--secret-out"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_import_key() {
+        let import = Ok(Cmd::ImportKey(ImportKey {
+            format: KeyFormat::OpenSsh,
+            path: PathBuf::from("id_ed25519"),
+        }));
+        assert_eq!(parse_slice(&["tako", "import-key", "--from", "openssh", "id_ed25519"]), import);
+
+        let import = Ok(Cmd::ImportKey(ImportKey {
+            format: KeyFormat::SeedHex,
+            path: PathBuf::from("-"),
+        }));
+        assert_eq!(parse_slice(&["tako", "import-key", "-", "--from=seed-hex"]), import);
+
+        assert!(parse_slice(&["tako", "import-key", "--from", "der", "key"]).is_err());
+        assert!(parse_slice(&["tako", "import-key", "key"]).is_err());
+        assert!(parse_slice(&["tako", "import-key", "--from", "pem"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_digest() {
+        let digest = Ok(Cmd::Digest(Digest {
+            algorithm: DigestAlgorithm::Sha256,
+            path: PathBuf::from("app.img"),
+        }));
+        assert_eq!(parse_slice(&["tako", "digest", "app.img"]), digest);
+
+        let digest = Ok(Cmd::Digest(Digest {
+            algorithm: DigestAlgorithm::Sha512,
+            path: PathBuf::from("app.img"),
+        }));
+        assert_eq!(parse_slice(&["tako", "digest", "--algo=sha512", "app.img"]), digest);
+
+        assert!(parse_slice(&["tako", "digest", "--algo", "md5", "app.img"]).is_err());
+        assert!(parse_slice(&["tako", "digest"]).is_err());
+        assert!(parse_slice(&["tako", "digest", "a.img", "b.img"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_export() {
+        let export = Ok(Cmd::Export(Export {
+            config_fname: "app.tako".to_string(),
+            output_path: PathBuf::from("app.bundle"),
+        }));
+        assert_eq!(parse_slice(&["tako", "export", "-o", "app.bundle", "app.tako"]), export);
+        assert_eq!(parse_slice(&["tako", "export", "app.tako", "--output=app.bundle"]), export);
+
+        assert!(parse_slice(&["tako", "export", "app.tako"]).is_err());
+        assert!(parse_slice(&["tako", "export", "-o", "app.bundle"]).is_err());
+        assert!(parse_slice(&["tako", "export", "-o", "app.bundle", "a.tako", "b.tako"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_import() {
+        let import = Ok(Cmd::Import(Import {
+            destination: PathBuf::from("/var/lib/app"),
+            public_key: PublicKey::from_base64("l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=").unwrap(),
+            bundle_path: PathBuf::from("app.bundle"),
+        }));
+        assert_eq!(parse_slice(&[
+            "tako", "import", "--destination", "/var/lib/app",
+            "--key", "l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=",
+            "app.bundle",
+        ]), import);
+
+        // The key is required, there is no config to take it from.
+        assert!(parse_slice(&["tako", "import", "-d", "/var/lib/app", "app.bundle"]).is_err());
+        assert!(parse_slice(&[
+            "tako", "import", "-d", "/var/lib/app", "-k", "not-a-key", "app.bundle",
+        ]).is_err());
+        assert!(parse_slice(&[
+            "tako", "import", "-k", "l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=", "app.bundle",
+        ]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_rekey_all() {
+        let rekey = Ok(Cmd::RekeyAll(RekeyAll {
+            root: PathBuf::from("/srv/images"),
+            old_key: PublicKey::from_base64("l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=").unwrap(),
+            secret_key: None,
+            secret_key_path: Some(PathBuf::from("new.key")),
+            manifest_name: None,
+            assume_yes: true,
+        }));
+        assert_eq!(parse_slice(&[
+            "tako", "rekey-all", "--root", "/srv/images",
+            "--old-key", "l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=",
+            "--new-key-file", "new.key", "--assume-yes",
+        ]), rekey);
+
+        let rekey = Ok(Cmd::RekeyAll(RekeyAll {
+            root: PathBuf::from("/srv/images"),
+            old_key: PublicKey::from_base64("l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=").unwrap(),
+            secret_key: None,
+            secret_key_path: Some(PathBuf::from("new.key")),
+            manifest_name: Some("app.manifest".to_string()),
+            assume_yes: false,
+        }));
+        assert_eq!(parse_slice(&[
+            "tako", "rekey-all", "--root", "/srv/images",
+            "--old-key", "l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=",
+            "--new-key-file", "new.key", "--manifest-name", "app.manifest",
+        ]), rekey);
+
+        assert!(parse_slice(&[
+            "tako", "rekey-all", "--old-key", "l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=",
+            "--new-key-file", "new.key",
+        ]).is_err());
+        assert!(parse_slice(&[
+            "tako", "rekey-all", "--root", "/srv/images", "--new-key-file", "new.key",
+        ]).is_err());
+        assert!(parse_slice(&[
+            "tako", "rekey-all", "--root", "/srv/images",
+            "--old-key", "l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=",
+            "--new-key", "secret", "--new-key-file", "new.key",
+        ]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_canonicalize() {
+        let canonicalize = Ok(Cmd::Canonicalize(Canonicalize {
+            output_path: PathBuf::from("/srv/app"),
+            public_keys: vec![
+                PublicKey::from_base64("l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=").unwrap(),
+            ],
+            manifest_name: Some("app.manifest".to_string()),
+        }));
+        assert_eq!(parse_slice(&[
+            "tako", "canonicalize", "--output", "/srv/app",
+            "--key", "l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=",
+            "--manifest-name", "app.manifest",
+        ]), canonicalize);
+
+        assert_eq!(
+            parse_slice(&["tako", "canonicalize", "-o", "/srv/app"]),
+            Ok(Cmd::Canonicalize(Canonicalize {
+                output_path: PathBuf::from("/srv/app"),
+                public_keys: Vec::new(),
+                manifest_name: None,
+            })),
+        );
+        assert!(parse_slice(&["tako", "canonicalize"]).is_err());
+        assert!(parse_slice(&["tako", "canonicalize", "-o", "/srv/app", "--key", "nope"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_index() {
+        let index = Ok(Cmd::Index(Index {
             secret_key: Some("secret".to_string()),
             secret_key_path: None,
-            output_path: PathBuf::from("/tmp"),
+            output_path: PathBuf::from("/srv/index"),
+            images: vec![
+                (PathBuf::from("/srv/app-foo"), Version::from("1.2.0")),
+                (PathBuf::from("/srv/app-bar"), Version::from("3.0.1")),
+            ],
+        }));
+        assert_eq!(parse_slice(&[
+            "tako", "index", "--key", "secret", "--output", "/srv/index",
+            "/srv/app-foo", "1.2.0", "/srv/app-bar", "3.0.1",
+        ]), index);
+        assert_eq!(parse_slice(&[
+            "tako", "index", "-k", "secret", "-o", "/srv/index", "--",
+            "/srv/app-foo", "1.2.0", "/srv/app-bar", "3.0.1",
+        ]), index);
+
+        // Every directory needs a version, and there is at least one image.
+        assert!(parse_slice(&["tako", "index", "-k", "secret", "-o", "/srv/index", "/srv/app-foo"]).is_err());
+        assert!(parse_slice(&["tako", "index", "-k", "secret", "-o", "/srv/index"]).is_err());
+        assert!(parse_slice(&["tako", "index", "-k", "secret", "/srv/app-foo", "1.2.0"]).is_err());
+        assert!(parse_slice(&[
+            "tako", "index", "-k", "secret", "-f", "key", "-o", "/srv/index", "/srv/app-foo", "1.2.0",
+        ]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_sign_archive() {
+        let sign = Ok(Cmd::SignArchive(SignArchive {
+            secret_key: None,
+            secret_key_path: Some(PathBuf::from("key")),
+            output_path: PathBuf::from("configs.tako-archive"),
+            tar_path: PathBuf::from("configs.tar"),
+        }));
+        assert_eq!(parse_slice(&[
+            "tako", "sign-archive", "-f", "key", "-o", "configs.tako-archive", "configs.tar",
+        ]), sign);
+
+        assert!(parse_slice(&["tako", "sign-archive", "-f", "key", "configs.tar"]).is_err());
+        assert!(parse_slice(&["tako", "sign-archive", "-f", "key", "-o", "out"]).is_err());
+        assert!(parse_slice(&["tako", "sign-archive", "-f", "key", "-o", "out", "a.tar", "b.tar"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_store() {
+        let store = Store {
+            secret_keys: vec!["secret".to_string()],
+            secret_key_paths: Vec::new(),
+            secret_key_dirs: Vec::new(),
+            secret_key_envs: Vec::new(),
+            config_path: None,
+            output_path: Some(PathBuf::from("/tmp")),
             version: Version::from("3.7.5"),
-            image_path: PathBuf::from("out.img"),
+            image_path: Some(PathBuf::from("out.img")),
+            annotations: Vec::new(),
+            expect_digest: None,
+            image_base: None,
+            name: None,
+            embed_public_key: false,
+            image_ext: None,
+            manifest_name: None,
+            owner: None,
+            group: None,
+            max_versions: None,
+            delta: false,
         };
         let expected = Ok(Cmd::Store(store));
 
@@ -470,6 +3127,386 @@ mod test {
             &["tako", "store", "-ksecret", "out.img", "3.7.5"]
         ).is_err());
 
-        // TODO: Verify --key-file/-f and environment variable getter.
+        // TODO: Verify environment variable getter.
+    }
+
+    #[test]
+    fn parse_parses_store_multiple_keys() {
+        let store = Store {
+            secret_keys: vec!["old".to_string(), "new".to_string()],
+            secret_key_paths: vec![PathBuf::from("key")],
+            secret_key_dirs: Vec::new(),
+            secret_key_envs: Vec::new(),
+            config_path: None,
+            output_path: Some(PathBuf::from("/tmp")),
+            version: Version::from("3.7.5"),
+            image_path: Some(PathBuf::from("out.img")),
+            annotations: Vec::new(),
+            expect_digest: None,
+            image_base: None,
+            name: None,
+            embed_public_key: false,
+            image_ext: None,
+            manifest_name: None,
+            owner: None,
+            group: None,
+            max_versions: None,
+            delta: false,
+        };
+        assert_eq!(parse_slice(
+            &["tako", "store", "-o", "/tmp", "-k", "old", "-f", "key", "--key=new", "out.img", "3.7.5"]
+        ), Ok(Cmd::Store(store)));
+    }
+
+    #[test]
+    fn parse_parses_store_key_file_stdin() {
+        let store = Store {
+            secret_keys: Vec::new(),
+            secret_key_paths: vec![PathBuf::from("-")],
+            secret_key_dirs: Vec::new(),
+            secret_key_envs: Vec::new(),
+            config_path: None,
+            output_path: Some(PathBuf::from("/tmp")),
+            version: Version::from("3.7.5"),
+            image_path: Some(PathBuf::from("out.img")),
+            annotations: Vec::new(),
+            expect_digest: None,
+            image_base: None,
+            name: None,
+            embed_public_key: false,
+            image_ext: None,
+            manifest_name: None,
+            owner: None,
+            group: None,
+            max_versions: None,
+            delta: false,
+        };
+        assert_eq!(parse_slice(
+            &["tako", "store", "-o", "/tmp", "--key-file", "-", "out.img", "3.7.5"]
+        ), Ok(Cmd::Store(store)));
+        assert!(parse_slice(
+            &["tako", "store", "-o", "/tmp", "-f", "-", "-f", "-", "out.img", "3.7.5"]
+        ).is_err());
+    }
+
+    #[test]
+    fn parse_parses_store_key_env() {
+        let store = Store {
+            secret_keys: Vec::new(),
+            secret_key_paths: Vec::new(),
+            secret_key_dirs: Vec::new(),
+            secret_key_envs: vec!["APP_SIGNING_KEY".to_string()],
+            config_path: None,
+            output_path: Some(PathBuf::from("/tmp")),
+            version: Version::from("3.7.5"),
+            image_path: Some(PathBuf::from("out.img")),
+            annotations: Vec::new(),
+            expect_digest: None,
+            image_base: None,
+            name: None,
+            embed_public_key: false,
+            image_ext: None,
+            manifest_name: None,
+            owner: None,
+            group: None,
+            max_versions: None,
+            delta: false,
+        };
+        assert_eq!(parse_slice(
+            &["tako", "store", "-o", "/tmp", "--key-env", "APP_SIGNING_KEY", "out.img", "3.7.5"]
+        ), Ok(Cmd::Store(store)));
+        assert!(parse_slice(
+            &["tako", "store", "-o", "/tmp", "--key-env", "A=B", "out.img", "3.7.5"]
+        ).is_err());
+    }
+
+    #[test]
+    fn parse_parses_store_owner_and_group() {
+        let store = Store {
+            secret_keys: vec!["secret".to_string()],
+            secret_key_paths: Vec::new(),
+            secret_key_dirs: Vec::new(),
+            secret_key_envs: Vec::new(),
+            config_path: None,
+            output_path: Some(PathBuf::from("/srv")),
+            version: Version::from("3.7.5"),
+            image_path: Some(PathBuf::from("out.img")),
+            annotations: Vec::new(),
+            expect_digest: None,
+            image_base: None,
+            name: None,
+            embed_public_key: false,
+            image_ext: None,
+            manifest_name: None,
+            owner: Some("www-data".to_string()),
+            group: Some("33".to_string()),
+            max_versions: None,
+            delta: false,
+        };
+        assert_eq!(parse_slice(
+            &["tako", "store", "-ksecret", "-o", "/srv", "--owner", "www-data", "--group=33", "out.img", "3.7.5"]
+        ), Ok(Cmd::Store(store)));
+        assert!(parse_slice(&["tako", "store", "-ksecret", "-o", "/srv", "out.img", "3.7.5", "--owner"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_store_name() {
+        let store = Store {
+            secret_keys: vec!["secret".to_string()],
+            secret_key_paths: Vec::new(),
+            secret_key_dirs: Vec::new(),
+            secret_key_envs: Vec::new(),
+            config_path: None,
+            output_path: Some(PathBuf::from("/srv")),
+            version: Version::from("3.7.5"),
+            image_path: Some(PathBuf::from("out.img")),
+            annotations: Vec::new(),
+            expect_digest: None,
+            image_base: None,
+            name: Some("app-foo".to_string()),
+            embed_public_key: false,
+            image_ext: None,
+            manifest_name: None,
+            owner: None,
+            group: None,
+            max_versions: None,
+            delta: false,
+        };
+        assert_eq!(parse_slice(
+            &["tako", "store", "-ksecret", "-o", "/srv", "--name", "app-foo", "out.img", "3.7.5"]
+        ), Ok(Cmd::Store(store)));
+        assert!(parse_slice(&["tako", "store", "-ksecret", "-o", "/srv", "--name=app foo", "out.img", "3.7.5"]).is_err());
+        assert!(parse_slice(&["tako", "store", "-ksecret", "-o", "/srv", "--name=", "out.img", "3.7.5"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_store_embed_public_key() {
+        let store = Store {
+            secret_keys: vec!["secret".to_string()],
+            secret_key_paths: Vec::new(),
+            secret_key_dirs: Vec::new(),
+            secret_key_envs: Vec::new(),
+            config_path: None,
+            output_path: Some(PathBuf::from("/srv")),
+            version: Version::from("3.7.5"),
+            image_path: Some(PathBuf::from("out.img")),
+            annotations: Vec::new(),
+            expect_digest: None,
+            image_base: None,
+            name: None,
+            embed_public_key: true,
+            image_ext: None,
+            manifest_name: None,
+            owner: None,
+            group: None,
+            max_versions: None,
+            delta: false,
+        };
+        assert_eq!(parse_slice(
+            &["tako", "store", "-ksecret", "-o", "/srv", "--embed-public-key", "out.img", "3.7.5"]
+        ), Ok(Cmd::Store(store)));
+    }
+
+    #[test]
+    fn parse_parses_store_image_ext_and_manifest_name() {
+        let store = Store {
+            secret_keys: vec!["secret".to_string()],
+            secret_key_paths: Vec::new(),
+            secret_key_dirs: Vec::new(),
+            secret_key_envs: Vec::new(),
+            config_path: None,
+            output_path: Some(PathBuf::from("/srv")),
+            version: Version::from("3.7.5"),
+            image_path: Some(PathBuf::from("out.img")),
+            annotations: Vec::new(),
+            expect_digest: None,
+            image_base: None,
+            name: None,
+            embed_public_key: false,
+            image_ext: Some(".img".to_string()),
+            manifest_name: Some("manifest.txt".to_string()),
+            owner: None,
+            group: None,
+            max_versions: None,
+            delta: false,
+        };
+        assert_eq!(parse_slice(
+            &["tako", "store", "-ksecret", "-o", "/srv", "--image-ext", ".img",
+              "--manifest-name", "manifest.txt", "out.img", "3.7.5"]
+        ), Ok(Cmd::Store(store)));
+        assert!(parse_slice(&["tako", "store", "-ksecret", "-o", "/srv", "--manifest-name=a/b", "out.img", "3.7.5"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_store_max_versions() {
+        match parse_slice(&["tako", "store", "-ksecret", "-o", "/srv", "--max-versions", "5", "out.img", "3.7.5"]) {
+            Ok(Cmd::Store(store)) => assert_eq!(store.max_versions, Some(5)),
+            other => panic!("Unexpected parse result: {:?}", other),
+        }
+        assert!(parse_slice(&["tako", "store", "-ksecret", "-o", "/srv", "--max-versions=0", "out.img", "3.7.5"]).is_err());
+        assert!(parse_slice(&["tako", "store", "-ksecret", "-o", "/srv", "--max-versions=x", "out.img", "3.7.5"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_store_delta() {
+        match parse_slice(&["tako", "store", "-ksecret", "-o", "/srv", "--delta", "out.img", "3.7.5"]) {
+            Ok(Cmd::Store(store)) => assert!(store.delta),
+            other => panic!("Unexpected parse result: {:?}", other),
+        }
+        assert!(parse_slice(&["tako", "store", "-ksecret", "-o", "/srv", "--delta", "--notes", "x", "3.7.5"]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_store_config() {
+        let store = Store {
+            secret_keys: Vec::new(),
+            secret_key_paths: vec![PathBuf::from("key")],
+            secret_key_dirs: Vec::new(),
+            secret_key_envs: Vec::new(),
+            config_path: Some(PathBuf::from("publish.conf")),
+            output_path: None,
+            version: Version::from("3.7.5"),
+            image_path: Some(PathBuf::from("out.img")),
+            annotations: Vec::new(),
+            expect_digest: None,
+            image_base: None,
+            name: None,
+            embed_public_key: false,
+            image_ext: None,
+            manifest_name: None,
+            owner: None,
+            group: None,
+            max_versions: None,
+            delta: false,
+        };
+        let expected = Ok(Cmd::Store(store));
+
+        // With a publish config, the server directory can be omitted.
+        assert_eq!(parse_slice(
+            &["tako", "store", "-c", "publish.conf", "-fkey", "out.img", "3.7.5"]
+        ), expected);
+        assert_eq!(parse_slice(
+            &["tako", "store", "out.img", "--config=publish.conf", "--key-file", "key", "3.7.5"]
+        ), expected);
+    }
+
+    #[test]
+    fn parse_parses_store_expect_digest() {
+        let hex = "9641a49d02e90cbb6213f202fb632da70cdc59073d42283cfcdc1d786454f17f";
+        let store = Store {
+            secret_keys: vec!["secret".to_string()],
+            secret_key_paths: Vec::new(),
+            secret_key_dirs: Vec::new(),
+            secret_key_envs: Vec::new(),
+            config_path: None,
+            output_path: Some(PathBuf::from("/tmp")),
+            version: Version::from("1.0.0"),
+            image_path: Some(PathBuf::from("https://example.com/1.0.0.img")),
+            annotations: Vec::new(),
+            expect_digest: Sha256::from_hex(hex.as_bytes()),
+            image_base: None,
+            name: None,
+            embed_public_key: false,
+            image_ext: None,
+            manifest_name: None,
+            owner: None,
+            group: None,
+            max_versions: None,
+            delta: false,
+        };
+        assert_eq!(parse_slice(&[
+            "tako", "store", "-k", "secret", "-o", "/tmp", "--expect-digest", hex,
+            "https://example.com/1.0.0.img", "1.0.0",
+        ]), Ok(Cmd::Store(store)));
+
+        // The digest must be complete and lowercase.
+        assert!(parse_slice(&[
+            "tako", "store", "-k", "secret", "-o", "/tmp", "--expect-digest", &hex[..62],
+            "out.img", "1.0.0",
+        ]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_store_annotate() {
+        let store = Store {
+            secret_keys: vec!["secret".to_string()],
+            secret_key_paths: Vec::new(),
+            secret_key_dirs: Vec::new(),
+            secret_key_envs: Vec::new(),
+            config_path: None,
+            output_path: Some(PathBuf::from("/tmp")),
+            version: Version::from("1.2.3"),
+            image_path: None,
+            annotations: vec![
+                ("label".to_string(), "stable".to_string()),
+                ("note".to_string(), "a=b c".to_string()),
+            ],
+            expect_digest: None,
+            image_base: None,
+            name: None,
+            embed_public_key: false,
+            image_ext: None,
+            manifest_name: None,
+            owner: None,
+            group: None,
+            max_versions: None,
+            delta: false,
+        };
+        assert_eq!(parse_slice(&[
+            "tako", "store", "-k", "secret", "-o", "/tmp",
+            "--annotate", "label=stable", "--annotate=note=a=b c", "1.2.3",
+        ]), Ok(Cmd::Store(store)));
+
+        // --notes sets the 'notes' annotation, on an existing version, or on
+        // the version that is stored.
+        let mut store = match parse_slice(&[
+            "tako", "store", "-k", "secret", "-o", "/tmp", "--notes", "Fixes the thing.", "1.2.3",
+        ]) {
+            Ok(Cmd::Store(store)) => store,
+            other => panic!("Expected store, got {:?}.", other),
+        };
+        assert_eq!(store.image_path, None);
+        assert_eq!(store.annotations, vec![("notes".to_string(), "Fixes the thing.".to_string())]);
+        store = match parse_slice(&[
+            "tako", "store", "-k", "secret", "-o", "/tmp", "--notes", "Fixes the thing.",
+            "out.img", "1.2.3",
+        ]) {
+            Ok(Cmd::Store(store)) => store,
+            other => panic!("Expected store, got {:?}.", other),
+        };
+        assert_eq!(store.image_path, Some(PathBuf::from("out.img")));
+        assert_eq!(store.version, Version::from("1.2.3"));
+        assert_eq!(store.annotations, vec![("notes".to_string(), "Fixes the thing.".to_string())]);
+
+        // An image cannot be combined with --annotate, and the key must be valid.
+        assert!(parse_slice(&[
+            "tako", "store", "-k", "secret", "-o", "/tmp", "--annotate", "label=stable",
+            "out.img", "1.2.3",
+        ]).is_err());
+        assert!(parse_slice(&[
+            "tako", "store", "-k", "secret", "-o", "/tmp", "--annotate", "=stable", "1.2.3",
+        ]).is_err());
+        assert!(parse_slice(&[
+            "tako", "store", "-k", "secret", "-o", "/tmp", "--annotate", "a label=x", "1.2.3",
+        ]).is_err());
+    }
+
+    #[test]
+    fn parse_parses_verify() {
+        let verify = Ok(Cmd::Verify(Verify {
+            config_fname: "foo.tako".to_string(),
+            version: Some(Version::from("1.2.0")),
+        }));
+        assert_eq!(parse_slice(&["tako", "verify", "foo.tako", "1.2.0"]), verify);
+
+        let verify_all = Ok(Cmd::Verify(Verify {
+            config_fname: "foo.tako".to_string(),
+            version: None,
+        }));
+        assert_eq!(parse_slice(&["tako", "verify", "--all", "foo.tako"]), verify_all);
+
+        assert!(parse_slice(&["tako", "verify", "foo.tako"]).is_err());
+        assert!(parse_slice(&["tako", "verify", "--all", "foo.tako", "1.2.0"]).is_err());
+        assert!(parse_slice(&["tako", "verify", "foo.tako", "1.2.0", "1.3.0"]).is_err());
     }
 }
@@ -7,16 +7,22 @@
 
 //! Configuration file parser.
 
-use std::path::PathBuf;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
 
 use base64;
+use ring;
 use ring::signature::Ed25519KeyPair;
 use untrusted::Input;
 
 use error::{Error, Result};
+use util;
+use util::Sha256;
 use version::Version;
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct PublicKey([u8; 32]);
 
 impl PublicKey {
@@ -29,15 +35,112 @@ impl PublicKey {
         bytes.copy_from_slice(pair.public_key_bytes());
         PublicKey(bytes)
     }
+
+    /// Parse a base64-encoded key, as printed by `tako gen-key`.
+    pub fn from_base64(key_base64: &str) -> Option<PublicKey> {
+        match base64::decode(key_base64) {
+            Ok(ref bytes) if bytes.len() == 32 => {
+                let mut result = [0_u8; 32];
+                result.copy_from_slice(&bytes[..]);
+                Some(PublicKey(result))
+            }
+            _ => None,
+        }
+    }
+
+    /// Format the key as base64, the format used in config files.
+    pub fn to_base64(&self) -> String {
+        base64::encode(&self.0[..])
+    }
+
+    /// Return the SHA256 digest of the key, to identify it compactly.
+    pub fn fingerprint(&self) -> Sha256 {
+        let digest = ring::digest::digest(&ring::digest::SHA256, &self.0[..]);
+        Sha256::copy_from_slice(digest.as_ref())
+    }
+}
+
+/// How fetch stops, starts, and restarts units, see `service`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RestartBackend {
+    Systemd,
+    OpenRc,
+
+    /// Run the command template from `RestartCommand=` for every unit.
+    Command(String),
 }
 
 #[derive(Debug)]
 pub struct Config {
+    /// The http or https uri to fetch from.
+    ///
+    /// For an `http+unix://` origin, this is an `http://localhost` uri with
+    /// the path part, and the socket is in `unix_socket_path`.
     pub origin: String,
-    pub public_key: PublicKey,
+    pub unix_socket_path: Option<PathBuf>,
+
+    /// The key that manifests must be signed with.
+    ///
+    /// If not set, fetch must run with `--tofu`, and trusts the key in the
+    /// first manifest that it fetches.
+    pub public_key: Option<PublicKey>,
     pub version: Version,
+
+    /// Versions that fetch considers, in addition to matching `version`: at
+    /// least the lower bound, and below the upper bound.
+    pub version_lower_bound: Option<Version>,
+    pub version_upper_bound: Option<Version>,
     pub destination: PathBuf,
+    pub manifest_timeout: Option<u64>,
+    pub image_timeout: Option<u64>,
     pub restart_units: Vec<String>,
+
+    /// Units to stop before `latest` changes, and to start afterwards.
+    pub stop_units: Vec<String>,
+
+    /// What acts on the units, systemd unless the config says otherwise.
+    pub restart_backend: RestartBackend,
+    pub pinned_certs: Vec<String>,
+
+    /// A PEM file and a directory of CA certificates to verify the origin
+    /// against, instead of the system trust store.
+    pub ca_cert: Option<PathBuf>,
+    pub ca_path: Option<PathBuf>,
+
+    /// Configs of images that must be updated together with this one.
+    ///
+    /// Relative paths are relative to the directory of this config.
+    pub requires: Vec<PathBuf>,
+
+    /// Command that must accept an image before it is installed.
+    pub verify_cmd: Option<String>,
+
+    /// Name that the manifest must declare, to catch an origin that points at
+    /// the wrong image.
+    pub name: Option<String>,
+
+    /// File name of the manifest at the origin, if it is not `manifest`.
+    pub manifest_name: Option<String>,
+
+    /// Uri of a signed index that pins the version of this image, and the
+    /// digest of its manifest, together with the images released with it.
+    pub index: Option<String>,
+
+    /// The key that the index must be signed with, if it is not `public_key`.
+    pub index_key: Option<PublicKey>,
+
+    /// Whether to flush files in the destination to disk before renaming them
+    /// into place. Fetch with `--no-fsync` disables it for every config.
+    pub fsync: bool,
+}
+
+/// Parse a version bound, which must be a version rather than a pattern.
+fn parse_version_bound(lineno: usize, value: &str) -> Result<Version> {
+    if value.is_empty() || value.contains('*') {
+        let msg = "Invalid version bound. Expected a version without wildcards, e.g. '2.0.0'.";
+        return Err(Error::InvalidConfig(lineno, msg))
+    }
+    Ok(Version::from(value))
 }
 
 fn parse_public_key(lineno: usize, key_base64: &str) -> Result<[u8; 32]> {
@@ -57,101 +160,637 @@ fn parse_public_key(lineno: usize, key_base64: &str) -> Result<[u8; 32]> {
     Ok(result)
 }
 
+/// Parse the origin, split off the socket path for `http+unix://` origins.
+///
+/// The syntax is `http+unix://<socket path>:<uri path>`, so the socket path
+/// cannot contain a colon. It cannot contain a NUL byte either, Curl takes it
+/// as a C string.
+fn parse_origin(lineno: usize, origin: &str) -> Result<(String, Option<PathBuf>)> {
+    let prefix = "http+unix://";
+    if !origin.starts_with(prefix) {
+        return Ok((String::from(origin), None))
+    }
+
+    let msg = "Invalid unix socket origin. \
+        Expected 'http+unix://<socket path>:<uri path>', \
+        e.g. 'http+unix:///run/registry.sock:/app'.";
+    let rest = &origin[prefix.len()..];
+    match rest.find(':') {
+        Some(n) if n > 0 && rest[n + 1..].starts_with("/") && !rest[..n].contains('\0') => {
+            let uri = format!("http://localhost{}", &rest[n + 1..]);
+            Ok((uri, Some(PathBuf::from(&rest[..n]))))
+        }
+        _ => Err(Error::InvalidConfig(lineno, msg)),
+    }
+}
+
+/// Translate an `oci://` origin into an http or https uri, and the tag, if any.
+///
+/// The syntax is `oci://<registry>/<repository>[:<tag>]`, like an OCI image
+/// reference. The origin is `https://<registry>/<repository>`, or http for a
+/// registry on localhost, as OCI tools assume for local registries. The tag
+/// pins the version. Returns None for origins that are not `oci://`.
+fn parse_oci_origin(lineno: usize, origin: &str) -> Result<Option<(String, Option<Version>)>> {
+    let prefix = "oci://";
+    if !origin.starts_with(prefix) {
+        return Ok(None)
+    }
+
+    let msg = "Invalid OCI origin. \
+        Expected 'oci://<registry>/<repository>[:<tag>]', \
+        e.g. 'oci://registry.example.com/team/app:1.2.0'. \
+        References by digest are not supported.";
+    let reference = &origin[prefix.len()..];
+    let (registry, repository) = match reference.find('/') {
+        Some(n) if n > 0 && n + 1 < reference.len() => (&reference[..n], &reference[n + 1..]),
+        _ => return Err(Error::InvalidConfig(lineno, msg)),
+    };
+    if repository.contains('@') || repository.ends_with("/") {
+        return Err(Error::InvalidConfig(lineno, msg))
+    }
+
+    // The registry may have a port, so the tag is after the last colon in the
+    // repository, not in the reference as a whole.
+    let (repository, tag) = match repository.rfind(':') {
+        Some(n) if n + 1 < repository.len() => (&repository[..n], Some(Version::from(&repository[n + 1..]))),
+        Some(..) => return Err(Error::InvalidConfig(lineno, msg)),
+        None => (repository, None),
+    };
+
+    let host = match registry.rfind(':') {
+        Some(n) if !registry.ends_with("]") => &registry[..n],
+        _ => registry,
+    };
+    let scheme = match host {
+        "localhost" | "127.0.0.1" | "[::1]" => "http",
+        _ => "https",
+    };
+
+    Ok(Some((format!("{}://{}/{}", scheme, registry, repository), tag)))
+}
+
+fn parse_pinned_cert(lineno: usize, pin: &str) -> Result<()> {
+    let msg = "Pinned certificate must be the base64-encoded SHA256 digest of \
+        the public key of the origin, formatted as 'sha256//<base64>'.";
+    if !pin.starts_with("sha256//") {
+        return Err(Error::InvalidConfig(lineno, msg))
+    }
+    match base64::decode(&pin[8..]) {
+        Ok(ref bytes) if bytes.len() == 32 => Ok(()),
+        _ => Err(Error::InvalidConfig(lineno, msg)),
+    }
+}
+
+fn parse_timeout(lineno: usize, timeout: &str) -> Result<u64> {
+    match util::parse_duration(timeout) {
+        Some(seconds) => Ok(seconds),
+        None => {
+            let msg = "Invalid timeout. Expected a number of seconds, \
+                optionally with suffix 's', 'm', or 'h', e.g. '30s' or '5m'.";
+            Err(Error::InvalidConfig(lineno, msg))
+        }
+    }
+}
+
+/// Return whether the name can be the file name of a manifest, a name without
+/// slashes or whitespace, that is not `.` or `..`.
+pub fn is_manifest_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." &&
+        !name.contains('/') && !name.contains(char::is_whitespace)
+}
+
+fn parse_manifest_name(lineno: usize, name: &str) -> Result<String> {
+    if is_manifest_name(name) {
+        Ok(String::from(name))
+    } else {
+        let msg = "Invalid ManifestName. Expected a file name, such as 'manifest.txt'.";
+        Err(Error::InvalidConfig(lineno, msg))
+    }
+}
+
+/// Call `on_pair` with the line number, key, and value of every line.
+///
+/// Blank lines and comments are skipped. This is the syntax that all of Tako's
+/// config files share, they differ only in the keys that they accept.
+pub fn for_each_pair<I, S, F>(lines: I, on_pair: F) -> Result<()>
+where I: IntoIterator<Item = S>,
+      S: AsRef<str>,
+      F: FnMut(usize, &str, &str) -> Result<()> {
+    match visit_pairs(lines, on_pair, true).into_iter().next() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Call `on_pair` for every line, and collect the errors.
+///
+/// With `fail_fast`, stop at the first error. Otherwise, continue with the next
+/// line, so a config with several mistakes can be fixed in one go.
+fn visit_pairs<I, S, F>(lines: I, mut on_pair: F, fail_fast: bool) -> Vec<Error>
+where I: IntoIterator<Item = S>,
+      S: AsRef<str>,
+      F: FnMut(usize, &str, &str) -> Result<()> {
+    let mut errors = Vec::new();
+    for (i, line_raw) in lines.into_iter().enumerate() {
+        if fail_fast && !errors.is_empty() {
+            break
+        }
+
+        // Line numbers in error messages are 1-based, like in editors.
+        let lineno = i + 1;
+
+        // Templating tools tend to indent, so whitespace around the line, and
+        // around keys and values, carries no meaning.
+        let line = line_raw.as_ref().trim();
+
+        // Allow empty lines in the config file.
+        if line.is_empty() {
+            continue
+        }
+
+        // Skip lines starting with '#' or ';' to allow comments. This is
+        // consistent with systemd's comment syntax. A '#' elsewhere in the
+        // line is part of the value, such as the fragment of a url.
+        if line.starts_with("#") || line.starts_with(";") {
+            continue
+        }
+
+        if let Some(n) = line.find('=') {
+            if let Err(err) = on_pair(lineno, line[..n].trim(), line[n + 1..].trim()) {
+                errors.push(err);
+            }
+        } else {
+            let msg = "Line contains no '='. \
+                Expected 'Key=value'-like key-value pair.";
+            errors.push(Error::InvalidConfig(lineno, msg));
+        }
+    }
+
+    errors
+}
+
+/// Read the lines of a config file.
+pub fn read_lines<P: AsRef<Path>>(fname: P) -> Result<Vec<String>> {
+    let f = fs::File::open(fname)?;
+    let buf_reader = io::BufReader::new(f);
+    let lines: io::Result<Vec<String>> = buf_reader.lines().collect();
+    Ok(lines?)
+}
+
 impl Config {
     pub fn parse<'a, I, S>(lines: I) -> Result<Config>
+    where I: IntoIterator<Item = S>,
+          S: AsRef<str> {
+        Config::parse_impl(lines, true).map_err(|mut errors| errors.swap_remove(0))
+    }
+
+    /// Parse the config, but rather than stopping at the first error, report
+    /// every invalid line, and every missing key.
+    pub fn parse_all<I, S>(lines: I) -> ::std::result::Result<Config, Vec<Error>>
+    where I: IntoIterator<Item = S>,
+          S: AsRef<str> {
+        Config::parse_impl(lines, false)
+    }
+
+    /// Parse the config. On failure, return at least one error, and with
+    /// `fail_fast`, exactly one.
+    fn parse_impl<I, S>(lines: I, fail_fast: bool) -> ::std::result::Result<Config, Vec<Error>>
     where I: IntoIterator<Item = S>,
           S: AsRef<str> {
         let mut origin = None;
+        let mut unix_socket_path = None;
         let mut public_key = None;
         let mut version = None;
+        let mut version_lower_bound = None;
+        let mut version_upper_bound = None;
         let mut destination = None;
+        let mut manifest_timeout = None;
+        let mut image_timeout = None;
         let mut restart_units = Vec::new();
+        let mut stop_units = Vec::new();
+        let mut restart_backend = None;
+        let mut restart_command = None;
+        let mut pinned_certs = Vec::new();
+        let mut ca_cert = None;
+        let mut ca_path = None;
+        let mut requires = Vec::new();
+        let mut verify_cmd = None;
+        let mut name = None;
+        let mut manifest_name = None;
+        let mut index = None;
+        let mut index_key = None;
+        let mut fsync = true;
 
-        for (lineno, line_raw) in lines.into_iter().enumerate() {
-            let line = line_raw.as_ref();
-
-            // Allow empty lines in the config file.
-            if line.len() == 0 {
-                continue
-            }
+        // The tag of an `oci://` origin, and the line of the origin.
+        let mut oci_tag = None;
 
-            // Skip lines starting with '#' or ';' to allow comments. This is
-            // consistent with systemd's comment syntax.
-            if line.starts_with("#") || line.starts_with(";") {
-                continue
-            }
-
-            if let Some(n) = line.find('=') {
-                let key = &line[..n];
-                let value = &line[n + 1..];
-                match key {
-                    "Origin" => {
-                        origin = Some(String::from(value));
-                    }
-                    "PublicKey" => {
-                        public_key = Some(parse_public_key(lineno, value)?);
+        let mut errors = visit_pairs(lines, |lineno, key, value| {
+            match key {
+                "Origin" => match parse_oci_origin(lineno, value)? {
+                    Some((uri, tag)) => {
+                        origin = Some(uri);
+                        unix_socket_path = None;
+                        oci_tag = tag.map(|t| (lineno, t));
                     }
-                    "Version" => {
-                        version = Some(Version::from(value));
+                    None => {
+                        let (uri, socket_path) = parse_origin(lineno, value)?;
+                        origin = Some(uri);
+                        unix_socket_path = socket_path;
+                        oci_tag = None;
                     }
-                    "Destination" => {
-                        destination = Some(PathBuf::from(value));
+                },
+                "PublicKey" => {
+                    public_key = Some(parse_public_key(lineno, value)?);
+                }
+                "Version" => {
+                    version = Some(Version::from(value));
+                }
+                "VersionLowerBound" => {
+                    version_lower_bound = Some(parse_version_bound(lineno, value)?);
+                }
+                "VersionUpperBound" => {
+                    version_upper_bound = Some((lineno, parse_version_bound(lineno, value)?));
+                }
+                "Destination" => {
+                    destination = Some(PathBuf::from(value));
+                }
+                "ManifestTimeout" => {
+                    manifest_timeout = Some(parse_timeout(lineno, value)?);
+                }
+                "ImageTimeout" => {
+                    image_timeout = Some(parse_timeout(lineno, value)?);
+                }
+                "RestartUnit" => {
+                    restart_units.push(String::from(value));
+                }
+                "StopUnit" => {
+                    stop_units.push(String::from(value));
+                }
+                "RestartBackend" => match value {
+                    "systemd" | "openrc" | "command" => restart_backend = Some((lineno, String::from(value))),
+                    _ => {
+                        let msg = "Invalid RestartBackend. Expected 'systemd', 'openrc', or 'command'.";
+                        return Err(Error::InvalidConfig(lineno, msg))
                     }
-                    "RestartUnit" => {
-                        restart_units.push(String::from(value));
+                },
+                "RestartCommand" => {
+                    if !value.contains("%unit") {
+                        let msg = "Invalid RestartCommand. Expected a command with '%unit' \
+                            in place of the unit, e.g. '/usr/local/bin/restart %unit'.";
+                        return Err(Error::InvalidConfig(lineno, msg))
                     }
+                    restart_command = Some((lineno, String::from(value)));
+                }
+                "PinnedCert" => {
+                    parse_pinned_cert(lineno, value)?;
+                    pinned_certs.push(String::from(value));
+                }
+                "CaCert" => {
+                    ca_cert = Some(PathBuf::from(value));
+                }
+                "CaPath" => {
+                    ca_path = Some(PathBuf::from(value));
+                }
+                "Requires" => {
+                    requires.push(PathBuf::from(value));
+                }
+                "VerifyCmd" => {
+                    verify_cmd = Some(String::from(value));
+                }
+                "Name" => {
+                    name = Some(String::from(value));
+                }
+                "ManifestName" => {
+                    manifest_name = Some(parse_manifest_name(lineno, value)?);
+                }
+                "Index" => {
+                    index = Some((lineno, String::from(value)));
+                }
+                "IndexKey" => {
+                    index_key = Some((lineno, parse_public_key(lineno, value)?));
+                }
+                "Fsync" => match value {
+                    "true" => fsync = true,
+                    "false" => fsync = false,
                     _ => {
-                        let msg = "Unknown key. Expected one of \
-                            'Origin', 'PublicKey', 'Version', 'Destination', \
-                            or 'RestartUnit'.";
+                        let msg = "Invalid Fsync. Expected 'true' or 'false'.";
                         return Err(Error::InvalidConfig(lineno, msg))
                     }
+                },
+                _ => {
+                    let msg = "Unknown key. Expected one of \
+                        'Origin', 'PublicKey', 'Version', 'VersionLowerBound', \
+                        'VersionUpperBound', 'Destination', 'ManifestTimeout', \
+                        'ImageTimeout', 'RestartUnit', 'StopUnit', \
+                        'RestartBackend', 'RestartCommand', 'PinnedCert', \
+                        'CaCert', 'CaPath', 'Requires', 'VerifyCmd', 'Name', \
+                        'ManifestName', 'Index', 'IndexKey', or 'Fsync'.";
+                    return Err(Error::InvalidConfig(lineno, msg))
                 }
+            }
+            Ok(())
+        }, fail_fast);
+
+        if origin.is_none() {
+            errors.push(Error::IncompleteConfig(
+                "Origin not set. Expected 'Origin='-line."
+            ));
+        }
+        if let Some((lineno, tag)) = oci_tag {
+            if version.is_some() {
+                let msg = "The tag of an OCI origin sets the version, \
+                    so 'Version=' cannot be set as well.";
+                errors.push(Error::InvalidConfig(lineno, msg));
             } else {
-                let msg = "Line contains no '='. \
-                    Expected 'Origin=https://example.com'-like key-value pair.";
-                return Err(Error::InvalidConfig(lineno, msg))
+                version = Some(tag);
+            }
+        }
+        let restart_backend = match (restart_backend, restart_command) {
+            (None, None) => RestartBackend::Systemd,
+            (Some((_, ref b)), None) if b == "systemd" => RestartBackend::Systemd,
+            (Some((_, ref b)), None) if b == "openrc" => RestartBackend::OpenRc,
+            (Some((_, ref b)), Some((lineno, ref cmd))) if b == "command" => {
+                if !stop_units.is_empty() && !cmd.contains("%action") {
+                    let msg = "With 'StopUnit=', the RestartCommand needs '%action' \
+                        in place of 'stop', 'start', or 'restart'.";
+                    errors.push(Error::InvalidConfig(lineno, msg));
+                }
+                RestartBackend::Command(cmd.clone())
+            }
+            (Some((lineno, _)), None) => {
+                let msg = "RestartBackend=command needs a 'RestartCommand=' line.";
+                errors.push(Error::InvalidConfig(lineno, msg));
+                RestartBackend::Systemd
+            }
+            (_, Some((lineno, _))) => {
+                let msg = "RestartCommand is only used with 'RestartBackend=command'.";
+                errors.push(Error::InvalidConfig(lineno, msg));
+                RestartBackend::Systemd
+            }
+        };
+        // The index is found by the name of the image, and it is never trusted
+        // on first use, so it needs a key.
+        match (&index, &index_key) {
+            (&Some((lineno, _)), _) if name.is_none() => {
+                let msg = "Index= needs a 'Name=' line, the name of the image in the index.";
+                errors.push(Error::InvalidConfig(lineno, msg));
+            }
+            (&Some((lineno, _)), &None) if public_key.is_none() => {
+                let msg = "Index= needs a 'PublicKey=' or 'IndexKey=' line to verify the index with.";
+                errors.push(Error::InvalidConfig(lineno, msg));
+            }
+            (&None, &Some((lineno, _))) => {
+                let msg = "IndexKey is only used with 'Index='.";
+                errors.push(Error::InvalidConfig(lineno, msg));
+            }
+            _ => {}
+        }
+        if let (&Some(ref lower), &Some((lineno, ref upper))) = (&version_lower_bound, &version_upper_bound) {
+            if upper <= lower {
+                let msg = "VersionUpperBound must be greater than VersionLowerBound, \
+                    the upper bound is exclusive.";
+                errors.push(Error::InvalidConfig(lineno, msg));
             }
         }
+        if version.is_none() {
+            errors.push(Error::IncompleteConfig(
+                "Version not set. Expected 'Version='-line. \
+                Use 'Version=*' to accept any version."
+            ));
+        }
+        if destination.is_none() {
+            errors.push(Error::IncompleteConfig(
+                "Destination not set. Expected 'Destination=/path'-line."
+            ));
+        }
+        if !errors.is_empty() {
+            return Err(errors)
+        }
 
         let config = Config {
-            origin: match origin {
-                Some(o) => o,
-                None => return Err(Error::IncompleteConfig(
-                    "Origin not set. Expected 'Origin='-line."
-                )),
-            },
-            public_key: match public_key {
-                Some(k) => PublicKey(k),
-                None => return Err(Error::IncompleteConfig(
-                    "Public key not set. Expected 'PublicKey='-line."
-                )),
-            },
-            version: match version {
-                Some(v) => v,
-                None => return Err(Error::IncompleteConfig(
-                    "Version not set. Expected 'Version='-line. \
-                    Use 'Version=*' to accept any version."
-                )),
-            },
-            destination: match destination {
-                Some(d) => d,
-                None => return Err(Error::IncompleteConfig(
-                    "Destination not set. Expected 'Destination=/path'-line."
-                )),
-            },
+            origin: origin.unwrap(),
+            unix_socket_path: unix_socket_path,
+            public_key: public_key.map(PublicKey),
+            version: version.unwrap(),
+            version_lower_bound: version_lower_bound,
+            version_upper_bound: version_upper_bound.map(|(_, v)| v),
+            destination: destination.unwrap(),
+            manifest_timeout: manifest_timeout,
+            image_timeout: image_timeout,
             restart_units: restart_units,
+            stop_units: stop_units,
+            restart_backend: restart_backend,
+            pinned_certs: pinned_certs,
+            ca_cert: ca_cert,
+            ca_path: ca_path,
+            requires: requires,
+            verify_cmd: verify_cmd,
+            name: name,
+            manifest_name: manifest_name,
+            index: index.map(|(_, uri)| uri),
+            index_key: index_key.map(|(_, key)| PublicKey(key)),
+            fsync: fsync,
         };
 
         Ok(config)
     }
+
+    /// Print the config in canonical `Key=Value` format, the inverse of `parse`.
+    pub fn serialize(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("Origin=");
+        match self.unix_socket_path {
+            Some(ref socket_path) => {
+                // Undo the split that `parse_origin` made.
+                out.push_str("http+unix://");
+                out.push_str(&socket_path.to_string_lossy());
+                out.push(':');
+                out.push_str(&self.origin["http://localhost".len()..]);
+            }
+            None => out.push_str(&self.origin),
+        }
+        out.push('\n');
+        if let Some(ref public_key) = self.public_key {
+            out.push_str("PublicKey=");
+            out.push_str(&public_key.to_base64());
+            out.push('\n');
+        }
+        out.push_str("Version=");
+        out.push_str(self.version.as_str());
+        out.push('\n');
+        if let Some(ref lower) = self.version_lower_bound {
+            out.push_str("VersionLowerBound=");
+            out.push_str(lower.as_str());
+            out.push('\n');
+        }
+        if let Some(ref upper) = self.version_upper_bound {
+            out.push_str("VersionUpperBound=");
+            out.push_str(upper.as_str());
+            out.push('\n');
+        }
+        out.push_str("Destination=");
+        out.push_str(&self.destination.to_string_lossy());
+        out.push('\n');
+
+        if let Some(seconds) = self.manifest_timeout {
+            out.push_str(&format!("ManifestTimeout={}s\n", seconds));
+        }
+
+        if let Some(seconds) = self.image_timeout {
+            out.push_str(&format!("ImageTimeout={}s\n", seconds));
+        }
+
+        for unit in &self.restart_units {
+            out.push_str("RestartUnit=");
+            out.push_str(unit);
+            out.push('\n');
+        }
+
+        for unit in &self.stop_units {
+            out.push_str("StopUnit=");
+            out.push_str(unit);
+            out.push('\n');
+        }
+
+        match self.restart_backend {
+            RestartBackend::Systemd => {}
+            RestartBackend::OpenRc => out.push_str("RestartBackend=openrc\n"),
+            RestartBackend::Command(ref cmd) => {
+                out.push_str("RestartBackend=command\nRestartCommand=");
+                out.push_str(cmd);
+                out.push('\n');
+            }
+        }
+
+        for pin in &self.pinned_certs {
+            out.push_str("PinnedCert=");
+            out.push_str(pin);
+            out.push('\n');
+        }
+
+        if let Some(ref path) = self.ca_cert {
+            out.push_str("CaCert=");
+            out.push_str(&path.to_string_lossy());
+            out.push('\n');
+        }
+
+        if let Some(ref path) = self.ca_path {
+            out.push_str("CaPath=");
+            out.push_str(&path.to_string_lossy());
+            out.push('\n');
+        }
+
+        for path in &self.requires {
+            out.push_str("Requires=");
+            out.push_str(&path.to_string_lossy());
+            out.push('\n');
+        }
+
+        if let Some(ref cmd) = self.verify_cmd {
+            out.push_str("VerifyCmd=");
+            out.push_str(cmd);
+            out.push('\n');
+        }
+
+        if let Some(ref name) = self.name {
+            out.push_str("Name=");
+            out.push_str(name);
+            out.push('\n');
+        }
+
+        if let Some(ref manifest_name) = self.manifest_name {
+            out.push_str("ManifestName=");
+            out.push_str(manifest_name);
+            out.push('\n');
+        }
+
+        if let Some(ref index) = self.index {
+            out.push_str("Index=");
+            out.push_str(index);
+            out.push('\n');
+        }
+
+        if let Some(ref index_key) = self.index_key {
+            out.push_str("IndexKey=");
+            out.push_str(&index_key.to_base64());
+            out.push('\n');
+        }
+
+        if !self.fsync {
+            out.push_str("Fsync=false\n");
+        }
+
+        out
+    }
+}
+
+/// Settings for `tako store` that can be read from a file.
+///
+/// All keys are optional, command-line flags take precedence.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct PublishConfig {
+    pub output_path: Option<PathBuf>,
+    pub secret_key_path: Option<PathBuf>,
+    pub image_base: Option<String>,
+    pub name: Option<String>,
+    pub image_ext: Option<String>,
+    pub manifest_name: Option<String>,
+    pub max_versions: Option<usize>,
+}
+
+impl PublishConfig {
+    pub fn parse<I, S>(lines: I) -> Result<PublishConfig>
+    where I: IntoIterator<Item = S>,
+          S: AsRef<str> {
+        let mut config = PublishConfig::default();
+
+        for_each_pair(lines, |lineno, key, value| {
+            match key {
+                "Output" => {
+                    config.output_path = Some(PathBuf::from(value));
+                }
+                "KeyFile" => {
+                    config.secret_key_path = Some(PathBuf::from(value));
+                }
+                "ImageBase" => {
+                    config.image_base = Some(String::from(value));
+                }
+                "Name" => {
+                    config.name = Some(String::from(value));
+                }
+                "ImageExt" => {
+                    config.image_ext = Some(String::from(value));
+                }
+                "ManifestName" => {
+                    config.manifest_name = Some(parse_manifest_name(lineno, value)?);
+                }
+                "MaxVersions" => match value.parse() {
+                    Ok(n) if n >= 1 => config.max_versions = Some(n),
+                    _ => {
+                        let msg = "Invalid MaxVersions. Expected a number of at least 1.";
+                        return Err(Error::InvalidConfig(lineno, msg))
+                    }
+                },
+                _ => {
+                    let msg = "Unknown key. Expected 'Output', 'KeyFile', 'ImageBase', 'Name', \
+                               'ImageExt', 'ManifestName', or 'MaxVersions'.";
+                    return Err(Error::InvalidConfig(lineno, msg))
+                }
+            }
+            Ok(())
+        })?;
+
+        Ok(config)
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use std::path::Path;
+    use std::path::{Path, PathBuf};
 
-    use super::Config;
+    use super::{Config, PublicKey, PublishConfig, RestartBackend};
+    use error::Error;
     use version::Version;
 
     #[test]
@@ -164,11 +803,23 @@ mod test {
         ];
         let config = Config::parse(&config_lines).unwrap();
         assert_eq!(&config.origin[..], "https://images.example.com/app-foo");
-        assert_eq!(config.public_key.0[..4], [0xf3, 0xea, 0xf9, 0x0c]);
+        assert_eq!(config.public_key.unwrap().0[..4], [0xf3, 0xea, 0xf9, 0x0c]);
         assert_eq!(config.destination.as_path(), Path::new("/var/lib/images/app-foo"));
         assert_eq!(config.version, Version::from("*"));
     }
 
+    #[test]
+    pub fn config_without_public_key_is_parsed() {
+        let config_lines = [
+            "Origin=https://images.example.com/app-foo",
+            "Destination=/var/lib/images/app-foo",
+            "Version=*",
+        ];
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.public_key, None);
+        assert!(!config.serialize().contains("PublicKey="));
+    }
+
     #[test]
     pub fn config_with_1_restart_units_is_parsed() {
         let config_lines = [
@@ -196,6 +847,384 @@ mod test {
         assert_eq!(&config.restart_units[..], &["foo", "bar"]);
     }
 
+    #[test]
+    pub fn config_with_stop_units_is_parsed() {
+        let config_lines = [
+            "Origin=https://images.example.com/app-foo",
+            "PublicKey=8+r5DKNN/cwI+h0oHxMtgdyND3S/5xDLHQu0hFUmq+g=",
+            "Version=1.*",
+            "Destination=/var/lib/images/app-foo",
+            "StopUnit=app.service",
+            "RestartUnit=foo",
+            "StopUnit=db.service",
+        ];
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(&config.stop_units[..], &["app.service", "db.service"]);
+        assert_eq!(&config.restart_units[..], &["foo"]);
+    }
+
+    #[test]
+    pub fn config_with_requires_is_parsed() {
+        let config_lines = [
+            "Origin=https://images.example.com/app-foo",
+            "PublicKey=8+r5DKNN/cwI+h0oHxMtgdyND3S/5xDLHQu0hFUmq+g=",
+            "Version=1.*",
+            "Destination=/var/lib/images/app-foo",
+            "Requires=app-bar.tako",
+            "Requires=/etc/tako/app-baz.tako",
+        ];
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(
+            &config.requires[..],
+            &[PathBuf::from("app-bar.tako"), PathBuf::from("/etc/tako/app-baz.tako")]
+        );
+        assert!(config.serialize().ends_with(
+            "Requires=app-bar.tako\nRequires=/etc/tako/app-baz.tako\n"
+        ));
+    }
+
+    #[test]
+    pub fn config_with_name_is_parsed() {
+        let config_lines = [
+            "Origin=https://images.example.com/app-foo",
+            "Version=*",
+            "Destination=/var/lib/images/app-foo",
+            "Name=app-foo",
+        ];
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.name, Some("app-foo".to_string()));
+        assert!(config.serialize().ends_with("\nName=app-foo\n"));
+    }
+
+    #[test]
+    pub fn config_with_restart_backend_is_parsed() {
+        let base = [
+            "Origin=https://images.example.com/app-foo",
+            "Version=*",
+            "Destination=/var/lib/images/app-foo",
+        ];
+        let parse_with = |extra: &[&str]| {
+            let lines: Vec<&str> = base.iter().chain(extra.iter()).cloned().collect();
+            Config::parse(&lines)
+        };
+
+        assert_eq!(parse_with(&[]).unwrap().restart_backend, RestartBackend::Systemd);
+        assert_eq!(parse_with(&["RestartBackend=systemd"]).unwrap().restart_backend, RestartBackend::Systemd);
+
+        let config = parse_with(&["RestartBackend=openrc"]).unwrap();
+        assert_eq!(config.restart_backend, RestartBackend::OpenRc);
+        assert!(config.serialize().ends_with("\nRestartBackend=openrc\n"));
+
+        let config = parse_with(&["RestartBackend=command", "RestartCommand=/usr/bin/svc %unit"]).unwrap();
+        assert_eq!(config.restart_backend, RestartBackend::Command("/usr/bin/svc %unit".to_string()));
+        assert!(config.serialize().ends_with("\nRestartBackend=command\nRestartCommand=/usr/bin/svc %unit\n"));
+
+        assert!(parse_with(&["RestartBackend=upstart"]).is_err());
+        assert!(parse_with(&["RestartBackend=command"]).is_err());
+        assert!(parse_with(&["RestartCommand=/usr/bin/svc %unit"]).is_err());
+        assert!(parse_with(&["RestartBackend=command", "RestartCommand=/usr/bin/svc"]).is_err());
+        assert!(parse_with(&["RestartBackend=command", "RestartCommand=/usr/bin/svc %unit", "StopUnit=a"]).is_err());
+        assert!(parse_with(&["RestartBackend=command", "RestartCommand=/usr/bin/svc %action %unit", "StopUnit=a"]).is_ok());
+    }
+
+    #[test]
+    pub fn config_with_index_is_parsed() {
+        let base = [
+            "Origin=https://images.example.com/app-foo",
+            "Version=*",
+            "Destination=/var/lib/images/app-foo",
+        ];
+        let parse_with = |extra: &[&str]| {
+            let lines: Vec<&str> = base.iter().chain(extra.iter()).cloned().collect();
+            Config::parse(&lines)
+        };
+
+        let config = parse_with(&[
+            "Name=app-foo",
+            "Index=https://images.example.com/index",
+            "IndexKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=",
+        ]).unwrap();
+        assert_eq!(config.index, Some("https://images.example.com/index".to_string()));
+        assert_eq!(config.index_key, PublicKey::from_base64("l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k="));
+        assert!(config.serialize().ends_with(
+            "\nIndex=https://images.example.com/index\n\
+            IndexKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=\n"
+        ));
+
+        // The index needs a name to look up, and a key.
+        assert!(parse_with(&["Index=https://images.example.com/index"]).is_err());
+        assert!(parse_with(&["Name=app-foo", "Index=https://images.example.com/index"]).is_err());
+        assert!(parse_with(&["IndexKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k="]).is_err());
+    }
+
+    #[test]
+    pub fn config_with_manifest_name_is_parsed() {
+        let config_lines = [
+            "Origin=https://images.example.com/app-foo",
+            "Version=*",
+            "Destination=/var/lib/images/app-foo",
+            "ManifestName=manifest.txt",
+        ];
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.manifest_name, Some("manifest.txt".to_string()));
+        assert!(config.serialize().ends_with("\nManifestName=manifest.txt\n"));
+
+        for name in &["", "..", "store/manifest", "the manifest"] {
+            let config_lines = [
+                "Origin=https://images.example.com/app-foo",
+                "Version=*",
+                "Destination=/var/lib/images/app-foo",
+                &format!("ManifestName={}", name)[..],
+            ];
+            assert!(Config::parse(&config_lines).is_err());
+        }
+    }
+
+    #[test]
+    pub fn config_with_unix_socket_origin_is_parsed() {
+        let config_lines = [
+            "Origin=http+unix:///run/registry.sock:/app",
+            "PublicKey=8+r5DKNN/cwI+h0oHxMtgdyND3S/5xDLHQu0hFUmq+g=",
+            "Version=*",
+            "Destination=/var/lib/images/app-foo",
+        ];
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(&config.origin[..], "http://localhost/app");
+        assert_eq!(config.unix_socket_path, Some(PathBuf::from("/run/registry.sock")));
+
+        // Serializing restores the original origin.
+        assert!(config.serialize().starts_with("Origin=http+unix:///run/registry.sock:/app\n"));
+    }
+
+    #[test]
+    pub fn config_with_oci_origin_is_parsed() {
+        let config_lines = [
+            "Origin=oci://registry.example.com:5000/team/app:1.2.0",
+            "PublicKey=8+r5DKNN/cwI+h0oHxMtgdyND3S/5xDLHQu0hFUmq+g=",
+            "Destination=/var/lib/images/app-foo",
+        ];
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(&config.origin[..], "https://registry.example.com:5000/team/app");
+        assert_eq!(config.version, Version::from("1.2.0"));
+
+        // Without tag, the version comes from 'Version=', and a local registry
+        // is served over http.
+        let config = Config::parse(&[
+            "Origin=oci://localhost:5000/app",
+            "Version=1.*",
+            "Destination=/var/lib/images/app-foo",
+        ]).unwrap();
+        assert_eq!(&config.origin[..], "http://localhost:5000/app");
+        assert_eq!(config.version, Version::from("1.*"));
+
+        // The version cannot be set twice, and digests are not supported.
+        assert!(Config::parse(&[
+            "Origin=oci://registry.example.com/app:1.2.0",
+            "Version=1.*",
+            "Destination=/var/lib/images/app-foo",
+        ]).is_err());
+        for origin in &["oci://app", "oci:///app", "oci://registry.example.com/app:",
+                        "oci://registry.example.com/app@sha256:9641a49d"] {
+            let line = format!("Origin={}", origin);
+            assert!(Config::parse(&[&line[..], "Version=*", "Destination=/tmp"]).is_err());
+        }
+    }
+
+    #[test]
+    pub fn parse_all_reports_every_error() {
+        let config_lines = [
+            "Origin=https://images.example.com/app-foo",
+            "PublicKey=not-base64",
+            "ManifestTimeout=soon",
+            "Destination=/var/lib/images/app-foo",
+            "Versoin=*",
+        ];
+        // Errors on lines 2, 3, and 5, and then the missing 'Version='.
+        let linenos: Vec<Option<usize>> = Config::parse_all(&config_lines)
+            .unwrap_err()
+            .iter()
+            .map(|e| match *e {
+                Error::InvalidConfig(lineno, _) => Some(lineno),
+                Error::InvalidPublicKeyData(lineno, _) => Some(lineno),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(linenos, vec![Some(2), Some(3), Some(5), None]);
+
+        // The regular parser stops at the first error.
+        match Config::parse(&config_lines) {
+            Err(Error::InvalidPublicKeyData(2, _)) => {}
+            _ => panic!("Expected the error on line 2."),
+        }
+    }
+
+    #[test]
+    pub fn parse_rejects_invalid_unix_socket_origin() {
+        for origin in &[
+            "Origin=http+unix:///run/registry.sock",
+            "Origin=http+unix:///run/registry.sock:app",
+            "Origin=http+unix://:/app",
+            "Origin=http+unix:///run/registry\0.sock:/app",
+        ] {
+            match Config::parse(&[origin]) {
+                Err(Error::InvalidConfig(1, _)) => {}
+                _ => panic!("Origin should be rejected."),
+            }
+        }
+    }
+
+    #[test]
+    pub fn config_with_timeouts_is_parsed() {
+        let config_lines = [
+            "Origin=https://images.example.com/app-foo",
+            "PublicKey=8+r5DKNN/cwI+h0oHxMtgdyND3S/5xDLHQu0hFUmq+g=",
+            "Version=*",
+            "Destination=/var/lib/images/app-foo",
+            "ManifestTimeout=10",
+            "ImageTimeout=30m",
+        ];
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.manifest_timeout, Some(10));
+        assert_eq!(config.image_timeout, Some(1800));
+
+        let config_lines = [
+            "Origin=https://images.example.com/app-foo",
+            "PublicKey=8+r5DKNN/cwI+h0oHxMtgdyND3S/5xDLHQu0hFUmq+g=",
+            "Version=*",
+            "Destination=/var/lib/images/app-foo",
+            "ImageTimeout=soon",
+        ];
+        match Config::parse(&config_lines) {
+            Err(Error::InvalidConfig(5, _)) => {}
+            _ => panic!("Timeout should be rejected on line 5."),
+        }
+
+        let config_lines = [
+            "Origin=https://images.example.com/app-foo",
+            "PublicKey=8+r5DKNN/cwI+h0oHxMtgdyND3S/5xDLHQu0hFUmq+g=",
+            "Version=*",
+            "Destination=/var/lib/images/app-foo",
+            "ManifestTimeout=18446744073709552",
+        ];
+        match Config::parse(&config_lines) {
+            Err(Error::InvalidConfig(5, _)) => {}
+            _ => panic!("Overflowing timeout should be rejected on line 5."),
+        }
+    }
+
+    #[test]
+    pub fn config_with_pinned_cert_is_parsed() {
+        let config_lines = [
+            "Origin=https://images.example.com/app-foo",
+            "PublicKey=8+r5DKNN/cwI+h0oHxMtgdyND3S/5xDLHQu0hFUmq+g=",
+            "Version=*",
+            "Destination=/var/lib/images/app-foo",
+            "PinnedCert=sha256//YhKJKSzoTt2b5FP18fvpHo7fJYqQCjAa3HWY3tvRMwE=",
+        ];
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(
+            &config.pinned_certs[..],
+            &["sha256//YhKJKSzoTt2b5FP18fvpHo7fJYqQCjAa3HWY3tvRMwE="]
+        );
+    }
+
+    #[test]
+    pub fn config_with_ca_cert_is_parsed() {
+        let config_lines = [
+            "Origin=https://images.example.com/app-foo",
+            "PublicKey=8+r5DKNN/cwI+h0oHxMtgdyND3S/5xDLHQu0hFUmq+g=",
+            "Version=*",
+            "Destination=/var/lib/images/app-foo",
+            "CaCert=/etc/tako/internal-ca.pem",
+            "CaPath=/etc/tako/ca.d",
+        ];
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.ca_cert, Some(PathBuf::from("/etc/tako/internal-ca.pem")));
+        assert_eq!(config.ca_path, Some(PathBuf::from("/etc/tako/ca.d")));
+        assert!(config.serialize().ends_with("CaCert=/etc/tako/internal-ca.pem\nCaPath=/etc/tako/ca.d\n"));
+    }
+
+    #[test]
+    pub fn config_with_version_bounds_is_parsed() {
+        let mut config_lines = [
+            "Origin=https://images.example.com/app-foo",
+            "Version=*",
+            "VersionLowerBound=2.0.0",
+            "VersionUpperBound=3.0.0",
+            "Destination=/var/lib/images/app-foo",
+        ];
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.version_lower_bound, Some(Version::from("2.0.0")));
+        assert_eq!(config.version_upper_bound, Some(Version::from("3.0.0")));
+        assert!(config.serialize().contains(
+            "Version=*\nVersionLowerBound=2.0.0\nVersionUpperBound=3.0.0\nDestination="
+        ));
+
+        // The upper bound is exclusive, so it must be above the lower bound.
+        config_lines[3] = "VersionUpperBound=2.0.0";
+        match Config::parse(&config_lines) {
+            Err(Error::InvalidConfig(4, _)) => {}
+            other => panic!("Expected error on line 4, got {:?}.", other),
+        }
+
+        config_lines[3] = "VersionUpperBound=3.*";
+        assert!(Config::parse(&config_lines).is_err());
+    }
+
+    #[test]
+    pub fn parse_rejects_invalid_public_key() {
+        let mut config_lines = [
+            "Origin=https://images.example.com/app-foo",
+            "Version=*",
+            "PublicKey=8+r5DKNN/cwI+h0oHxMtgdyND3S/5xDLHQu0hFUmq+g!",
+            "Destination=/var/lib/images/app-foo",
+        ];
+        match Config::parse(&config_lines) {
+            Err(Error::InvalidPublicKeyData(3, _)) => {}
+            other => panic!("Expected invalid base64 on line 3, got {:?}.", other),
+        }
+
+        // Valid base64, but 31 bytes rather than 32.
+        config_lines[2] = "PublicKey=8+r5DKNN/cwI+h0oHxMtgdyND3S/5xDLHQu0hFUmqw==";
+        match Config::parse(&config_lines) {
+            Err(Error::InvalidConfig(3, _)) => {}
+            other => panic!("Expected a key of the wrong length on line 3, got {:?}.", other),
+        }
+    }
+
+    #[test]
+    pub fn parse_rejects_invalid_pinned_cert() {
+        let pins = [
+            // Not a sha256 pin.
+            "PinnedCert=/etc/ssl/origin.pem",
+            // Not 32 bytes.
+            "PinnedCert=sha256//YhKJKSzoTt2b5FP18fvpHo7fJYqQCjAa",
+            // Not base64.
+            "PinnedCert=sha256//YhKJKSzoTt2b5FP18fvpHo7fJYqQCjAa3HWY3tvRMw!!",
+        ];
+        for pin in &pins {
+            match Config::parse(&[pin]) {
+                Err(Error::InvalidConfig(1, ..)) => { /* This is expected. */ },
+                _ => panic!("Pinned cert should be rejected."),
+            }
+        }
+    }
+
+    #[test]
+    pub fn config_with_verify_cmd_is_parsed() {
+        let config_lines = [
+            "Origin=https://images.example.com/app-foo",
+            "PublicKey=8+r5DKNN/cwI+h0oHxMtgdyND3S/5xDLHQu0hFUmq+g=",
+            "Version=*",
+            "Destination=/var/lib/images/app-foo",
+            "VerifyCmd=/usr/bin/scan-image --strict \"$1\"",
+        ];
+        let config = Config::parse(&config_lines).unwrap();
+        let cmd = "/usr/bin/scan-image --strict \"$1\"";
+        assert_eq!(config.verify_cmd, Some(cmd.to_string()));
+        assert!(config.serialize().ends_with(&format!("VerifyCmd={}\n", cmd)));
+    }
+
     #[test]
     pub fn parse_skips_comments() {
         let config_lines = [
@@ -209,5 +1238,163 @@ mod test {
         assert!(Config::parse(&config_lines).is_ok());
     }
 
+    #[test]
+    pub fn parse_trims_whitespace_and_skips_indented_comments() {
+        let config_lines = [
+            "  # managed by ansible",
+            "",
+            "   ",
+            " Origin = https://images.example.com/app-foo#fragment ",
+            "\tPublicKey=8+r5DKNN/cwI+h0oHxMtgdyND3S/5xDLHQu0hFUmq+g=",
+            "    ; The image is pinned to the 1.x series.",
+            "Version =1.*",
+            "Destination= /var/lib/images/app-foo",
+        ];
+        let config = Config::parse(&config_lines).unwrap();
+        assert_eq!(config.origin, "https://images.example.com/app-foo#fragment");
+        assert_eq!(config.version, Version::from("1.*"));
+        assert_eq!(config.destination, PathBuf::from("/var/lib/images/app-foo"));
+        assert!(config.public_key.is_some());
+    }
+
+    #[test]
+    pub fn serialize_outputs_canonical_config() {
+        let config_lines = [
+            "# Keys can occur in any order.",
+            "RestartUnit=foo",
+            "ImageTimeout=2h",
+            "Destination=/var/lib/images/app-foo",
+            "Version=1.*",
+            "PublicKey=8+r5DKNN/cwI+h0oHxMtgdyND3S/5xDLHQu0hFUmq+g=",
+            "Origin=https://images.example.com/app-foo",
+            "RestartUnit=bar",
+        ];
+        let config = Config::parse(&config_lines).unwrap();
+        let expected = "Origin=https://images.example.com/app-foo\n\
+            PublicKey=8+r5DKNN/cwI+h0oHxMtgdyND3S/5xDLHQu0hFUmq+g=\n\
+            Version=1.*\n\
+            Destination=/var/lib/images/app-foo\n\
+            ImageTimeout=7200s\n\
+            RestartUnit=foo\n\
+            RestartUnit=bar\n";
+        assert_eq!(config.serialize(), expected);
+    }
+
+    #[test]
+    pub fn serialize_then_parse_is_identity() {
+        let config_lines = [
+            "Origin=https://images.example.com/app-foo",
+            "PublicKey=8+r5DKNN/cwI+h0oHxMtgdyND3S/5xDLHQu0hFUmq+g=",
+            "Version=*",
+            "Destination=/var/lib/images/app-foo",
+        ];
+        let serialized = Config::parse(&config_lines).unwrap().serialize();
+        let reparsed = Config::parse(serialized.lines()).unwrap();
+        assert_eq!(reparsed.serialize(), serialized);
+    }
+
+    #[test]
+    pub fn config_with_fsync_disabled_is_parsed() {
+        let config_lines = [
+            "Origin=https://images.example.com/app-foo",
+            "Version=*",
+            "Destination=/var/lib/images/app-foo",
+            "Fsync=false",
+        ];
+        let config = Config::parse(&config_lines).unwrap();
+        assert!(!config.fsync);
+        assert!(config.serialize().ends_with("Fsync=false\n"));
+        assert!(Config::parse(&config_lines[..3]).unwrap().fsync);
+
+        let invalid = ["Origin=https://images.example.com/app-foo", "Fsync=no"];
+        assert!(Config::parse(&invalid).is_err());
+    }
+
+    #[test]
+    pub fn parse_ignores_trailing_newline() {
+        let with_newline = "Origin=https://images.example.com/app-foo\n\
+            PublicKey=8+r5DKNN/cwI+h0oHxMtgdyND3S/5xDLHQu0hFUmq+g=\n\
+            Version=*\n\
+            Destination=/var/lib/images/app-foo\n";
+        let without_newline = &with_newline[..with_newline.len() - 1];
+        let config_a = Config::parse(with_newline.split('\n')).unwrap();
+        let config_b = Config::parse(without_newline.split('\n')).unwrap();
+        assert_eq!(config_a.serialize(), config_b.serialize());
+    }
+
+    #[test]
+    pub fn parse_rejects_blank_config_as_incomplete() {
+        let config_lines = ["", "", ""];
+        match Config::parse(&config_lines) {
+            Err(Error::IncompleteConfig(..)) => { /* This is expected. */ },
+            _ => panic!("Config should be rejected as incomplete."),
+        }
+    }
+
+    #[test]
+    pub fn parse_reports_1_based_line_numbers() {
+        let config_lines = [
+            "Origin=https://images.example.com/app-foo",
+            "",
+            "Wrong!",
+        ];
+        match Config::parse(&config_lines) {
+            Err(Error::InvalidConfig(3, ..)) => { /* This is expected. */ },
+            _ => panic!("Config should be rejected on line 3."),
+        }
+
+        let config_lines = ["Origin=https://images.example.com/app-foo\n"];
+        match Config::parse(config_lines[0].split('\n')) {
+            Err(Error::IncompleteConfig(..)) => { /* This is expected. */ },
+            _ => panic!("Config should be rejected as incomplete."),
+        }
+
+        let config_lines = ["Wrong!"];
+        match Config::parse(&config_lines) {
+            Err(Error::InvalidConfig(1, ..)) => { /* This is expected. */ },
+            _ => panic!("Config should be rejected on line 1."),
+        }
+    }
+
+    #[test]
+    pub fn publish_config_is_parsed() {
+        let config_lines = [
+            "# Comments are allowed, like in other configs.",
+            "Output=/srv/images/app-foo",
+            "KeyFile=/etc/tako/secret-key",
+            "ImageBase=https://cdn.example.com/app-foo",
+            "Name=app-foo",
+            "ImageExt=.img",
+            "ManifestName=manifest.txt",
+            "MaxVersions=10",
+        ];
+        let config = PublishConfig::parse(&config_lines).unwrap();
+        assert_eq!(config.output_path, Some(PathBuf::from("/srv/images/app-foo")));
+        assert_eq!(config.secret_key_path, Some(PathBuf::from("/etc/tako/secret-key")));
+        assert_eq!(config.image_base, Some("https://cdn.example.com/app-foo".to_string()));
+        assert_eq!(config.name, Some("app-foo".to_string()));
+        assert_eq!(config.image_ext, Some(".img".to_string()));
+        assert_eq!(config.manifest_name, Some("manifest.txt".to_string()));
+        assert_eq!(config.max_versions, Some(10));
+        assert!(PublishConfig::parse(&["MaxVersions=0"]).is_err());
+        assert!(PublishConfig::parse(&["ManifestName=../manifest"]).is_err());
+
+        // All keys are optional.
+        let config = PublishConfig::parse(&[""]).unwrap();
+        assert_eq!(config, PublishConfig::default());
+    }
+
+    #[test]
+    pub fn publish_config_rejects_fetch_keys() {
+        let config_lines = [
+            "Output=/srv/images/app-foo",
+            "Origin=https://images.example.com/app-foo",
+        ];
+        match PublishConfig::parse(&config_lines) {
+            Err(Error::InvalidConfig(2, ..)) => { /* This is expected. */ },
+            _ => panic!("Config should be rejected on line 2."),
+        }
+    }
+
     // TODO: Test error cases.
 }
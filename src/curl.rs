@@ -7,36 +7,100 @@
 
 //! Interface to libcurl. Not as bloated as the curl and curl-sys crates.
 
+use std::cmp;
 use std::ffi::{CStr, CString};
 use std::io;
+use std::io::Read;
 use std::mem;
 use std::os::raw;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+use std::rc::Rc;
 use std::slice;
+use std::str;
+use std::time;
 
 use error::{Error, Result};
 
 enum Curl {}
+enum CurlShare {}
+enum CurlSlist {}
 
 type CurlOption = raw::c_int;
+type CurlShareOption = raw::c_int;
 type CurlCode = raw::c_int;
 
+const CURLOPT_LOW_SPEED_LIMIT: CurlOption = 19;
+const CURLOPT_LOW_SPEED_TIME: CurlOption = 20;
+const CURLOPT_NOBODY: CurlOption = 44;
+const CURLOPT_UPLOAD: CurlOption = 46;
 const CURLOPT_FOLLOWLOCATION: CurlOption = 52;
+const CURLOPT_SSL_VERIFYPEER: CurlOption = 64;
 const CURLOPT_MAXREDIRS: CurlOption = 68;
 const CURLOPT_HTTP_VERSION: CurlOption = 84;
+const CURLOPT_FTP_CREATE_MISSING_DIRS: CurlOption = 110;
+const CURLOPT_TIMEOUT_MS: CurlOption = 155;
 const CURLOPT_TCP_FASTOPEN: CurlOption = 244;
 const CURLOPT_WRITEDATA: CurlOption = 10_001;
 const CURLOPT_ERRORBUFFER: CurlOption = 10_010;
 const CURLOPT_URL: CurlOption = 10_002;
+const CURLOPT_RANGE: CurlOption = 10_007;
+const CURLOPT_READDATA: CurlOption = 10_009;
+const CURLOPT_HTTPHEADER: CurlOption = 10_023;
+const CURLOPT_QUOTE: CurlOption = 10_028;
+const CURLOPT_HEADERDATA: CurlOption = 10_029;
+const CURLOPT_POSTQUOTE: CurlOption = 10_039;
+const CURLOPT_CAINFO: CurlOption = 10_065;
+const CURLOPT_CAPATH: CurlOption = 10_097;
+const CURLOPT_SHARE: CurlOption = 10_100;
+const CURLOPT_PINNEDPUBLICKEY: CurlOption = 10_230;
+const CURLOPT_UNIX_SOCKET_PATH: CurlOption = 10_231;
 const CURLOPT_WRITEFUNCTION: CurlOption = 20_011;
+const CURLOPT_READFUNCTION: CurlOption = 20_012;
+const CURLOPT_HEADERFUNCTION: CurlOption = 20_079;
+
+type CurlInfo = raw::c_int;
+
+const CURLINFO_RESPONSE_CODE: CurlInfo = 0x20_0002;
+const CURLINFO_TOTAL_TIME: CurlInfo = 0x30_0003;
+const CURLINFO_NAMELOOKUP_TIME: CurlInfo = 0x30_0004;
+const CURLINFO_CONNECT_TIME: CurlInfo = 0x30_0005;
+const CURLINFO_SIZE_DOWNLOAD: CurlInfo = 0x30_0008;
+const CURLINFO_SPEED_DOWNLOAD: CurlInfo = 0x30_0009;
+const CURLINFO_APPCONNECT_TIME: CurlInfo = 0x30_0021;
+const CURLINFO_NUM_CONNECTS: CurlInfo = 0x20_001a;
+const CURLINFO_HTTP_VERSION: CurlInfo = 0x20_002e;
+
+const CURLSHOPT_SHARE: CurlShareOption = 1;
+
+const CURL_LOCK_DATA_DNS: raw::c_int = 3;
+const CURL_LOCK_DATA_SSL_SESSION: raw::c_int = 4;
+const CURL_LOCK_DATA_CONNECT: raw::c_int = 5;
+
+const CURLE_OPERATION_TIMEDOUT: CurlCode = 28;
+const CURLE_REMOTE_FILE_NOT_FOUND: CurlCode = 78;
+
+const CURL_READFUNC_ABORT: usize = 0x1000_0000;
 
 const CURL_HTTP_VERSION_2TLS: raw::c_int = 4;
 
+/// The largest value that fits a long on every platform, which is only 32 bits
+/// on some.
+const MAX_LONG: u64 = 0x7fff_ffff;
+
 #[link(name = "curl")]
 extern {
     fn curl_easy_init() -> *mut Curl;
     fn curl_easy_cleanup(curl: *mut Curl);
     fn curl_easy_setopt(curl: *mut Curl, option: CurlOption, ...) -> CurlCode;
     fn curl_easy_perform(curl: *mut Curl) -> CurlCode;
+    fn curl_easy_getinfo(curl: *mut Curl, info: CurlInfo, ...) -> CurlCode;
+    fn curl_share_init() -> *mut CurlShare;
+    fn curl_share_setopt(share: *mut CurlShare, option: CurlShareOption, ...) -> raw::c_int;
+    fn curl_share_cleanup(share: *mut CurlShare) -> raw::c_int;
+    fn curl_slist_append(list: *mut CurlSlist, string: *const raw::c_char) -> *mut CurlSlist;
+    fn curl_slist_free_all(list: *mut CurlSlist);
 }
 
 type Handler<'a> = Box<'a + FnMut(&[u8])>;
@@ -51,8 +115,121 @@ extern "C" fn write_callback(ptr: *mut raw::c_char, size: usize, nmemb: usize, u
     len
 }
 
+type ReadHandler<'a> = Box<'a + FnMut(&mut [u8]) -> usize>;
+
+type ReadCallback = extern "C" fn(*mut raw::c_char, usize, usize, *mut raw::c_void) -> usize;
+
+extern "C" fn read_callback(ptr: *mut raw::c_char, size: usize, nitems: usize, userdata: *mut raw::c_void) -> usize {
+    let len = size * nitems;
+    let slice = unsafe { slice::from_raw_parts_mut(ptr as *mut u8, len) };
+    let handler: &mut ReadHandler = unsafe { mem::transmute(userdata) };
+    (*handler)(slice)
+}
+
+/// Validators of a response, to make a conditional request for it later.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// The outcome of a conditional download.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Conditional {
+    /// The server sent the file, with these validators.
+    Modified(Validators),
+
+    /// The file did not change since the validators that we sent.
+    NotModified,
+}
+
+/// Statistics of a single download, as Curl reports them.
+///
+/// Times are in seconds since the start of the transfer. The phases that did
+/// not happen, for instance the TLS handshake for plain http, or all of them
+/// for a reused connection, are zero.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transfer {
+    pub uri: String,
+    pub bytes: u64,
+    pub bytes_per_second: f64,
+    pub namelookup_time: f64,
+    pub connect_time: f64,
+    pub tls_time: f64,
+    pub total_time: f64,
+
+    /// The http version of the response, e.g. "1.1" or "2", empty if unknown.
+    pub http_version: &'static str,
+
+    /// The number of connections opened for the transfer. Zero means that it
+    /// reused a connection of an earlier transfer.
+    pub new_connections: u64,
+}
+
+/// Caches that multiple handles share: connections, name lookups, and TLS
+/// sessions.
+///
+/// Curl requires locking when handles on different threads share a cache. We
+/// do not provide that, instead every thread has its own `SHARE`.
+struct Share {
+    share: *mut CurlShare,
+}
+
+impl Share {
+    fn new() -> Share {
+        let share = unsafe { curl_share_init() };
+        assert!(!share.is_null(), "Failed to initialize Curl share.");
+
+        // Sharing connections requires Curl 7.57. If it is older, handles
+        // still share name lookups and TLS sessions, so ignore the result.
+        unsafe {
+            curl_share_setopt(share, CURLSHOPT_SHARE, CURL_LOCK_DATA_DNS);
+            curl_share_setopt(share, CURLSHOPT_SHARE, CURL_LOCK_DATA_SSL_SESSION);
+            curl_share_setopt(share, CURLSHOPT_SHARE, CURL_LOCK_DATA_CONNECT);
+        }
+
+        Share { share: share }
+    }
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        unsafe { curl_share_cleanup(self.share) };
+    }
+}
+
+thread_local! {
+    /// The caches of the handles on this thread that share connections.
+    static SHARE: Rc<Share> = Rc::new(Share::new());
+}
+
+/// If the header line is `<name>: <value>`, return the value.
+///
+/// Header names are case-insensitive.
+fn header_value(line: &[u8], name: &str) -> Option<String> {
+    let line = str::from_utf8(line).ok()?;
+    let colon = line.find(':')?;
+    if !line[..colon].eq_ignore_ascii_case(name) {
+        return None
+    }
+    Some(line[colon + 1..].trim().to_string())
+}
+
 pub struct Handle {
-    curl: *mut Curl
+    curl: *mut Curl,
+
+    /// Statistics of the downloads since the last `take_transfers`.
+    transfers: Vec<Transfer>,
+
+    /// Timeout of a single transfer in seconds, zero for no timeout.
+    timeout: u64,
+
+    /// Time after which no transfer may continue, see `set_deadline`.
+    deadline: Option<time::Instant>,
+
+    /// The caches that this handle shares, kept alive until the handle is
+    /// cleaned up, see `share_connections`.
+    share: Option<Rc<Share>>,
 }
 
 impl Handle {
@@ -61,7 +238,193 @@ impl Handle {
         assert!(!curl.is_null(), "Failed to initialize Curl.");
 
         Handle {
-            curl: curl
+            curl: curl,
+            transfers: Vec::new(),
+            timeout: 0,
+            deadline: None,
+            share: None,
+        }
+    }
+
+    /// Reuse connections, name lookups, and TLS sessions of other handles on
+    /// this thread that share them, and let them reuse those of this handle.
+    ///
+    /// Fetching multiple configs from one host then needs only one connection
+    /// and one TLS handshake. Do not call this for a handle with a pinned key,
+    /// it could reuse a connection whose key was never checked against the pin.
+    pub fn share_connections(&mut self) {
+        let share = SHARE.with(|share| share.clone());
+        unsafe { curl_easy_setopt(self.curl, CURLOPT_SHARE, share.share) };
+        self.share = Some(share);
+    }
+
+    /// Return the statistics of the downloads so far, failed ones included,
+    /// and forget them.
+    pub fn take_transfers(&mut self) -> Vec<Transfer> {
+        self.transfers.drain(..).collect()
+    }
+
+    fn get_info_long(&self, info: CurlInfo) -> raw::c_long {
+        let mut value: raw::c_long = 0;
+        unsafe { curl_easy_getinfo(self.curl, info, &mut value) };
+        value
+    }
+
+    fn get_info_double(&self, info: CurlInfo) -> f64 {
+        let mut value: raw::c_double = 0.0;
+        unsafe { curl_easy_getinfo(self.curl, info, &mut value) };
+        value
+    }
+
+    /// Record the statistics of the transfer that just completed.
+    fn record_transfer(&mut self, uri: &str) {
+        let transfer = Transfer {
+            uri: String::from(uri),
+            bytes: self.get_info_double(CURLINFO_SIZE_DOWNLOAD) as u64,
+            bytes_per_second: self.get_info_double(CURLINFO_SPEED_DOWNLOAD),
+            namelookup_time: self.get_info_double(CURLINFO_NAMELOOKUP_TIME),
+            connect_time: self.get_info_double(CURLINFO_CONNECT_TIME),
+            tls_time: self.get_info_double(CURLINFO_APPCONNECT_TIME),
+            total_time: self.get_info_double(CURLINFO_TOTAL_TIME),
+            http_version: match self.get_info_long(CURLINFO_HTTP_VERSION) {
+                1 => "1.0",
+                2 => "1.1",
+                3 => "2",
+                30 => "3",
+                _ => "",
+            },
+            new_connections: self.get_info_long(CURLINFO_NUM_CONNECTS) as u64,
+        };
+        self.transfers.push(transfer);
+    }
+
+    /// Only accept servers that present one of the given public keys.
+    ///
+    /// Pins are in Curl's format, `sha256//<base64>`.
+    pub fn set_pinned_public_keys(&mut self, pins: &[String]) -> Result<()> {
+        // Curl copies the string, so it need not outlive this call.
+        let pins_cstr = match CString::new(pins.join(";")) {
+            Ok(s) => s,
+            Err(..) => return Err(Error::OperationError("A pinned public key contains a NUL byte.")),
+        };
+        // Pinning is supported by all TLS backends that Curl commonly uses. If
+        // it is not, we must not continue without the pin.
+        let code = unsafe { curl_easy_setopt(self.curl, CURLOPT_PINNEDPUBLICKEY, pins_cstr.as_ptr()) };
+        if code != 0 {
+            return Err(Error::OperationError("Curl does not support public key pinning with its TLS backend."))
+        }
+        Ok(())
+    }
+
+    /// Enable or disable verification of the certificate chain of the peer.
+    ///
+    /// This does not disable hostname verification, nor public key pinning.
+    pub fn set_verify_peer(&mut self, verify: bool) {
+        unsafe { curl_easy_setopt(self.curl, CURLOPT_SSL_VERIFYPEER, verify as raw::c_long) };
+    }
+
+    /// Verify the certificate chain of the peer against the CA certificates in
+    /// the given PEM file, instead of the default trust store.
+    pub fn set_ca_info(&mut self, path: &Path) -> Result<()> {
+        // Curl copies the string, so it need not outlive this call.
+        let path_cstr = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let code = unsafe { curl_easy_setopt(self.curl, CURLOPT_CAINFO, path_cstr.as_ptr()) };
+        if code != 0 {
+            return Err(Error::OperationError("Curl does not support a CA bundle with its TLS backend."))
+        }
+        Ok(())
+    }
+
+    /// Verify the certificate chain of the peer against the CA certificates in
+    /// the given directory, which must be prepared with `c_rehash`.
+    ///
+    /// Not every TLS backend of Curl supports this, then it is an error, rather
+    /// than silently falling back to the default trust store.
+    pub fn set_ca_path(&mut self, path: &Path) -> Result<()> {
+        let path_cstr = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let code = unsafe { curl_easy_setopt(self.curl, CURLOPT_CAPATH, path_cstr.as_ptr()) };
+        if code != 0 {
+            return Err(Error::OperationError("Curl does not support a CA directory with its TLS backend."))
+        }
+        Ok(())
+    }
+
+    /// Connect to the given unix domain socket instead of over tcp.
+    ///
+    /// The host part of the uri is then only used for the Host header.
+    pub fn set_unix_socket_path(&mut self, path: &Path) -> Result<()> {
+        // Curl copies the string, so it need not outlive this call.
+        let path_cstr = match CString::new(path.as_os_str().as_bytes()) {
+            Ok(s) => s,
+            Err(..) => return Err(Error::OperationError("The unix socket path contains a NUL byte.")),
+        };
+        // If Curl was built without unix socket support, we cannot continue,
+        // because we would connect over tcp to localhost instead.
+        let code = unsafe { curl_easy_setopt(self.curl, CURLOPT_UNIX_SOCKET_PATH, path_cstr.as_ptr()) };
+        if code != 0 {
+            return Err(Error::OperationError("Curl does not support unix domain sockets."))
+        }
+        Ok(())
+    }
+
+    /// Abort transfers that take longer than the timeout in total.
+    ///
+    /// A timeout of zero disables the timeout. The setting applies to all
+    /// subsequent downloads.
+    pub fn set_timeout(&mut self, seconds: u64) {
+        self.timeout = seconds;
+    }
+
+    /// Abort transfers that are still running at the deadline, and refuse to
+    /// start new ones after it. Such transfers fail with `DeadlineExceeded`.
+    pub fn set_deadline(&mut self, deadline: time::Instant) {
+        self.deadline = Some(deadline);
+    }
+
+    pub fn deadline(&self) -> Option<time::Instant> {
+        self.deadline
+    }
+
+    fn is_past_deadline(&self) -> bool {
+        match self.deadline {
+            Some(deadline) => time::Instant::now() >= deadline,
+            None => false,
+        }
+    }
+
+    /// Set the timeout for the next transfer, the shorter of the timeout and
+    /// the time until the deadline.
+    fn apply_timeout(&mut self) -> Result<()> {
+        let mut timeout_ms = self.timeout.saturating_mul(1000);
+        if let Some(deadline) = self.deadline {
+            let now = time::Instant::now();
+            if now >= deadline {
+                return Err(Error::DeadlineExceeded)
+            }
+            let remaining = deadline - now;
+            // Round up, a timeout of zero would disable the timeout.
+            let remaining_ms = remaining.as_secs()
+                .saturating_mul(1000)
+                .saturating_add(remaining.subsec_nanos() as u64 / 1_000_000 + 1);
+            if timeout_ms == 0 || remaining_ms < timeout_ms {
+                timeout_ms = remaining_ms;
+            }
+        }
+        // This clamps to about 24 days, which is as good as no timeout.
+        let timeout_ms = cmp::min(timeout_ms, MAX_LONG);
+        unsafe { curl_easy_setopt(self.curl, CURLOPT_TIMEOUT_MS, timeout_ms as raw::c_long) };
+        Ok(())
+    }
+
+    /// Abort transfers that receive no data at all for the given duration.
+    ///
+    /// Unlike a total timeout, this does not depend on the size of the
+    /// download. A duration of zero disables the check.
+    pub fn set_stall_timeout(&mut self, seconds: u64) {
+        unsafe {
+            curl_easy_setopt(self.curl, CURLOPT_LOW_SPEED_LIMIT, 1 as raw::c_long);
+            let seconds = cmp::min(seconds, MAX_LONG);
+            curl_easy_setopt(self.curl, CURLOPT_LOW_SPEED_TIME, seconds as raw::c_long);
         }
     }
 
@@ -82,6 +445,29 @@ impl Handle {
     }
 
     pub fn download<'a, F>(&'a mut self, uri: &str, on_data: F) -> Result<()> where F: 'a + FnMut(&[u8]) {
+        if self.download_if_exists(uri, on_data)? {
+            Ok(())
+        } else {
+            Err(Error::DownloadError(format!("Remote file not found: {}", uri)))
+        }
+    }
+
+    /// Check whether the non-empty file at the uri exists.
+    ///
+    /// This downloads only the first byte. It works for protocols that report
+    /// missing files, such as sftp. An http server returns a page instead.
+    pub fn exists(&mut self, uri: &str) -> Result<bool> {
+        let range_cstr = CString::new("0-0").unwrap();
+        unsafe { curl_easy_setopt(self.curl, CURLOPT_RANGE, range_cstr.as_ptr()) };
+        let result = self.download_if_exists(uri, |_| ());
+        unsafe { curl_easy_setopt(self.curl, CURLOPT_RANGE, ptr::null::<raw::c_char>()) };
+        result
+    }
+
+    /// Download the uri, return false if the remote file does not exist.
+    ///
+    /// Like `exists`, this only applies to protocols that report missing files.
+    pub fn download_if_exists<'a, F>(&'a mut self, uri: &str, on_data: F) -> Result<bool> where F: 'a + FnMut(&[u8]) {
         // Box the handler, so we have a function to pass as userdata. We need
         // to box the handler, and then we pass a pointer to *this box on the
         // stack* as userdata. We cannot directly pass on_data as userdata,
@@ -90,6 +476,8 @@ impl Handle {
         // So pass a pointer to the box.
         let mut handler: Handler = Box::new(on_data);
 
+        self.apply_timeout()?;
+
         // TODO: Handle the error case (a null in the uri) better. For instance
         // by validating uris in the config parser.
         let uri_cstr = CString::new(uri).unwrap();
@@ -121,15 +509,204 @@ impl Handle {
 
             curl_easy_setopt(self.curl, CURLOPT_URL, uri_cstr.as_ptr());
 
-            if curl_easy_perform(self.curl) != 0 {
-                // Error. There should be something in the buffer.
-                let msg = CStr::from_ptr(error_buffer.as_ptr());
-                return Err(Error::DownloadError(msg.to_string_lossy().into_owned()));
+            let code = curl_easy_perform(self.curl);
+            self.record_transfer(uri);
+
+            match code {
+                0 => {}
+                CURLE_REMOTE_FILE_NOT_FOUND => return Ok(false),
+                CURLE_OPERATION_TIMEDOUT if self.is_past_deadline() => {
+                    return Err(Error::DeadlineExceeded)
+                }
+                _ => {
+                    // Error. There should be something in the buffer.
+                    let msg = CStr::from_ptr(error_buffer.as_ptr());
+                    return Err(Error::DownloadError(msg.to_string_lossy().into_owned()));
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Download the uri over http, unless it did not change since the response
+    /// that the validators were taken from.
+    ///
+    /// The server decides based on the validators, and responds with 304 Not
+    /// Modified if the file did not change. Then nothing is passed to
+    /// `on_data`.
+    pub fn download_conditional<'a, F>(
+        &'a mut self,
+        uri: &str,
+        validators: &Validators,
+        on_data: F,
+    ) -> Result<Conditional> where F: 'a + FnMut(&[u8]) {
+        // Curl copies the strings when appending, but it does not copy the
+        // list, so the list must outlive the transfer.
+        let mut request_headers = Vec::new();
+        if let Some(ref etag) = validators.etag {
+            request_headers.push(format!("If-None-Match: {}", etag));
+        }
+        if let Some(ref last_modified) = validators.last_modified {
+            request_headers.push(format!("If-Modified-Since: {}", last_modified));
+        }
+        let mut headers: *mut CurlSlist = ptr::null_mut();
+        for header in &request_headers {
+            let header_cstr = CString::new(&header[..]).unwrap();
+            headers = unsafe { curl_slist_append(headers, header_cstr.as_ptr()) };
+            assert!(!headers.is_null(), "Failed to allocate Curl header list.");
+        }
+
+        let mut response = Validators::default();
+        let result = {
+            let response_ref = &mut response;
+            // See `download` for why we pass a pointer to a box.
+            let mut header_handler: Handler = Box::new(|line: &[u8]| {
+                // After a redirect, only the headers of the final response count.
+                if line.starts_with(b"HTTP/") {
+                    *response_ref = Validators::default();
+                }
+                if let Some(etag) = header_value(line, "ETag") {
+                    response_ref.etag = Some(etag);
+                }
+                if let Some(last_modified) = header_value(line, "Last-Modified") {
+                    response_ref.last_modified = Some(last_modified);
+                }
+            });
+
+            unsafe {
+                let userdata: *mut raw::c_void = mem::transmute(&mut header_handler);
+                curl_easy_setopt(self.curl, CURLOPT_HTTPHEADER, headers);
+                curl_easy_setopt(self.curl, CURLOPT_HEADERFUNCTION, write_callback as WriteCallback);
+                curl_easy_setopt(self.curl, CURLOPT_HEADERDATA, userdata);
             }
+
+            let result = self.download(uri, on_data);
+
+            // Reset the options that point at data that is about to be freed.
+            // Without header function and header data, Curl drops headers.
+            unsafe {
+                curl_easy_setopt(self.curl, CURLOPT_HTTPHEADER, ptr::null_mut::<CurlSlist>());
+                curl_easy_setopt(self.curl, CURLOPT_HEADERFUNCTION, ptr::null::<raw::c_void>());
+                curl_easy_setopt(self.curl, CURLOPT_HEADERDATA, ptr::null::<raw::c_void>());
+                curl_slist_free_all(headers);
+            }
+
+            result
+        };
+        result?;
+
+        let mut response_code: raw::c_long = 0;
+        unsafe { curl_easy_getinfo(self.curl, CURLINFO_RESPONSE_CODE, &mut response_code) };
+
+        if response_code == 304 {
+            Ok(Conditional::NotModified)
+        } else {
+            Ok(Conditional::Modified(response))
+        }
+    }
+
+    /// Upload everything that the reader produces to the uri.
+    ///
+    /// Missing directories in the path of the uri are created. After a
+    /// successful upload, Curl executes the post commands on the server, for
+    /// instance `rename <from> <to>` for sftp. Commands with a `*` prefix may
+    /// fail without failing the upload.
+    pub fn upload<R: Read>(&mut self, uri: &str, reader: &mut R, post_commands: &[String]) -> Result<()> {
+        self.apply_timeout()?;
+        let uri_cstr = CString::new(uri).unwrap();
+        let error_buffer = [0 as raw::c_char; 256];
+        let mut read_result = Ok(());
+
+        let code = {
+            let read_result_ref = &mut read_result;
+            // See `download` for why we pass a pointer to a box.
+            let mut handler: ReadHandler = Box::new(|buffer: &mut [u8]| {
+                match reader.read(buffer) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        *read_result_ref = Err(e);
+                        CURL_READFUNC_ABORT
+                    }
+                }
+            });
+
+            // Curl copies the strings when appending, but it does not copy the
+            // list, so the list must outlive the transfer.
+            let mut commands: *mut CurlSlist = ptr::null_mut();
+            for command in post_commands {
+                let command_cstr = CString::new(&command[..]).unwrap();
+                commands = unsafe { curl_slist_append(commands, command_cstr.as_ptr()) };
+                assert!(!commands.is_null(), "Failed to allocate Curl command list.");
+            }
+
+            unsafe {
+                let userdata: *mut raw::c_void = mem::transmute(&mut handler);
+
+                curl_easy_setopt(self.curl, CURLOPT_UPLOAD, 1 as raw::c_long);
+                curl_easy_setopt(self.curl, CURLOPT_FTP_CREATE_MISSING_DIRS, 1 as raw::c_long);
+                curl_easy_setopt(self.curl, CURLOPT_READFUNCTION, read_callback as ReadCallback);
+                curl_easy_setopt(self.curl, CURLOPT_READDATA, userdata);
+                curl_easy_setopt(self.curl, CURLOPT_POSTQUOTE, commands);
+                curl_easy_setopt(self.curl, CURLOPT_ERRORBUFFER, error_buffer.as_ptr());
+                curl_easy_setopt(self.curl, CURLOPT_URL, uri_cstr.as_ptr());
+
+                let code = curl_easy_perform(self.curl);
+
+                // Reset the options that would affect later transfers on this
+                // handle, and that point at data that is about to be freed.
+                curl_easy_setopt(self.curl, CURLOPT_UPLOAD, 0 as raw::c_long);
+                curl_easy_setopt(self.curl, CURLOPT_POSTQUOTE, ptr::null_mut::<CurlSlist>());
+                curl_slist_free_all(commands);
+
+                code
+            }
+        };
+
+        // If reading failed, that is the cause of the Curl error.
+        read_result?;
+
+        if code != 0 {
+            let msg = unsafe { CStr::from_ptr(error_buffer.as_ptr()) };
+            return Err(Error::UploadError(msg.to_string_lossy().into_owned()));
         }
 
         Ok(())
     }
+
+    /// Execute commands on the server, without transferring a file.
+    ///
+    /// The uri must be a directory, with a trailing slash, such as
+    /// `sftp://host/srv/app/`. The commands are as for `upload`.
+    pub fn run_commands(&mut self, uri: &str, commands: &[String]) -> Result<()> {
+        // Curl copies the strings when appending, but it does not copy the
+        // list, so the list must outlive the transfer.
+        let mut list: *mut CurlSlist = ptr::null_mut();
+        for command in commands {
+            let command_cstr = CString::new(&command[..]).unwrap();
+            list = unsafe { curl_slist_append(list, command_cstr.as_ptr()) };
+            assert!(!list.is_null(), "Failed to allocate Curl command list.");
+        }
+
+        unsafe {
+            curl_easy_setopt(self.curl, CURLOPT_QUOTE, list);
+            curl_easy_setopt(self.curl, CURLOPT_NOBODY, 1 as raw::c_long);
+        }
+
+        let result = self.download(uri, |_| ());
+
+        unsafe {
+            curl_easy_setopt(self.curl, CURLOPT_QUOTE, ptr::null_mut::<CurlSlist>());
+            curl_easy_setopt(self.curl, CURLOPT_NOBODY, 0 as raw::c_long);
+            curl_slist_free_all(list);
+        }
+
+        // The commands change the server, so report failures like an upload.
+        match result {
+            Err(Error::DownloadError(msg)) => Err(Error::UploadError(msg)),
+            other => other,
+        }
+    }
 }
 
 impl Drop for Handle {
@@ -0,0 +1,239 @@
+// Tako -- Take container image.
+// Copyright 2018 Arian van Putten, Ruud van Asseldonk, Tako Marks.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Binary deltas between images, so fetch can download only what changed.
+//!
+//! A delta is a sequence of instructions to copy a range of the base image, or
+//! to insert bytes from the delta itself, which together reconstruct the target
+//! image. `diff` finds the ranges to copy the way rsync does: it indexes the
+//! blocks of the base by a rolling checksum, and slides a window over the
+//! target. The delta is not trusted: the manifest pins its digest, and the
+//! digest of the result is verified after applying. See docs/delta-format.md.
+
+use std::collections::HashMap;
+use std::io::Write;
+
+use error::{Error, Result};
+
+/// The first line of a delta, which includes the format version.
+const MAGIC: &'static [u8] = b"Tako Delta 1\n";
+
+/// The size of the base blocks that the target is matched against.
+const BLOCK_LEN: usize = 4096;
+
+const OP_END: u8 = 0;
+const OP_COPY: u8 = 1;
+const OP_INSERT: u8 = 2;
+
+/// A checksum of a window that can be moved by a byte in constant time, the
+/// weak checksum of rsync.
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Rolling {
+        let mut rolling = Rolling { a: 0, b: 0, len: window.len() as u32 };
+        for &byte in window {
+            rolling.a = rolling.a.wrapping_add(byte as u32);
+            rolling.b = rolling.b.wrapping_add(rolling.a);
+        }
+        rolling
+    }
+
+    /// Move the window one byte forward, dropping `out` and adding `in_`.
+    fn roll(&mut self, out: u8, in_: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(in_ as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.b << 16) | (self.a & 0xffff)
+    }
+}
+
+fn push_u64(out: &mut Vec<u8>, x: u64) {
+    for i in 0..8 {
+        out.push((x >> (8 * i)) as u8);
+    }
+}
+
+fn push_insert(out: &mut Vec<u8>, bytes: &[u8]) {
+    if !bytes.is_empty() {
+        out.push(OP_INSERT);
+        push_u64(out, bytes.len() as u64);
+        out.extend_from_slice(bytes);
+    }
+}
+
+/// Compute a delta that turns `base` into `target`.
+pub fn diff(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut index: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, block) in base.chunks(BLOCK_LEN).enumerate() {
+        if block.len() == BLOCK_LEN {
+            index.entry(Rolling::new(block).digest()).or_insert_with(Vec::new).push(i * BLOCK_LEN);
+        }
+    }
+
+    let mut out = MAGIC.to_vec();
+    let mut literal_start = 0;
+    let mut pos = 0;
+    let mut rolling = None;
+
+    while pos + BLOCK_LEN <= target.len() {
+        let window = &target[pos..pos + BLOCK_LEN];
+        let digest = rolling.get_or_insert_with(|| Rolling::new(window)).digest();
+
+        // The checksum is weak, so compare the bytes of every candidate.
+        let found = index.get(&digest).and_then(|offsets| {
+            offsets.iter().cloned().find(|&offset| &base[offset..offset + BLOCK_LEN] == window)
+        });
+
+        if let Some(mut offset) = found {
+            // Extend the match past the block as far as the bytes agree, and
+            // back into the pending literal, so unchanged runs become a single
+            // copy, also when they do not start at a block boundary.
+            let mut len = BLOCK_LEN;
+            while pos + len < target.len() && offset + len < base.len() && base[offset + len] == target[pos + len] {
+                len += 1;
+            }
+            while pos > literal_start && offset > 0 && base[offset - 1] == target[pos - 1] {
+                pos -= 1;
+                offset -= 1;
+                len += 1;
+            }
+            push_insert(&mut out, &target[literal_start..pos]);
+            out.push(OP_COPY);
+            push_u64(&mut out, offset as u64);
+            push_u64(&mut out, len as u64);
+            pos += len;
+            literal_start = pos;
+            rolling = None;
+            continue
+        }
+
+        if pos + BLOCK_LEN < target.len() {
+            if let Some(ref mut r) = rolling {
+                r.roll(target[pos], target[pos + BLOCK_LEN]);
+            }
+        }
+        pos += 1;
+    }
+
+    push_insert(&mut out, &target[literal_start..]);
+    out.push(OP_END);
+    out
+}
+
+/// Read a little-endian u64 at the offset, and advance the offset.
+fn read_u64(delta: &[u8], offset: &mut usize) -> Result<u64> {
+    if delta.len() < *offset + 8 {
+        return Err(Error::InvalidDelta("Unexpected end of delta."))
+    }
+    let mut x = 0;
+    for i in 0..8 {
+        x |= (delta[*offset + i] as u64) << (8 * i);
+    }
+    *offset += 8;
+    Ok(x)
+}
+
+/// Apply a delta to `base`, and write the target to `out`.
+pub fn apply<W: Write>(base: &[u8], delta: &[u8], out: &mut W) -> Result<()> {
+    if !delta.starts_with(MAGIC) {
+        return Err(Error::InvalidDelta("Expected 'Tako Delta 1' header."))
+    }
+
+    let mut offset = MAGIC.len();
+    loop {
+        let op = *delta.get(offset).ok_or(Error::InvalidDelta("Unexpected end of delta."))?;
+        offset += 1;
+        match op {
+            OP_END => break,
+            OP_COPY => {
+                let start = read_u64(delta, &mut offset)?;
+                let len = read_u64(delta, &mut offset)?;
+                if start > base.len() as u64 || len > base.len() as u64 - start {
+                    return Err(Error::InvalidDelta("Copy range is outside of the base image."))
+                }
+                out.write_all(&base[start as usize..(start + len) as usize])?;
+            }
+            OP_INSERT => {
+                let len = read_u64(delta, &mut offset)?;
+                if len > (delta.len() - offset) as u64 {
+                    return Err(Error::InvalidDelta("Unexpected end of delta."))
+                }
+                out.write_all(&delta[offset..offset + len as usize])?;
+                offset += len as usize;
+            }
+            _ => return Err(Error::InvalidDelta("Unknown instruction.")),
+        }
+    }
+
+    if offset != delta.len() {
+        return Err(Error::InvalidDelta("Unexpected trailing data after delta."))
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BLOCK_LEN, apply, diff};
+
+    /// Return pseudorandom bytes, so blocks do not repeat.
+    fn get_test_image(len: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed;
+        (0..len).map(|_| {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (x >> 16) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn diff_then_apply_reconstructs_target() {
+        let base = get_test_image(10 * BLOCK_LEN + 123, 1);
+        let mut target = base.clone();
+        // Change some bytes in the middle, insert some, and append some.
+        target[3 * BLOCK_LEN + 17] ^= 0xff;
+        let inserted = get_test_image(999, 2);
+        let n = 6 * BLOCK_LEN + 5;
+        let tail = target.split_off(n);
+        target.extend_from_slice(&inserted);
+        target.extend_from_slice(&tail);
+        target.extend_from_slice(b"appended");
+
+        let delta = diff(&base, &target);
+        assert!(delta.len() < 999 + 200, "Delta is {} bytes.", delta.len());
+
+        let mut out = Vec::new();
+        apply(&base, &delta, &mut out).unwrap();
+        assert_eq!(out, target);
+
+        // Without anything in common, the delta holds the target.
+        let unrelated = get_test_image(100, 3);
+        let mut out = Vec::new();
+        apply(&base, &diff(&base, &unrelated), &mut out).unwrap();
+        assert_eq!(out, unrelated);
+    }
+
+    #[test]
+    fn apply_rejects_invalid_delta() {
+        let base = get_test_image(2 * BLOCK_LEN, 1);
+        let delta = diff(&base, &base);
+        let mut out = Vec::new();
+        assert!(apply(&base[..BLOCK_LEN], &delta, &mut out).is_err());
+        assert!(apply(&base, &delta[..delta.len() - 1], &mut out).is_err());
+        assert!(apply(&base, &delta[1..], &mut out).is_err());
+
+        let mut trailing = delta.clone();
+        trailing.push(0);
+        assert!(apply(&base, &trailing, &mut out).is_err());
+    }
+}
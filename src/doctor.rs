@@ -0,0 +1,167 @@
+// Tako -- Take container image.
+// Copyright 2018 Arian van Putten, Ruud van Asseldonk, Tako Marks.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Diagnoses problems in a server directory.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::PathBuf;
+
+use cli;
+use error::{Error, Result};
+use manifest;
+use manifest::Manifest;
+use util;
+
+/// Print a category of findings, one per line, indented below a title.
+fn print_category(title: &str, findings: &[String]) {
+    if findings.is_empty() {
+        println!("{}: none.", title);
+    } else {
+        println!("{} ({}):", title, findings.len());
+        for finding in findings {
+            println!("  {}", finding);
+        }
+    }
+}
+
+/// Check the server directory, print a report, and optionally remove orphans.
+///
+/// Returns whether the server directory is healthy. Missing images and orphaned
+/// files do not count as unhealthy: an image may be removed from the server
+/// deliberately, while its entry stays in the manifest, and orphans are
+/// harmless apart from the disk space they take.
+///
+/// Orphans are only removed if there is a manifest, and if it verifies when a
+/// key is given. Otherwise we cannot tell which files are referenced, and
+/// removing them might delete every image.
+pub fn doctor(doctor: cli::Doctor) -> Result<bool> {
+    let mut is_healthy = true;
+    let mut is_trusted = true;
+
+    let mut manifest_path = doctor.output_path.clone();
+    match doctor.manifest_name {
+        Some(ref name) => manifest_path.push(name),
+        None => manifest_path.push(manifest::DEFAULT_NAME),
+    }
+
+    let manifest = match fs::File::open(&manifest_path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            println!("Manifest: not found, every file in the store is orphaned.");
+            is_trusted = false;
+            Manifest::new()
+        }
+        Err(e) => return Err(e.into()),
+        Ok(mut f) => {
+            let mut bytes = Vec::new();
+            f.read_to_end(&mut bytes)?;
+
+            // Parse without verifying first, so we can still check the images
+            // if the signature is invalid.
+            let manifest = Manifest::parse_unverified(&bytes[..])?;
+            let n = manifest.entries().len();
+            match doctor.public_key {
+                None => println!("Manifest: {} entries, signature not checked.", n),
+                Some(ref key) => match Manifest::parse(&bytes[..], key) {
+                    Ok(..) => println!("Manifest: {} entries, signature is valid.", n),
+                    Err(..) => {
+                        println!("Manifest: {} entries, signature is INVALID.", n);
+                        is_healthy = false;
+                        is_trusted = false;
+                    }
+                },
+            }
+            manifest
+        }
+    };
+
+    let mut store_dir = doctor.output_path.clone();
+    store_dir.push("store");
+
+    let mut referenced = HashSet::new();
+    let mut missing = Vec::new();
+    let mut damaged = Vec::new();
+
+    for entry in manifest.entries() {
+        let mut digest_hex = String::new();
+        util::append_hex(&mut digest_hex, entry.digest.as_ref());
+        let finding = format!("{} {}", entry.version.as_str(), digest_hex);
+
+        // Images are stored with the extension of the manifest, if any.
+        digest_hex.push_str(manifest.image_ext());
+        let mut image_path = store_dir.clone();
+        image_path.push(&digest_hex);
+        referenced.insert(digest_hex);
+
+        // Deltas are verified by fetch when it downloads them, here it is
+        // enough that they are not orphans.
+        for delta in &entry.deltas {
+            let mut delta_hex = String::new();
+            util::append_hex(&mut delta_hex, delta.digest.as_ref());
+            delta_hex.push_str(".delta");
+            referenced.insert(delta_hex);
+        }
+
+        if !image_path.is_file() {
+            missing.push(finding);
+        } else if util::sha256sum(&image_path)? != entry.digest {
+            damaged.push(finding);
+        }
+    }
+
+    let mut orphans: Vec<PathBuf> = Vec::new();
+    if store_dir.is_dir() {
+        for dir_entry in fs::read_dir(&store_dir)? {
+            let dir_entry = dir_entry?;
+            // Tako only writes regular files to the store, leave anything
+            // else to the operator.
+            if !dir_entry.file_type()?.is_file() {
+                continue
+            }
+            let is_referenced = match dir_entry.file_name().to_str() {
+                Some(fname) => referenced.contains(fname),
+                None => false,
+            };
+            if !is_referenced {
+                orphans.push(dir_entry.path());
+            }
+        }
+    }
+    orphans.sort();
+
+    let orphan_names: Vec<String> = orphans
+        .iter()
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect();
+
+    print_category("Missing images", &missing);
+    print_category("Damaged images", &damaged);
+    print_category("Orphaned files", &orphan_names);
+
+    if !damaged.is_empty() {
+        is_healthy = false;
+    }
+
+    if doctor.fix && !is_trusted {
+        let msg = "Refusing to remove orphaned files without a valid manifest.";
+        return Err(Error::OperationError(msg))
+    }
+
+    if doctor.fix && !orphans.is_empty() {
+        let question = format!("Remove {} orphaned files?", orphans.len());
+        if util::confirm(&question, doctor.assume_yes)? {
+            for orphan in &orphans {
+                fs::remove_file(orphan)?;
+            }
+            println!("Removed {} orphaned files.", orphans.len());
+        }
+    }
+
+    Ok(is_healthy)
+}
@@ -7,11 +7,16 @@
 
 //! Errors that Tako can encounter.
 
+use std::error;
+use std::fmt;
 use std::io;
+use std::path::PathBuf;
 use std::result;
 
 use base64;
 
+use util;
+use util::Sha256;
 use version::Version;
 
 #[derive(Debug)]
@@ -28,24 +33,78 @@ pub enum Error {
     /// Secret key could not be parsed as base64, or the decoded key is invalid.
     InvalidSecretKeyData,
 
+    /// A secret key file could not be read or is invalid. Holds the path.
+    InvalidSecretKeyFile(String, Box<Error>),
+
+    /// A public key file does not hold a base64-encoded public key. Holds the path.
+    InvalidPublicKeyFile(String),
+
+    /// A key passed to `import-key` is not a valid key in the given format.
+    InvalidImportKey(&'static str),
+
     /// Error in manifest file.
     InvalidManifest(&'static str),
 
+    /// The config archive is malformed.
+    InvalidArchive(&'static str),
+
+    /// The config archive is not signed, or not by the archive key.
+    InvalidArchiveSignature(&'static str),
+
+    /// A config is not in the config archive, for instance because a config
+    /// in the archive requires one outside of it. Holds the path.
+    NotInArchive(String),
+
+    /// A delta is malformed, or does not fit its base image.
+    InvalidDelta(&'static str),
+
+    /// The CA certificates from `CaCert=` or `CaPath=` could not be loaded.
+    /// Holds the path and the reason.
+    InvalidCaCert(PathBuf, String),
+
+    /// The index is malformed.
+    InvalidIndex(&'static str),
+
+    /// The index does not list the image of the config. Holds the name.
+    NotInIndex(String),
+
+    /// The manifest of an image differs from the one that the index pins.
+    /// Holds the name of the image.
+    IndexMismatch(String),
+
+    /// A bundle for `import` is malformed.
+    InvalidBundle(&'static str),
+
+    /// A manifest that `store` signed does not verify or parse back the same.
+    /// Holds the cause. The manifest was not written.
+    SelfCheckFailed(Box<Error>),
+
     /// Signature in manifest could not be parsed as base64.
     InvalidSignatureData(base64::DecodeError),
 
     /// Signature verification failed.
     InvalidSignature,
 
+    /// The manifest is signed with a different key than the one trusted on
+    /// first use. Holds the fingerprints of the trusted key and the new key.
+    UntrustedKey(Sha256, Sha256),
+
     /// Digest verification of a (possibly newly) stored image failed.
     InvalidDigest,
 
+    /// A downloaded image does not match the digest in the manifest. Holds
+    /// the expected digest and the actual digest.
+    DigestMismatch(Sha256, Sha256),
+
     /// An operational error occurred.
     OperationError(&'static str),
 
     /// Curl failed in some way.
     DownloadError(String),
 
+    /// Curl failed to upload a file.
+    UploadError(String),
+
     /// Store failed because the version already exists.
     ///
     /// This can happen for two reasons:
@@ -56,8 +115,68 @@ pub enum Error {
 
     /// There exists no version that matches the required bounds.
     ///
-    /// E.g. we have 1.0, 1.1, and 1.2, but we require 2.*.
-    NoCandidate,
+    /// E.g. we have 1.0, 1.1, and 1.2, but we require 2.*. Holds the version
+    /// pattern from the config, and the versions that the manifest contains.
+    NoCandidate(Version, Vec<Version>),
+
+    /// The candidate is older than the installed version, and downgrades were
+    /// not allowed. Holds the installed version and the candidate version.
+    Downgrade(Version, Version),
+
+    /// The origin lists no version as high as it listed before, which
+    /// `--detect-rollback` refuses. Holds the recorded and the listed version.
+    Rollback(Version, Version),
+
+    /// The manifest does not declare the name that the config expects. Holds
+    /// the expected name, and the declared name, if any.
+    NameMismatch(String, Option<String>),
+
+    /// The candidate is not the version that `--expected-version` asserted.
+    /// Holds the expected version and the candidate version.
+    UnexpectedVersion(Version, Version),
+
+    /// A config that the fetched config requires could not be fetched, so
+    /// none of the group was applied. Holds the required config filename.
+    RequirementFailed(String, Box<Error>),
+
+    /// A systemctl command on a systemd unit failed. Holds the command, e.g.
+    /// `restart`, and the unit.
+    UnitFailed(&'static str, String),
+
+    /// systemctl reported that a unit does not exist. Holds the command and
+    /// the unit.
+    UnitNotFound(&'static str, String),
+
+    /// The config has units to act on, but the service manager does not run
+    /// on this host. Holds its name.
+    ServiceManagerUnavailable(&'static str),
+
+    /// A unit was not active after it was (re)started. Holds the unit and the
+    /// state that `systemctl is-active` reported.
+    UnitNotActive(String, String),
+
+    /// The config acts on a unit that the policy does not allow. Holds the unit.
+    PolicyViolation(String),
+
+    /// The destination differs from what Tako installed there, and `--repair`
+    /// was not given. Holds the destination and what differs.
+    Drift(PathBuf, String),
+
+    /// The `VerifyCmd=` of the config rejected the image. Holds the command.
+    Vetoed(String),
+
+    /// The time budget of `--deadline` ran out.
+    DeadlineExceeded,
+
+    /// The user for `--owner` does not exist. Holds the user.
+    UnknownUser(String),
+
+    /// The group for `--group` does not exist. Holds the group.
+    UnknownGroup(String),
+
+    /// A destructive command needs confirmation, but stdin is not a terminal
+    /// to ask on, and `--assume-yes` was not given.
+    ConfirmationRequired,
 
     /// IO error.
     IoError(io::Error),
@@ -69,6 +188,210 @@ impl From<io::Error> for Error {
     }
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InvalidConfig(lineno, msg) => write!(f, "Invalid config on line {}: {}", lineno, msg),
+            Error::IncompleteConfig(msg) => write!(f, "Incomplete config: {}", msg),
+            Error::InvalidPublicKeyData(lineno, ref err) => {
+                write!(f, "Invalid public key on line {}: {}", lineno, err)
+            }
+            Error::InvalidSecretKeyData => write!(f, "Invalid secret key."),
+            Error::InvalidSecretKeyFile(ref path, ref err) => {
+                write!(f, "Failed to load secret key from {}: {}", path, err)
+            }
+            Error::InvalidPublicKeyFile(ref path) => {
+                write!(f, "Invalid public key in {}. Expected a base64-encoded key, \
+                    as printed by 'tako gen-key'.", path)
+            }
+            Error::InvalidImportKey(msg) => write!(f, "Invalid key to import: {}", msg),
+            Error::InvalidManifest(msg) => write!(f, "Invalid manifest: {}", msg),
+            Error::InvalidArchive(msg) => write!(f, "Invalid config archive: {}", msg),
+            Error::InvalidArchiveSignature(msg) => {
+                write!(f, "Config archive signature verification failed: {}", msg)
+            }
+            Error::NotInArchive(ref path) => write!(f, "The config archive does not contain {}.", path),
+            Error::InvalidDelta(msg) => write!(f, "Invalid delta: {}", msg),
+            Error::InvalidCaCert(ref path, ref reason) => {
+                write!(f, "Failed to load CA certificates from {}: {}", path.display(), reason)
+            }
+            Error::InvalidIndex(msg) => write!(f, "Invalid index: {}", msg),
+            Error::NotInIndex(ref name) => write!(f, "The index does not list image '{}'.", name),
+            Error::IndexMismatch(ref name) => write!(
+                f,
+                "The manifest of image '{}' differs from the manifest that the index pins.",
+                name,
+            ),
+            Error::InvalidBundle(msg) => write!(f, "Invalid bundle: {}", msg),
+            Error::SelfCheckFailed(ref err) => {
+                write!(f, "The signed manifest failed verification, it was not written: {}", err)
+            }
+            Error::InvalidSignatureData(ref err) => write!(f, "Invalid manifest signature: {}", err),
+            Error::InvalidSignature => write!(f, "Manifest signature verification failed."),
+            Error::UntrustedKey(ref trusted, ref key) => {
+                let mut trusted_hex = String::new();
+                let mut key_hex = String::new();
+                util::append_hex(&mut trusted_hex, trusted.as_ref());
+                util::append_hex(&mut key_hex, key.as_ref());
+                write!(
+                    f,
+                    "THE KEY THAT SIGNS THE MANIFEST HAS CHANGED. Trusted key has fingerprint {}, \
+                    but the manifest is signed with key {}. If the key was rotated deliberately, \
+                    delete 'trusted-key' in the destination directory to trust the new key.",
+                    trusted_hex, key_hex,
+                )
+            }
+            Error::InvalidDigest => write!(f, "Image digest does not match the manifest."),
+            Error::DigestMismatch(ref expected, ref actual) => {
+                let mut expected_hex = String::new();
+                let mut actual_hex = String::new();
+                util::append_hex(&mut expected_hex, expected.as_ref());
+                util::append_hex(&mut actual_hex, actual.as_ref());
+                write!(
+                    f,
+                    "Downloaded image does not match the manifest. Expected digest {}, got {}.",
+                    expected_hex, actual_hex,
+                )
+            }
+            Error::OperationError(msg) => write!(f, "{}", msg),
+            Error::DownloadError(ref msg) => write!(f, "Download failed: {}", msg),
+            Error::UploadError(ref msg) => write!(f, "Upload failed: {}", msg),
+            Error::Duplicate(ref version) => {
+                write!(f, "Version {} exists already with a different image.", version.as_str())
+            }
+            Error::NoCandidate(ref pattern, ref available) => {
+                write!(f, "No version matches {}.", pattern.as_str())?;
+                if available.is_empty() {
+                    write!(f, " The manifest contains no versions.")
+                } else {
+                    write!(f, " Available versions:")?;
+                    for version in available {
+                        write!(f, " {}", version.as_str())?;
+                    }
+                    write!(f, ".")
+                }
+            }
+            Error::Downgrade(ref installed, ref candidate) => write!(
+                f,
+                "Refusing to downgrade from version {} to {}.",
+                installed.as_str(), candidate.as_str(),
+            ),
+            Error::Rollback(ref recorded, ref highest) => write!(
+                f,
+                "The origin listed version {} before, but now lists no version newer than {}.",
+                recorded.as_str(), highest.as_str(),
+            ),
+            Error::NameMismatch(ref expected, Some(ref actual)) => write!(
+                f,
+                "Expected a manifest for image {}, but the origin serves image {}.",
+                expected, actual,
+            ),
+            Error::NameMismatch(ref expected, None) => write!(
+                f,
+                "Expected a manifest for image {}, but the manifest declares no name.",
+                expected,
+            ),
+            Error::UnexpectedVersion(ref expected, ref candidate) => write!(
+                f,
+                "Expected to install version {}, but the selected version is {}.",
+                expected.as_str(), candidate.as_str(),
+            ),
+            Error::RequirementFailed(ref fname, ref err) => {
+                write!(f, "Required config {} failed: {}", fname, err)
+            }
+            Error::UnitFailed(command, ref unit) => write!(f, "Failed to {} {}.", command, unit),
+            Error::UnitNotFound(command, ref unit) => {
+                write!(f, "Failed to {} {}: the unit does not exist.", command, unit)
+            }
+            Error::ServiceManagerUnavailable(name) => write!(
+                f,
+                "RestartUnit= or StopUnit= is configured, but {} is not available on this host.",
+                name,
+            ),
+            Error::UnitNotActive(ref unit, ref state) => {
+                write!(f, "Unit {} is not active after starting it, its state is '{}'.", unit, state)
+            }
+            Error::PolicyViolation(ref unit) => {
+                write!(f, "The policy does not allow configs to act on unit {}.", unit)
+            }
+            Error::Drift(ref destination, ref what) => write!(
+                f,
+                "The destination {} drifted from what Tako installed: {}.",
+                destination.display(), what,
+            ),
+            Error::Vetoed(ref cmd) => write!(f, "Verify command '{}' rejected the image.", cmd),
+            Error::DeadlineExceeded => write!(f, "The deadline set with --deadline passed."),
+            Error::UnknownUser(ref user) => write!(f, "User '{}' does not exist.", user),
+            Error::UnknownGroup(ref group) => write!(f, "Group '{}' does not exist.", group),
+            Error::ConfirmationRequired => write!(
+                f,
+                "Cannot ask for confirmation, stdin is not a terminal. \
+                Pass --assume-yes to proceed without asking."
+            ),
+            Error::IoError(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    // The messages are in `Display`; `description` is required until Rust 1.27.
+    fn description(&self) -> &str {
+        "Tako error"
+    }
+
+    // This is `cause` rather than `source`, which needs Rust 1.30.
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::InvalidPublicKeyData(_, ref err) => Some(err),
+            Error::InvalidSignatureData(ref err) => Some(err),
+            Error::InvalidSecretKeyFile(_, ref err) => Some(&**err),
+            Error::SelfCheckFailed(ref err) => Some(&**err),
+            Error::RequirementFailed(_, ref err) => Some(&**err),
+            Error::IoError(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
 pub type Result<T> = result::Result<T, Error>;
 
-// TODO: Implement std::error::Error for Error.
+#[cfg(test)]
+mod test {
+    use std::error::Error as StdError;
+    use std::io;
+
+    use base64;
+
+    use super::Error;
+    use util::Sha256;
+    use version::Version;
+
+    #[test]
+    #[allow(deprecated)]
+    fn errors_format_with_context() {
+        let config_error = Error::InvalidConfig(7, "Unknown key.");
+        assert_eq!(config_error.to_string(), "Invalid config on line 7: Unknown key.");
+
+        let decode_error = base64::decode("!").unwrap_err();
+        let key_error = Error::InvalidPublicKeyData(3, decode_error);
+        assert!(key_error.to_string().starts_with("Invalid public key on line 3: "));
+        assert!(key_error.cause().is_some());
+
+        let duplicate = Error::Duplicate(Version::from("1.2.0"));
+        assert!(duplicate.to_string().contains("1.2.0"));
+        assert!(duplicate.cause().is_none());
+
+        let io_error = Error::from(io::Error::new(io::ErrorKind::NotFound, "No such file"));
+        assert_eq!(io_error.to_string(), "No such file");
+        assert!(io_error.cause().is_some());
+
+        let expected = Sha256::copy_from_slice(&[1; 32]);
+        let actual = Sha256::copy_from_slice(&[2; 32]);
+        let mismatch = Error::DigestMismatch(expected, actual);
+        assert!(mismatch.to_string().contains(&format!("Expected digest {}, got {}.", "01".repeat(32), "02".repeat(32))));
+
+        let wrapped = Error::RequirementFailed("a.tako".to_string(), Box::new(Error::InvalidDigest));
+        assert!(!wrapped.to_string().is_empty());
+        assert_eq!(wrapped.cause().unwrap().to_string(), Error::InvalidDigest.to_string());
+    }
+}
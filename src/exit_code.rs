@@ -0,0 +1,141 @@
+// Tako -- Take container image.
+// Copyright 2018 Arian van Putten, Ruud van Asseldonk, Tako Marks.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! The exit codes of Tako.
+//!
+//! Scripts rely on these, so once a code is documented, it must not change
+//! meaning. `tako help exit-codes` prints the table below.
+
+use error::Error;
+
+/// Every command succeeded.
+pub const SUCCESS: i32 = 0;
+
+/// A command failed for a reason without a more specific code below. The
+/// cause is on stderr.
+pub const FAILURE: i32 = 1;
+
+/// A fetch failed because the deadline set with `--deadline` passed.
+pub const DEADLINE: i32 = 2;
+
+/// A fetch with `--report-only` found an update for at least one config.
+pub const UPDATE_AVAILABLE: i32 = 3;
+
+/// The arguments, a config, or a key were invalid.
+pub const USAGE: i32 = 4;
+
+/// A download or upload failed.
+pub const NETWORK: i32 = 5;
+
+/// A signature, digest, or other check that authenticates what Tako
+/// downloaded or installed failed. This may be an attack.
+pub const VERIFICATION: i32 = 6;
+
+/// Reading or writing a local file failed.
+pub const IO: i32 = 7;
+
+/// The exit codes and what they mean, for `tako help exit-codes`.
+pub const EXIT_CODES: &'static [(i32, &'static str)] = &[
+    (SUCCESS, "Success. For fetch, this includes configs with no candidate, \
+               unless --init was given."),
+    (FAILURE, "Failure without a more specific code below. For fetch, at \
+               least one config failed. For doctor, the server directory has \
+               problems. The cause is printed on stderr."),
+    (DEADLINE, "For fetch, at least one config failed because the --deadline \
+                passed."),
+    (UPDATE_AVAILABLE, "For fetch --report-only, no config failed, and at \
+                        least one config has an update available."),
+    (USAGE, "Invalid arguments, config, or key."),
+    (NETWORK, "A download or upload failed."),
+    (VERIFICATION, "A signature, digest, or other check of what was \
+                    downloaded or installed failed. This may be an attack, \
+                    investigate before retrying."),
+    (IO, "Reading or writing a local file failed."),
+];
+
+/// Return the exit code for a failed command.
+pub fn for_error(error: &Error) -> i32 {
+    match *error {
+        Error::InvalidConfig(..) |
+        Error::IncompleteConfig(..) |
+        Error::InvalidPublicKeyData(..) |
+        Error::InvalidPublicKeyFile(..) |
+        Error::InvalidSecretKeyData |
+        Error::InvalidSecretKeyFile(..) |
+        Error::InvalidImportKey(..) |
+        Error::InvalidCaCert(..) |
+        Error::UnknownUser(..) |
+        Error::UnknownGroup(..) => USAGE,
+        Error::DownloadError(..) | Error::UploadError(..) => NETWORK,
+        Error::InvalidSignatureData(..) |
+        Error::InvalidSignature |
+        Error::InvalidArchiveSignature(..) |
+        Error::UntrustedKey(..) |
+        Error::InvalidDigest |
+        Error::DigestMismatch(..) |
+        Error::Rollback(..) |
+        Error::NameMismatch(..) |
+        Error::IndexMismatch(..) |
+        Error::Drift(..) => VERIFICATION,
+        Error::DeadlineExceeded => DEADLINE,
+        Error::IoError(..) => IO,
+        Error::RequirementFailed(_, ref cause) => for_error(cause),
+        // Listed one by one rather than with `_`, so that a new variant has
+        // to be classified here.
+        Error::InvalidManifest(..) |
+        Error::InvalidArchive(..) |
+        Error::NotInArchive(..) |
+        Error::InvalidDelta(..) |
+        Error::InvalidIndex(..) |
+        Error::NotInIndex(..) |
+        Error::InvalidBundle(..) |
+        Error::SelfCheckFailed(..) |
+        Error::OperationError(..) |
+        Error::Duplicate(..) |
+        Error::NoCandidate(..) |
+        Error::Downgrade(..) |
+        Error::UnexpectedVersion(..) |
+        Error::UnitFailed(..) |
+        Error::UnitNotFound(..) |
+        Error::ServiceManagerUnavailable(..) |
+        Error::UnitNotActive(..) |
+        Error::PolicyViolation(..) |
+        Error::Vetoed(..) |
+        Error::ConfirmationRequired => FAILURE,
+    }
+}
+
+/// Return the exit code for a run in which the given commands failed.
+///
+/// A failed verification is the most important to notice, so it takes
+/// precedence, then the deadline, then the specific codes in numeric order,
+/// and `FAILURE` only if no other code applies.
+pub fn for_errors(errors: &[&Error]) -> i32 {
+    let codes: Vec<i32> = errors.iter().map(|e| for_error(e)).collect();
+    let precedence = [VERIFICATION, DEADLINE, USAGE, NETWORK, IO];
+    precedence.iter().cloned().find(|c| codes.contains(c)).unwrap_or(FAILURE)
+}
+
+#[cfg(test)]
+mod test {
+    use error::Error;
+    use super::{DEADLINE, FAILURE, VERIFICATION, for_error, for_errors};
+
+    #[test]
+    fn for_errors_prefers_verification_then_deadline() {
+        let deadline = Error::RequirementFailed("a.tako".to_string(), Box::new(Error::DeadlineExceeded));
+        let digest = Error::InvalidDigest;
+        let other = Error::OperationError("Something else.");
+        let archive = Error::InvalidArchiveSignature("The archive is not signed.");
+        assert_eq!(for_error(&deadline), DEADLINE);
+        assert_eq!(for_error(&archive), VERIFICATION);
+        assert_eq!(for_errors(&[&other]), FAILURE);
+        assert_eq!(for_errors(&[&other, &deadline]), DEADLINE);
+        assert_eq!(for_errors(&[&deadline, &digest, &other]), VERIFICATION);
+        assert_eq!(for_errors(&[&other, &deadline, &archive]), VERIFICATION);
+    }
+}
@@ -9,52 +9,457 @@
 
 use std::fs;
 use std::io;
-use std::io::{BufRead, BufWriter, Write};
+use std::io::{BufWriter, Read, Write};
 use std::os::unix;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc;
+use std::thread;
+use std::time;
 
+use filebuffer::FileBuffer;
 use ring::digest;
 
-use config::Config;
+use cache;
+use cli;
+use config;
+use config::{Config, PublicKey, RestartBackend};
 use curl;
+use delta;
 use error::{Error, Result};
 use manifest;
-use manifest::Manifest;
+use manifest::{Entry, Index, IndexEntry, Manifest};
+use policy::Policy;
+use rollback;
+use service::{OpenRc, RestartCommand, ServiceManager, Systemd};
+use state::State;
+use transport::Transport;
+use trust;
 use util;
 use util::Sha256;
+use version::Version;
 
-fn load_config(config_fname: &str) -> Result<Config> {
-    let f = fs::File::open(config_fname)?;
-    let buf_reader = io::BufReader::new(f);
-    let lines: io::Result<Vec<String>> = buf_reader.lines().collect();
-    Config::parse(lines?.iter())
+/// The result of a fetch for a single config that did not fail.
+#[derive(Debug)]
+pub enum Outcome {
+    /// The latest image changed to this entry.
+    Updated(Entry),
+
+    /// The latest image was this entry already.
+    Unchanged(Entry),
+
+    /// There was no version that matches the config, nothing changed.
+    NoCandidate,
+}
+
+impl Outcome {
+    /// Return the entry of the installed image, if a version was selected.
+    pub fn entry(&self) -> Option<&Entry> {
+        match *self {
+            Outcome::Updated(ref e) | Outcome::Unchanged(ref e) => Some(e),
+            Outcome::NoCandidate => None,
+        }
+    }
+
+    /// Format the outcome as shell variable assignments, one per line.
+    ///
+    /// All variables are always printed, empty if they do not apply, so a
+    /// script that sources the output never sees stale values from elsewhere.
+    pub fn format_env(&self, destination: &Path) -> String {
+        let (updated, entry) = match *self {
+            Outcome::Updated(ref e) => ("1", Some(e)),
+            Outcome::Unchanged(ref e) => ("0", Some(e)),
+            Outcome::NoCandidate => ("0", None),
+        };
+        let mut version = String::new();
+        let mut digest = String::new();
+        if let Some(entry) = entry {
+            version.push_str(entry.version.as_str());
+            util::append_hex(&mut digest, entry.digest.as_ref());
+        }
+
+        let mut out = String::new();
+        let vars = [
+            ("TAKO_UPDATED", updated),
+            ("TAKO_VERSION", &version[..]),
+            ("TAKO_DIGEST", &digest[..]),
+            ("TAKO_DESTINATION", &destination.to_string_lossy()[..]),
+        ];
+        for &(name, value) in &vars {
+            out.push_str(name);
+            out.push('=');
+            util::append_shell_string(&mut out, value);
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// What a fetch with `--report-only` found for a single config.
+#[derive(Debug)]
+pub enum Pending {
+    /// The selected version is installed already.
+    UpToDate(Version),
+
+    /// The selected version is not installed. Holds the installed version, if
+    /// any, and the selected version.
+    Update(Option<Version>, Version),
+}
+
+impl Pending {
+    /// Format as `up to date: <version>` or `update available: <a> -> <b>`.
+    pub fn format_text(&self) -> String {
+        match *self {
+            Pending::UpToDate(ref v) => format!("up to date: {}", v.as_str()),
+            Pending::Update(Some(ref installed), ref v) => {
+                format!("update available: {} -> {}", installed.as_str(), v.as_str())
+            }
+            Pending::Update(None, ref v) => format!("update available: none -> {}", v.as_str()),
+        }
+    }
+}
+
+/// Results of fetching multiple configs, for a summary at the end.
+pub struct Report {
+    results: Vec<(String, Result<Outcome>)>,
+
+    /// Statistics of the downloads of every config, in the same order.
+    transfers: Vec<Vec<curl::Transfer>>,
+}
+
+impl Report {
+    pub fn new() -> Report {
+        Report {
+            results: Vec::new(),
+            transfers: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, config_fname: String, result: Result<Outcome>, transfers: Vec<curl::Transfer>) {
+        self.results.push((config_fname, result));
+        self.transfers.push(transfers);
+    }
+
+    /// Return the config filename and result of every fetch, in order.
+    pub fn results(&self) -> &[(String, Result<Outcome>)] {
+        &self.results
+    }
+
+    pub fn num_updated(&self) -> usize {
+        self.results.iter().filter(|r| match r.1 {
+            Ok(Outcome::Updated(..)) => true,
+            _ => false,
+        }).count()
+    }
+
+    pub fn num_failed(&self) -> usize {
+        self.results.iter().filter(|r| r.1.is_err()).count()
+    }
+
+    pub fn num_unchanged(&self) -> usize {
+        self.results.len() - self.num_updated() - self.num_failed()
+    }
+
+    /// Format a one-line summary, followed by one line per failed config.
+    ///
+    /// With `color`, the status words are colored with ANSI escape codes.
+    pub fn format_text(&self, color: bool) -> String {
+        let mut out = format!("{} ", self.num_updated());
+        util::append_colored(&mut out, "updated", util::GREEN, color);
+        out.push_str(&format!(", {} ", self.num_unchanged()));
+        util::append_colored(&mut out, "unchanged", util::YELLOW, color);
+        out.push_str(&format!(", {} ", self.num_failed()));
+        util::append_colored(&mut out, "failed", util::RED, color);
+        out.push_str(".\n");
+        for &(ref config_fname, ref result) in &self.results {
+            if result.is_err() {
+                util::append_colored(&mut out, "Failed", util::RED, color);
+                out.push_str(": ");
+                out.push_str(config_fname);
+                out.push('\n');
+            }
+        }
+        out
+    }
+
+    /// Format the summary and the result per config as a json object.
+    pub fn format_json(&self) -> String {
+        let mut out = format!(
+            "{{\"updated\":{},\"unchanged\":{},\"failed\":{},\"configs\":[",
+            self.num_updated(),
+            self.num_unchanged(),
+            self.num_failed(),
+        );
+        for (i, &(ref config_fname, ref result)) in self.results.iter().enumerate() {
+            if i > 0 { out.push(','); }
+            out.push_str("{\"config\":");
+            util::append_json_string(&mut out, config_fname);
+            let (status, entry) = match *result {
+                Ok(Outcome::Updated(ref e)) => ("updated", Some(e)),
+                Ok(Outcome::Unchanged(ref e)) => ("unchanged", Some(e)),
+                Ok(Outcome::NoCandidate) => ("no-candidate", None),
+                Err(..) => ("failed", None),
+            };
+            out.push_str(",\"status\":");
+            util::append_json_string(&mut out, status);
+            if let Some(entry) = entry {
+                out.push_str(",\"version\":");
+                util::append_json_string(&mut out, entry.version.as_str());
+                out.push_str(",\"digest\":\"");
+                util::append_hex(&mut out, entry.digest.as_ref());
+                out.push('"');
+                if !entry.annotations.is_empty() {
+                    out.push_str(",\"annotations\":{");
+                    for (j, &(ref key, ref value)) in entry.annotations.iter().enumerate() {
+                        if j > 0 { out.push(','); }
+                        util::append_json_string(&mut out, key);
+                        out.push(':');
+                        util::append_json_string(&mut out, value);
+                    }
+                    out.push('}');
+                }
+            }
+            if let Err(ref err) = *result {
+                out.push_str(",\"error\":");
+                util::append_json_string(&mut out, &err.to_string());
+            }
+            out.push_str(",\"transfers\":[");
+            for (j, transfer) in self.transfers[i].iter().enumerate() {
+                if j > 0 { out.push(','); }
+                append_transfer_json(&mut out, transfer);
+            }
+            out.push_str("]}");
+        }
+        out.push_str("]}\n");
+        out
+    }
+}
+
+/// Format the statistics of a download as a json object, append to the string.
+fn append_transfer_json(out: &mut String, transfer: &curl::Transfer) {
+    out.push_str("{\"uri\":");
+    util::append_json_string(out, &transfer.uri);
+    out.push_str(&format!(
+        ",\"bytes\":{},\"seconds\":{:.6},\"bytes_per_second\":{:.0},\
+        \"namelookup_seconds\":{:.6},\"connect_seconds\":{:.6},\"tls_seconds\":{:.6},\
+        \"http_version\":\"{}\",\"new_connections\":{}}}",
+        transfer.bytes,
+        transfer.total_time,
+        transfer.bytes_per_second,
+        transfer.namelookup_time,
+        transfer.connect_time,
+        transfer.tls_time,
+        transfer.http_version,
+        transfer.new_connections,
+    ));
+}
+
+pub fn load_config(config_fname: &str) -> Result<Config> {
+    let lines = config::read_lines(config_fname)?;
+    Config::parse(lines.iter())
+}
+
+/// Load a config to fetch, from a file, from `--set` for `cli::ARGS_CONFIG`,
+/// or from the config archive.
+///
+/// With a config archive, every config must come from it, configs on disk are
+/// not read at all.
+pub fn load_fetch_config(fetch: &cli::Fetch, config_fname: &str) -> Result<Config> {
+    if config_fname == cli::ARGS_CONFIG && !fetch.config_args.is_empty() {
+        Config::parse(fetch.config_args.iter())
+    } else if fetch.config_archive.is_some() {
+        match fetch.archived_configs.iter().find(|&&(ref fname, _)| fname == config_fname) {
+            Some(&(_, ref lines)) => Config::parse(lines.iter()),
+            None => Err(Error::NotInArchive(config_fname.to_string())),
+        }
+    } else {
+        load_config(config_fname)
+    }
+}
+
+/// Download the remote manifest and verify its signature.
+///
+/// Returns the raw bytes, which are exactly the bytes that the signature
+/// covers, together with the parsed manifest. With `--tofu`, a config without
+/// public key accepts the key that was trusted on first use, see `trust`. With
+/// `--cache-dir`, the download is skipped if the cached manifest is current.
+pub fn download_manifest<T: Transport>(fetch: &cli::Fetch, config: &Config, transport: &mut T) -> Result<(Vec<u8>, Manifest)> {
+    let uri = manifest_uri(config);
+
+    let socket_path = config.unix_socket_path.as_ref().map(|path| path.as_path());
+    let now = unix_time();
+    let cached = match fetch.cache_dir {
+        Some(ref cache_dir) if !fetch.no_cache => {
+            let ttl = fetch.cache_ttl.unwrap_or(DEFAULT_CACHE_TTL);
+            match cache::load(cache_dir, &uri, socket_path)? {
+                Some(ref c) if now.saturating_sub(c.fetched_at) >= ttl => None,
+                other => other,
+            }
+        }
+        _ => None,
+    };
+
+    let no_validators = curl::Validators::default();
+    let request_validators = cached.as_ref().map(|c| &c.validators).unwrap_or(&no_validators);
+
+    let mut manifest_bytes = Vec::new();
+    let response = transport.get_conditional(&uri, request_validators, |chunk| {
+        manifest_bytes.extend_from_slice(chunk)
+    })?;
+
+    let validators = match (response, cached) {
+        (curl::Conditional::NotModified, Some(c)) => {
+            manifest_bytes = c.manifest_bytes;
+            None
+        }
+        (curl::Conditional::NotModified, None) => {
+            let msg = "The server responded 'Not Modified' to an unconditional request.";
+            return Err(Error::OperationError(msg))
+        }
+        (curl::Conditional::Modified(validators), _) => Some(validators),
+    };
+
+    // Also verify a cached manifest, the cache directory need not be trusted.
+    let verified = trust::manifest_key(config, fetch.tofu, &manifest_bytes[..])
+        .and_then(|public_key| Manifest::parse(&manifest_bytes[..], &public_key));
+    let manifest = match verified {
+        Ok(m) => m,
+        Err(e) => {
+            if fetch.preserve_temp {
+                keep_rejected_manifest(config, &manifest_bytes[..]);
+            }
+            return Err(e)
+        }
+    };
+
+    // Cache the manifest only if the server sent validators, without them we
+    // cannot make a conditional request.
+    if let (Some(cache_dir), Some(validators)) = (fetch.cache_dir.as_ref(), validators) {
+        if validators.etag.is_some() || validators.last_modified.is_some() {
+            let cached = cache::CachedManifest {
+                validators: validators,
+                fetched_at: now,
+                manifest_bytes: manifest_bytes.clone(),
+            };
+            cache::store(cache_dir, &uri, socket_path, &cached)?;
+        }
+    }
+
+    Ok((manifest_bytes, manifest))
+}
+
+/// Append a path to a base uri, with a single slash in between.
+fn join_uri(base: &str, path: &str) -> String {
+    let mut uri = base.to_string();
+    if !uri.ends_with("/") { uri.push('/'); }
+    uri.push_str(path);
+    uri
+}
+
+/// Return the uri of the manifest of the origin in the config, named
+/// `manifest`, unless the config sets `ManifestName=`.
+pub fn manifest_uri(config: &Config) -> String {
+    match config.manifest_name {
+        Some(ref name) => join_uri(&config.origin, name),
+        None => join_uri(&config.origin, manifest::DEFAULT_NAME),
+    }
+}
+
+/// Write a manifest that failed verification to `manifest.rejected` in the
+/// destination directory, for `--preserve-temp`.
+///
+/// Failing to do so is only reported, the verification error is what matters.
+fn keep_rejected_manifest(config: &Config, manifest_bytes: &[u8]) {
+    let path = config.destination.join("manifest.rejected");
+    match fs::File::create(&path).and_then(|mut f| f.write_all(manifest_bytes)) {
+        Ok(()) => eprintln!("Kept the rejected manifest at {}.", path.display()),
+        Err(e) => eprintln!("Failed to keep the rejected manifest at {}: {}", path.display(), e),
+    }
+}
+
+/// Return the current time in seconds since the unix epoch.
+fn unix_time() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Download the index at the uri, and verify it with `IndexKey=` from the
+/// config, or with `PublicKey=` if the config does not set an index key.
+pub fn fetch_index<T: Transport>(config: &Config, uri: &str, transport: &mut T) -> Result<Index> {
+    let mut index_bytes = Vec::new();
+    transport.get(uri, |chunk| {
+        index_bytes.extend_from_slice(chunk);
+        Ok(())
+    })?;
+    Index::parse_any(&index_bytes[..], config.index_key.as_ref().or(config.public_key.as_ref()))
+}
+
+/// Check that the manifest is the one that the index pins, if there is an index.
+fn check_pin(config: &Config, pin: Option<&IndexEntry>, manifest_bytes: &[u8]) -> Result<()> {
+    let pin = match pin {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+    let digest = digest::digest(&digest::SHA256, manifest_bytes);
+    if digest.as_ref() == pin.manifest_digest.as_ref() {
+        Ok(())
+    } else {
+        let name = config.name.clone().unwrap_or_else(|| pin.name.clone());
+        Err(Error::IndexMismatch(name))
+    }
 }
 
 /// Fetch the remote manifest, store it locally if it is valid, and return it.
-pub fn fetch_manifest(config: &Config, curl_handle: &mut curl::Handle) -> Result<Manifest> {
+///
+/// With a pin from an index, the manifest must be the one that the index
+/// pins, otherwise it is rejected before anything is stored.
+pub fn fetch_manifest<T: Transport>(
+    fetch: &cli::Fetch,
+    config: &Config,
+    pin: Option<&IndexEntry>,
+    transport: &mut T,
+) -> Result<Manifest> {
     // TODO: If we fail to load this manifest, it is not clear to the user
     // that this is about the local manifest, rather than the remote one. We
     // should extend the error type to include this info.
     // TODO: In the case of a key rotation, after updating the key in the
     // config, we would no longer be able to load the currently stored manifest.
     // How to deal with that? Allow multiple public keys in the config?
-    let local_manifest = Manifest::load_local(&config.destination, &config.public_key)?;
+    let local_manifest = match Manifest::load_local_bytes(&config.destination)? {
+        Some(bytes) => {
+            let public_key = trust::manifest_key(config, fetch.tofu, &bytes[..])?;
+            Some(Manifest::parse(&bytes[..], &public_key)?)
+        }
+        None => None,
+    };
 
-    let mut uri = config.origin.to_string();
-    if !uri.ends_with("/") { uri.push('/'); }
-    uri.push_str("manifest");
+    let (manifest_bytes, remote_manifest) = download_manifest(fetch, config, transport)?;
+    check_pin(config, pin, &manifest_bytes[..])?;
 
-    let mut manifest_bytes = Vec::new();
-    curl_handle.download(&uri, |chunk| manifest_bytes.extend_from_slice(chunk))?;
+    // Check the name before trusting the key, so that with --tofu, an origin
+    // that serves the wrong image does not get its key trusted either.
+    check_name(config, &remote_manifest)?;
+
+    // The signature is valid, so if this is the first fetch with --tofu, this
+    // is the moment to trust the key.
+    trust::trust(config, &manifest_bytes[..])?;
 
-    let remote_manifest = Manifest::parse(&manifest_bytes[..], &config.public_key)?;
+    // Check this before the subset check below, which would also reject a
+    // manifest that lost entries, but without naming the cause. The record
+    // also outlives the local manifest, if that is deleted.
+    if let Some(mode) = fetch.detect_rollback {
+        rollback::check(config, mode, &remote_manifest)?;
+    }
 
     // If there was a local manifest already, it must be a subset of the remote
     // one. Otherwise, if we overwrite the local manifest, that would remove
     // entries, and those entries might exist on disk -- one of them might be
     // the image currently in use. If we would erase that from the manifest,
-    // then we would no longer know what that image is. So bail out.
-    if Some(false) == local_manifest.map(|m| m.is_subset_of(&remote_manifest)) {
+    // then we would no longer know what that image is. So bail out. Only the
+    // oldest versions may go, when the publisher prunes them.
+    if Some(false) == local_manifest.map(|m| remote_manifest.is_successor_of(&m)) {
         let msg = "The remote manifest is not a superset of the local manifest. Rejecting remote manifest.";
         return Err(Error::OperationError(msg))
     }
@@ -67,7 +472,87 @@ pub fn fetch_manifest(config: &Config, curl_handle: &mut curl::Handle) -> Result
     Ok(remote_manifest)
 }
 
-fn fetch_image(uri: &str, target_fname: &Path, digest: &Sha256, curl_handle: &mut curl::Handle) -> Result<()> {
+/// Check that the manifest declares the name that the config expects, if any.
+///
+/// The name is covered by the signature, so a mismatch means that the origin
+/// points at a different image, not that somebody tampered with the manifest.
+pub fn check_name(config: &Config, manifest: &Manifest) -> Result<()> {
+    match config.name {
+        Some(ref name) if manifest.name() != Some(&name[..]) => {
+            let actual = manifest.name().map(String::from);
+            Err(Error::NameMismatch(name.clone(), actual))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Download to a file while computing the SHA256 digest, return the digest.
+///
+/// The digest is computed and the file is written on a separate thread, so the
+/// download does not wait for hashing and the disk, and the other way around.
+/// The caller is responsible for deleting the file if the download fails.
+pub fn download_digest<T: Transport>(
+    uri: &str,
+    fname: &Path,
+    buffer_size: usize,
+    transport: &mut T,
+) -> Result<Sha256> {
+    let f = BufWriter::with_capacity(buffer_size, fs::File::create(fname)?);
+
+    // The channel is bounded, so when the disk is slower than the network, the
+    // download slows down, rather than buffering the image in memory.
+    let (sender, receiver) = mpsc::sync_channel(PIPELINE_CHUNKS);
+    let writer = thread::spawn(move || hash_write(receiver, f));
+
+    let downloaded = transport.get(uri, |chunk| {
+        // The writer only hangs up when writing failed, it reports why below.
+        sender.send(chunk.to_vec()).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    });
+
+    // Closing the channel tells the writer that the download is complete.
+    drop(sender);
+    let written = writer.join().expect("The image writer panicked.");
+
+    // A failed write is the cause of a failed download, so report it first.
+    let digest = written?;
+    downloaded?;
+    Ok(digest)
+}
+
+/// Hash and write the chunks from the channel until it closes, return the digest.
+fn hash_write<W: Write>(chunks: mpsc::Receiver<Vec<u8>>, mut out: W) -> io::Result<Sha256> {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    for chunk in chunks {
+        ctx.update(&chunk);
+        out.write_all(&chunk)?;
+    }
+    // Flush explicitly, dropping the writer would ignore errors.
+    out.flush()?;
+    Ok(Sha256::copy_from_slice(ctx.finish().as_ref()))
+}
+
+/// Download into a writer while computing the SHA256 digest, return the digest.
+pub fn download_digest_to<W: Write, T: Transport>(uri: &str, out: &mut W, transport: &mut T) -> Result<Sha256> {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    {
+        let ctx_ref = &mut ctx;
+        transport.get(uri, |chunk| {
+            ctx_ref.update(chunk);
+            out.write_all(chunk)
+        })?;
+    }
+    Ok(Sha256::copy_from_slice(ctx.finish().as_ref()))
+}
+
+fn fetch_image<T: Transport>(
+    uri: &str,
+    target_fname: &Path,
+    config: &Config,
+    candidate: &Entry,
+    preserve_temp: bool,
+    buffer_size: usize,
+    transport: &mut T,
+) -> Result<()> {
     // Download to store/<hexdigest>.new. Then later rename the file to its
     // final path. This ensures that when the program crashes or is killed mid-
     // download, next time we will start the download again immediately. Also,
@@ -78,23 +563,27 @@ fn fetch_image(uri: &str, target_fname: &Path, digest: &Sha256, curl_handle: &mu
     // In case of error, delete the temp file.
     let guard = util::FileGuard::new(&tmp_fname);
 
-    let mut ctx = digest::Context::new(&digest::SHA256);
-    {
-        let ctx_ref = &mut ctx;
-        let mut f = BufWriter::new(fs::File::create(&tmp_fname)?);
-        curl_handle.download_io(uri, |chunk| {
-            ctx_ref.update(chunk);
-            f.write_all(chunk)
-        })?;
-    }
-    let actual_digest = ctx.finish();
+    let actual_digest = download_digest(uri, &tmp_fname, buffer_size, transport)?;
 
     // The comparison is not constant time, but that is not an issue here; a
     // digest cannot be bruteforced byte by byte until it matches.
-    let is_digest_valid = actual_digest.as_ref() == digest.as_ref();
+    let verified = if actual_digest != candidate.digest {
+        Err(Error::DigestMismatch(candidate.digest.clone(), actual_digest))
+    } else {
+        run_verify_cmd(config, candidate, &tmp_fname)
+    };
 
-    if !is_digest_valid {
-        return Err(Error::InvalidDigest)
+    if let Err(e) = verified {
+        // With --preserve-temp, keep what the server sent for inspection, at
+        // store/<hexdigest>.rejected, outside of the valid names in the store.
+        if preserve_temp {
+            let rejected_fname = target_fname.with_extension("rejected");
+            match guard.move_readonly(&rejected_fname) {
+                Ok(()) => eprintln!("Kept the rejected download at {}.", rejected_fname.display()),
+                Err(e) => eprintln!("Failed to keep the rejected download: {}", e),
+            }
+        }
+        return Err(e)
     }
 
     // The store should be immutable, make the file readonly. Then move it into
@@ -104,75 +593,1335 @@ fn fetch_image(uri: &str, target_fname: &Path, digest: &Sha256, curl_handle: &mu
     Ok(())
 }
 
+/// Reconstruct the image of a candidate from a delta against an image in the store.
+///
+/// Returns false if the candidate has no delta from an image that the store
+/// has. The result is verified against the digest in the manifest, so a wrong
+/// delta or a damaged base can never produce an accepted image.
+fn fetch_delta<T: Transport>(
+    fetch: &cli::Fetch,
+    config: &Config,
+    manifest: &Manifest,
+    candidate: &Entry,
+    target_fname: &Path,
+    transport: &mut T,
+) -> Result<bool> {
+    let store_dir = target_fname.parent().unwrap();
+    let (delta, base_fname) = match candidate.deltas.iter().map(|delta| {
+        let mut base_hex = String::new();
+        util::append_hex(&mut base_hex, delta.base.as_ref());
+        (delta, store_dir.join(base_hex))
+    }).find(|&(_, ref base_fname)| base_fname.is_file()) {
+        Some(found) => found,
+        None => return Ok(false),
+    };
+
+    let mut delta_path = String::from("store/");
+    util::append_hex(&mut delta_path, delta.digest.as_ref());
+    delta_path.push_str(".delta");
+    let uri = match manifest.image_base() {
+        Some(image_base) => join_uri(image_base, &delta_path),
+        None => join_uri(&config.origin, &delta_path),
+    };
+
+    if fetch.prints_progress() {
+        println!("Fetching delta for {} from {} ...", candidate.version.as_str(), uri);
+    }
+
+    let delta_fname = target_fname.with_extension("delta");
+    let delta_guard = util::FileGuard::new(&delta_fname);
+    let buffer_size = fetch.write_buffer_size.unwrap_or(DEFAULT_WRITE_BUFFER_SIZE);
+    if download_digest(&uri, &delta_fname, buffer_size, transport)? != delta.digest {
+        return Err(Error::InvalidDigest)
+    }
+
+    let tmp_fname = target_fname.with_extension("new");
+    let guard = util::FileGuard::new(&tmp_fname);
+    {
+        let base_bytes = FileBuffer::open(&base_fname)?;
+        let delta_bytes = FileBuffer::open(&delta_fname)?;
+        let mut out = BufWriter::with_capacity(buffer_size, fs::File::create(&tmp_fname)?);
+        delta::apply(&base_bytes, &delta_bytes, &mut out)?;
+        out.flush()?;
+    }
+    drop(delta_guard);
+
+    if util::sha256sum(&tmp_fname)? != candidate.digest {
+        return Err(Error::InvalidDigest)
+    }
+    run_verify_cmd(config, candidate, &tmp_fname)?;
+    guard.move_readonly(target_fname)?;
+
+    Ok(true)
+}
+
+/// Download the image of a candidate, from a delta if the manifest has one
+/// that applies, and from the full image otherwise.
+///
+/// A delta that fails to download or apply is not fatal, then fetch downloads
+/// the full image instead. Only a veto from the verify command is final.
+fn fetch_image_or_delta<T: Transport>(
+    fetch: &cli::Fetch,
+    config: &Config,
+    manifest: &Manifest,
+    candidate: &Entry,
+    uri: &str,
+    target_fname: &Path,
+    transport: &mut T,
+) -> Result<()> {
+    match fetch_delta(fetch, config, manifest, candidate, target_fname, transport) {
+        Ok(true) => return Ok(()),
+        Ok(false) => {}
+        Err(e @ Error::Vetoed(..)) => return Err(e),
+        Err(e) => eprintln!(
+            "Failed to apply the delta for {}, downloading the full image instead: {}",
+            candidate.version.as_str(), e,
+        ),
+    }
+    let buffer_size = fetch.write_buffer_size.unwrap_or(DEFAULT_WRITE_BUFFER_SIZE);
+    fetch_image(uri, target_fname, config, candidate, fetch.preserve_temp, buffer_size, transport)
+}
+
+/// Run the `VerifyCmd=` of the config on an image, if it has one.
+///
+/// The command runs with `sh -c`, with the path of the image in `$1` and in
+/// `TAKO_IMAGE`, and the version and digest in `TAKO_VERSION` and `TAKO_DIGEST`.
+/// A nonzero exit status rejects the image.
+fn run_verify_cmd(config: &Config, candidate: &Entry, image_path: &Path) -> Result<()> {
+    let cmd = match config.verify_cmd {
+        Some(ref cmd) => cmd,
+        None => return Ok(()),
+    };
+
+    let mut digest_hex = String::new();
+    util::append_hex(&mut digest_hex, candidate.digest.as_ref());
+
+    let status = process::Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .arg("tako-verify")
+        .arg(image_path)
+        .env("TAKO_IMAGE", image_path)
+        .env("TAKO_VERSION", candidate.version.as_str())
+        .env("TAKO_DIGEST", digest_hex)
+        .status()?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::Vetoed(cmd.clone()))
+    }
+}
+
+/// Return whether the `latest` symlink points at the target path already.
+fn is_symlink_current<P: AsRef<Path>>(config: &Config, target_path: P) -> bool {
+    let mut sympath = config.destination.clone();
+    sympath.push("latest");
+
+    match sympath.read_link() {
+        Ok(ref points_at) => points_at == target_path.as_ref(),
+        Err(..) => false,
+    }
+}
+
+/// Report that the destination drifted from what Tako installed there.
+///
+/// Without `--repair`, that is an error, and the destination is left as it is
+/// for inspection. With it, the units are marked as not restarted, so they
+/// restart once `latest` points at an intact image again.
+fn handle_drift(fetch: &cli::Fetch, config: &Config, what: String) -> Result<()> {
+    if !fetch.repair {
+        return Err(Error::Drift(config.destination.clone(), what))
+    }
+    eprintln!("Repairing {}: {}.", config.destination.display(), what);
+    if let Some(mut state) = State::load_local(&config.destination)? {
+        state.restarted_units.clear();
+        state.store_local(&config.destination)?;
+    }
+    Ok(())
+}
+
+/// Check that `latest`, if it exists, is a symlink to an image in the store.
+///
+/// Tako only ever points `latest` at `store/<hexdigest>`. Anything else was put
+/// there by hand. That the image matches its digest is checked when fetching
+/// it, see `fetch_candidate`.
+fn check_latest(fetch: &cli::Fetch, config: &Config) -> Result<()> {
+    let sympath = config.destination.join("latest");
+    let what = match sympath.read_link() {
+        Ok(ref target) => {
+            let is_store_path = target.to_str()
+                .map(|t| t.starts_with("store/") && Sha256::from_hex(&t.as_bytes()[6..]).is_some())
+                .unwrap_or(false);
+            if is_store_path {
+                return Ok(())
+            }
+            format!("latest points at {}, not at an image in the store", target.display())
+        }
+        Err(..) if fs::symlink_metadata(&sympath).is_ok() => "latest is not a symlink".to_string(),
+        Err(..) => return Ok(()),
+    };
+    handle_drift(fetch, config, what)
+}
+
+/// Return whether the `latest` symlink exists, pointing at any image.
+fn has_symlink(config: &Config) -> bool {
+    config.destination.join("latest").read_link().is_ok()
+}
+
 /// Create the symlink to the target path `store/<hexdigest>`.
 ///
 /// This is a no-op if the symlink exists and points to the target path already.
-fn update_symlink<P: AsRef<Path>>(config: &Config, target_path: P) -> io::Result<()> {
+/// Returns whether the symlink changed.
+fn update_symlink<P: AsRef<Path>>(config: &Config, target_path: P) -> io::Result<bool> {
+    if is_symlink_current(config, &target_path) {
+        return Ok(false)
+    }
+
+    // The symlink does not exist, or it points at something else than the
+    // target. Creating a symlink does not overwrite an existing one, so create
+    // a new symlink next to it, and rename that over the old one. The rename
+    // is atomic, so `latest` always points at a complete image.
     let mut sympath = config.destination.clone();
     sympath.push("latest");
+    let mut sympath_tmp = config.destination.clone();
+    sympath_tmp.push("latest.new");
 
-    match sympath.read_link() {
-        Ok(ref points_at) if points_at == target_path.as_ref() => return Ok(()),
-        // Other cases are nonexisting symlink, or symlink pointing at
-        // something else than the target. In both cases we create (overwrite)
-        // the symlink.
-        _ => unix::fs::symlink(target_path.as_ref(), sympath)
+    // A stale temporary symlink may remain if we crashed before the rename.
+    match fs::remove_file(&sympath_tmp) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        other => other?,
+    }
+
+    unix::fs::symlink(target_path.as_ref(), &sympath_tmp)?;
+    fs::rename(&sympath_tmp, &sympath)?;
+    util::sync_dir(&config.destination)?;
+    Ok(true)
+}
+
+/// Check that a unit that was just (re)started stays active.
+///
+/// Polls `systemctl is-active` until the grace period is over. A unit may be
+/// activating for a while, but it must be active at the end, and it must not
+/// fail or stop in the meantime.
+fn verify_active<S: ServiceManager>(manager: &mut S, unit: &str, grace: u64) -> Result<()> {
+    let deadline = time::Instant::now() + time::Duration::from_secs(grace);
+    loop {
+        let state = manager.active_state(unit)?;
+        let is_down = state == "failed" || state == "inactive";
+        let is_over = time::Instant::now() >= deadline;
+        if is_down || (is_over && state != "active") {
+            return Err(Error::UnitNotActive(unit.to_string(), state))
+        }
+        if is_over {
+            return Ok(())
+        }
+        thread::sleep(time::Duration::from_secs(1));
+    }
+}
+
+/// Point `latest` at the candidate, and restart the units that use it.
+///
+/// Units in `StopUnit=` are stopped before `latest` changes, and started in
+/// reverse order afterwards. Then the units in `RestartUnit=` are restarted in
+/// order. If a unit fails, the units after it are left alone. With
+/// `--allow-missing-restart-unit`, a restart unit that does not exist is
+/// skipped, but a unit that exists and fails to restart still fails. If the
+/// service manager does not run on this host, no unit is touched, and that is
+/// an error, unless `--ignore-restart-units-without-systemd` skips the units.
+///
+/// Records progress in the state file, such that if we crash halfway, the next
+/// run starts and restarts the units that were not yet done. Returns whether
+/// the symlink changed.
+///
+/// A new version with the image of the installed version only updates the
+/// state, unless `--force-restart` is given. With `--init`, or with
+/// `--no-restart-on-first-install` when there is no `latest` yet, the units
+/// are left alone.
+fn apply<P: AsRef<Path>, S: ServiceManager>(
+    fetch: &cli::Fetch,
+    config: &Config,
+    candidate: &Entry,
+    target_path: P,
+    manager: &mut S,
+) -> Result<bool> {
+    // All units that we act on, for when none of them need to be touched.
+    let mut all_units = config.stop_units.clone();
+    all_units.extend(config.restart_units.iter().cloned());
+
+    let mut state = match State::load_local(&config.destination)? {
+        // We applied this image before, possibly not all restarts completed.
+        // If it is a different version with the same image, for instance a
+        // re-tag, then the units run this content already, and restarting
+        // them would only disrupt them, unless that is what was asked for.
+        Some(ref state) if state.digest == candidate.digest => {
+            let mut state = state.clone();
+            if state.version.as_str() != candidate.version.as_str() {
+                if fetch.force_restart {
+                    state.restarted_units = Vec::new();
+                } else if fetch.prints_progress() {
+                    println!(
+                        "Content of {} is unchanged from {}, skipping restart.",
+                        candidate.version.as_str(), state.version.as_str(),
+                    );
+                }
+                state.version = candidate.version.clone();
+            }
+            state
+        }
+        // We did not apply the image, but the symlink points at it already.
+        // Then it was installed by hand, or by a version of Tako that did not
+        // record state. Assume that the units use it already.
+        None if is_symlink_current(config, &target_path) => State {
+            version: candidate.version.clone(),
+            digest: candidate.digest.clone(),
+            restarted_units: all_units.clone(),
+        },
+        // With --init, the units are about to start with the new image, or
+        // they depend on it and cannot start without it, so there is no need
+        // to restart them. On a first install, the operator may want to start
+        // them by other means.
+        _ => {
+            let is_first_install = !has_symlink(config);
+            let skip_units = fetch.init || (fetch.no_restart_on_first_install && is_first_install);
+            if skip_units && !fetch.init && !all_units.is_empty() && fetch.prints_progress() {
+                println!("First install of {}, not restarting units.", candidate.version.as_str());
+            }
+            State {
+                version: candidate.version.clone(),
+                digest: candidate.digest.clone(),
+                restarted_units: if skip_units { all_units.clone() } else { Vec::new() },
+            }
+        }
+    };
+
+    // Check for the service manager before anything changes, so a host
+    // without it fails cleanly, or leaves the units alone if asked to.
+    let pending_units: Vec<&String> = all_units
+        .iter()
+        .filter(|unit| !state.restarted_units.contains(unit))
+        .collect();
+    if !pending_units.is_empty() && !manager.is_available()? {
+        if !fetch.ignore_restart_units_without_systemd {
+            return Err(Error::ServiceManagerUnavailable(manager.name()))
+        }
+        for unit in &pending_units {
+            eprintln!("Skipping {}: {} is not available on this host.", unit, manager.name());
+        }
+        state.restarted_units = all_units.clone();
+    }
+
+    // Record the intent to restart before changing the symlink, so a crash
+    // after changing it cannot lose the pending restarts.
+    state.store_local(&config.destination)?;
+
+    let pending_stop_units: Vec<&String> = config.stop_units
+        .iter()
+        .filter(|unit| !state.restarted_units.contains(unit))
+        .collect();
+
+    // If the symlink is current already, we are resuming after a crash or a
+    // failed start, and the units are stopped already, or running the new
+    // image already. Starting them is harmless in either case.
+    if !is_symlink_current(config, &target_path) {
+        for unit in &pending_stop_units {
+            if fetch.prints_progress() {
+                println!("Stopping {} ...", unit);
+            }
+            manager.run("stop", unit)?;
+        }
     }
+
+    let is_changed = update_symlink(config, &target_path)?;
+
+    // A unit that does not stay active is not recorded as restarted, so the
+    // next run restarts and checks it again.
+    let verify_grace = fetch.verify_grace.unwrap_or(DEFAULT_VERIFY_GRACE);
+
+    for unit in pending_stop_units.iter().rev() {
+        if fetch.prints_progress() {
+            println!("Starting {} ...", unit);
+        }
+        manager.run("start", unit)?;
+        if fetch.verify_active {
+            verify_active(manager, unit, verify_grace)?;
+        }
+        state.restarted_units.push((*unit).clone());
+        state.store_local(&config.destination)?;
+    }
+
+    for unit in &config.restart_units {
+        if state.restarted_units.contains(unit) {
+            continue
+        }
+        if fetch.prints_progress() {
+            println!("Restarting {} ...", unit);
+        }
+        match manager.run("restart", unit) {
+            // A missing unit has nothing to restart, so it is done, and the
+            // next run does not try it again.
+            Err(Error::UnitNotFound(..)) if fetch.allow_missing_restart_unit => {
+                eprintln!("Skipping {}: the unit does not exist on this host.", unit);
+            }
+            Err(e) => return Err(e),
+            Ok(()) => if fetch.verify_active {
+                verify_active(manager, unit, verify_grace)?;
+            },
+        }
+        state.restarted_units.push(unit.clone());
+        state.store_local(&config.destination)?;
+    }
+
+    Ok(is_changed)
 }
 
-/// Check for, download, and apply updates as given in the config.
-pub fn fetch(config_fname: &str) -> Result<()> {
-    let config = load_config(config_fname)?;
-    println!("config: {:?}", config);
+/// Time allowed for downloading the manifest, if not configured otherwise.
+const DEFAULT_MANIFEST_TIMEOUT: u64 = 30;
+
+/// Size of the buffer between a download and the image file, if not configured
+/// otherwise. Downloads arrive in chunks of at most 16 KiB, this makes about one
+/// write call per megabyte instead.
+pub const DEFAULT_WRITE_BUFFER_SIZE: usize = 1 << 20;
+
+/// Number of downloaded chunks that may wait for the thread that hashes and
+/// writes them, about 1 MiB at 16 KiB per chunk.
+const PIPELINE_CHUNKS: usize = 64;
+
+/// Time that a unit must stay active for --verify-active, if not configured
+/// otherwise.
+const DEFAULT_VERIFY_GRACE: u64 = 10;
+
+/// Download a cached manifest unconditionally after this many seconds, if not
+/// configured otherwise.
+const DEFAULT_CACHE_TTL: u64 = 3600;
 
+/// Abort downloads that receive no data at all for this many seconds.
+const STALL_TIMEOUT: u64 = 60;
+
+/// Create a curl handle with the certificate options from the config and cli.
+///
+/// The handle is set up with the manifest timeout, because the manifest is
+/// always downloaded first. Call `set_image_timeout` before downloading an image.
+pub fn new_curl_handle(
+    fetch: &cli::Fetch,
+    config: &Config,
+    deadline: Option<time::Instant>,
+) -> Result<curl::Handle> {
     let mut curl_handle = curl::Handle::new();
+    if let Some(deadline) = deadline {
+        curl_handle.set_deadline(deadline);
+    }
 
-    let manifest = fetch_manifest(&config, &mut curl_handle)?;
+    let manifest_timeout = fetch.manifest_timeout
+        .or(config.manifest_timeout)
+        .unwrap_or(DEFAULT_MANIFEST_TIMEOUT);
+    curl_handle.set_timeout(manifest_timeout);
+    curl_handle.set_stall_timeout(STALL_TIMEOUT);
 
-    let (lower, upper) = config.version.pattern_to_bounds();
-    let candidate = manifest.latest_compatible_entry(&lower, &upper).ok_or(Error::NoCandidate)?;
+    if let Some(ref socket_path) = config.unix_socket_path {
+        curl_handle.set_unix_socket_path(socket_path)?;
+    }
 
-    let mut uri = config.origin.to_string();
-    if !uri.ends_with("/") { uri.push('/'); }
-    let prefix_len = uri.len();
-    uri.push_str("store/");
-    util::append_hex(&mut uri, candidate.digest.as_ref());
-    let store_path = &uri[prefix_len..];
+    set_ca_certs(fetch, config, &mut curl_handle)?;
+
+    if config.pinned_certs.is_empty() {
+        curl_handle.share_connections();
+    } else {
+        curl_handle.set_pinned_public_keys(&config.pinned_certs)?;
+    }
+
+    if fetch.accept_expired_cert {
+        // Without a pin, not verifying the certificate chain would mean that
+        // we accept any server. The manifest signature still protects the
+        // integrity of the images, but we do not want to talk to just anyone.
+        if config.pinned_certs.is_empty() {
+            let msg = "--accept-expired-cert requires 'PinnedCert=' in the config.";
+            return Err(Error::OperationError(msg))
+        }
+        // Curl cannot relax only the expiry check, so we disable chain
+        // verification altogether, and rely on the pin to identify the server.
+        eprintln!(
+            "Warning: Not verifying the certificate chain of {}. \
+            The origin is authenticated by its pinned public key only.",
+            config.origin
+        );
+        curl_handle.set_verify_peer(false);
+    }
+
+    Ok(curl_handle)
+}
+
+/// Trust the CA certificates from `--cacert` and `--capath`, or from the config.
+fn set_ca_certs(fetch: &cli::Fetch, config: &Config, curl_handle: &mut curl::Handle) -> Result<()> {
+    if let Some(ca_cert) = fetch.ca_cert.as_ref().or(config.ca_cert.as_ref()) {
+        check_ca_cert(ca_cert)?;
+        curl_handle.set_ca_info(ca_cert)?;
+    }
+    if let Some(ca_path) = fetch.ca_path.as_ref().or(config.ca_path.as_ref()) {
+        if let Err(e) = fs::read_dir(ca_path) {
+            return Err(Error::InvalidCaCert(ca_path.clone(), e.to_string()))
+        }
+        curl_handle.set_ca_path(ca_path)?;
+    }
+    Ok(())
+}
+
+/// Check that a CA bundle can be read, and holds at least one certificate.
+///
+/// Curl would only fail at the first TLS handshake, with a less clear message,
+/// or for an empty bundle, with a verification failure that blames the origin.
+fn check_ca_cert(path: &Path) -> Result<()> {
+    let mut bytes = Vec::new();
+    if let Err(e) = fs::File::open(path).and_then(|mut f| f.read_to_end(&mut bytes)) {
+        return Err(Error::InvalidCaCert(path.to_path_buf(), e.to_string()))
+    }
+    let marker = b"-----BEGIN CERTIFICATE-----";
+    if !bytes.windows(marker.len()).any(|w| w == &marker[..]) {
+        let reason = "Expected PEM certificates, as '-----BEGIN CERTIFICATE-----' blocks.";
+        return Err(Error::InvalidCaCert(path.to_path_buf(), reason.to_string()))
+    }
+    Ok(())
+}
 
-    println!("Fetching {} from {} ...", candidate.version.as_str(), uri);
+/// Set the timeout for image downloads, which by default is unlimited.
+///
+/// We do not know the size of the image in advance, so we cannot pick a
+/// timeout that suits it. The stall timeout still catches hung downloads.
+pub fn set_image_timeout(fetch: &cli::Fetch, config: &Config, curl_handle: &mut curl::Handle) {
+    let image_timeout = fetch.image_timeout.or(config.image_timeout).unwrap_or(0);
+    curl_handle.set_timeout(image_timeout);
+}
+
+/// Download and verify the manifest, and write it to a file or stdout.
+///
+/// Unlike a regular fetch, this does not touch the destination directory.
+pub fn fetch_manifest_only(
+    fetch: &cli::Fetch,
+    config_fname: &str,
+    deadline: Option<time::Instant>,
+) -> Result<()> {
+    let mut config = load_fetch_config(fetch, config_fname)?;
+
+    if let Some(public_key) = load_override_key(fetch)? {
+        if config.public_key.is_some() && !fetch.force {
+            let msg = "The config sets 'PublicKey=' already. \
+                       Pass --force to verify against the key from the command line instead.";
+            return Err(Error::OperationError(msg))
+        }
+        config.public_key = Some(public_key);
+    }
+
+    let mut curl_handle = new_curl_handle(fetch, &config, deadline)?;
+
+    // Only continue past this point if the signature is valid, so we never
+    // write an untrusted manifest.
+    let (manifest_bytes, manifest) = download_manifest(fetch, &config, &mut curl_handle)?;
+    check_name(&config, &manifest)?;
+
+    match fetch.out_path {
+        Some(ref out_path) => {
+            // Write to a temporary file and rename it, so a reader never
+            // observes a partially written manifest.
+            let mut tmp_path = out_path.clone().into_os_string();
+            tmp_path.push(".new");
+            let tmp_path = PathBuf::from(tmp_path);
+            let guard = util::FileGuard::new(&tmp_path);
+            fs::File::create(&tmp_path)?.write_all(&manifest_bytes[..])?;
+            guard.move_readonly(out_path)?;
+        }
+        None => {
+            let stdout = io::stdout();
+            stdout.lock().write_all(&manifest_bytes[..])?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Download and verify the manifest, and select the version that a fetch would
+/// install, without downloading the image.
+///
+/// The version must be the one that `--expected-version` asserts, if any.
+fn select_remote(
+    fetch: &cli::Fetch,
+    config: &Config,
+    deadline: Option<time::Instant>,
+) -> Result<(Manifest, Entry)> {
+    let pin = fetch_pins(fetch, &[config], deadline, &mut Vec::new())?.pop().and_then(|p| p);
+    let mut curl_handle = new_curl_handle(fetch, config, deadline)?;
+
+    let (manifest_bytes, manifest) = download_manifest(fetch, config, &mut curl_handle)?;
+    check_pin(config, pin.as_ref(), &manifest_bytes[..])?;
+    check_name(config, &manifest)?;
+    let candidate = match pin {
+        Some(ref pin) => select_pinned(config, &manifest, pin)?.clone(),
+        None => manifest.select_candidate(
+            &config.version,
+            config.version_lower_bound.as_ref(),
+            config.version_upper_bound.as_ref(),
+        )?.clone(),
+    };
+
+    if let Some(ref expected) = fetch.expected_version {
+        if candidate.version != *expected {
+            return Err(Error::UnexpectedVersion(expected.clone(), candidate.version.clone()))
+        }
+    }
+
+    Ok((manifest, candidate))
+}
+
+/// Download and verify the manifest, select a version, and compare it against
+/// the installed version, for `--report-only`.
+///
+/// Unlike a regular fetch, this does not download the image, and does not
+/// write to the destination directory. Required configs are not checked.
+pub fn report_only(
+    fetch: &cli::Fetch,
+    config_fname: &str,
+    deadline: Option<time::Instant>,
+) -> Result<Pending> {
+    let config = load_fetch_config(fetch, config_fname)?;
+    let (_, candidate) = select_remote(fetch, &config, deadline)?;
+
+    let installed = match State::load_local(&config.destination)? {
+        Some(state) => state.version,
+        None => return Ok(Pending::Update(None, candidate.version.clone())),
+    };
+
+    // Fail with the same downgrade error that a regular fetch would fail with.
+    if candidate.version == installed {
+        Ok(Pending::UpToDate(installed))
+    } else if candidate.version < installed && !fetch.allow_downgrade {
+        Err(Error::Downgrade(installed, candidate.version.clone()))
+    } else {
+        Ok(Pending::Update(Some(installed), candidate.version.clone()))
+    }
+}
+
+/// Return the key from `--key`, or read it from `--key-file`, if either is given.
+fn load_override_key(fetch: &cli::Fetch) -> Result<Option<PublicKey>> {
+    let path = match (fetch.public_key.as_ref(), fetch.public_key_path.as_ref()) {
+        (Some(key), _) => return Ok(Some(key.clone())),
+        (None, Some(path)) => path,
+        (None, None) => return Ok(None),
+    };
+    let lines = config::read_lines(path)?;
+    match lines.first().and_then(|line| PublicKey::from_base64(line.trim())) {
+        Some(key) => Ok(Some(key)),
+        None => Err(Error::InvalidPublicKeyFile(path.display().to_string())),
+    }
+}
+
+/// Write the digest of the installed image to a file, as `sha256:<hex>`.
+///
+/// Without an entry, because the fetch failed or selected no version, remove
+/// the file instead, so it never names an image that may not have landed.
+pub fn update_digest_file(path: &Path, entry: Option<&Entry>) -> Result<()> {
+    let entry = match entry {
+        Some(e) => e,
+        None => return match fs::remove_file(path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            other => Ok(other?),
+        },
+    };
+
+    let mut out = String::from("sha256:");
+    util::append_hex(&mut out, entry.digest.as_ref());
+    out.push('\n');
+
+    // Write to a temporary file and rename it, so a reader never observes a
+    // partially written digest.
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".new");
+    let tmp_path = PathBuf::from(tmp_path);
+    let guard = util::FileGuard::new(&tmp_path);
+    fs::File::create(&tmp_path)?.write_all(out.as_bytes())?;
+    guard.move_readonly(path)?;
+
+    Ok(())
+}
+
+/// An image that has been downloaded and verified, but not applied yet.
+struct Staged {
+    config: Config,
+    candidate: Entry,
+}
+
+/// Download and verify the manifest and the newest compatible image.
+///
+/// This does not change what `latest` points at. Call `commit` for that.
+///
+/// With a pin from an index, the candidate is the version that the index pins.
+///
+/// Statistics of the downloads, failed ones included, are added to `transfers`.
+fn stage(
+    fetch: &cli::Fetch,
+    mut config: Config,
+    pin: Option<&IndexEntry>,
+    deadline: Option<time::Instant>,
+    transfers: &mut Vec<curl::Transfer>,
+) -> Result<Staged> {
+    config.destination = util::resolve_dir(&config.destination)?;
+    set_fsync(fetch, &config);
+    check_latest(fetch, &config)?;
+    let mut curl_handle = new_curl_handle(fetch, &config, deadline)?;
+
+    let manifest = fetch_manifest(fetch, &config, pin, &mut curl_handle);
+    transfers.extend(curl_handle.take_transfers());
+    let manifest = manifest?;
+
+    // With --fallback-to-older, try every compatible version, newest first.
+    // An index pins a single version, there is nothing to fall back to.
+    let candidates = if let Some(pin) = pin {
+        vec![select_pinned(&config, &manifest, pin)?]
+    } else {
+        let candidates = manifest.select_candidates(
+            &config.version,
+            config.version_lower_bound.as_ref(),
+            config.version_upper_bound.as_ref(),
+        )?;
+        if fetch.fallback_to_older {
+            candidates
+        } else {
+            candidates.into_iter().take(1).collect()
+        }
+    };
+
+    // Check the assertion before downloading anything, so a failed assertion
+    // leaves the destination untouched. It cannot be combined with fallback.
+    if let Some(ref expected) = fetch.expected_version {
+        if candidates[0].version != *expected {
+            return Err(Error::UnexpectedVersion(expected.clone(), candidates[0].version.clone()))
+        }
+    }
+
+    let installed = if fetch.allow_downgrade {
+        None
+    } else {
+        State::load_local(&config.destination)?
+    };
+
+    let mut first_error = None;
+
+    for candidate in candidates {
+        // The newest compatible version should only ever go up. If it went
+        // down, for instance because entries were removed from the manifest,
+        // then that is likely a mistake on the server, and we should not roll
+        // back. This also stops the fallback at the installed version.
+        if let Some(ref state) = installed {
+            if candidate.version < state.version {
+                return Err(Error::Downgrade(state.version.clone(), candidate.version.clone()))
+            }
+        }
+
+        match fetch_candidate(fetch, &config, &manifest, candidate, &mut curl_handle, transfers) {
+            Ok(()) => {
+                let staged = Staged {
+                    config: config,
+                    candidate: candidate.clone(),
+                };
+                return Ok(staged)
+            }
+            Err(e) => {
+                // Only an image that is missing, damaged, or rejected is a
+                // reason to try an older version, other errors would fail for
+                // any version.
+                let is_image_error = match e {
+                    Error::InvalidDigest | Error::DigestMismatch(..) |
+                    Error::DownloadError(..) | Error::Vetoed(..) => true,
+                    _ => false,
+                };
+                if !fetch.fallback_to_older || !is_image_error {
+                    return Err(e)
+                }
+                eprintln!("Skipping version {}: {}", candidate.version.as_str(), e);
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+
+    // There is at least one candidate, so if we get here, it failed.
+    Err(first_error.expect("At least one candidate failed."))
+}
+
+/// Return the entry of the version that the index pins.
+///
+/// The version must match the pattern and the bounds in the config, like any
+/// candidate.
+fn select_pinned<'a>(config: &Config, manifest: &'a Manifest, pin: &IndexEntry) -> Result<&'a Entry> {
+    let candidates = manifest.select_candidates(
+        &config.version,
+        config.version_lower_bound.as_ref(),
+        config.version_upper_bound.as_ref(),
+    )?;
+    match candidates.into_iter().find(|e| e.version == pin.version) {
+        Some(entry) => Ok(entry),
+        None if manifest.entries().iter().any(|e| e.version == pin.version) => {
+            Err(Error::NoCandidate(config.version.clone(), vec![pin.version.clone()]))
+        }
+        None => {
+            let name = config.name.clone().unwrap_or_else(|| pin.name.clone());
+            Err(Error::IndexMismatch(name))
+        }
+    }
+}
+
+/// Download and verify the index of every config that names one, once per
+/// index, and return the entry of every config, in order.
+///
+/// Configs without an index get no entry. Statistics of the downloads are
+/// added to `transfers`.
+fn fetch_pins(
+    fetch: &cli::Fetch,
+    configs: &[&Config],
+    deadline: Option<time::Instant>,
+    transfers: &mut Vec<curl::Transfer>,
+) -> Result<Vec<Option<IndexEntry>>> {
+    let mut indexes: Vec<(&str, Index)> = Vec::new();
+    let mut pins = Vec::with_capacity(configs.len());
+
+    for config in configs {
+        let uri = match config.index {
+            Some(ref uri) => &uri[..],
+            None => {
+                pins.push(None);
+                continue
+            }
+        };
+        if !indexes.iter().any(|&(u, _)| u == uri) {
+            let mut curl_handle = new_curl_handle(fetch, config, deadline)?;
+            let index = fetch_index(config, uri, &mut curl_handle);
+            transfers.extend(curl_handle.take_transfers());
+            indexes.push((uri, index?));
+        }
+        let index = &indexes.iter().find(|&&(u, _)| u == uri).expect("Index was fetched above.").1;
+
+        // The config has a name, it does not parse without one.
+        let name = config.name.as_ref().expect("Config with 'Index=' has 'Name='.");
+        match index.entry(name) {
+            Some(entry) => pins.push(Some(entry.clone())),
+            None => return Err(Error::NotInIndex(name.clone())),
+        }
+    }
+
+    Ok(pins)
+}
+
+/// Flush the files of this config to disk, unless `--no-fsync` or `Fsync=false`
+/// says otherwise. Configs are fetched one by one, so this applies to the
+/// files that are written until the next config.
+fn set_fsync(fetch: &cli::Fetch, config: &Config) {
+    util::set_fsync(!fetch.no_fsync && config.fsync);
+}
+
+/// Return the path of the image of an entry relative to the destination,
+/// `store/<hexdigest>`.
+pub fn store_path(entry: &Entry) -> String {
+    let mut store_path = String::from("store/");
+    util::append_hex(&mut store_path, entry.digest.as_ref());
+    store_path
+}
+
+/// Return the uri to download an image from, given its store path.
+///
+/// The manifest may point elsewhere for the images, for instance at object
+/// storage. The manifest is signed, and the digest authenticates the image.
+/// On the server, the image file may have an extension that the manifest
+/// records; in the destination it never has one.
+pub fn image_uri(config: &Config, manifest: &Manifest, store_path: &str) -> String {
+    let mut uri = match manifest.image_base() {
+        Some(image_base) => join_uri(image_base, store_path),
+        None => join_uri(&config.origin, store_path),
+    };
+    uri.push_str(manifest.image_ext());
+    uri
+}
+
+/// Print the uris that a fetch of the config would download from, for
+/// `--print-url`.
+///
+/// The digest of the image is only known from the manifest, and the manifest
+/// can move images elsewhere with `ImageBase=`, or give them an extension with
+/// `ImageExt=`. So without `--expected-version`, this downloads nothing, and
+/// prints an unresolved image uri with a placeholder for the digest. With it,
+/// this downloads and verifies the manifest, and prints the uri of the image
+/// of that version.
+pub fn print_urls(
+    fetch: &cli::Fetch,
+    config_fname: &str,
+    deadline: Option<time::Instant>,
+) -> Result<()> {
+    let config = load_fetch_config(fetch, config_fname)?;
+    let image_line = match fetch.expected_version {
+        Some(..) => {
+            let (manifest, candidate) = select_remote(fetch, &config, deadline)?;
+            format!("image    {}", image_uri(&config, &manifest, &store_path(&candidate)))
+        }
+        None => format!("images   {} (unresolved)", join_uri(&config.origin, "store/<digest>")),
+    };
+    println!("manifest {}", manifest_uri(&config));
+    println!("{}", image_line);
+    if let Some(ref socket_path) = config.unix_socket_path {
+        println!("socket   {}", socket_path.display());
+    }
+    Ok(())
+}
+
+/// Create a curl handle for a manifest with `ImageBase=`, set up for images.
+///
+/// Pins and the unix socket apply to the origin, not to the image host, so
+/// the handle gets the timeouts, the deadline of the origin handle, and the
+/// CA certificates from `CaCert=` and `CaPath=`, but no pins and no socket.
+pub fn new_image_handle(
+    fetch: &cli::Fetch,
+    config: &Config,
+    origin_handle: &curl::Handle,
+) -> Result<curl::Handle> {
+    let mut image_handle = curl::Handle::new();
+    image_handle.share_connections();
+    image_handle.set_stall_timeout(STALL_TIMEOUT);
+    if let Some(deadline) = origin_handle.deadline() {
+        image_handle.set_deadline(deadline);
+    }
+    set_image_timeout(fetch, config, &mut image_handle);
+    set_ca_certs(fetch, config, &mut image_handle)?;
+    Ok(image_handle)
+}
+
+/// Return whether the image of the candidate is installed, per the state.
+///
+/// This only looks at the state and the symlink, it does not hash the image.
+fn is_installed(config: &Config, candidate: &Entry) -> Result<bool> {
+    let store_path = store_path(candidate);
+    let is_recorded = match State::load_local(&config.destination)? {
+        Some(state) => state.digest == candidate.digest,
+        None => false,
+    };
+    Ok(is_recorded && is_symlink_current(config, &store_path) && config.destination.join(&store_path).is_file())
+}
+
+/// Download and verify the image of a candidate, unless the store has it.
+///
+/// With `--no-download-if-present`, an installed image is trusted as it is,
+/// unless `--repair` asks to verify it.
+fn fetch_candidate(
+    fetch: &cli::Fetch,
+    config: &Config,
+    manifest: &Manifest,
+    candidate: &Entry,
+    curl_handle: &mut curl::Handle,
+    transfers: &mut Vec<curl::Transfer>,
+) -> Result<()> {
+    if fetch.no_download_if_present && !fetch.repair && is_installed(config, candidate)? {
+        if fetch.prints_progress() {
+            println!("The image of {} is installed already, up to date.", candidate.version.as_str());
+        }
+        return Ok(())
+    }
+
+    let store_path = store_path(candidate);
+    let uri = image_uri(config, manifest, &store_path);
+
+    if fetch.prints_progress() {
+        println!("Fetching {} from {} ...", candidate.version.as_str(), uri);
+        if let Some(notes) = candidate.annotation("notes") {
+            println!("Notes for {}: {}", candidate.version.as_str(), notes);
+        }
+    }
 
     // The target filename is store/<hexdigest> in the configured
     // destination directory.
     let mut target_fname = config.destination.clone();
-    target_fname.push(store_path);
+    target_fname.push(&store_path);
 
     // Create the store directory inside the target directory, if it does not
     // exist already. Do not create any of the parent dirs, this is the
     // responsibility of the user. The unwrap is safe here; by construction the
     // path has at least two components.
-    let store_dirname = target_fname.parent().unwrap();
-    if !store_dirname.is_dir() {
-        fs::create_dir(store_dirname)?;
+    {
+        let store_dirname = target_fname.parent().unwrap();
+        if !store_dirname.is_dir() {
+            fs::create_dir(store_dirname)?;
+        }
     }
 
-    if target_fname.is_file() {
-        // If the target file exists in the store already, don't download it
-        // again, but do verify its integrity. If damaged, delete the file from
-        // the store, such that on the next run we will download it again, and
-        // also to prevent the damaged (or tampered with) file from being used.
-        if util::sha256sum(&target_fname)? != candidate.digest {
+    // If the target file exists in the store already, don't download it
+    // again, but do verify its integrity. If damaged, delete the file from the
+    // store, such that on the next run we will download it again, and also to
+    // prevent the damaged (or tampered with) file from being used. If it is
+    // the installed image, then the destination drifted, and only `--repair`
+    // may replace it.
+    let mut is_stored = target_fname.is_file();
+    if is_stored && util::sha256sum(&target_fname)? != candidate.digest {
+        if !is_symlink_current(config, &store_path) {
             let _ = fs::remove_file(&target_fname);
-            // TODO: Also delete the symlink if it happened to point at the
-            // corrupted file?
             return Err(Error::InvalidDigest)
         }
+        let what = format!("the image of version {} does not match its digest", candidate.version.as_str());
+        handle_drift(fetch, config, what)?;
+        fs::remove_file(&target_fname)?;
+        is_stored = false;
+    }
+
+    if is_stored {
+        // An image in the store may predate the verify command, so verify it
+        // before installing it too. The installed image has been accepted, and
+        // we should not delete it from under `latest`.
+        if !is_symlink_current(config, &store_path) {
+            if let Err(e) = run_verify_cmd(config, candidate, &target_fname) {
+                let _ = fs::remove_file(&target_fname);
+                return Err(e)
+            }
+        }
     } else {
         // If the file was not in the store, download it. This performs an on
         // the fly integrity check.
-        fetch_image(&uri, &target_fname, &candidate.digest, &mut curl_handle)?;
+        if manifest.image_base().is_some() {
+            let mut image_handle = new_image_handle(fetch, config, curl_handle)?;
+            let result = fetch_image_or_delta(fetch, config, manifest, candidate, &uri, &target_fname, &mut image_handle);
+            transfers.extend(image_handle.take_transfers());
+            result?;
+        } else {
+            set_image_timeout(fetch, config, curl_handle);
+            let result = fetch_image_or_delta(fetch, config, manifest, candidate, &uri, &target_fname, curl_handle);
+            transfers.extend(curl_handle.take_transfers());
+            result?;
+        }
     }
 
-    update_symlink(&config, &store_path)?;
-
     Ok(())
 }
+
+/// Point `latest` at a staged image, and restart the units that use it.
+fn commit(fetch: &cli::Fetch, staged: &Staged) -> Result<Outcome> {
+    install(fetch, &staged.config, &staged.candidate)
+}
+
+/// Point `latest` at an image in the store, and restart the units that use it.
+///
+/// The image must be in the store at `store_path` already, and verified.
+pub fn install(fetch: &cli::Fetch, config: &Config, candidate: &Entry) -> Result<Outcome> {
+    set_fsync(fetch, config);
+    let target_path = store_path(candidate);
+    let is_changed = match config.restart_backend {
+        RestartBackend::Systemd => apply(fetch, config, candidate, target_path, &mut Systemd)?,
+        RestartBackend::OpenRc => apply(fetch, config, candidate, target_path, &mut OpenRc)?,
+        RestartBackend::Command(ref cmd) => {
+            apply(fetch, config, candidate, target_path, &mut RestartCommand::new(cmd))?
+        }
+    };
+    if is_changed {
+        Ok(Outcome::Updated(candidate.clone()))
+    } else {
+        Ok(Outcome::Unchanged(candidate.clone()))
+    }
+}
+
+/// Load the configs that a config requires, and the configs they require.
+///
+/// Returns the filename and config of every required config once, in the
+/// order in which they are first encountered.
+fn load_requirements(fetch: &cli::Fetch, config_fname: &str, config: &Config) -> Result<Vec<(String, Config)>> {
+    let mut seen = vec![String::from(config_fname)];
+    let mut result: Vec<(String, Config)> = Vec::new();
+
+    // Resolve relative paths against the directory of the requiring config.
+    let mut pending: Vec<String> = resolve_requires(config_fname, config);
+    pending.reverse();
+
+    while let Some(fname) = pending.pop() {
+        if seen.contains(&fname) {
+            continue
+        }
+        seen.push(fname.clone());
+
+        let required = match load_fetch_config(fetch, &fname) {
+            Ok(c) => c,
+            Err(e) => return Err(Error::RequirementFailed(fname, Box::new(e))),
+        };
+        let mut requires = resolve_requires(&fname, &required);
+        requires.reverse();
+        pending.extend(requires);
+        result.push((fname, required));
+    }
+
+    Ok(result)
+}
+
+/// Return the `Requires=` paths of a config, relative to the working directory.
+fn resolve_requires(config_fname: &str, config: &Config) -> Vec<String> {
+    let dir = Path::new(config_fname).parent().unwrap_or(Path::new(""));
+    config.requires
+        .iter()
+        .map(|p| dir.join(p).to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Check for, download, and apply updates as given in the config, which was
+/// loaded from `config_fname` with `load_fetch_config`.
+///
+/// If the config requires other configs, their images are downloaded and
+/// verified first, and none of them is applied unless all of them can be.
+/// Statistics of the downloads, failed ones included, are added to `transfers`.
+///
+/// Downloads that are still running at the deadline fail. If the deadline
+/// passes before everything is verified, nothing is applied.
+pub fn fetch(
+    fetch: &cli::Fetch,
+    config_fname: &str,
+    config: Config,
+    deadline: Option<time::Instant>,
+    transfers: &mut Vec<curl::Transfer>,
+) -> Result<Outcome> {
+    let requirements = load_requirements(fetch, config_fname, &config)?;
+
+    // Check the units before downloading anything, so a config that the
+    // policy does not allow leaves the destination untouched.
+    let policy_path = fetch.policy_path.as_ref().map(|path| path.as_path());
+    if let Some(policy) = Policy::load(policy_path)? {
+        for &(ref fname, ref required) in &requirements {
+            if let Err(e) = policy.check(required) {
+                return Err(Error::RequirementFailed(fname.clone(), Box::new(e)))
+            }
+        }
+        policy.check(&config)?;
+    }
+
+    // Verify the indexes before any manifest, so that if the index does not
+    // list an image, nothing is downloaded at all.
+    let mut pins = {
+        let mut configs: Vec<&Config> = requirements.iter().map(|&(_, ref c)| c).collect();
+        configs.push(&config);
+        fetch_pins(fetch, &configs, deadline, transfers)?
+    };
+    let pin = pins.pop().expect("There is a pin for the config itself.");
+
+    let mut staged_requirements = Vec::new();
+    for ((fname, required), required_pin) in requirements.into_iter().zip(pins) {
+        match stage(fetch, required, required_pin.as_ref(), deadline, transfers) {
+            Ok(staged) => staged_requirements.push((fname, staged)),
+            Err(e) => return Err(Error::RequirementFailed(fname, Box::new(e))),
+        }
+    }
+
+    let staged = stage(fetch, config, pin.as_ref(), deadline, transfers)?;
+
+    if let Some(deadline) = deadline {
+        if time::Instant::now() >= deadline {
+            return Err(Error::DeadlineExceeded)
+        }
+    }
+
+    // Everything verified, now apply the requirements before the image that
+    // requires them, so its units restart with the requirements in place.
+    for &(ref fname, ref staged_requirement) in &staged_requirements {
+        if let Err(e) = commit(fetch, staged_requirement) {
+            return Err(Error::RequirementFailed(fname.clone(), Box::new(e)))
+        }
+    }
+
+    commit(fetch, &staged)
+}
+
+#[cfg(test)]
+mod test {
+    use ring::digest;
+    use ring::signature::Ed25519KeyPair;
+    use ring::test::rand::FixedSliceRandom;
+    use untrusted::Input;
+
+    use cli;
+    use config::{Config, PublicKey};
+    use error::Error;
+    use manifest::{Entry, Index, IndexEntry, Manifest};
+    use transport::MemoryTransport;
+    use util::Sha256;
+    use version::Version;
+    use std::sync::mpsc;
+
+    use super::{check_pin, download_digest_to, download_manifest, fetch_index, hash_write, image_uri, select_pinned, store_path};
+
+    fn key_pair(seed: &[u8; 32]) -> Ed25519KeyPair {
+        let rng = FixedSliceRandom { bytes: &seed[..] };
+        let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        Ed25519KeyPair::from_pkcs8(Input::from(&pkcs8_bytes)).unwrap()
+    }
+
+    /// A config that trusts the key from seed `test-key-very-security-such-safe`.
+    fn get_test_config() -> Config {
+        Config::parse(&[
+            "Origin=https://images.example.com/app-foo",
+            "PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=",
+            "Version=*",
+            "Destination=/var/lib/images/app-foo",
+        ]).unwrap()
+    }
+
+    fn get_test_manifest() -> Manifest {
+        let mut manifest = Manifest::new();
+        manifest.insert(Entry {
+            version: Version::from("1.0.0"),
+            digest: Sha256([7; 32]),
+            annotations: Vec::new(),
+            deltas: Vec::new(),
+        }).unwrap();
+        manifest
+    }
+
+    #[test]
+    fn download_manifest_accepts_manifest_signed_with_config_key() {
+        let pair = key_pair(b"test-key-very-security-such-safe");
+        let manifest = get_test_manifest();
+        let mut transport = MemoryTransport::new();
+        transport.insert(
+            "https://images.example.com/app-foo/manifest",
+            manifest.serialize(&[pair]).as_bytes(),
+        );
+
+        let fetch = cli::Fetch::new(Vec::new());
+        let (_, downloaded) = download_manifest(&fetch, &get_test_config(), &mut transport).unwrap();
+        assert_eq!(downloaded, manifest);
+        assert_eq!(&transport.requests[..], &["https://images.example.com/app-foo/manifest"]);
+    }
+
+    #[test]
+    fn download_manifest_rejects_other_key_and_tampering() {
+        let fetch = cli::Fetch::new(Vec::new());
+        let uri = "https://images.example.com/app-foo/manifest";
+
+        let other_pair = key_pair(b"second-key-for-rotation-testing!");
+        let mut transport = MemoryTransport::new();
+        transport.insert(uri, get_test_manifest().serialize(&[other_pair]).as_bytes());
+        match download_manifest(&fetch, &get_test_config(), &mut transport) {
+            Err(Error::InvalidSignature) => {}
+            other => panic!("Expected InvalidSignature, got {:?}.", other.map(|(_, m)| m)),
+        }
+
+        let pair = key_pair(b"test-key-very-security-such-safe");
+        let tampered = get_test_manifest().serialize(&[pair]).replace("1.0.0", "1.0.1");
+        let mut transport = MemoryTransport::new();
+        transport.insert(uri, tampered.as_bytes());
+        assert!(download_manifest(&fetch, &get_test_config(), &mut transport).is_err());
+
+        let mut transport = MemoryTransport::new();
+        assert!(download_manifest(&fetch, &get_test_config(), &mut transport).is_err());
+    }
+
+    #[test]
+    fn fetch_index_pins_manifest_and_version() {
+        let pair = key_pair(b"test-key-very-security-such-safe");
+        let manifest_bytes = get_test_manifest().serialize(&[key_pair(b"test-key-very-security-such-safe")]);
+        let mut index = Index::new();
+        index.insert(IndexEntry {
+            name: "app-foo".to_string(),
+            version: Version::from("1.0.0"),
+            manifest_digest: Sha256::copy_from_slice(digest::digest(&digest::SHA256, manifest_bytes.as_bytes()).as_ref()),
+        }).unwrap();
+
+        let uri = "https://images.example.com/index";
+        let mut transport = MemoryTransport::new();
+        transport.insert(uri, index.serialize(&[pair]).as_bytes());
+
+        let mut config = get_test_config();
+        config.name = Some("app-foo".to_string());
+        let index = fetch_index(&config, uri, &mut transport).unwrap();
+        let pin = index.entry("app-foo").unwrap();
+
+        check_pin(&config, Some(pin), manifest_bytes.as_bytes()).unwrap();
+        let manifest = get_test_manifest();
+        assert_eq!(select_pinned(&config, &manifest, pin).unwrap().version, Version::from("1.0.0"));
+
+        // A manifest that was published after the index is not the pinned one.
+        let mut newer = get_test_manifest();
+        newer.insert(Entry {
+            version: Version::from("1.1.0"),
+            digest: Sha256([8; 32]),
+            annotations: Vec::new(),
+            deltas: Vec::new(),
+        }).unwrap();
+        let newer_bytes = newer.serialize(&[key_pair(b"test-key-very-security-such-safe")]);
+        match check_pin(&config, Some(pin), newer_bytes.as_bytes()) {
+            Err(Error::IndexMismatch(ref name)) if name == "app-foo" => {}
+            other => panic!("Expected IndexMismatch, got {:?}.", other),
+        }
+
+        // The pinned version must still match the pattern of the config.
+        config.version = Version::from("2.*");
+        assert!(select_pinned(&config, &manifest, pin).is_err());
+
+        // The index must be signed with the key from the config.
+        config.public_key = None;
+        config.index_key = Some(PublicKey::from_pair(&key_pair(b"second-key-for-rotation-testing!")));
+        match fetch_index(&config, uri, &mut transport) {
+            Err(Error::InvalidSignature) => {}
+            other => panic!("Expected InvalidSignature, got {:?}.", other),
+        }
+    }
+
+    #[test]
+    fn download_digest_to_writes_and_hashes_image() {
+        let image = b"This is not really a squashfs image, but close enough.";
+        let uri = "https://images.example.com/app-foo/store/0707";
+        let mut transport = MemoryTransport::new();
+        transport.insert(uri, &image[..]);
+
+        let mut out = Vec::new();
+        let actual = download_digest_to(uri, &mut out, &mut transport).unwrap();
+        let expected = digest::digest(&digest::SHA256, &image[..]);
+        assert_eq!(actual, Sha256::copy_from_slice(expected.as_ref()));
+        assert_eq!(&out[..], &image[..]);
+
+        let missing = "https://images.example.com/app-foo/store/0808";
+        assert!(download_digest_to(missing, &mut Vec::new(), &mut transport).is_err());
+    }
+
+    #[test]
+    fn hash_write_hashes_and_writes_every_chunk() {
+        let (sender, receiver) = mpsc::sync_channel(2);
+        let writer = ::std::thread::spawn(move || {
+            let mut out = Vec::new();
+            let digest = hash_write(receiver, &mut out).unwrap();
+            (digest, out)
+        });
+        for chunk in &[&b"This is not "[..], b"really a squashfs ", b"image."] {
+            sender.send(chunk.to_vec()).unwrap();
+        }
+        drop(sender);
+
+        let (actual, out) = writer.join().unwrap();
+        let image = b"This is not really a squashfs image.";
+        let expected = digest::digest(&digest::SHA256, &image[..]);
+        assert_eq!(actual, Sha256::copy_from_slice(expected.as_ref()));
+        assert_eq!(&out[..], &image[..]);
+    }
+
+    #[test]
+    fn manifest_name_and_image_ext_change_the_uris() {
+        let config = Config::parse(&[
+            "Origin=https://images.example.com/app-foo",
+            "PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=",
+            "Version=*",
+            "Destination=/var/lib/images/app-foo",
+            "ManifestName=manifest.txt",
+        ]).unwrap();
+        let pair = key_pair(b"test-key-very-security-such-safe");
+        let mut manifest = get_test_manifest();
+        manifest.set_image_ext(".img").unwrap();
+        let mut transport = MemoryTransport::new();
+        transport.insert(
+            "https://images.example.com/app-foo/manifest.txt",
+            manifest.serialize(&[pair]).as_bytes(),
+        );
+
+        let fetch = cli::Fetch::new(Vec::new());
+        let (_, downloaded) = download_manifest(&fetch, &config, &mut transport).unwrap();
+        let entry = &downloaded.entries()[0];
+        let hex = "0707070707070707070707070707070707070707070707070707070707070707";
+        assert_eq!(store_path(entry), format!("store/{}", hex));
+        assert_eq!(
+            image_uri(&config, &downloaded, &store_path(entry)),
+            format!("https://images.example.com/app-foo/store/{}.img", hex),
+        );
+    }
+}
@@ -0,0 +1,295 @@
+// Tako -- Take container image.
+// Copyright 2018 Arian van Putten, Ruud van Asseldonk, Tako Marks.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Conversion of existing Ed25519 keys into the format that Tako uses.
+//!
+//! Tako expects secret keys as base64-encoded PKCS#8 v2 documents, which hold
+//! the 32-byte seed and the public key. Ring can parse these, but it can only
+//! produce them for keys it generates itself, so we build the document here.
+//! It has a fixed layout for Ed25519, and Ring verifies it when parsing it.
+
+use std::fs;
+use std::io;
+use std::io::Read;
+
+use base64;
+use ring::signature::Ed25519KeyPair;
+use untrusted::Input;
+
+use cli::{ImportKey, KeyFormat};
+use error::{Error, Result};
+
+/// DER of a PKCS#8 v2 Ed25519 key up to the seed.
+const PKCS8_V2_PREFIX: [u8; 16] = [
+    0x30, 0x53, 0x02, 0x01, 0x01, 0x30, 0x05, 0x06,
+    0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+
+/// DER of a PKCS#8 v2 Ed25519 key between the seed and the public key.
+const PKCS8_V2_MIDDLE: [u8; 5] = [0xa1, 0x23, 0x03, 0x21, 0x00];
+
+/// DER of a PKCS#8 v1 Ed25519 key up to the seed, the seed is all that follows.
+/// This is what `openssl genpkey -algorithm ed25519` writes.
+const PKCS8_V1_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06,
+    0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+
+const SEED_LEN: usize = 32;
+
+/// Read the key to import from a file, or from stdin if the path is `-`.
+fn read_input(import: &ImportKey) -> Result<String> {
+    let mut input = String::new();
+    if import.path.to_str() == Some("-") {
+        io::stdin().read_to_string(&mut input)?;
+    } else {
+        fs::File::open(&import.path)?.read_to_string(&mut input)?;
+    }
+    Ok(input)
+}
+
+/// Extract the base64-encoded body between the PEM header and footer lines.
+fn decode_pem(input: &str, label: &str) -> Result<Vec<u8>> {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+    let mut body = String::new();
+    let mut is_inside = false;
+    for line in input.lines().map(|line| line.trim()) {
+        if line == begin {
+            is_inside = true;
+        } else if line == end && is_inside {
+            let msg = "The PEM body is not valid base64.";
+            return base64::decode(&body).map_err(|_| Error::InvalidImportKey(msg))
+        } else if is_inside {
+            body.push_str(line);
+        }
+    }
+    Err(Error::InvalidImportKey("Expected a PEM block with BEGIN and END lines."))
+}
+
+fn parse_seed_hex(input: &str) -> Result<Vec<u8>> {
+    let hex = input.trim();
+    let msg = "Expected 64 hexadecimal characters.";
+    if hex.len() != 2 * SEED_LEN || !hex.is_ascii() {
+        return Err(Error::InvalidImportKey(msg))
+    }
+    let mut seed = Vec::with_capacity(SEED_LEN);
+    for i in 0..SEED_LEN {
+        let byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16);
+        seed.push(byte.map_err(|_| Error::InvalidImportKey(msg))?);
+    }
+    Ok(seed)
+}
+
+fn parse_pem(input: &str) -> Result<Vec<u8>> {
+    let der = decode_pem(input, "PRIVATE KEY")?;
+    let is_v1 = der.len() == PKCS8_V1_PREFIX.len() + SEED_LEN
+        && der[..PKCS8_V1_PREFIX.len()] == PKCS8_V1_PREFIX[..];
+    let is_v2 = der.len() == PKCS8_V2_PREFIX.len() + SEED_LEN + PKCS8_V2_MIDDLE.len() + SEED_LEN
+        && der[..PKCS8_V2_PREFIX.len()] == PKCS8_V2_PREFIX[..];
+    if !is_v1 && !is_v2 {
+        let msg = "Expected an unencrypted PKCS#8 Ed25519 private key.";
+        return Err(Error::InvalidImportKey(msg))
+    }
+    // A v2 document holds the public key too. If it does not match the seed,
+    // the document is damaged, and we should not silently repair it.
+    if is_v2 {
+        let msg = "The public key in the PKCS#8 document does not match the private key.";
+        Ed25519KeyPair::from_pkcs8(Input::from(&der)).map_err(|_| Error::InvalidImportKey(msg))?;
+    }
+    // The seed is at the same offset in both versions.
+    Ok(der[PKCS8_V1_PREFIX.len()..PKCS8_V1_PREFIX.len() + SEED_LEN].to_vec())
+}
+
+/// Reads the length-prefixed strings and integers of the OpenSSH key format.
+struct SshReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> SshReader<'a> {
+    fn read_u32(&mut self) -> Result<u32> {
+        if self.bytes.len() < 4 {
+            return Err(Error::InvalidImportKey("The OpenSSH key is truncated."))
+        }
+        let b = self.bytes;
+        let n = ((b[0] as u32) << 24) | ((b[1] as u32) << 16) | ((b[2] as u32) << 8) | b[3] as u32;
+        self.bytes = &self.bytes[4..];
+        Ok(n)
+    }
+
+    fn read_string(&mut self) -> Result<&'a [u8]> {
+        let len = self.read_u32()? as usize;
+        if self.bytes.len() < len {
+            return Err(Error::InvalidImportKey("The OpenSSH key is truncated."))
+        }
+        let (string, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(string)
+    }
+}
+
+fn parse_openssh(input: &str) -> Result<Vec<u8>> {
+    let blob = decode_pem(input, "OPENSSH PRIVATE KEY")?;
+    let magic = b"openssh-key-v1\0";
+    if !blob.starts_with(&magic[..]) {
+        return Err(Error::InvalidImportKey("Expected an OpenSSH private key."))
+    }
+    let mut reader = SshReader { bytes: &blob[magic.len()..] };
+
+    let cipher = reader.read_string()?;
+    let _kdf = reader.read_string()?;
+    let _kdf_options = reader.read_string()?;
+    if cipher != b"none" {
+        let msg = "The OpenSSH key is encrypted. Remove the passphrase with 'ssh-keygen -p' first.";
+        return Err(Error::InvalidImportKey(msg))
+    }
+    if reader.read_u32()? != 1 {
+        return Err(Error::InvalidImportKey("Expected a single key in the OpenSSH key file."))
+    }
+    let _public_blob = reader.read_string()?;
+
+    // The private section starts with two equal check numbers, to detect a
+    // wrong passphrase. Then follows the key type, the public key, and the
+    // seed followed by the public key again.
+    let mut private = SshReader { bytes: reader.read_string()? };
+    if private.read_u32()? != private.read_u32()? {
+        return Err(Error::InvalidImportKey("The check numbers of the OpenSSH key do not match."))
+    }
+    if private.read_string()? != b"ssh-ed25519" {
+        return Err(Error::InvalidImportKey("Expected an Ed25519 key, not an RSA or ECDSA key."))
+    }
+    let public_key = private.read_string()?;
+    let secret_key = private.read_string()?;
+    if secret_key.len() != 2 * SEED_LEN || &secret_key[SEED_LEN..] != public_key {
+        return Err(Error::InvalidImportKey("The OpenSSH key is not a valid Ed25519 key."))
+    }
+    Ok(secret_key[..SEED_LEN].to_vec())
+}
+
+/// Wrap a seed in a PKCS#8 v2 document, and verify it by parsing it.
+fn seed_to_pkcs8(seed: &[u8]) -> Result<Vec<u8>> {
+    let msg = "Expected a 32-byte Ed25519 seed.";
+    let key_pair = Ed25519KeyPair::from_seed_unchecked(Input::from(seed))
+        .map_err(|_| Error::InvalidImportKey(msg))?;
+
+    let mut pkcs8 = PKCS8_V2_PREFIX.to_vec();
+    pkcs8.extend_from_slice(seed);
+    pkcs8.extend_from_slice(&PKCS8_V2_MIDDLE[..]);
+    pkcs8.extend_from_slice(key_pair.public_key_bytes());
+
+    Ed25519KeyPair::from_pkcs8(Input::from(&pkcs8)).map_err(|_| Error::InvalidImportKey(msg))?;
+    Ok(pkcs8)
+}
+
+/// Convert the key in the given format to a PKCS#8 v2 document.
+pub fn convert(format: KeyFormat, input: &str) -> Result<Vec<u8>> {
+    let seed = match format {
+        KeyFormat::OpenSsh => parse_openssh(input)?,
+        KeyFormat::SeedHex => parse_seed_hex(input)?,
+        KeyFormat::Pem => parse_pem(input)?,
+    };
+    seed_to_pkcs8(&seed)
+}
+
+/// Read the key to import, and return it as a PKCS#8 v2 document.
+pub fn import_key(import: &ImportKey) -> Result<Vec<u8>> {
+    let input = read_input(import)?;
+    convert(import.format, &input)
+}
+
+#[cfg(test)]
+mod test {
+    use base64;
+    use ring::signature::Ed25519KeyPair;
+    use untrusted::Input;
+
+    use cli::KeyFormat;
+    use super::convert;
+
+    // Test vector 1 from RFC 8032.
+    const SEED_HEX: &'static str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+    const PUBLIC_KEY: &'static str = "11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=";
+
+    fn public_key_of(pkcs8: &[u8]) -> String {
+        let key_pair = Ed25519KeyPair::from_pkcs8(Input::from(pkcs8)).unwrap();
+        base64::encode(key_pair.public_key_bytes())
+    }
+
+    fn seed() -> Vec<u8> {
+        (0..32).map(|i| u8::from_str_radix(&SEED_HEX[2 * i..2 * i + 2], 16).unwrap()).collect()
+    }
+
+    fn pem(label: &str, der: &[u8]) -> String {
+        format!("-----BEGIN {}-----\n{}\n-----END {}-----\n", label, base64::encode(der), label)
+    }
+
+    fn ssh_string(out: &mut Vec<u8>, bytes: &[u8]) {
+        let n = bytes.len();
+        out.extend_from_slice(&[(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]);
+        out.extend_from_slice(bytes);
+    }
+
+    fn openssh(cipher: &[u8], key_type: &[u8]) -> String {
+        let seed = seed();
+        let public_key = base64::decode(PUBLIC_KEY).unwrap();
+        let mut secret_key = seed.clone();
+        secret_key.extend_from_slice(&public_key);
+
+        let mut private = vec![1, 2, 3, 4, 1, 2, 3, 4];
+        ssh_string(&mut private, key_type);
+        ssh_string(&mut private, &public_key);
+        ssh_string(&mut private, &secret_key);
+        ssh_string(&mut private, b"user@host");
+
+        let mut blob = b"openssh-key-v1\0".to_vec();
+        ssh_string(&mut blob, cipher);
+        ssh_string(&mut blob, b"none");
+        ssh_string(&mut blob, b"");
+        blob.extend_from_slice(&[0, 0, 0, 1]);
+        ssh_string(&mut blob, b"public blob");
+        ssh_string(&mut blob, &private);
+        pem("OPENSSH PRIVATE KEY", &blob)
+    }
+
+    #[test]
+    fn convert_imports_seed_hex() {
+        let pkcs8 = convert(KeyFormat::SeedHex, &format!("{}\n", SEED_HEX)).unwrap();
+        assert_eq!(public_key_of(&pkcs8), PUBLIC_KEY);
+        assert!(convert(KeyFormat::SeedHex, &SEED_HEX[2..]).is_err());
+        assert!(convert(KeyFormat::SeedHex, &SEED_HEX.replace("9d", "zz")).is_err());
+    }
+
+    #[test]
+    fn convert_imports_pkcs8_pem() {
+        let mut v1 = vec![
+            0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06,
+            0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+        ];
+        v1.extend_from_slice(&seed());
+        let pkcs8 = convert(KeyFormat::Pem, &pem("PRIVATE KEY", &v1)).unwrap();
+        assert_eq!(public_key_of(&pkcs8), PUBLIC_KEY);
+
+        // Converting the result again is the identity.
+        assert_eq!(convert(KeyFormat::Pem, &pem("PRIVATE KEY", &pkcs8)).unwrap(), pkcs8);
+
+        // A v2 document with a public key that does not match is rejected.
+        let mut damaged = pkcs8.clone();
+        let n = damaged.len();
+        damaged[n - 1] ^= 1;
+        assert!(convert(KeyFormat::Pem, &pem("PRIVATE KEY", &damaged)).is_err());
+        assert!(convert(KeyFormat::Pem, &pem("PRIVATE KEY", &v1[..40])).is_err());
+        assert!(convert(KeyFormat::Pem, &pem("EC PRIVATE KEY", &v1)).is_err());
+    }
+
+    #[test]
+    fn convert_imports_openssh() {
+        let pkcs8 = convert(KeyFormat::OpenSsh, &openssh(b"none", b"ssh-ed25519")).unwrap();
+        assert_eq!(public_key_of(&pkcs8), PUBLIC_KEY);
+        assert!(convert(KeyFormat::OpenSsh, &openssh(b"aes256-ctr", b"ssh-ed25519")).is_err());
+        assert!(convert(KeyFormat::OpenSsh, &openssh(b"none", b"ssh-rsa")).is_err());
+    }
+}
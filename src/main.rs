@@ -24,59 +24,554 @@ extern crate untrusted;
 
 use std::process;
 use std::env;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time;
 
 use ring::rand::SystemRandom;
 use ring::signature::Ed25519KeyPair;
 use untrusted::Input;
 
+mod archive;
+mod bundle;
+mod cache;
 mod cli;
 mod config;
 mod curl;
+mod delta;
+mod doctor;
 mod error;
+mod exit_code;
 mod fetch;
+mod import_key;
 mod manifest;
+mod metrics;
+mod policy;
+mod rollback;
+mod service;
+mod sftp;
+mod state;
 mod store;
+mod transport;
+mod trust;
 mod util;
+mod verify;
 mod version;
 
 use error::Error;
+use version::Version;
 
-fn run_init(config_fname: &String) {
-    println!("Run for {}.", config_fname);
-    // TODO: Check if store is good (optionally check digest).
-    // Only run fetch if required.
-    fetch::fetch(config_fname).unwrap();
+fn run_fetch_one(
+    fetch: &cli::Fetch,
+    config_fname: &String,
+    deadline: Option<time::Instant>,
+    transfers: &mut Vec<curl::Transfer>,
+) -> error::Result<fetch::Outcome> {
+    if fetch.prints_progress() {
+        println!("Run for {}.", config_fname);
+    }
+    // Keep the version bounds of the config, to explain a missing candidate.
+    let mut bounds = (None, None);
+    // Once the deadline has passed, the remaining configs fail without
+    // downloading anything.
+    let result = match deadline {
+        Some(deadline) if time::Instant::now() >= deadline => Err(Error::DeadlineExceeded),
+        _ => fetch::load_fetch_config(fetch, config_fname).and_then(|config| {
+            bounds = (config.version_lower_bound.clone(), config.version_upper_bound.clone());
+            fetch::fetch(fetch, config_fname, config, deadline, transfers)
+        }),
+    };
+    let result = match result {
+        // During normal operation, no candidate is not an error. We just
+        // don't do anything, as there is nothing we can do. But with --init,
+        // we must provide an image, so then the absence of one is an error.
+        // TODO: Check if store is good (optionally check digest).
+        // Only run fetch if required.
+        Err(ref e @ Error::NoCandidate(..)) if !fetch.init => {
+            if fetch.prints_progress() {
+                println!("No candidate to fetch. {}", e);
+                if let Some(msg) = describe_bounds(&bounds, e) {
+                    println!("{}", msg);
+                }
+            }
+            Ok(fetch::Outcome::NoCandidate)
+        }
+        Err(e) => {
+            let mut failed = String::new();
+            util::append_colored(&mut failed, "Failed", util::RED, fetch.uses_color(2));
+            eprintln!("{} to fetch {}: {}", failed, config_fname, e);
+            if let Some(msg) = describe_bounds(&bounds, &e) {
+                eprintln!("{}", msg);
+            }
+            if let Error::Downgrade(..) = e {
+                eprintln!("Pass --allow-downgrade to install the older version anyway.");
+            }
+            if let Error::Rollback(..) = e {
+                eprintln!("If versions were removed deliberately, delete 'highest-version' \
+                          in the destination directory.");
+            }
+            Err(e)
+        }
+        ok => ok,
+    };
+
+    if let Some(ref path) = fetch.digest_path {
+        let entry = result.as_ref().ok().and_then(|outcome| outcome.entry());
+        if let Err(e) = fetch::update_digest_file(path, entry) {
+            eprintln!("Failed to update digest file {}: {}", path.display(), e);
+            if result.is_ok() {
+                return Err(e)
+            }
+        }
+    }
+
+    result
+}
+
+/// For a fetch without candidate, describe the lower and upper version bound
+/// of the config, if it has any, and the highest version that the manifest has.
+fn describe_bounds(bounds: &(Option<Version>, Option<Version>), error: &Error) -> Option<String> {
+    let available = match *error {
+        Error::NoCandidate(_, ref available) => available,
+        _ => return None,
+    };
+    let bounds = match *bounds {
+        (None, None) => return None,
+        (Some(ref lower), None) => format!("at least {}", lower.as_str()),
+        (None, Some(ref upper)) => format!("below {}", upper.as_str()),
+        (Some(ref lower), Some(ref upper)) => format!("at least {} and below {}", lower.as_str(), upper.as_str()),
+    };
+    let mut msg = format!("The config only allows versions {}.", bounds);
+    if let Some(highest) = available.iter().max() {
+        msg.push_str(&format!(" The highest available version is {}.", highest.as_str()));
+    }
+    Some(msg)
+}
+
+/// Return the deadline for the run, if --deadline was given.
+fn fetch_deadline(fetch: &cli::Fetch) -> Option<time::Instant> {
+    fetch.deadline.map(|secs| time::Instant::now() + time::Duration::from_secs(secs))
+}
+
+/// Exit with the status for the failed configs, see `exit_code::for_errors`.
+fn exit_failed(errors: &[&Error]) -> ! {
+    process::exit(exit_code::for_errors(errors));
+}
+
+/// Update the metrics file, if one was given, with the results of this run.
+///
+/// A failure to write the metrics is reported, and makes Tako exit with a
+/// nonzero status, even if all fetches succeeded.
+fn write_metrics(fetch: &cli::Fetch, results: &[(&str, bool)]) {
+    let path = match fetch.metrics_path {
+        Some(ref p) => p,
+        None => return,
+    };
+
+    let now = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    // The metrics file belongs to no config, so 'Fsync=' of the last config
+    // that was fetched does not apply to it.
+    util::set_fsync(!fetch.no_fsync);
+
+    let result = metrics::Metrics::load(path).and_then(|mut metrics| {
+        for &(config_fname, success) in results {
+            // Report the version from the state file, because that is also
+            // known when this fetch failed or did not change anything.
+            let installed_version = fetch::load_fetch_config(fetch, config_fname)
+                .and_then(|config| state::State::load_local(&config.destination))
+                .ok()
+                .and_then(|state| state)
+                .map(|state| String::from(state.version.as_str()));
+            metrics.record(config_fname, now, success, installed_version);
+        }
+        metrics.store(path)
+    });
+
+    if let Err(e) = result {
+        eprintln!("Failed to write metrics to {}: {}", path.display(), e);
+        process::exit(exit_code::for_error(&e));
+    }
+}
+
+/// Fetch a single config and print the outcome as shell variables.
+fn run_fetch_env(fetch: cli::Fetch) {
+    let config_fname = &fetch.config_fnames[0];
+    let deadline = fetch_deadline(&fetch);
+    let result = run_fetch_one(&fetch, config_fname, deadline, &mut Vec::new());
+    write_metrics(&fetch, &[(config_fname, result.is_ok())]);
+    let outcome = match result {
+        Ok(outcome) => outcome,
+        Err(ref e) => exit_failed(&[e]),
+    };
+    // The fetch loaded the config already, so this is not expected to fail,
+    // but the file might have changed in the meantime.
+    match fetch::load_fetch_config(&fetch, config_fname) {
+        Ok(config) => print!("{}", outcome.format_env(&config.destination)),
+        Err(e) => {
+            eprintln!("Failed to load {}: {}", config_fname, e);
+            process::exit(exit_code::for_error(&e));
+        }
+    }
+}
+
+/// Fetch only the manifest of a single config.
+fn run_fetch_manifest(fetch: cli::Fetch) {
+    let config_fname = &fetch.config_fnames[0];
+    if let Err(e) = fetch::fetch_manifest_only(&fetch, config_fname, fetch_deadline(&fetch)) {
+        eprintln!("Failed to fetch manifest for {}: {}", config_fname, e);
+        exit_failed(&[&e]);
+    }
+}
+
+/// Print the uris of a single config without fetching the image.
+fn run_fetch_print_url(fetch: cli::Fetch) {
+    let config_fname = &fetch.config_fnames[0];
+    if let Err(e) = fetch::print_urls(&fetch, config_fname, fetch_deadline(&fetch)) {
+        eprintln!("Failed to resolve the urls of {}: {}", config_fname, e);
+        process::exit(exit_code::for_error(&e));
+    }
+}
+
+/// Report per config whether an update is available, without installing it.
+fn run_fetch_report(fetch: cli::Fetch) {
+    let deadline = fetch_deadline(&fetch);
+    let mut errors = Vec::new();
+    let mut num_pending = 0;
+    for fname in &fetch.config_fnames {
+        match fetch::report_only(&fetch, fname, deadline) {
+            Ok(pending) => {
+                if let fetch::Pending::Update(..) = pending {
+                    num_pending += 1;
+                }
+                println!("{}: {}", fname, pending.format_text());
+            }
+            Err(ref e @ Error::NoCandidate(..)) => println!("{}: no candidate. {}", fname, e),
+            Err(e) => {
+                eprintln!("Failed to check {}: {}", fname, e);
+                errors.push(e);
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        let errors: Vec<&Error> = errors.iter().collect();
+        exit_failed(&errors);
+    }
+    if num_pending > 0 {
+        process::exit(exit_code::UPDATE_AVAILABLE);
+    }
 }
 
-fn run_fetch(config_fname: &String) {
-    println!("Run for {}.", config_fname);
-    match fetch::fetch(config_fname) {
-        Ok(()) => {},
-        Err(Error::NoCandidate) => {
-            // During normal operation, no candidate is not an error. We just
-            // don't do anything, as there is nothing we can do.
-            // TODO: Print more details (bounds and actual available).
-            println!("No candidate to fetch.");
+/// Verify the config archive, and fetch the configs in it, rather than files.
+fn load_config_archive(fetch: &mut cli::Fetch, path: &Path, public_key: &config::PublicKey) {
+    let configs = match archive::load_configs(path, public_key) {
+        Ok(configs) => configs,
+        Err(e) => {
+            eprintln!("Failed to load config archive {}: {}", path.display(), e);
+            process::exit(exit_code::for_error(&e));
         }
-        Err(e) => panic!("{:?}", e),
+    };
+    if configs.is_empty() {
+        eprintln!("The config archive {} contains no config with extension '.tako'.", path.display());
+        process::exit(exit_code::FAILURE);
+    }
+    for (member, lines) in configs {
+        let fname = path.join(member).to_string_lossy().into_owned();
+        fetch.config_fnames.push(fname.clone());
+        fetch.archived_configs.push((fname, lines));
+    }
+}
+
+fn run_fetch(mut fetch: cli::Fetch) {
+    if let (Some(path), Some(key)) = (fetch.config_archive.clone(), fetch.archive_key.clone()) {
+        load_config_archive(&mut fetch, &path, &key);
+    }
+    if fetch.print_url {
+        return run_fetch_print_url(fetch)
+    }
+    if fetch.report_only {
+        return run_fetch_report(fetch)
+    }
+    if fetch.manifest_only {
+        return run_fetch_manifest(fetch)
+    }
+    if fetch.format == cli::Format::Env {
+        return run_fetch_env(fetch)
+    }
+
+    // Fetch every config, also when fetching one of them fails, and print a
+    // summary at the end, so one failure does not hide the others.
+    let deadline = fetch_deadline(&fetch);
+    let mut report = fetch::Report::new();
+    for fname in &fetch.config_fnames {
+        let mut transfers = Vec::new();
+        let result = run_fetch_one(&fetch, fname, deadline, &mut transfers);
+        report.push(fname.clone(), result, transfers);
+    }
+
+    let results: Vec<(&str, bool)> = report.results()
+        .iter()
+        .map(|&(ref fname, ref result)| (&fname[..], result.is_ok()))
+        .collect();
+    write_metrics(&fetch, &results);
+
+    match fetch.format {
+        cli::Format::Text if !fetch.quiet => {
+            print!("{}", report.format_text(fetch.uses_color(1)))
+        }
+        cli::Format::Text => {}
+        cli::Format::Json => print!("{}", report.format_json()),
+        cli::Format::Env => unreachable!("Handled by run_fetch_env."),
+    }
+
+    if report.num_failed() > 0 {
+        let errors: Vec<&Error> = report.results()
+            .iter()
+            .filter_map(|&(_, ref result)| result.as_ref().err())
+            .collect();
+        exit_failed(&errors);
     }
 }
 
 fn run_store(store: cli::Store) {
-    store::store(store).unwrap();
+    if let Err(e) = store::store(store) {
+        eprintln!("Failed to store image: {}", e);
+        process::exit(exit_code::for_error(&e));
+    }
+}
+
+fn run_seed(seed: cli::Seed) {
+    if let Err(e) = store::seed(seed) {
+        eprintln!("Failed to seed server directory: {}", e);
+        process::exit(exit_code::for_error(&e));
+    }
 }
 
-fn run_gen_key() -> Result<(), ring::error::Unspecified> {
+fn run_print_config(config_fname: &str) {
+    let lines = match config::read_lines(config_fname) {
+        Ok(lines) => lines,
+        Err(e) => {
+            eprintln!("Failed to load {}: {}", config_fname, e);
+            process::exit(exit_code::for_error(&e));
+        }
+    };
+    // Report all mistakes at once, so they can be fixed in one go.
+    match config::Config::parse_all(lines.iter()) {
+        Ok(config) => print!("{}", config.serialize()),
+        Err(errors) => {
+            for e in &errors {
+                eprintln!("{}: {}", config_fname, e);
+            }
+            let errors: Vec<&Error> = errors.iter().collect();
+            exit_failed(&errors);
+        }
+    }
+}
+
+fn run_keys(config_fnames: &[String]) {
+    let mut is_ok = true;
+    for fname in config_fnames {
+        match fetch::load_config(fname).map(|config| config.public_key) {
+            Ok(Some(key)) => {
+                let mut out = format!("{}: sha256:", fname);
+                util::append_hex(&mut out, key.fingerprint().as_ref());
+                println!("{} {}", out, key.to_base64());
+            }
+            Ok(None) => eprintln!("{}: Config does not contain a PublicKey= line.", fname),
+            Err(e) => {
+                eprintln!("{}: {}", fname, e);
+                is_ok = false;
+            }
+        }
+    }
+    if !is_ok {
+        process::exit(exit_code::FAILURE);
+    }
+}
+
+fn run_doctor(doctor: cli::Doctor) {
+    match doctor::doctor(doctor) {
+        Ok(true) => {}
+        Ok(false) => process::exit(exit_code::FAILURE),
+        Err(e) => {
+            eprintln!("Failed to check server directory: {}", e);
+            process::exit(exit_code::for_error(&e));
+        }
+    }
+}
+
+fn run_digest(digest: cli::Digest) {
+    let algorithm = match digest.algorithm {
+        cli::DigestAlgorithm::Sha256 => &ring::digest::SHA256,
+        cli::DigestAlgorithm::Sha512 => &ring::digest::SHA512,
+    };
+    match util::digest_file(&digest.path, algorithm) {
+        Ok(d) => {
+            let mut out = format!("{}:", digest.algorithm.name());
+            util::append_hex(&mut out, d.as_ref());
+            println!("{}", out);
+        }
+        Err(e) => {
+            eprintln!("Failed to compute digest of {}: {}", digest.path.display(), e);
+            process::exit(exit_code::for_error(&e));
+        }
+    }
+}
+
+fn run_export(export: cli::Export) {
+    match bundle::export(&export) {
+        Ok(entry) => println!("Exported {} to {}.", entry.version.as_str(), export.output_path.display()),
+        Err(e) => {
+            eprintln!("Failed to export {}: {}", export.config_fname, e);
+            process::exit(exit_code::for_error(&e));
+        }
+    }
+}
+
+fn run_import(import: cli::Import) {
+    match bundle::import(&import) {
+        Ok(fetch::Outcome::Updated(entry)) => println!("Installed {}.", entry.version.as_str()),
+        Ok(outcome) => {
+            if let Some(entry) = outcome.entry() {
+                println!("Version {} was installed already.", entry.version.as_str());
+            }
+        }
+        Err(e) => {
+            eprintln!("Failed to import {}: {}", import.bundle_path.display(), e);
+            process::exit(exit_code::for_error(&e));
+        }
+    }
+}
+
+fn run_rekey_all(rekey: cli::RekeyAll) {
+    match store::rekey_all(rekey) {
+        Ok(0) => {}
+        Ok(..) => process::exit(exit_code::FAILURE),
+        Err(e) => {
+            eprintln!("Failed to rekey manifests: {}", e);
+            process::exit(exit_code::for_error(&e));
+        }
+    }
+}
+
+fn run_canonicalize(canonicalize: cli::Canonicalize) {
+    match store::canonicalize(&canonicalize) {
+        Ok(message) => print!("{}", message),
+        Err(e) => {
+            eprintln!("Failed to canonicalize manifest: {}", e);
+            process::exit(exit_code::for_error(&e));
+        }
+    }
+}
+
+fn run_index(index: cli::Index) {
+    if let Err(e) = store::index(&index) {
+        eprintln!("Failed to write index: {}", e);
+        process::exit(exit_code::for_error(&e));
+    }
+}
+
+fn run_sign_archive(sign: cli::SignArchive) {
+    if let Err(e) = store::sign_archive(&sign) {
+        eprintln!("Failed to sign archive: {}", e);
+        process::exit(exit_code::for_error(&e));
+    }
+}
+
+fn run_verify(verify: cli::Verify) {
+    match verify::verify(&verify) {
+        Ok(true) => {}
+        Ok(false) => process::exit(exit_code::VERIFICATION),
+        Err(e) => {
+            eprintln!("Failed to verify {}: {}", verify.config_fname, e);
+            process::exit(exit_code::for_error(&e));
+        }
+    }
+}
+
+fn run_gen_key(gen_key: cli::GenKey) {
     // Generate a key pair in PKCS#8 (v2) format.
     let rng = SystemRandom::new();
-    let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng)?;
+    let result = Ed25519KeyPair::generate_pkcs8(&rng).and_then(|pkcs8_bytes| {
+        if gen_key.secret_out.is_none() && gen_key.public_out.is_none() {
+            print_key_pair(&pkcs8_bytes)
+        } else {
+            write_key_pair(&pkcs8_bytes, &gen_key)
+        }
+    });
+    if result.is_err() {
+        eprintln!("Failed to generate key pair.");
+        process::exit(exit_code::FAILURE);
+    }
+}
+
+/// Write a key pair in PKCS#8 (v2) format to the files from `--secret-out`
+/// and `--public-out`, and print the key that has no file.
+fn write_key_pair(pkcs8_bytes: &[u8], gen_key: &cli::GenKey) -> Result<(), ring::error::Unspecified> {
+    let key_pair = Ed25519KeyPair::from_pkcs8(Input::from(pkcs8_bytes))?;
+    let secret_key_b64 = base64::encode(pkcs8_bytes);
+    let public_key_b64 = base64::encode(key_pair.public_key_bytes());
+
+    match gen_key.secret_out {
+        // Create the file with the restrictive mode, rather than changing it
+        // after writing, so the key is never readable by others. Do not replace
+        // an existing file, it might hold a key that is in use.
+        Some(ref path) => write_key_file(
+            path,
+            &secret_key_b64,
+            fs::OpenOptions::new().write(true).create_new(true).mode(0o600),
+        ),
+        None => println!("Secret key (save to an encrypted secret store):\n{}", secret_key_b64),
+    }
+
+    match gen_key.public_out {
+        Some(ref path) => write_key_file(
+            path,
+            &public_key_b64,
+            fs::OpenOptions::new().write(true).create(true).truncate(true),
+        ),
+        None => println!("Public key:\n{}", public_key_b64),
+    }
+
+    Ok(())
+}
+
+/// Write a base64-encoded key and a newline to a file, or exit on failure.
+fn write_key_file(path: &Path, key_b64: &str, options: &fs::OpenOptions) {
+    let result = options.open(path).and_then(|mut f| {
+        f.write_all(key_b64.as_bytes())?;
+        f.write_all(b"\n")
+    });
+    if let Err(e) = result {
+        eprintln!("Failed to write key to {}: {}", path.display(), e);
+        process::exit(exit_code::for_error(&Error::from(e)));
+    }
+}
+
+fn run_import_key(import: cli::ImportKey) {
+    let result = import_key::import_key(&import).and_then(|pkcs8_bytes| {
+        print_key_pair(&pkcs8_bytes).map_err(|_| Error::InvalidSecretKeyData)
+    });
+    if let Err(e) = result {
+        eprintln!("Failed to import key from {}: {}", import.path.display(), e);
+        process::exit(exit_code::for_error(&e));
+    }
+}
 
-    let key_pair = Ed25519KeyPair::from_pkcs8(Input::from(&pkcs8_bytes))?;
+/// Print a key pair in PKCS#8 (v2) format as secret key and public key.
+fn print_key_pair(pkcs8_bytes: &[u8]) -> Result<(), ring::error::Unspecified> {
+    let key_pair = Ed25519KeyPair::from_pkcs8(Input::from(pkcs8_bytes))?;
 
     // There is no particular reason to encode these as base64, apart from that
     // it is easy to deal with in config files (for the public key), and it can
     // be safely printed to stdout and copied from there.
-    let secret_key_b64 = base64::encode(&pkcs8_bytes[..]);
+    let secret_key_b64 = base64::encode(pkcs8_bytes);
     let public_key_b64 = base64::encode(key_pair.public_key_bytes());
 
     // Print the private key to stdout, rather than writing it to a file. This
@@ -97,16 +592,27 @@ fn main() {
     use cli::Cmd;
     let args = env::args().collect();
     match cli::parse(args) {
-        Ok(Cmd::Fetch(fnames)) => fnames.iter().for_each(run_fetch),
-        Ok(Cmd::Init(fnames)) => fnames.iter().for_each(run_init),
+        Ok(Cmd::Fetch(fetch)) => run_fetch(fetch),
         Ok(Cmd::Store(store)) => run_store(store),
-        // TODO: Implement a better error handler.
-        Ok(Cmd::GenKey) => run_gen_key().unwrap(),
+        Ok(Cmd::Seed(seed)) => run_seed(seed),
+        Ok(Cmd::GenKey(gen_key)) => run_gen_key(gen_key),
+        Ok(Cmd::ImportKey(import)) => run_import_key(import),
+        Ok(Cmd::PrintConfig(fname)) => run_print_config(&fname),
+        Ok(Cmd::Keys(fnames)) => run_keys(&fnames),
+        Ok(Cmd::Doctor(doctor)) => run_doctor(doctor),
+        Ok(Cmd::Digest(digest)) => run_digest(digest),
+        Ok(Cmd::Export(export)) => run_export(export),
+        Ok(Cmd::Import(import)) => run_import(import),
+        Ok(Cmd::RekeyAll(rekey)) => run_rekey_all(rekey),
+        Ok(Cmd::Canonicalize(canonicalize)) => run_canonicalize(canonicalize),
+        Ok(Cmd::Index(index)) => run_index(index),
+        Ok(Cmd::SignArchive(sign)) => run_sign_archive(sign),
+        Ok(Cmd::Verify(verify)) => run_verify(verify),
         Ok(Cmd::Help(cmd)) => cli::print_usage(cmd),
-        Ok(Cmd::Version) => cli::print_version(),
+        Ok(Cmd::Version(format)) => cli::print_version(format),
         Err(msg) => {
-            println!("{}", msg); // TODO: stderr.
-            process::exit(1);
+            eprintln!("{}", msg);
+            process::exit(exit_code::USAGE);
         }
     }
 }
@@ -5,12 +5,13 @@
 // you may not use this file except in compliance with the License.
 // A copy of the License has been included in the root of the repository.
 
-//! Manifest file parser.
+//! Manifest file parser, and the parser of the index that groups manifests.
 
 use std::cmp::Ordering;
 use std::fs;
 use std::io;
 use std::io::{Read, Write};
+use std::mem;
 use std::path::{Path, PathBuf};
 use std::str;
 
@@ -25,10 +26,38 @@ use util;
 use util::Sha256;
 use version::Version;
 
+/// The file name of the manifest in a server directory or destination, unless
+/// `ManifestName=` sets a different one.
+pub const DEFAULT_NAME: &'static str = "manifest";
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Entry {
     pub version: Version,
     pub digest: Sha256,
+
+    /// `Annotation` fields of the entry, as key and value, in order.
+    pub annotations: Vec<(String, String)>,
+
+    /// `Delta` fields of the entry, deltas that turn an older image into this
+    /// one, in order.
+    pub deltas: Vec<Delta>,
+}
+
+/// A delta from a base image to the image of an entry, see `delta`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Delta {
+    /// The digest of the image that the delta applies to.
+    pub base: Sha256,
+
+    /// The digest of the delta file, which is at `store/<hexdigest>.delta`.
+    pub digest: Sha256,
+}
+
+impl Entry {
+    /// Return the value of the annotation with the given key, if there is one.
+    pub fn annotation(&self, key: &str) -> Option<&str> {
+        self.annotations.iter().find(|&&(ref k, _)| k == key).map(|&(_, ref v)| &v[..])
+    }
 }
 
 // Implement Ord manually for Entry; the generated one would also compare
@@ -49,31 +78,153 @@ impl PartialOrd for Entry {
 #[derive(Debug, Eq, PartialEq)]
 pub struct Manifest {
     entries: Vec<Entry>,
+
+    /// Base url to download images from, instead of the origin.
+    image_base: Option<String>,
+
+    /// Name of the image, which fetchers can check against their config.
+    name: Option<String>,
+
+    /// Extension of the image files in the store, such as `.img`.
+    image_ext: Option<String>,
+
+    /// Whether to write the signing keys in `PublicKey` header fields, for
+    /// fetchers that trust the key on first use.
+    embed_public_keys: bool,
+}
+
+/// A parsed manifest, together with what is needed to verify it.
+struct Unverified {
+    manifest: Manifest,
+
+    /// The keys in the `PublicKey` header fields, in order.
+    public_keys: Vec<PublicKey>,
+
+    /// The signatures, in order.
+    signatures: Vec<[u8; 64]>,
+
+    /// The length of the signed part, everything before the first signature.
+    signed_len: usize,
+}
+
+/// Parse a nonempty string of ascii digits.
+fn parse_u32(digits: &[u8]) -> Option<u32> {
+    if digits.is_empty() || !digits.iter().all(|b| b.is_ascii_digit()) {
+        return None
+    }
+    str::from_utf8(digits).ok().and_then(|s| s.parse().ok())
+}
+
+/// Parse header and return the major and minor version number.
+///
+/// The minor version is optional, `Tako Manifest 1` is version 1.0. Minor
+/// versions only add optional fields, so we accept any minor version, but we
+/// reject major versions that we do not know.
+fn parse_header(header: &[u8]) -> Result<(u32, u32)> {
+    let msg = "Manifest does not contain expected 'Tako Manifest 1' header.";
+    let prefix = b"Tako Manifest ";
+    if !header.starts_with(prefix) {
+        return Err(Error::InvalidManifest(msg))
+    }
+
+    let version = &header[prefix.len()..];
+    let (major, minor) = match version.iter().position(|&b| b == b'.') {
+        Some(i) => (parse_u32(&version[..i]), parse_u32(&version[i + 1..])),
+        None => (parse_u32(version), Some(0)),
+    };
+
+    match (major, minor) {
+        (Some(1), Some(minor)) => Ok((1, minor)),
+        (Some(..), Some(..)) => {
+            let msg = "Manifest version is not supported.";
+            Err(Error::InvalidManifest(msg))
+        }
+        _ => Err(Error::InvalidManifest(msg)),
+    }
+}
+
+/// Parse a `Key=Value` field line, return the key and value.
+///
+/// Fields can occur in the manifest header, and indented below an entry. We
+/// ignore fields that we do not know, so that they can be added to the format
+/// without breaking older versions of Tako.
+fn parse_field(line: &[u8]) -> Result<(&[u8], &[u8])> {
+    match line.iter().position(|&b| b == b'=') {
+        Some(i) if i > 0 && !line[..i].contains(&b' ') => Ok((&line[..i], &line[i + 1..])),
+        _ => {
+            let msg = "Invalid manifest field, expected 'Key=Value'.";
+            Err(Error::InvalidManifest(msg))
+        }
+    }
+}
+
+/// Parse the `ImageBase` header field, an http or https url.
+///
+/// A trailing slash is removed, so the url can be joined with `/store/...`.
+fn parse_image_base(value: &[u8]) -> Result<String> {
+    let msg = "Invalid ImageBase field, expected an http:// or https:// url.";
+    match str::from_utf8(value) {
+        Ok(url) if url.starts_with("https://") || url.starts_with("http://") => {
+            let mut url = url;
+            while url.ends_with('/') {
+                url = &url[..url.len() - 1];
+            }
+            Ok(String::from(url))
+        }
+        _ => Err(Error::InvalidManifest(msg)),
+    }
+}
+
+/// Parse the `Name` header field, a nonempty name without whitespace.
+fn parse_name(value: &[u8]) -> Result<String> {
+    let msg = "Invalid Name field, expected a nonempty name without whitespace.";
+    match str::from_utf8(value) {
+        Ok(name) if !name.is_empty() && !name.contains(char::is_whitespace) => Ok(String::from(name)),
+        _ => Err(Error::InvalidManifest(msg)),
+    }
+}
+
+/// Parse the `ImageExt` header field, a dot followed by alphanumeric parts
+/// separated by dots, such as `.img` or `.tar.gz`.
+fn parse_image_ext(value: &[u8]) -> Result<String> {
+    let msg = "Invalid ImageExt field, expected an extension such as '.img'.";
+    let is_valid = value.first() == Some(&b'.') && value[1..]
+        .split(|&b| b == b'.')
+        .all(|part| !part.is_empty() && part.iter().all(|b| b.is_ascii_alphanumeric()));
+    match str::from_utf8(value) {
+        Ok(ext) if is_valid => Ok(String::from(ext)),
+        _ => Err(Error::InvalidManifest(msg)),
+    }
+}
+
+/// Parse the `PublicKey` header field, a base64-encoded Ed25519 public key.
+fn parse_public_key(value: &[u8]) -> Result<PublicKey> {
+    let msg = "Invalid PublicKey field, expected a base64-encoded Ed25519 public key.";
+    match str::from_utf8(value).ok().and_then(PublicKey::from_base64) {
+        Some(key) => Ok(key),
+        None => Err(Error::InvalidManifest(msg)),
+    }
 }
 
-/// Parse header and return the version number.
-fn parse_header(header: &[u8]) -> Result<u32> {
-    if header == b"Tako Manifest 1" {
-        Ok(1)
-    } else if header.starts_with(b"Tako Manifest") {
-        let msg = "Manifest version is not supported.";
-        Err(Error::InvalidManifest(msg))
-    } else {
-        let msg = "Manifest does not contain expected 'Tako Manifest 1' header.";
-        Err(Error::InvalidManifest(msg))
+/// Parse the `Annotation` entry field, `<key>=<value>`.
+fn parse_annotation(value: &[u8]) -> Result<(String, String)> {
+    let msg = "Invalid Annotation field, expected 'Annotation=<key>=<value>'.";
+    let (key, value) = parse_field(value).map_err(|_| Error::InvalidManifest(msg))?;
+    match (str::from_utf8(key), str::from_utf8(value)) {
+        (Ok(k), Ok(v)) => Ok((String::from(k), String::from(v))),
+        _ => Err(Error::InvalidManifest(msg)),
     }
 }
 
-/// Parse a lowercase ascii byte in [0, ..., f], return value as int.
-fn parse_hex(ch: u8) -> Option<u8> {
-    if ch < b'0' { return None }
-    if ch > b'f' { return None }
-    if ch <= b'9' {
-        Some(ch - b'0')
-    } else if ch >= b'a' {
-        Some(ch - b'a' + 10)
-    } else {
-        None
+/// Parse the `Delta` entry field, `<base digest> <delta digest>`.
+fn parse_delta(value: &[u8]) -> Result<Delta> {
+    let msg = "Invalid Delta field, expected 'Delta=<base digest> <delta digest>'.";
+    if value.len() != 64 + 1 + 64 || value[64] != b' ' {
+        return Err(Error::InvalidManifest(msg))
+    }
+    match (Sha256::from_hex(&value[..64]), Sha256::from_hex(&value[65..])) {
+        (Some(base), Some(digest)) => Ok(Delta { base: base, digest: digest }),
+        _ => Err(Error::InvalidManifest(msg)),
     }
 }
 
@@ -98,31 +249,21 @@ fn parse_entry(line: &[u8]) -> Result<Entry> {
         return Err(Error::InvalidManifest(msg))
     }
 
-    let mut sha256 = [0_u8; 32];
-    for (dst, hex) in sha256.iter_mut().zip(sha256_hex.chunks(2)) {
-        // There is also u8::form_str_radix, but then we would need to do UTF-8
-        // validation first, and all the error handling is just as messy as just
-        // doing it manually. As an additional benefit, we are stricter to only
-        // allow lowercase hexadecimal.
-
-        // Indexing does not go out of bounds here because we verified the
-        // length above.
-        let msg = "Invalid entry hash. Must be lowercase hexadecimal.";
-        let high = parse_hex(hex[0]).ok_or(Error::InvalidManifest(msg))?;
-        let low = parse_hex(hex[1]).ok_or(Error::InvalidManifest(msg))?;
-        *dst = (high << 4) + low;
-    }
+    let msg = "Invalid entry hash. Must be lowercase hexadecimal.";
+    let sha256 = Sha256::from_hex(sha256_hex).ok_or(Error::InvalidManifest(msg))?;
 
     let entry = Entry {
         version: Version::new(version),
-        digest: Sha256(sha256),
+        digest: sha256,
+        annotations: Vec::new(),
+        deltas: Vec::new(),
     };
 
     Ok(entry)
 }
 
 /// Parse the base64-encoded signature line.
-fn parse_signature(sig_base64: &[u8]) -> Result<[u8; 64]> {
+pub fn parse_signature(sig_base64: &[u8]) -> Result<[u8; 64]> {
     let bytes = match base64::decode(sig_base64) {
         Ok(bs) => bs,
         Err(err) => return Err(Error::InvalidSignatureData(err)),
@@ -139,14 +280,67 @@ fn parse_signature(sig_base64: &[u8]) -> Result<[u8; 64]> {
     Ok(result)
 }
 
+/// Return whether any of the signatures over the message was made with any of
+/// the keys.
+pub fn is_signed_by_any<'a, I>(message: &[u8], signatures: &[[u8; 64]], public_keys: I) -> bool
+where I: IntoIterator<Item = &'a PublicKey> {
+    public_keys.into_iter().any(|public_key| {
+        signatures.iter().any(|signature_bytes| {
+            let pub_key = public_key.as_input();
+            let sig = Input::from(&signature_bytes[..]);
+            signature::verify(&signature::ED25519, pub_key, Input::from(message), sig).is_ok()
+        })
+    })
+}
+
 impl Manifest {
     pub fn new() -> Manifest {
         Manifest {
             entries: Vec::new(),
+            image_base: None,
+            name: None,
+            image_ext: None,
+            embed_public_keys: false,
         }
     }
 
     pub fn parse(bytes: &[u8], public_key: &PublicKey) -> Result<Manifest> {
+        Manifest::parse_any(bytes, Some(public_key))
+    }
+
+    /// Parse the manifest, and verify that any of its signatures was made with
+    /// any of the given keys.
+    pub fn parse_any<'a, I>(bytes: &[u8], public_keys: I) -> Result<Manifest>
+    where I: IntoIterator<Item = &'a PublicKey> {
+        let unverified = Manifest::parse_unverified_impl(bytes)?;
+
+        // Every signature covers everything before the first signature.
+        let message = &bytes[..unverified.signed_len];
+        if !is_signed_by_any(message, &unverified.signatures, public_keys) {
+            return Err(Error::InvalidSignature)
+        }
+
+        Ok(unverified.manifest)
+    }
+
+    /// Parse the manifest without verifying its signature.
+    ///
+    /// The result must not be trusted. This is only useful for diagnostics,
+    /// when we do not have the public key.
+    pub fn parse_unverified(bytes: &[u8]) -> Result<Manifest> {
+        Manifest::parse_unverified_impl(bytes).map(|unverified| unverified.manifest)
+    }
+
+    /// Return the public keys in the `PublicKey` header fields, in order.
+    ///
+    /// The keys are not verified: anybody who can change the manifest can put
+    /// their own key in there. A key can only be trusted by comparing it
+    /// against a key that was trusted before.
+    pub fn parse_public_keys(bytes: &[u8]) -> Result<Vec<PublicKey>> {
+        Manifest::parse_unverified_impl(bytes).map(|unverified| unverified.public_keys)
+    }
+
+    fn parse_unverified_impl(bytes: &[u8]) -> Result<Unverified> {
         let mut lines = bytes.split(|b| *b == b'\n');
         let mut entries = Vec::new();
 
@@ -156,14 +350,29 @@ impl Manifest {
         let header = lines.next().ok_or(err_trunc)?;
         let _version = parse_header(header)?;
 
-        // Then a blank line.
-        let err_trunc = Error::InvalidManifest("Unexpected end of manifest.");
-        if lines.next().ok_or(err_trunc)? != b"" {
-            let msg = "Expected blank line after header line.";
-            return Err(Error::InvalidManifest(msg))
+        // Then optionally header fields, terminated by a blank line. Fields
+        // that we do not know are ignored.
+        let mut image_base = None;
+        let mut name = None;
+        let mut image_ext = None;
+        let mut public_keys = Vec::new();
+        loop {
+            let err_trunc = Error::InvalidManifest("Unexpected end of manifest.");
+            let line = lines.next().ok_or(err_trunc)?;
+            if line == b"" {
+                break
+            }
+            match parse_field(line)? {
+                (b"ImageBase", value) => image_base = Some(parse_image_base(value)?),
+                (b"Name", value) => name = Some(parse_name(value)?),
+                (b"ImageExt", value) => image_ext = Some(parse_image_ext(value)?),
+                (b"PublicKey", value) => public_keys.push(parse_public_key(value)?),
+                _ => {}
+            }
         }
 
-        // Then one version per line.
+        // Then one version per line, each optionally followed by fields of
+        // that entry, indented by two spaces.
         for line in &mut lines {
             if line == b"" {
                 // A blank line indicates the end of the manifest, only the
@@ -171,39 +380,119 @@ impl Manifest {
                 break
             }
 
+            if line.starts_with(b"  ") {
+                let entry: &mut Entry = match entries.last_mut() {
+                    Some(e) => e,
+                    None => {
+                        let msg = "Expected entry before indented entry field.";
+                        return Err(Error::InvalidManifest(msg))
+                    }
+                };
+                match parse_field(&line[2..])? {
+                    (b"Annotation", value) => entry.annotations.push(parse_annotation(value)?),
+                    (b"Delta", value) => entry.deltas.push(parse_delta(value)?),
+                    _ => {}
+                }
+                continue
+            }
+
             entries.push(parse_entry(line)?);
         }
 
-        let err_trunc = Error::InvalidManifest("Unexpected end of manifest.");
-        let signature_line = lines.next().ok_or(err_trunc)?;
-        let signature_bytes = parse_signature(signature_line)?;
-
-        // We expect the file to end with a trailing newline, and nothing after
-        // that.
-        if lines.next() != Some(b"") {
-            let msg = "Expected newline at end of manifest.";
-            return Err(Error::InvalidManifest(msg))
+        // Then one or more signature lines. We expect the file to end with a
+        // trailing newline, and nothing after that.
+        let mut signatures = Vec::new();
+        let mut signatures_len = 0;
+        loop {
+            match lines.next() {
+                Some(b"") if !signatures.is_empty() => break,
+                Some(line) => {
+                    signatures.push(parse_signature(line)?);
+                    signatures_len += line.len() + 1;
+                }
+                None if signatures.is_empty() => {
+                    let msg = "Unexpected end of manifest.";
+                    return Err(Error::InvalidManifest(msg))
+                }
+                None => {
+                    let msg = "Expected newline at end of manifest.";
+                    return Err(Error::InvalidManifest(msg))
+                }
+            }
         }
         if lines.next() != None {
             let msg = "Unexpected trailing data after manifest.";
             return Err(Error::InvalidManifest(msg))
         }
 
-        // The signature and newline are 89 bytes. Everything before that is
-        // included in the signature.
-        let message = Input::from(&bytes[..bytes.len() - 89]);
-        let pub_key = public_key.as_input();
-        let sig = Input::from(&signature_bytes);
-
-        if signature::verify(&signature::ED25519, pub_key, message, sig).is_err() {
-            return Err(Error::InvalidSignature)
-        }
-
         let manifest = Manifest {
             entries: entries,
+            image_base: image_base,
+            name: name,
+            image_ext: image_ext,
+            embed_public_keys: !public_keys.is_empty(),
         };
 
-        Ok(manifest)
+        let unverified = Unverified {
+            manifest: manifest,
+            public_keys: public_keys,
+            signatures: signatures,
+            signed_len: bytes.len() - signatures_len,
+        };
+
+        Ok(unverified)
+    }
+
+    /// Return the entries, ordered by ascending version.
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries[..]
+    }
+
+    /// Return the base url to download images from, if it is not the origin.
+    ///
+    /// Images are at `<base>/store/<hexdigest>`.
+    pub fn image_base(&self) -> Option<&str> {
+        self.image_base.as_ref().map(|s| &s[..])
+    }
+
+    /// Set the base url to download images from, an http or https url.
+    pub fn set_image_base(&mut self, image_base: &str) -> Result<()> {
+        self.image_base = Some(parse_image_base(image_base.as_bytes())?);
+        Ok(())
+    }
+
+    /// Return the name of the image, if the manifest declares one.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(|s| &s[..])
+    }
+
+    /// Set the name of the image, a nonempty name without whitespace.
+    pub fn set_name(&mut self, name: &str) -> Result<()> {
+        self.name = Some(parse_name(name.as_bytes())?);
+        Ok(())
+    }
+
+    /// Return the extension of the image files in the store, or an empty
+    /// string if they have none.
+    ///
+    /// Images are at `store/<hexdigest><ext>`.
+    pub fn image_ext(&self) -> &str {
+        match self.image_ext {
+            Some(ref ext) => &ext[..],
+            None => "",
+        }
+    }
+
+    /// Set the extension of the image files, such as `.img`.
+    pub fn set_image_ext(&mut self, image_ext: &str) -> Result<()> {
+        self.image_ext = Some(parse_image_ext(image_ext.as_bytes())?);
+        Ok(())
+    }
+
+    /// Write the signing keys in `PublicKey` header fields from now on. A
+    /// manifest that has them keeps them.
+    pub fn set_embed_public_keys(&mut self) {
+        self.embed_public_keys = true;
     }
 
     /// Return whether all entries of self also occur in other.
@@ -216,8 +505,9 @@ impl Manifest {
         // more, but then we just skip over them.
         for entry in &self.entries {
             loop {
+                // Annotations may change, only the image must stay the same.
                 match entries_other.next() {
-                    Some(ref e) if *e == entry => break,
+                    Some(e) if e.version == entry.version && e.digest == entry.digest => break,
                     Some(..) => continue,
                     None => return false,
                 }
@@ -227,42 +517,171 @@ impl Manifest {
         true
     }
 
-    /// Print the manifest as a string and sign it, the inverse of `parse`.
-    pub fn serialize(&self, key_pair: &Ed25519KeyPair) -> String {
+    /// Return whether this manifest may replace an older one.
+    ///
+    /// Every entry of the older manifest must occur in this one, except that
+    /// the oldest versions may have been pruned, as `tako store --max-versions`
+    /// does: an entry that is missing must be older than every entry that
+    /// remains. Any other removal would make us forget what an image is.
+    pub fn is_successor_of(&self, older: &Manifest) -> bool {
+        let lowest = match self.entries.first() {
+            Some(entry) => &entry.version,
+            None => return older.entries.is_empty(),
+        };
+        let unpruned = Manifest {
+            entries: older.entries.iter().filter(|e| e.version >= *lowest).cloned().collect(),
+            image_base: None,
+            name: None,
+            image_ext: None,
+            embed_public_keys: false,
+        };
+        unpruned.is_subset_of(self)
+    }
+
+    /// Remove the oldest entries, such that at most `max_len` remain. Returns
+    /// the removed entries, oldest first.
+    pub fn prune(&mut self, max_len: usize) -> Vec<Entry> {
+        let excess = self.entries.len().saturating_sub(max_len);
+        let kept = self.entries.split_off(excess);
+        mem::replace(&mut self.entries, kept)
+    }
+
+    /// Print the manifest as a string and sign it with every key pair, the
+    /// inverse of `parse`.
+    ///
+    /// Requires at least one key pair. With more than one, the manifest has a
+    /// signature per key, so fetchers that trust any of the keys accept it.
+    pub fn serialize(&self, key_pairs: &[Ed25519KeyPair]) -> String {
+        assert!(!key_pairs.is_empty(), "Need a key pair to sign the manifest.");
+
+        let public_keys: Vec<_> = key_pairs.iter().map(PublicKey::from_pair).collect();
+        let mut out = self.serialize_unsigned(&public_keys);
+
+        // Every signature covers the same message, not the signatures before it,
+        // so they can be verified independently.
+        let signatures: Vec<_> = key_pairs.iter().map(|key_pair| key_pair.sign(out.as_bytes())).collect();
+        for signature in &signatures {
+            out.push_str(&base64::encode(signature.as_ref()));
+            out.push('\n');
+        }
+
+        out
+    }
+
+    /// Print the message that the signatures of the manifest cover.
+    ///
+    /// This is `serialize` up to and including the blank line before the
+    /// signatures, with a `PublicKey=` line per key if the manifest embeds its
+    /// keys. External tools can sign these bytes, and append the base64-encoded
+    /// signatures, one per line.
+    pub fn serialize_unsigned(&self, public_keys: &[PublicKey]) -> String {
         // Premature optimization: estimate the output size, so we have to do
-        // only a single allocation. 18 bytes for header (including newlines),
-        // 64 bytes per entry for the hash, 15 for version, space, and newline.
-        // And then 90 bytes for the signature including newlines.
-        let n = 18 + self.entries.len() * (15 + 64) + 90;
+        // only a single allocation. 20 bytes for the header, per key 55 bytes
+        // for the public key field and 89 for the signature (including
+        // newlines), 64 bytes per entry for the hash, 15 for version, space,
+        // and newline, and 2 for the blank lines.
+        let n = 20 + public_keys.len() * (55 + 89) + self.entries.len() * (15 + 64) + 2;
         let mut out = String::with_capacity(n);
 
-        out.push_str("Tako Manifest 1\n\n");
+        // Header fields were added in minor version 1.1, multiple signatures
+        // in 1.2, annotations in 1.3, the name in 1.4, the image extension in
+        // 1.5, and deltas in 1.6. Write the lowest version that has everything
+        // this manifest uses, older versions of Tako reject newer manifests.
+        if self.entries.iter().any(|e| !e.deltas.is_empty()) {
+            out.push_str("Tako Manifest 1.6\n");
+        } else if self.image_ext.is_some() {
+            out.push_str("Tako Manifest 1.5\n");
+        } else if self.name.is_some() {
+            out.push_str("Tako Manifest 1.4\n");
+        } else if self.entries.iter().any(|e| !e.annotations.is_empty()) {
+            out.push_str("Tako Manifest 1.3\n");
+        } else if public_keys.len() > 1 {
+            out.push_str("Tako Manifest 1.2\n");
+        } else if self.embed_public_keys || self.image_base.is_some() {
+            out.push_str("Tako Manifest 1.1\n");
+        } else {
+            out.push_str("Tako Manifest 1\n");
+        }
+        // Include the public keys if asked to, so fetchers that trust on first
+        // use can learn them.
+        if self.embed_public_keys {
+            for public_key in public_keys {
+                out.push_str("PublicKey=");
+                out.push_str(&public_key.to_base64());
+                out.push('\n');
+            }
+        }
+        if let Some(ref image_base) = self.image_base {
+            out.push_str("ImageBase=");
+            out.push_str(image_base);
+            out.push('\n');
+        }
+        if let Some(ref name) = self.name {
+            out.push_str("Name=");
+            out.push_str(name);
+            out.push('\n');
+        }
+        if let Some(ref image_ext) = self.image_ext {
+            out.push_str("ImageExt=");
+            out.push_str(image_ext);
+            out.push('\n');
+        }
+        out.push('\n');
         for entry in &self.entries {
             out.push_str(entry.version.as_str());
             out.push(' ');
             util::append_hex(&mut out, &entry.digest.as_ref());
             out.push('\n');
+            for &(ref key, ref value) in &entry.annotations {
+                out.push_str("  Annotation=");
+                out.push_str(key);
+                out.push('=');
+                out.push_str(value);
+                out.push('\n');
+            }
+            for delta in &entry.deltas {
+                out.push_str("  Delta=");
+                util::append_hex(&mut out, delta.base.as_ref());
+                out.push(' ');
+                util::append_hex(&mut out, delta.digest.as_ref());
+                out.push('\n');
+            }
         }
 
         out.push('\n');
 
-        let signature = key_pair.sign(out.as_bytes());
-        let signature_b64 = base64::encode(signature.as_ref());
-
-        out.push_str(&signature_b64);
-        out.push('\n');
-
         out
     }
 
     /// Load a locally stored manifest from a store directory.
     ///
-    /// If the manifest exists, it is parsed and returned. If it does not exist,
-    /// None is returned, rather than an Err.
-    pub fn load_local(dir: &Path, public_key: &PublicKey) -> Result<Option<Manifest>> {
-        // Open the current manifest. If it does not exist that is not an error.
-        let mut path = PathBuf::from(dir);
-        path.push("manifest");
+    /// If the manifest exists, it is parsed and returned if it is signed with
+    /// any of the keys. If it does not exist, None is returned, rather than an
+    /// Err.
+    pub fn load_local(dir: &Path, public_keys: &[PublicKey]) -> Result<Option<Manifest>> {
+        Manifest::load_file(&dir.join(DEFAULT_NAME), public_keys)
+    }
+
+    /// Load a manifest file, like `load_local`, but from any file name.
+    pub fn load_file(path: &Path, public_keys: &[PublicKey]) -> Result<Option<Manifest>> {
+        match Manifest::load_file_bytes(path)? {
+            Some(manifest_bytes) => Ok(Some(Manifest::parse_any(&manifest_bytes[..], public_keys)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Read a locally stored manifest from a store directory, without parsing.
+    ///
+    /// If the manifest does not exist, None is returned, rather than an Err.
+    pub fn load_local_bytes(dir: &Path) -> Result<Option<Vec<u8>>> {
+        Manifest::load_file_bytes(&dir.join(DEFAULT_NAME))
+    }
+
+    /// Read a manifest file, without parsing, for server directories where
+    /// the manifest has a different name.
+    ///
+    /// If the file does not exist, None is returned, rather than an Err.
+    pub fn load_file_bytes(path: &Path) -> Result<Option<Vec<u8>>> {
         let mut f = match fs::File::open(path) {
             Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
             other => other?,
@@ -271,7 +690,30 @@ impl Manifest {
         let mut manifest_bytes = Vec::new();
         f.read_to_end(&mut manifest_bytes)?;
 
-        Ok(Some(Manifest::parse(&manifest_bytes[..], public_key)?))
+        Ok(Some(manifest_bytes))
+    }
+
+    /// Return the entry with the given version, if there is one.
+    pub fn entry(&self, version: &Version) -> Option<&Entry> {
+        self.entries.iter().find(|e| e.version == *version)
+    }
+
+    /// Set an annotation on the entry with the given version. If the entry has
+    /// the key already, its value is replaced.
+    ///
+    /// If there is no such entry, the error lists the versions that the
+    /// manifest has.
+    pub fn annotate(&mut self, version: &Version, key: &str, value: &str) -> Result<()> {
+        let available: Vec<Version> = self.entries.iter().map(|e| e.version.clone()).collect();
+        let entry = match self.entries.iter_mut().find(|e| e.version == *version) {
+            Some(e) => e,
+            None => return Err(Error::NoCandidate(version.clone(), available)),
+        };
+        match entry.annotations.iter().position(|&(ref k, _)| k == key) {
+            Some(i) => entry.annotations[i].1 = String::from(value),
+            None => entry.annotations.push((String::from(key), String::from(value))),
+        }
+        Ok(())
     }
 
     /// Insert a new entry, keeping the entries ordered.
@@ -306,16 +748,244 @@ impl Manifest {
             .filter(|e| *lower <= e.version && e.version <= *upper)
             .next()
     }
+
+    /// Return all entries that match the pattern, the largest version first.
+    ///
+    /// With bounds, only versions that are at least `min` and below `below`
+    /// are candidates. If there are none, the error lists the versions that
+    /// the manifest has.
+    pub fn select_candidates(
+        &self,
+        pattern: &Version,
+        min: Option<&Version>,
+        below: Option<&Version>,
+    ) -> Result<Vec<&Entry>> {
+        let (lower, upper) = pattern.pattern_to_bounds();
+        let candidates: Vec<&Entry> = self.entries
+            .iter()
+            .rev()
+            .filter(|e| lower <= e.version && e.version <= upper)
+            .filter(|e| match min { Some(v) => *v <= e.version, None => true })
+            .filter(|e| match below { Some(v) => e.version < *v, None => true })
+            .collect();
+        if candidates.is_empty() {
+            Err(Error::NoCandidate(
+                pattern.clone(),
+                self.entries.iter().map(|e| e.version.clone()).collect(),
+            ))
+        } else {
+            Ok(candidates)
+        }
+    }
+
+    /// Return the entry with the largest version that matches the pattern,
+    /// within the bounds, see `select_candidates`.
+    ///
+    /// If there is none, the error lists the versions that the manifest has.
+    pub fn select_candidate(
+        &self,
+        pattern: &Version,
+        min: Option<&Version>,
+        below: Option<&Version>,
+    ) -> Result<&Entry> {
+        if min.is_none() && below.is_none() {
+            let (lower, upper) = pattern.pattern_to_bounds();
+            if let Some(entry) = self.latest_compatible_entry(&lower, &upper) {
+                return Ok(entry)
+            }
+        }
+        // With bounds, the pattern bounds are not enough. Without a candidate,
+        // this reports the versions that the manifest has.
+        self.select_candidates(pattern, min, below).map(|candidates| candidates[0])
+    }
+}
+
+/// The header line of an index.
+const INDEX_HEADER: &'static [u8] = b"Tako Index 1";
+
+/// An image in an index: the version that the release consists of, and the
+/// manifest that lists it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IndexEntry {
+    /// The name of the image, as declared by `Name=` in its manifest.
+    pub name: String,
+    pub version: Version,
+
+    /// The SHA256 digest of the manifest file of the image.
+    pub manifest_digest: Sha256,
+}
+
+/// A signed list of images that are released together, see
+/// `docs/index-format.md`.
+///
+/// The index pins the exact manifest of every image, so fetchers of the images
+/// in a release install versions from the same publish.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Index {
+    /// The entries, ordered by name.
+    entries: Vec<IndexEntry>,
+}
+
+/// Parse a single index entry line, `<name> <version> <manifest digest>`.
+fn parse_index_entry(line: &[u8]) -> Result<IndexEntry> {
+    let msg = "Invalid index entry, expected '<name> <version> <manifest digest>'.";
+    let parts: Vec<&[u8]> = line.split(|&b| b == b' ').collect();
+    if parts.len() != 3 || parts[1].is_empty() {
+        return Err(Error::InvalidIndex(msg))
+    }
+    let name = parse_name(parts[0]).map_err(|_| Error::InvalidIndex(msg))?;
+    let version = str::from_utf8(parts[1]).map_err(|_| Error::InvalidIndex(msg))?;
+
+    let msg = "Invalid manifest digest in index. Must be 64 lowercase hexadecimal characters.";
+    let digest = Sha256::from_hex(parts[2]).ok_or(Error::InvalidIndex(msg))?;
+
+    let entry = IndexEntry {
+        name: name,
+        version: Version::from(version),
+        manifest_digest: digest,
+    };
+
+    Ok(entry)
+}
+
+impl Index {
+    pub fn new() -> Index {
+        Index {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Parse the index, and verify that any of its signatures was made with
+    /// any of the given keys.
+    pub fn parse_any<'a, I>(bytes: &[u8], public_keys: I) -> Result<Index>
+    where I: IntoIterator<Item = &'a PublicKey> {
+        let mut lines = bytes.split(|b| *b == b'\n');
+        let mut signed_len = 0;
+
+        let msg = "Index does not contain expected 'Tako Index 1' header.";
+        match lines.next() {
+            Some(line) if line == INDEX_HEADER => signed_len += line.len() + 1,
+            _ => return Err(Error::InvalidIndex(msg)),
+        }
+
+        // Then optionally header fields, such as `PublicKey`, terminated by a
+        // blank line. None of them affect verification.
+        loop {
+            let line = lines.next().ok_or(Error::InvalidIndex("Unexpected end of index."))?;
+            signed_len += line.len() + 1;
+            if line == b"" {
+                break
+            }
+            if parse_field(line).is_err() {
+                return Err(Error::InvalidIndex("Invalid index field, expected 'Key=Value'."))
+            }
+        }
+
+        // Then one image per line, ordered by name, terminated by a blank line.
+        let mut index = Index::new();
+        loop {
+            let line = lines.next().ok_or(Error::InvalidIndex("Unexpected end of index."))?;
+            signed_len += line.len() + 1;
+            if line == b"" {
+                break
+            }
+            let entry = parse_index_entry(line)?;
+            if index.entries.last().map(|e| e.name >= entry.name) == Some(true) {
+                let msg = "Index entries must be ordered by name, and names must be unique.";
+                return Err(Error::InvalidIndex(msg))
+            }
+            index.entries.push(entry);
+        }
+
+        // Then one or more signatures, and nothing after the final newline.
+        let mut signatures = Vec::new();
+        loop {
+            match lines.next() {
+                Some(b"") if !signatures.is_empty() => break,
+                Some(line) => signatures.push(parse_signature(line)?),
+                None => return Err(Error::InvalidIndex("Expected signatures and a newline at the end.")),
+            }
+        }
+        if lines.next().is_some() {
+            return Err(Error::InvalidIndex("Unexpected trailing data after index."))
+        }
+
+        if !is_signed_by_any(&bytes[..signed_len], &signatures, public_keys) {
+            return Err(Error::InvalidSignature)
+        }
+
+        Ok(index)
+    }
+
+    /// Return the entry of the image with the given name, if there is one.
+    pub fn entry(&self, name: &str) -> Option<&IndexEntry> {
+        self.entries.iter().find(|e| e.name == name)
+    }
+
+    /// Insert an entry, keeping the entries ordered by name.
+    pub fn insert(&mut self, entry: IndexEntry) -> Result<()> {
+        match self.entries.binary_search_by(|e| e.name.cmp(&entry.name)) {
+            Ok(..) => Err(Error::InvalidIndex("An image name occurs twice.")),
+            Err(i) => {
+                self.entries.insert(i, entry);
+                Ok(())
+            }
+        }
+    }
+
+    /// Print the message that the signatures of the index cover.
+    ///
+    /// This is `serialize` up to and including the blank line before the
+    /// signatures, with a `PublicKey=` line per key.
+    pub fn serialize_unsigned(&self, public_keys: &[PublicKey]) -> String {
+        let mut out = String::from("Tako Index 1\n");
+        for public_key in public_keys {
+            out.push_str("PublicKey=");
+            out.push_str(&public_key.to_base64());
+            out.push('\n');
+        }
+        out.push('\n');
+        for entry in &self.entries {
+            out.push_str(&entry.name);
+            out.push(' ');
+            out.push_str(entry.version.as_str());
+            out.push(' ');
+            util::append_hex(&mut out, entry.manifest_digest.as_ref());
+            out.push('\n');
+        }
+        out.push('\n');
+        out
+    }
+
+    /// Print the index as a string and sign it with every key pair, the
+    /// inverse of `parse_any`.
+    pub fn serialize(&self, key_pairs: &[Ed25519KeyPair]) -> String {
+        assert!(!key_pairs.is_empty(), "Need a key pair to sign the index.");
+
+        let public_keys: Vec<_> = key_pairs.iter().map(PublicKey::from_pair).collect();
+        let mut out = self.serialize_unsigned(&public_keys);
+        let signatures: Vec<_> = key_pairs.iter().map(|key_pair| key_pair.sign(out.as_bytes())).collect();
+        for signature in &signatures {
+            out.push_str(&base64::encode(signature.as_ref()));
+            out.push('\n');
+        }
+        out
+    }
 }
 
 /// Store a manifest locally. Writes first and then swaps the file.
 ///
 /// Takes the target directory path and manifest bytes.
 pub fn store_local(path: &Path, bytes: &[u8]) -> Result<()> {
-    let mut path_tmp = PathBuf::from(path);
-    let mut path_final = PathBuf::from(path);
-    path_tmp.push("manifest.new");
-    path_final.push("manifest");
+    store_file(&path.join(DEFAULT_NAME), bytes)
+}
+
+/// Store a manifest under the given file name. Writes first to the name with
+/// `.new` appended, and then swaps the file.
+pub fn store_file(path_final: &Path, bytes: &[u8]) -> Result<()> {
+    let mut path_tmp = path_final.as_os_str().to_os_string();
+    path_tmp.push(".new");
+    let path_tmp = PathBuf::from(path_tmp);
 
     // Delete the file if the write fails.
     let guard = util::FileGuard::new(&path_tmp);
@@ -326,20 +996,21 @@ pub fn store_local(path: &Path, bytes: &[u8]) -> Result<()> {
     buf_writer.write_all(bytes)?;
 
     // Rename the manifest over the old manifest, mark it read-only.
-    guard.move_readonly(&path_final)?;
+    guard.move_readonly(path_final)?;
 
     Ok(())
 }
 
 #[cfg(test)]
 mod test {
+    use base64;
     use ring::signature::Ed25519KeyPair;
     use ring::test::rand::FixedSliceRandom;
     use untrusted::Input;
 
     use config::PublicKey;
     use error::Error;
-    use super::{Entry, Manifest, Sha256, parse_entry};
+    use super::{Entry, Index, IndexEntry, Manifest, Sha256, parse_entry, parse_header};
     use version::Version;
 
     fn get_test_key_pair() -> Ed25519KeyPair {
@@ -355,6 +1026,14 @@ mod test {
         Ed25519KeyPair::from_pkcs8(Input::from(&pkcs8_bytes)).unwrap()
     }
 
+    /// A second key pair, to test manifests with multiple signatures.
+    fn get_second_key_pair() -> Ed25519KeyPair {
+        let seed = b"second-key-for-rotation-testing!";
+        let rng = FixedSliceRandom { bytes: &seed[..] };
+        let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        Ed25519KeyPair::from_pkcs8(Input::from(&pkcs8_bytes)).unwrap()
+    }
+
     fn get_test_public_key() -> PublicKey {
         PublicKey::from_pair(&get_test_key_pair())
     }
@@ -373,6 +1052,8 @@ mod test {
         Entry {
             version: Version::from(version),
             digest: get_test_sha256(),
+            annotations: Vec::new(),
+            deltas: Vec::new(),
         }
     }
 
@@ -384,15 +1065,85 @@ mod test {
         assert_eq!(entry.digest, get_test_sha256());
     }
 
+    /// Append a signature line to the message, signed with the test key.
+    fn sign(message: &str) -> Vec<u8> {
+        let signature = get_test_key_pair().sign(message.as_bytes());
+        let mut out = String::from(message);
+        out.push_str(&base64::encode(signature.as_ref()));
+        out.push('\n');
+        out.into_bytes()
+    }
+
+    #[test]
+    fn parse_header_parses_major_and_minor_version() {
+        assert_eq!(parse_header(b"Tako Manifest 1").unwrap(), (1, 0));
+        assert_eq!(parse_header(b"Tako Manifest 1.0").unwrap(), (1, 0));
+        assert_eq!(parse_header(b"Tako Manifest 1.7").unwrap(), (1, 7));
+        assert!(parse_header(b"Tako Manifest").is_err());
+        assert!(parse_header(b"Tako Manifest 1.").is_err());
+        assert!(parse_header(b"Tako Manifest +1").is_err());
+        assert!(parse_header(b"Tako Manifesto 1").is_err());
+    }
+
     #[test]
     fn parse_rejects_unknown_version() {
-        let raw = b"Tako Manifest 1.1\n\nWrong!\n";
+        let raw = sign("Tako Manifest 2\n\n\
+            1.0.0 b101acf3c4870594bb4363090d5ab966c193fb329e2f2db2096708e08c4913e2\n\n");
         match Manifest::parse(&raw[..], &get_test_public_key()) {
             Err(Error::InvalidManifest(..)) => { /* This is expected. */ },
             _ => panic!("Manifest should be rejected."),
         }
     }
 
+    #[test]
+    fn parse_ignores_unknown_fields() {
+        let raw = sign("Tako Manifest 1.3\n\
+            Publisher=Tako Marks\n\
+            \n\
+            1.0.0 b101acf3c4870594bb4363090d5ab966c193fb329e2f2db2096708e08c4913e2\n  \
+              Size=1024\n  \
+              Notes=Fixes the thing. With=signs.\n\
+            2.0.0 b7b01c6f6772529c66b945e559cb1f46546ef62063e44c1d1068725157ae1cda\n\n");
+        let manifest = Manifest::parse(&raw[..], &get_test_public_key()).unwrap();
+        assert_eq!(manifest.entries.len(), 2);
+        assert_eq!(manifest.entries[1].version, Version::from("2.0.0"));
+    }
+
+    #[test]
+    fn parse_verifies_signature_over_unknown_fields() {
+        let mut raw = sign("Tako Manifest 1.1\n\n\
+            1.0.0 b101acf3c4870594bb4363090d5ab966c193fb329e2f2db2096708e08c4913e2\n  \
+              Size=1024\n\n");
+        // Change the ignored field. The signature covers it, so it must no
+        // longer verify.
+        let i = raw.windows(9).position(|w| w == b"Size=1024").unwrap();
+        raw[i + 5] = b'2';
+        match Manifest::parse(&raw[..], &get_test_public_key()) {
+            Err(Error::InvalidSignature) => { /* This is expected. */ },
+            _ => panic!("Manifest should be rejected."),
+        }
+    }
+
+    #[test]
+    fn parse_rejects_malformed_fields() {
+        let raws = [
+            // Entry field before the first entry.
+            sign("Tako Manifest 1\n\n  Size=1024\n\n"),
+            // Field without '='.
+            sign("Tako Manifest 1\nPublisher\n\n\n"),
+            // Entry field without '='.
+            sign("Tako Manifest 1\n\n\
+                1.0.0 b101acf3c4870594bb4363090d5ab966c193fb329e2f2db2096708e08c4913e2\n  \
+                  Size\n\n"),
+        ];
+        for raw in &raws {
+            match Manifest::parse(&raw[..], &get_test_public_key()) {
+                Err(Error::InvalidManifest(..)) => { /* This is expected. */ },
+                _ => panic!("Manifest should be rejected."),
+            }
+        }
+    }
+
     #[test]
     fn parse_parses_single_entry_manifest() {
         let raw = b"Tako Manifest 1\n\n\
@@ -416,6 +1167,38 @@ mod test {
         }
     }
 
+    #[test]
+    fn parse_rejects_manifest_without_final_newline() {
+        let raw = b"Tako Manifest 1\n\n\
+            1.0.0 b101acf3c4870594bb4363090d5ab966c193fb329e2f2db2096708e08c4913e2\n\n\
+            R9fjMZ9e2c5IrfByS53H6ur0VSWQfdTgAS2Y3t3lYcH9+ogDGtrbe65GhgEmDDD20Gfy8VyZQ82byF+NSANwDg==";
+        match Manifest::parse(&raw[..], &get_test_public_key()) {
+            Err(Error::InvalidManifest(..)) => { /* This is expected. */ },
+            _ => panic!("Manifest should be rejected."),
+        }
+    }
+
+    #[test]
+    fn parse_rejects_manifest_with_extra_final_newline() {
+        let raw = b"Tako Manifest 1\n\n\
+            1.0.0 b101acf3c4870594bb4363090d5ab966c193fb329e2f2db2096708e08c4913e2\n\n\
+            R9fjMZ9e2c5IrfByS53H6ur0VSWQfdTgAS2Y3t3lYcH9+ogDGtrbe65GhgEmDDD20Gfy8VyZQ82byF+NSANwDg==\n\n";
+        match Manifest::parse(&raw[..], &get_test_public_key()) {
+            Err(Error::InvalidManifest(..)) => { /* This is expected. */ },
+            _ => panic!("Manifest should be rejected."),
+        }
+    }
+
+    #[test]
+    fn parse_rejects_blank_manifest() {
+        for raw in &[&b""[..], &b"\n"[..], &b"\n\n\n\n"[..]] {
+            match Manifest::parse(raw, &get_test_public_key()) {
+                Err(Error::InvalidManifest(..)) => { /* This is expected. */ },
+                _ => panic!("Manifest should be rejected."),
+            }
+        }
+    }
+
     #[test]
     fn parse_parses_double_entry_manifest() {
         let raw = b"Tako Manifest 1\n\n\
@@ -434,8 +1217,12 @@ mod test {
         let entry = get_test_entry("1.0.0");
         let manifest = Manifest {
             entries: vec![entry],
+            image_base: None,
+            name: None,
+            image_ext: None,
+            embed_public_keys: false,
         };
-        let serialized = manifest.serialize(&get_test_key_pair());
+        let serialized = manifest.serialize(&[get_test_key_pair()]);
         let expected = "Tako Manifest 1\n\n\
             1.0.0 9641a49d02e90cbb6213f202fb632da70cdc59073d42283cfcdc1d786454f17f\n\n\
             ttye/o4X1aOQQwk8Rf9OHLyqhfhi440qgH8cxw8ol/UgoSj7e1tQbhoA44Q+vEonigVwPMl82j6T0X7hTbziAQ==\n";
@@ -445,15 +1232,239 @@ mod test {
     #[test]
     fn serialize_then_parse_is_identity() {
         let entry = get_test_entry("1.0.0");
-        let manifest = Manifest {
+        let mut manifest = Manifest {
             entries: vec![entry],
+            image_base: None,
+            name: None,
+            image_ext: None,
+            embed_public_keys: false,
         };
-        let serialized = manifest.serialize(&get_test_key_pair());
+        let serialized = manifest.serialize(&[get_test_key_pair()]);
+        let deserialized = Manifest::parse(
+            serialized.as_bytes(),
+            &get_test_public_key()
+        ).unwrap();
+        assert_eq!(deserialized, manifest);
+
+        manifest.set_image_base("https://cdn.example.com/app").unwrap();
+        let serialized = manifest.serialize(&[get_test_key_pair()]);
+        assert!(serialized.starts_with("Tako Manifest 1.1\nImageBase=https://cdn.example.com/app\n\n"));
+        let deserialized = Manifest::parse(
+            serialized.as_bytes(),
+            &get_test_public_key()
+        ).unwrap();
+        assert_eq!(deserialized, manifest);
+
+        manifest.set_name("app-foo").unwrap();
+        let serialized = manifest.serialize(&[get_test_key_pair()]);
+        assert!(serialized.starts_with("Tako Manifest 1.4\n"));
+        assert!(serialized.contains("\nImageBase=https://cdn.example.com/app\nName=app-foo\n\n"));
+        let deserialized = Manifest::parse(
+            serialized.as_bytes(),
+            &get_test_public_key()
+        ).unwrap();
+        assert_eq!(deserialized, manifest);
+        assert_eq!(deserialized.name(), Some("app-foo"));
+
+        manifest.set_image_ext(".img").unwrap();
+        let serialized = manifest.serialize(&[get_test_key_pair()]);
+        assert!(serialized.starts_with("Tako Manifest 1.5\n"));
+        assert!(serialized.contains("\nName=app-foo\nImageExt=.img\n\n"));
         let deserialized = Manifest::parse(
             serialized.as_bytes(),
             &get_test_public_key()
         ).unwrap();
         assert_eq!(deserialized, manifest);
+        assert_eq!(deserialized.image_ext(), ".img");
+    }
+
+    #[test]
+    fn annotate_writes_entry_fields() {
+        let mut manifest = Manifest {
+            entries: vec![get_test_entry("1.0.0"), get_test_entry("1.1.0")],
+            image_base: None,
+            name: None,
+            image_ext: None,
+            embed_public_keys: false,
+        };
+        let original = Manifest {
+            entries: manifest.entries.clone(),
+            image_base: None,
+            name: None,
+            image_ext: None,
+            embed_public_keys: false,
+        };
+        manifest.annotate(&Version::from("1.0.0"), "label", "stable").unwrap();
+        manifest.annotate(&Version::from("1.0.0"), "note", "first").unwrap();
+        manifest.annotate(&Version::from("1.0.0"), "label", "lts").unwrap();
+
+        let serialized = manifest.serialize(&[get_test_key_pair()]);
+        assert!(serialized.starts_with("Tako Manifest 1.3\n"));
+        assert!(serialized.contains("f\n  Annotation=label=lts\n  Annotation=note=first\n1.1.0 "));
+        let deserialized = Manifest::parse(serialized.as_bytes(), &get_test_public_key()).unwrap();
+        assert_eq!(deserialized, manifest);
+
+        // Annotations may change, the entries are still the same.
+        assert!(original.is_subset_of(&manifest));
+
+        match manifest.annotate(&Version::from("2.0.0"), "label", "stable") {
+            Err(Error::NoCandidate(..)) => { /* This is expected. */ },
+            _ => panic!("Annotating a missing version should fail."),
+        }
+    }
+
+    #[test]
+    fn prune_removes_oldest_entries() {
+        let mut manifest = Manifest {
+            entries: vec![get_test_entry("1.0.0"), get_test_entry("1.1.0"), get_test_entry("2.0.0")],
+            image_base: None,
+            name: None,
+            image_ext: None,
+            embed_public_keys: false,
+        };
+        let original = Manifest {
+            entries: manifest.entries.clone(),
+            image_base: None,
+            name: None,
+            image_ext: None,
+            embed_public_keys: false,
+        };
+        assert_eq!(manifest.prune(5), Vec::new());
+        assert_eq!(manifest.prune(2), vec![get_test_entry("1.0.0")]);
+        assert_eq!(manifest.entries, vec![get_test_entry("1.1.0"), get_test_entry("2.0.0")]);
+
+        // Fetchers accept a manifest that lost its oldest entries, but not one
+        // that lost any other entry.
+        assert!(manifest.is_successor_of(&original));
+        assert!(!original.is_subset_of(&manifest));
+        let without_middle = Manifest {
+            entries: vec![get_test_entry("1.0.0"), get_test_entry("2.0.0")],
+            image_base: None,
+            name: None,
+            image_ext: None,
+            embed_public_keys: false,
+        };
+        assert!(!without_middle.is_successor_of(&original));
+        assert!(!Manifest::new().is_successor_of(&original));
+        assert!(original.is_successor_of(&manifest));
+    }
+
+    #[test]
+    fn parse_validates_image_base() {
+        let entry = "1.0.0 9641a49d02e90cbb6213f202fb632da70cdc59073d42283cfcdc1d786454f17f\n\n";
+
+        let message = format!("Tako Manifest 1.1\nImageBase=https://cdn.example.com/app/\n\n{}", entry);
+        let manifest = Manifest::parse(&sign(&message)[..], &get_test_public_key()).unwrap();
+        assert_eq!(manifest.image_base(), Some("https://cdn.example.com/app"));
+
+        for base in &["file:///etc", "ftp://example.com", "cdn.example.com"] {
+            let message = format!("Tako Manifest 1.1\nImageBase={}\n\n{}", base, entry);
+            assert!(Manifest::parse(&sign(&message)[..], &get_test_public_key()).is_err());
+        }
+    }
+
+    #[test]
+    fn parse_validates_name() {
+        let entry = "1.0.0 9641a49d02e90cbb6213f202fb632da70cdc59073d42283cfcdc1d786454f17f\n\n";
+
+        let message = format!("Tako Manifest 1.4\nName=app-foo\n\n{}", entry);
+        let manifest = Manifest::parse(&sign(&message)[..], &get_test_public_key()).unwrap();
+        assert_eq!(manifest.name(), Some("app-foo"));
+
+        for name in &["", "app foo"] {
+            let message = format!("Tako Manifest 1.4\nName={}\n\n{}", name, entry);
+            assert!(Manifest::parse(&sign(&message)[..], &get_test_public_key()).is_err());
+        }
+    }
+
+    #[test]
+    fn parse_validates_image_ext() {
+        let entry = "1.0.0 9641a49d02e90cbb6213f202fb632da70cdc59073d42283cfcdc1d786454f17f\n\n";
+
+        let message = format!("Tako Manifest 1.5\nImageExt=.tar.gz\n\n{}", entry);
+        let manifest = Manifest::parse(&sign(&message)[..], &get_test_public_key()).unwrap();
+        assert_eq!(manifest.image_ext(), ".tar.gz");
+
+        for ext in &["", "img", ".", ".img/x", "..img", ".img."] {
+            let message = format!("Tako Manifest 1.5\nImageExt={}\n\n{}", ext, entry);
+            assert!(Manifest::parse(&sign(&message)[..], &get_test_public_key()).is_err());
+        }
+    }
+
+    #[test]
+    fn parse_public_keys_returns_embedded_keys() {
+        let entry = "1.0.0 9641a49d02e90cbb6213f202fb632da70cdc59073d42283cfcdc1d786454f17f\n\n";
+
+        let message = format!("Tako Manifest 1\n\n{}", entry);
+        assert_eq!(Manifest::parse_public_keys(&sign(&message)[..]).unwrap(), Vec::new());
+
+        // Keys are embedded only when asked for.
+        let serialized = Manifest::new().serialize(&[get_test_key_pair()]);
+        assert!(serialized.starts_with("Tako Manifest 1\n\n"));
+        assert_eq!(Manifest::parse_public_keys(serialized.as_bytes()).unwrap(), Vec::new());
+
+        let mut manifest = Manifest::new();
+        manifest.set_embed_public_keys();
+        let serialized = manifest.serialize(&[get_test_key_pair()]);
+        assert!(serialized.starts_with("Tako Manifest 1.1\nPublicKey="));
+        let keys = Manifest::parse_public_keys(serialized.as_bytes()).unwrap();
+        assert_eq!(keys, vec![get_test_public_key()]);
+
+        // A manifest with keys keeps them.
+        let parsed = Manifest::parse(serialized.as_bytes(), &get_test_public_key()).unwrap();
+        assert_eq!(parsed, manifest);
+
+        let serialized = manifest.serialize(&[get_second_key_pair(), get_test_key_pair()]);
+        let keys = Manifest::parse_public_keys(serialized.as_bytes()).unwrap();
+        assert_eq!(keys, vec![PublicKey::from_pair(&get_second_key_pair()), get_test_public_key()]);
+
+        let message = format!("Tako Manifest 1.1\nPublicKey=l0D28J2f\n\n{}", entry);
+        assert!(Manifest::parse_public_keys(&sign(&message)[..]).is_err());
+    }
+
+    #[test]
+    fn serialize_signs_with_every_key() {
+        let mut manifest = Manifest::new();
+        manifest.insert(get_test_entry("1.0.0")).unwrap();
+        let serialized = manifest.serialize(&[get_test_key_pair(), get_second_key_pair()]);
+        assert!(serialized.starts_with("Tako Manifest 1.2\n"));
+
+        let second_public_key = PublicKey::from_pair(&get_second_key_pair());
+        for key in &[get_test_public_key(), second_public_key.clone()] {
+            assert_eq!(Manifest::parse(serialized.as_bytes(), key).unwrap(), manifest);
+        }
+
+        // The signatures must not cover each other, so without the first one,
+        // the second one must still verify.
+        let mut lines: Vec<&str> = serialized.lines().collect();
+        let n = lines.len();
+        lines.remove(n - 2);
+        let second_only = format!("{}\n", lines.join("\n"));
+        assert!(Manifest::parse(second_only.as_bytes(), &second_public_key).is_ok());
+        assert!(Manifest::parse(second_only.as_bytes(), &get_test_public_key()).is_err());
+    }
+
+    #[test]
+    fn serialize_unsigned_is_the_signed_message() {
+        let mut manifest = Manifest::new();
+        manifest.insert(get_test_entry("1.0.0")).unwrap();
+        let key_pairs = [get_test_key_pair(), get_second_key_pair()];
+        let public_keys: Vec<_> = key_pairs.iter().map(PublicKey::from_pair).collect();
+        let message = manifest.serialize_unsigned(&public_keys);
+        let serialized = manifest.serialize(&key_pairs);
+        assert!(serialized.starts_with(&message));
+        assert_eq!(serialized[message.len()..].lines().count(), 2);
+    }
+
+    #[test]
+    fn parse_any_rejects_manifest_without_trusted_signature() {
+        let serialized = Manifest::new().serialize(&[get_test_key_pair()]);
+        let second_public_key = PublicKey::from_pair(&get_second_key_pair());
+        assert!(Manifest::parse_any(serialized.as_bytes(), Some(&second_public_key)).is_err());
+        assert!(Manifest::parse_any(
+            serialized.as_bytes(),
+            &[second_public_key, get_test_public_key()],
+        ).is_ok());
     }
 
     #[test]
@@ -472,6 +1483,25 @@ mod test {
         assert_eq!(m_0_1, m_1_0);
     }
 
+    #[test]
+    fn serialize_is_reproducible() {
+        let key_pairs = [get_test_key_pair()];
+        let entry0 = get_test_entry("0.0.0");
+        let entry1 = get_test_entry("1.0.0");
+
+        let mut m_0_1 = Manifest::new();
+        m_0_1.set_image_base("https://images.example.com").unwrap();
+        m_0_1.insert(entry0.clone()).unwrap();
+        m_0_1.insert(entry1.clone()).unwrap();
+
+        let mut m_1_0 = Manifest::new();
+        m_1_0.insert(entry1).unwrap();
+        m_1_0.insert(entry0).unwrap();
+        m_1_0.set_image_base("https://images.example.com").unwrap();
+
+        assert_eq!(m_0_1.serialize(&key_pairs), m_1_0.serialize(&key_pairs));
+    }
+
     #[test]
     fn insert_allows_reinsert_if_identical() {
         let entry = get_test_entry("0.0.0");
@@ -528,6 +1558,10 @@ mod test {
                 get_test_entry("1.2.1"),
                 get_test_entry("2.0.0"),
             ],
+            image_base: None,
+            name: None,
+            image_ext: None,
+            embed_public_keys: false,
         };
 
         let (u, w) = Version::from("*").pattern_to_bounds();
@@ -554,4 +1588,119 @@ mod test {
         let entry = manifest.latest_compatible_entry(&u, &w);
         assert!(entry.is_none());
     }
+
+    #[test]
+    fn select_candidate_respects_bounds() {
+        let manifest = Manifest {
+            entries: vec![
+                get_test_entry("1.9.0"),
+                get_test_entry("2.0.0"),
+                get_test_entry("2.3.1"),
+                get_test_entry("3.0.0"),
+                get_test_entry("3.1.0"),
+            ],
+            image_base: None,
+            name: None,
+            image_ext: None,
+            embed_public_keys: false,
+        };
+        let any = Version::from("*");
+        let lower = Version::from("2.0.0");
+        let upper = Version::from("3.0.0");
+
+        // The lower bound is inclusive, the upper bound is exclusive.
+        let versions: Vec<_> = manifest.select_candidates(&any, Some(&lower), Some(&upper)).unwrap()
+            .iter().map(|e| e.version.as_str()).collect();
+        assert_eq!(versions, vec!["2.3.1", "2.0.0"]);
+
+        let entry = manifest.select_candidate(&any, Some(&lower), None).unwrap();
+        assert_eq!(entry.version, Version::from("3.1.0"));
+        let entry = manifest.select_candidate(&any, None, Some(&lower)).unwrap();
+        assert_eq!(entry.version, Version::from("1.9.0"));
+
+        // The bounds narrow the pattern, they do not widen it.
+        let entry = manifest.select_candidate(&Version::from("3.*"), None, Some(&upper));
+        match entry {
+            Err(Error::NoCandidate(_, available)) => assert_eq!(available.len(), 5),
+            _ => panic!("Expected NoCandidate error."),
+        }
+    }
+
+    #[test]
+    fn select_candidate_returns_no_candidate_with_available_versions() {
+        let manifest = Manifest {
+            entries: vec![
+                get_test_entry("1.0.0"),
+                get_test_entry("1.1.0"),
+            ],
+            image_base: None,
+            name: None,
+            image_ext: None,
+            embed_public_keys: false,
+        };
+
+        let entry = manifest.select_candidate(&Version::from("1.*"), None, None).unwrap();
+        assert_eq!(entry.version, Version::from("1.1.0"));
+
+        let versions: Vec<_> = manifest.select_candidates(&Version::from("1.*"), None, None).unwrap()
+            .iter().map(|e| e.version.as_str()).collect();
+        assert_eq!(versions, vec!["1.1.0", "1.0.0"]);
+        assert!(manifest.select_candidates(&Version::from("2.*"), None, None).is_err());
+
+        match manifest.select_candidate(&Version::from("2.*"), None, None) {
+            Err(Error::NoCandidate(pattern, available)) => {
+                assert_eq!(pattern, Version::from("2.*"));
+                assert_eq!(available, vec![Version::from("1.0.0"), Version::from("1.1.0")]);
+            }
+            _ => panic!("Expected NoCandidate error."),
+        }
+    }
+
+    fn get_test_index() -> Index {
+        let mut index = Index::new();
+        for &(name, version) in &[("app-foo", "1.2.0"), ("app-bar", "3.0.1")] {
+            index.insert(IndexEntry {
+                name: name.to_string(),
+                version: Version::from(version),
+                manifest_digest: get_test_sha256(),
+            }).unwrap();
+        }
+        index
+    }
+
+    #[test]
+    fn index_serialize_then_parse_is_identity() {
+        let index = get_test_index();
+        let serialized = index.serialize(&[get_test_key_pair()]);
+        assert!(serialized.starts_with(
+            "Tako Index 1\n\
+            PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=\n\
+            \n\
+            app-bar 3.0.1 9641a49d02e90cbb6213f202fb632da70cdc59073d42283cfcdc1d786454f17f\n\
+            app-foo 1.2.0 9641a49d02e90cbb6213f202fb632da70cdc59073d42283cfcdc1d786454f17f\n\
+            \n"
+        ));
+        assert_eq!(Index::parse_any(serialized.as_bytes(), Some(&get_test_public_key())).unwrap(), index);
+        assert_eq!(index.entry("app-foo").unwrap().version, Version::from("1.2.0"));
+        assert!(index.entry("app-baz").is_none());
+    }
+
+    #[test]
+    fn index_parse_rejects_other_key_and_tampering() {
+        let serialized = get_test_index().serialize(&[get_test_key_pair()]);
+        let second_public_key = PublicKey::from_pair(&get_second_key_pair());
+        match Index::parse_any(serialized.as_bytes(), Some(&second_public_key)) {
+            Err(Error::InvalidSignature) => {}
+            other => panic!("Expected InvalidSignature, got {:?}.", other),
+        }
+        let tampered = serialized.replace("3.0.1", "3.0.2");
+        assert!(Index::parse_any(tampered.as_bytes(), Some(&get_test_public_key())).is_err());
+
+        // Entries out of order, or a duplicate name, are invalid regardless.
+        let mut index = get_test_index();
+        let entry = index.entry("app-bar").unwrap().clone();
+        assert!(index.insert(entry).is_err());
+        let swapped = serialized.replacen("app-bar", "app-zzz", 1);
+        assert!(Index::parse_any(swapped.as_bytes(), None).is_err());
+    }
 }
@@ -0,0 +1,269 @@
+// Tako -- Take container image.
+// Copyright 2018 Arian van Putten, Ruud van Asseldonk, Tako Marks.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Metrics about fetch runs, in Prometheus text format.
+//!
+//! The metrics file is meant for the textfile collector of node_exporter.
+//! Counters and the time of the last success must survive across runs, so we
+//! read them back from the previous metrics file. Configs that were not part of
+//! this run keep their previous values, such that multiple timers can share a
+//! metrics file, as long as they do not run at the same time.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use error::Result;
+use util;
+
+/// Metrics of a single config.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct ConfigMetrics {
+    /// Unix time of the last fetch, successful or not.
+    pub last_fetch: u64,
+
+    /// Unix time of the last successful fetch.
+    pub last_success: Option<u64>,
+
+    /// The version that `latest` points at, if known.
+    pub installed_version: Option<String>,
+
+    /// Number of failed fetches.
+    pub errors_total: u64,
+}
+
+/// Metrics of all configs in a metrics file, by config filename.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Metrics {
+    configs: BTreeMap<String, ConfigMetrics>,
+}
+
+/// Append a string as a quoted Prometheus label value.
+fn append_label_value(out: &mut String, value: &str) {
+    out.push('"');
+    for ch in value.chars() {
+        match ch {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            ch => out.push(ch),
+        }
+    }
+    out.push('"');
+}
+
+/// A metric name, its labels, and the unparsed value.
+type Sample<'a> = (&'a str, Vec<(&'a str, String)>, &'a str);
+
+/// Parse a sample line `name{label="value",...} number`.
+///
+/// Returns None for lines that are not in the format that `serialize` writes.
+fn parse_sample<'a>(line: &'a str) -> Option<Sample<'a>> {
+    let open = line.find('{')?;
+    let name = &line[..open];
+    let mut labels = Vec::new();
+    let mut rest = &line[open + 1..];
+
+    loop {
+        if rest.starts_with('}') {
+            rest = &rest[1..];
+            break
+        }
+        let eq = rest.find("=\"")?;
+        let label = &rest[..eq];
+        let mut value = String::new();
+        let mut chars = rest[eq + 2..].char_indices();
+        let end = loop {
+            match chars.next()? {
+                (i, '"') => break eq + 2 + i + 1,
+                (_, '\\') => match chars.next()? {
+                    (_, 'n') => value.push('\n'),
+                    (_, ch) => value.push(ch),
+                },
+                (_, ch) => value.push(ch),
+            }
+        };
+        labels.push((label, value));
+        rest = &rest[end..];
+        if rest.starts_with(',') {
+            rest = &rest[1..];
+        }
+    }
+
+    if !rest.starts_with(' ') {
+        return None
+    }
+    Some((name, labels, &rest[1..]))
+}
+
+impl Metrics {
+    /// Parse metrics in the format that `serialize` writes.
+    ///
+    /// Lines that are not understood are skipped, so a damaged or foreign
+    /// metrics file loses history, but does not prevent writing new metrics.
+    pub fn parse(text: &str) -> Metrics {
+        let mut metrics = Metrics::default();
+
+        for line in text.lines() {
+            if line.starts_with('#') {
+                continue
+            }
+            let (name, labels, value) = match parse_sample(line) {
+                Some(sample) => sample,
+                None => continue,
+            };
+            let config = match labels.iter().find(|&&(k, _)| k == "config") {
+                Some(&(_, ref v)) => v.clone(),
+                None => continue,
+            };
+            let entry = metrics.configs.entry(config).or_insert_with(ConfigMetrics::default);
+            match name {
+                "tako_last_fetch_timestamp" => {
+                    entry.last_fetch = value.parse().unwrap_or(0);
+                }
+                "tako_last_success_timestamp" => {
+                    entry.last_success = value.parse().ok();
+                }
+                "tako_fetch_errors_total" => {
+                    entry.errors_total = value.parse().unwrap_or(0);
+                }
+                "tako_installed_info" => {
+                    if let Some(&(_, ref v)) = labels.iter().find(|&&(k, _)| k == "version") {
+                        entry.installed_version = Some(v.clone());
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        metrics
+    }
+
+    /// Record a fetch of the config at the given unix time.
+    pub fn record(&mut self, config_fname: &str, now: u64, success: bool, installed_version: Option<String>) {
+        let entry = self.configs
+            .entry(String::from(config_fname))
+            .or_insert_with(ConfigMetrics::default);
+        entry.last_fetch = now;
+        if success {
+            entry.last_success = Some(now);
+        } else {
+            entry.errors_total += 1;
+        }
+        if installed_version.is_some() {
+            entry.installed_version = installed_version;
+        }
+    }
+
+    /// Format the metrics in Prometheus text format.
+    pub fn serialize(&self) -> String {
+        let mut out = String::new();
+
+        let families: [(&str, &str, &str); 4] = [
+            ("tako_last_fetch_timestamp", "gauge", "Unix time of the last fetch."),
+            ("tako_last_success_timestamp", "gauge", "Unix time of the last successful fetch."),
+            ("tako_installed_info", "gauge", "The version that latest points at."),
+            ("tako_fetch_errors_total", "counter", "Number of failed fetches."),
+        ];
+
+        for &(name, kind, help) in &families {
+            out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+            for (config, m) in &self.configs {
+                let mut labels = String::from("config=");
+                append_label_value(&mut labels, config);
+                let value = match name {
+                    "tako_last_fetch_timestamp" => m.last_fetch,
+                    "tako_last_success_timestamp" => match m.last_success {
+                        Some(t) => t,
+                        None => continue,
+                    },
+                    "tako_installed_info" => match m.installed_version {
+                        Some(ref version) => {
+                            labels.push_str(",version=");
+                            append_label_value(&mut labels, version);
+                            1
+                        }
+                        None => continue,
+                    },
+                    _ => m.errors_total,
+                };
+                out.push_str(&format!("{}{{{}}} {}\n", name, labels, value));
+            }
+        }
+
+        out
+    }
+
+    /// Load a metrics file. If it does not exist, the metrics are empty.
+    pub fn load(path: &Path) -> Result<Metrics> {
+        let mut text = String::new();
+        match fs::File::open(path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Metrics::default()),
+            Err(e) => return Err(e.into()),
+            Ok(mut f) => { f.read_to_string(&mut text)?; }
+        }
+        Ok(Metrics::parse(&text))
+    }
+
+    /// Write the metrics file. Writes first and then swaps the file, so the
+    /// collector never reads a partial file.
+    pub fn store(&self, path: &Path) -> Result<()> {
+        let mut path_tmp = path.as_os_str().to_os_string();
+        path_tmp.push(".new");
+        let path_tmp = PathBuf::from(path_tmp);
+
+        // Delete the file if the write fails.
+        let guard = util::FileGuard::new(&path_tmp);
+        fs::File::create(&path_tmp)?.write_all(self.serialize().as_bytes())?;
+        guard.move_readonly(path)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Metrics;
+
+    #[test]
+    fn record_accumulates_errors_and_keeps_last_success() {
+        let mut metrics = Metrics::default();
+        metrics.record("a.tako", 100, true, Some("1.0.0".to_string()));
+        metrics.record("a.tako", 200, false, None);
+        metrics.record("a.tako", 300, false, None);
+
+        let a = metrics.configs.get("a.tako").unwrap();
+        assert_eq!(a.last_fetch, 300);
+        assert_eq!(a.last_success, Some(100));
+        assert_eq!(a.errors_total, 2);
+        assert_eq!(a.installed_version, Some("1.0.0".to_string()));
+    }
+
+    #[test]
+    fn serialize_then_parse_is_identity() {
+        let mut metrics = Metrics::default();
+        metrics.record("/etc/tako/\"quoted\"\\app.tako", 100, true, Some("1.0.0".to_string()));
+        metrics.record("b.tako", 200, false, None);
+
+        let text = metrics.serialize();
+        assert!(text.contains("tako_installed_info{config=\"/etc/tako/\\\"quoted\\\"\\\\app.tako\",version=\"1.0.0\"} 1\n"));
+        assert!(text.contains("tako_fetch_errors_total{config=\"b.tako\"} 1\n"));
+        assert!(!text.contains("tako_last_success_timestamp{config=\"b.tako\"}"));
+        assert!(!text.contains("tako_installed_info{config=\"b.tako\""));
+        assert_eq!(Metrics::parse(&text), metrics);
+    }
+
+    #[test]
+    fn parse_skips_malformed_lines() {
+        let text = "garbage\ntako_fetch_errors_total{config=\"a\" 3\ntako_fetch_errors_total{config=\"b\"} 3\n";
+        let metrics = Metrics::parse(text);
+        assert_eq!(metrics.configs.get("a"), None);
+        assert_eq!(metrics.configs.get("b").unwrap().errors_total, 3);
+    }
+}
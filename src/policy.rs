@@ -0,0 +1,140 @@
+// Tako -- Take container image.
+// Copyright 2018 Arian van Putten, Ruud van Asseldonk, Tako Marks.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! The system policy, which bounds what configs may do.
+//!
+//! Tako often runs as root, and on a shared host, the configs of different
+//! images may be maintained by different teams. The policy file is owned by
+//! the administrator of the host, and limits which units a config may stop,
+//! start, and restart. Without a policy file, configs are not limited.
+
+use std::io;
+use std::path::Path;
+
+use config;
+use config::Config;
+use error::{Error, Result};
+
+/// The policy file that fetch reads, unless `--policy` is given.
+pub const DEFAULT_POLICY_PATH: &'static str = "/etc/tako/policy";
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Policy {
+    /// Patterns of the units that configs may act on. If empty, any unit.
+    pub allowed_units: Vec<String>,
+}
+
+impl Policy {
+    pub fn parse<I, S>(lines: I) -> Result<Policy>
+    where I: IntoIterator<Item = S>,
+          S: AsRef<str> {
+        let mut allowed_units = Vec::new();
+
+        config::for_each_pair(lines, |lineno, key, value| {
+            match key {
+                "AllowUnit" => allowed_units.push(String::from(value)),
+                _ => {
+                    let msg = "Unknown key. Expected 'AllowUnit'.";
+                    return Err(Error::InvalidConfig(lineno, msg))
+                }
+            }
+            Ok(())
+        })?;
+
+        let policy = Policy {
+            allowed_units: allowed_units,
+        };
+
+        Ok(policy)
+    }
+
+    /// Load the policy from `--policy`, or from the default path.
+    ///
+    /// If no path was given and the default file does not exist, None is
+    /// returned, rather than an Err. A path that was given must exist.
+    pub fn load(path: Option<&Path>) -> Result<Option<Policy>> {
+        let lines = match path {
+            Some(path) => config::read_lines(path)?,
+            None => match config::read_lines(DEFAULT_POLICY_PATH) {
+                Ok(lines) => lines,
+                Err(Error::IoError(ref e)) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            },
+        };
+        Ok(Some(Policy::parse(lines)?))
+    }
+
+    /// Check that the policy allows every unit that the config acts on.
+    pub fn check(&self, config: &Config) -> Result<()> {
+        if self.allowed_units.is_empty() {
+            return Ok(())
+        }
+        for unit in config.stop_units.iter().chain(config.restart_units.iter()) {
+            if !self.allowed_units.iter().any(|p| matches_pattern(p.as_bytes(), unit.as_bytes())) {
+                return Err(Error::PolicyViolation(unit.clone()))
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Return whether the name matches the pattern, where `*` matches any run of
+/// bytes, including an empty one.
+fn matches_pattern(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((&b'*', rest)) => (0..name.len() + 1).any(|i| matches_pattern(rest, &name[i..])),
+        Some((&c, rest)) => match name.split_first() {
+            Some((&n, name_rest)) if n == c => matches_pattern(rest, name_rest),
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use config::Config;
+    use super::{Policy, matches_pattern};
+
+    #[test]
+    fn matches_pattern_handles_wildcards() {
+        assert!(matches_pattern(b"app.service", b"app.service"));
+        assert!(matches_pattern(b"app-*.service", b"app-web.service"));
+        assert!(matches_pattern(b"app-*.service", b"app-.service"));
+        assert!(matches_pattern(b"*", b"sshd.service"));
+        assert!(matches_pattern(b"*@*.service", b"worker@1.service"));
+        assert!(!matches_pattern(b"app-*.service", b"sshd.service"));
+        assert!(!matches_pattern(b"app.service", b"app.service.d"));
+        assert!(!matches_pattern(b"app-*", b"app"));
+    }
+
+    #[test]
+    fn check_rejects_units_outside_the_policy() {
+        let config = Config::parse(&[
+            "Origin=https://images.example.com/app-foo",
+            "PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=",
+            "Version=*",
+            "Destination=/var/lib/app-foo",
+            "StopUnit=app-foo.service",
+            "RestartUnit=sshd.service",
+        ]).unwrap();
+
+        let permissive = Policy::parse(&["# No units listed, so any unit is allowed."]).unwrap();
+        assert!(permissive.check(&config).is_ok());
+
+        let strict = Policy::parse(&["AllowUnit=app-*.service"]).unwrap();
+        assert!(strict.check(&config).is_err());
+
+        let both = Policy::parse(&["AllowUnit=app-*.service", "AllowUnit=sshd.service"]).unwrap();
+        assert!(both.check(&config).is_ok());
+    }
+
+    #[test]
+    fn parse_rejects_unknown_keys() {
+        assert!(Policy::parse(&["AllowOrigin=https://example.com"]).is_err());
+    }
+}
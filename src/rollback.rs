@@ -0,0 +1,169 @@
+// Tako -- Take container image.
+// Copyright 2018 Arian van Putten, Ruud van Asseldonk, Tako Marks.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Detection of origins that stop listing versions they listed before.
+//!
+//! With `--detect-rollback`, fetch records the highest version that the origin
+//! has ever listed in `highest-version` in the destination directory, together
+//! with the origin. If a later manifest from the same origin lists no version
+//! that high, then the origin either serves an old manifest, which could be a
+//! rollback attack, or versions were pruned, which is likely a mistake. When
+//! the config points at a different origin, the record starts over.
+
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use cli::RollbackMode;
+use config;
+use config::Config;
+use error::{Error, Result};
+use manifest::Manifest;
+use util;
+use version::Version;
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct HighestVersion {
+    /// The origin that listed the version.
+    pub origin: String,
+
+    /// The highest version that the origin has listed.
+    pub version: Version,
+}
+
+impl HighestVersion {
+    pub fn parse<I, S>(lines: I) -> Result<HighestVersion>
+    where I: IntoIterator<Item = S>,
+          S: AsRef<str> {
+        let mut origin = None;
+        let mut version = None;
+
+        config::for_each_pair(lines, |lineno, key, value| {
+            match key {
+                "Origin" => origin = Some(String::from(value)),
+                "Version" => version = Some(Version::from(value)),
+                _ => {
+                    let msg = "Unknown key. Expected 'Origin' or 'Version'.";
+                    return Err(Error::InvalidConfig(lineno, msg))
+                }
+            }
+            Ok(())
+        })?;
+
+        let highest = HighestVersion {
+            origin: match origin {
+                Some(o) => o,
+                None => return Err(Error::IncompleteConfig(
+                    "Origin not set in highest-version file. Expected 'Origin='-line."
+                )),
+            },
+            version: match version {
+                Some(v) => v,
+                None => return Err(Error::IncompleteConfig(
+                    "Version not set in highest-version file. Expected 'Version='-line."
+                )),
+            },
+        };
+
+        Ok(highest)
+    }
+
+    /// Print the record in `Key=Value` format, the inverse of `parse`.
+    pub fn serialize(&self) -> String {
+        format!("Origin={}\nVersion={}\n", self.origin, self.version.as_str())
+    }
+
+    /// Load the record from a destination directory.
+    ///
+    /// If nothing has been recorded yet, None is returned, rather than an Err.
+    pub fn load_local(destination: &Path) -> Result<Option<HighestVersion>> {
+        let mut path = PathBuf::from(destination);
+        path.push("highest-version");
+        match config::read_lines(&path) {
+            Ok(lines) => Ok(Some(HighestVersion::parse(lines)?)),
+            Err(Error::IoError(ref e)) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Store the record in a destination directory. Writes first and then
+    /// swaps the file.
+    pub fn store_local(&self, destination: &Path) -> Result<()> {
+        let mut path_tmp = PathBuf::from(destination);
+        let mut path_final = PathBuf::from(destination);
+        path_tmp.push("highest-version.new");
+        path_final.push("highest-version");
+
+        // Delete the file if the write fails.
+        let guard = util::FileGuard::new(&path_tmp);
+        fs::File::create(&path_tmp)?.write_all(self.serialize().as_bytes())?;
+        guard.move_readonly(&path_final)?;
+
+        Ok(())
+    }
+}
+
+/// Compare the highest version in a verified manifest against the record.
+///
+/// If the manifest lists a higher version than the record, or the config
+/// points at a different origin, the record is updated. If it lists only
+/// lower versions, this warns or fails depending on the mode, and the record
+/// is kept.
+pub fn check(config: &Config, mode: RollbackMode, manifest: &Manifest) -> Result<()> {
+    let highest = match manifest.entries().iter().map(|e| &e.version).max() {
+        Some(v) => v,
+        // An empty manifest is reported as no candidate later on.
+        None => return Ok(()),
+    };
+
+    if let Some(recorded) = HighestVersion::load_local(&config.destination)? {
+        if recorded.origin == config.origin {
+            if *highest == recorded.version {
+                return Ok(())
+            }
+            if *highest < recorded.version {
+                return match mode {
+                    RollbackMode::Refuse => Err(Error::Rollback(recorded.version, highest.clone())),
+                    RollbackMode::Warn => {
+                        eprintln!("Warning: {}", Error::Rollback(recorded.version, highest.clone()));
+                        Ok(())
+                    }
+                }
+            }
+        }
+    }
+
+    let record = HighestVersion {
+        origin: config.origin.clone(),
+        version: highest.clone(),
+    };
+    record.store_local(&config.destination)
+}
+
+#[cfg(test)]
+mod test {
+    use version::Version;
+    use super::HighestVersion;
+
+    #[test]
+    fn serialize_then_parse_is_identity() {
+        let highest = HighestVersion {
+            origin: "https://images.example.com/app-foo".to_string(),
+            version: Version::from("2.1.0"),
+        };
+        let serialized = highest.serialize();
+        assert_eq!(HighestVersion::parse(serialized.lines()).unwrap(), highest);
+    }
+
+    #[test]
+    fn parse_rejects_incomplete_record() {
+        assert!(HighestVersion::parse(&["Version=1.0.0"]).is_err());
+        assert!(HighestVersion::parse(&["Origin=https://example.com"]).is_err());
+        assert!(HighestVersion::parse(&["Origin=https://example.com", "Digest=abc"]).is_err());
+    }
+}
@@ -0,0 +1,206 @@
+// Tako -- Take container image.
+// Copyright 2018 Arian van Putten, Ruud van Asseldonk, Tako Marks.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! The service manager that fetch stops, starts, and restarts units with.
+//!
+//! Fetch acts on units through `ServiceManager`. The config selects one with
+//! `RestartBackend=`: `Systemd`, the default, `OpenRc`, or `RestartCommand`,
+//! which runs a command from the config for every unit.
+
+use std::io;
+use std::path::Path;
+use std::process;
+
+use error::{Error, Result};
+
+pub trait ServiceManager {
+    /// The name of the service manager, for messages.
+    fn name(&self) -> &'static str;
+
+    /// Return whether the service manager runs on this host.
+    ///
+    /// On hosts without it, such as minimal containers, acting on a unit
+    /// would fail, so fetch checks this before it touches any unit.
+    fn is_available(&mut self) -> Result<bool>;
+
+    /// Run a command on a unit, `stop`, `start`, or `restart`.
+    fn run(&mut self, command: &'static str, unit: &str) -> Result<()>;
+
+    /// Return the state of the unit, such as `active`, `activating`, or
+    /// `failed`, in the terms of `systemctl is-active`.
+    fn active_state(&mut self, unit: &str) -> Result<String>;
+}
+
+/// The status that systemctl exits with if a unit does not exist, the LSB
+/// status for "program is not installed".
+const SYSTEMCTL_UNIT_NOT_FOUND: i32 = 5;
+
+/// Acts on units with `systemctl`.
+pub struct Systemd;
+
+impl ServiceManager for Systemd {
+    fn name(&self) -> &'static str {
+        "systemd"
+    }
+
+    /// Systemd is available if `systemctl` exists, and systemd is the init
+    /// system. In a container, systemctl may be installed while systemd does
+    /// not run, then `systemctl is-system-running` reports `offline`.
+    fn is_available(&mut self) -> Result<bool> {
+        let output = match process::Command::new("systemctl").arg("is-system-running").output() {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            other => other?,
+        };
+        let state = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        Ok(!state.is_empty() && state != "offline")
+    }
+
+    fn run(&mut self, command: &'static str, unit: &str) -> Result<()> {
+        let status = process::Command::new("systemctl")
+            .arg(command)
+            .arg(unit)
+            .status()?;
+
+        match status.code() {
+            Some(0) => Ok(()),
+            Some(SYSTEMCTL_UNIT_NOT_FOUND) => Err(Error::UnitNotFound(command, unit.to_string())),
+            _ => Err(Error::UnitFailed(command, unit.to_string())),
+        }
+    }
+
+    fn active_state(&mut self, unit: &str) -> Result<String> {
+        let output = process::Command::new("systemctl")
+            .arg("is-active")
+            .arg(unit)
+            .output()?;
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+}
+
+/// The file that OpenRC writes when it boots, with the current runlevel.
+const OPENRC_SOFTLEVEL: &'static str = "/run/openrc/softlevel";
+
+/// Acts on units, services in OpenRC terms, with `rc-service`.
+pub struct OpenRc;
+
+impl ServiceManager for OpenRc {
+    fn name(&self) -> &'static str {
+        "OpenRC"
+    }
+
+    fn is_available(&mut self) -> Result<bool> {
+        Ok(Path::new(OPENRC_SOFTLEVEL).is_file())
+    }
+
+    /// Run `rc-service <unit> <command>`. OpenRC has no distinct status for a
+    /// service that does not exist, so check for its init script first.
+    fn run(&mut self, command: &'static str, unit: &str) -> Result<()> {
+        if !Path::new("/etc/init.d").join(unit).is_file() {
+            return Err(Error::UnitNotFound(command, unit.to_string()))
+        }
+
+        let status = process::Command::new("rc-service")
+            .arg(unit)
+            .arg(command)
+            .status()?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(Error::UnitFailed(command, unit.to_string()))
+        }
+    }
+
+    /// Translate the status that `rc-service <unit> status` prints, such as
+    /// ` * status: started`, into the systemd state.
+    fn active_state(&mut self, unit: &str) -> Result<String> {
+        let output = process::Command::new("rc-service")
+            .arg(unit)
+            .arg("status")
+            .output()?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let status = match stdout.rfind(':') {
+            Some(i) => stdout[i + 1..].trim(),
+            None => stdout.trim(),
+        };
+        let state = match status {
+            "started" => "active",
+            "starting" => "activating",
+            "stopped" => "inactive",
+            "crashed" => "failed",
+            other => other,
+        };
+        Ok(state.to_string())
+    }
+}
+
+/// Acts on units by running `RestartCommand=` from the config.
+///
+/// The command is split on whitespace, and does not run through a shell. In
+/// every word, `%unit` is replaced with the unit, and `%action` with `stop`,
+/// `start`, or `restart`.
+pub struct RestartCommand<'a> {
+    template: &'a str,
+}
+
+impl<'a> RestartCommand<'a> {
+    pub fn new(template: &'a str) -> RestartCommand<'a> {
+        RestartCommand {
+            template: template,
+        }
+    }
+
+    /// Return the program and its arguments for the command on a unit.
+    fn expand(&self, command: &str, unit: &str) -> Vec<String> {
+        self.template
+            .split_whitespace()
+            .map(|word| word.replace("%unit", unit).replace("%action", command))
+            .collect()
+    }
+}
+
+impl<'a> ServiceManager for RestartCommand<'a> {
+    fn name(&self) -> &'static str {
+        "the restart command"
+    }
+
+    /// The command is whatever the config says, so it is always there. If it
+    /// does not exist, running it fails.
+    fn is_available(&mut self) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn run(&mut self, command: &'static str, unit: &str) -> Result<()> {
+        let args = self.expand(command, unit);
+        let status = process::Command::new(&args[0]).args(&args[1..]).status()?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(Error::UnitFailed(command, unit.to_string()))
+        }
+    }
+
+    fn active_state(&mut self, _unit: &str) -> Result<String> {
+        let msg = "--verify-active is not supported with 'RestartBackend=command'.";
+        Err(Error::OperationError(msg))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RestartCommand;
+
+    #[test]
+    fn restart_command_expands_unit_and_action() {
+        let command = RestartCommand::new("/usr/local/bin/svc  --unit=%unit %action");
+        assert_eq!(
+            command.expand("restart", "app.service"),
+            vec!["/usr/local/bin/svc", "--unit=app.service", "restart"],
+        );
+    }
+}
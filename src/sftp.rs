@@ -0,0 +1,187 @@
+// Tako -- Take container image.
+// Copyright 2018 Arian van Putten, Ruud van Asseldonk, Tako Marks.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Publishing to a server directory on a remote host over sftp.
+//!
+//! Files are uploaded under a temporary name and then renamed into place, so a
+//! fetcher never sees a partially uploaded file. Sftp servers may refuse to
+//! rename over an existing file (OpenSSH does). When the new manifest is still
+//! at its temporary name after the rename, the old manifest is removed, and the
+//! new one is renamed into place. In between, fetchers fail to download the
+//! manifest, and they retry on their next run.
+
+use std::io::Read;
+use std::path::Path;
+
+use config::PublicKey;
+use curl;
+use error::Result;
+use manifest::Manifest;
+
+/// Quote a path for a Curl sftp command, which would split it at spaces.
+///
+/// Curl reads a path in double quotes, with a backslash before a double quote
+/// or backslash in the path.
+fn quote_path(path: &str) -> String {
+    let mut quoted = String::with_capacity(path.len() + 2);
+    quoted.push('"');
+    for ch in path.chars() {
+        if ch == '"' || ch == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(ch);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// A server directory on a remote host, e.g. `sftp://user@host/srv/app`.
+#[derive(Debug, Eq, PartialEq)]
+pub struct RemoteDir {
+    /// The uri of the directory, without trailing slash.
+    uri: String,
+
+    /// The path of the directory on the server, without trailing slash.
+    path: String,
+}
+
+impl RemoteDir {
+    /// Interpret an output path as remote directory, if it is an sftp url.
+    pub fn from_output_path(output_path: &Path) -> Option<RemoteDir> {
+        let prefix = "sftp://";
+        let mut uri = match output_path.to_str() {
+            Some(s) if s.starts_with(prefix) => s,
+            _ => return None,
+        };
+        while uri.ends_with('/') {
+            uri = &uri[..uri.len() - 1];
+        }
+
+        // The path starts at the first slash after the host. Without a path,
+        // the directory is the root directory, and its path is empty.
+        let host_and_path = &uri[prefix.len()..];
+        let path = match host_and_path.find('/') {
+            Some(i) => &host_and_path[i..],
+            None => "",
+        };
+
+        let remote = RemoteDir {
+            uri: String::from(uri),
+            path: String::from(path),
+        };
+        Some(remote)
+    }
+
+    fn file_uri(&self, fname: &str) -> String {
+        format!("{}/{}", self.uri, fname)
+    }
+
+    /// Return the path of a file on the server, quoted for a Curl command.
+    fn file_path(&self, fname: &str) -> String {
+        quote_path(&format!("{}/{}", self.path, fname))
+    }
+
+    /// Download the manifest, if the server directory has one, and verify that
+    /// it is signed with any of the keys.
+    pub fn load_manifest(&self, public_keys: &[PublicKey]) -> Result<Option<Manifest>> {
+        let mut manifest_bytes = Vec::new();
+        let mut curl_handle = curl::Handle::new();
+        let uri = self.file_uri("manifest");
+        let exists = curl_handle.download_if_exists(&uri, |chunk| {
+            manifest_bytes.extend_from_slice(chunk);
+        })?;
+
+        if exists {
+            Ok(Some(Manifest::parse_any(&manifest_bytes[..], public_keys)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Check whether `store/<fname>` exists. The file name is the hexdigest,
+    /// followed by the image extension of the manifest, if it has one.
+    pub fn has_image(&self, fname: &str) -> Result<bool> {
+        let mut curl_handle = curl::Handle::new();
+        curl_handle.exists(&self.file_uri(&format!("store/{}", fname)))
+    }
+
+    /// Upload an image to `store/<fname>` and make it read-only.
+    pub fn upload_image<R: Read>(&self, fname: &str, image: &mut R) -> Result<()> {
+        let fname_tmp = format!("store/{}.new", fname);
+        let fname_final = format!("store/{}", fname);
+        let commands = [
+            format!("chmod 444 {}", self.file_path(&fname_tmp)),
+            format!("rename {} {}", self.file_path(&fname_tmp), self.file_path(&fname_final)),
+        ];
+        let mut curl_handle = curl::Handle::new();
+        curl_handle.upload(&self.file_uri(&fname_tmp), image, &commands)
+    }
+
+    /// Upload the manifest, replacing the existing one.
+    ///
+    /// If the server refuses to rename over the existing manifest, remove it
+    /// first, and rename again.
+    pub fn upload_manifest(&self, bytes: &[u8]) -> Result<()> {
+        let path_tmp = self.file_path("manifest.new");
+        let path_final = self.file_path("manifest");
+        let commands = [
+            format!("chmod 444 {}", path_tmp),
+            // The server may refuse to overwrite the manifest, see below.
+            format!("*rename {} {}", path_tmp, path_final),
+        ];
+        let mut curl_handle = curl::Handle::new();
+        let mut reader = bytes;
+        curl_handle.upload(&self.file_uri("manifest.new"), &mut reader, &commands)?;
+
+        if !curl_handle.exists(&self.file_uri("manifest.new"))? {
+            return Ok(())
+        }
+
+        let commands = [
+            format!("rm {}", path_final),
+            format!("rename {} {}", path_tmp, path_final),
+        ];
+        curl_handle.run_commands(&self.file_uri(""), &commands)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use super::{RemoteDir, quote_path};
+
+    fn parse(output_path: &str) -> Option<RemoteDir> {
+        RemoteDir::from_output_path(Path::new(output_path))
+    }
+
+    #[test]
+    fn from_output_path_ignores_local_paths() {
+        assert_eq!(parse("/srv/app"), None);
+        assert_eq!(parse("srv/sftp://host/app"), None);
+        assert_eq!(parse("https://host/app"), None);
+    }
+
+    #[test]
+    fn from_output_path_splits_path() {
+        let remote = parse("sftp://user@host/srv/app/").unwrap();
+        assert_eq!(remote.file_uri("manifest"), "sftp://user@host/srv/app/manifest");
+        assert_eq!(remote.file_path("manifest"), "\"/srv/app/manifest\"");
+    }
+
+    #[test]
+    fn from_output_path_handles_root() {
+        let remote = parse("sftp://host").unwrap();
+        assert_eq!(remote.file_uri("manifest"), "sftp://host/manifest");
+        assert_eq!(remote.file_path("manifest"), "\"/manifest\"");
+    }
+
+    #[test]
+    fn quote_path_escapes_quotes_and_backslashes() {
+        assert_eq!(quote_path("/srv/my app"), "\"/srv/my app\"");
+        assert_eq!(quote_path("/srv/a\"b\\c"), "\"/srv/a\\\"b\\\\c\"");
+    }
+}
@@ -0,0 +1,162 @@
+// Tako -- Take container image.
+// Copyright 2018 Arian van Putten, Ruud van Asseldonk, Tako Marks.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Records what fetch applied to a destination directory.
+//!
+//! Installing an image consists of two steps: pointing the `latest` symlink at
+//! the new image, and restarting the units that use it. If Tako crashes in
+//! between, the next run finds the symlink up to date already. The state file
+//! tells it which restarts are still pending.
+
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use config;
+use error::{Error, Result};
+use util;
+use util::Sha256;
+use version::Version;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct State {
+    /// The version that `latest` points at, or is about to point at.
+    pub version: Version,
+
+    /// The digest of the image that `latest` points at, or is about to point at.
+    pub digest: Sha256,
+
+    /// The units that have been restarted since `latest` changed to this image.
+    pub restarted_units: Vec<String>,
+}
+
+impl State {
+    pub fn parse<I, S>(lines: I) -> Result<State>
+    where I: IntoIterator<Item = S>,
+          S: AsRef<str> {
+        let mut version = None;
+        let mut digest = None;
+        let mut restarted_units = Vec::new();
+
+        config::for_each_pair(lines, |lineno, key, value| {
+            match key {
+                "Version" => {
+                    version = Some(Version::from(value));
+                }
+                "Digest" => {
+                    let msg = "Invalid digest. Expected 64 lowercase hexadecimal characters.";
+                    let sha256 = Sha256::from_hex(value.as_bytes());
+                    digest = Some(sha256.ok_or(Error::InvalidConfig(lineno, msg))?);
+                }
+                "RestartedUnit" => {
+                    restarted_units.push(String::from(value));
+                }
+                _ => {
+                    let msg = "Unknown key. Expected one of \
+                        'Version', 'Digest', or 'RestartedUnit'.";
+                    return Err(Error::InvalidConfig(lineno, msg))
+                }
+            }
+            Ok(())
+        })?;
+
+        let state = State {
+            version: match version {
+                Some(v) => v,
+                None => return Err(Error::IncompleteConfig(
+                    "Version not set in state file. Expected 'Version='-line."
+                )),
+            },
+            digest: match digest {
+                Some(d) => d,
+                None => return Err(Error::IncompleteConfig(
+                    "Digest not set in state file. Expected 'Digest='-line."
+                )),
+            },
+            restarted_units: restarted_units,
+        };
+
+        Ok(state)
+    }
+
+    /// Print the state in `Key=Value` format, the inverse of `parse`.
+    pub fn serialize(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("Version=");
+        out.push_str(self.version.as_str());
+        out.push_str("\nDigest=");
+        util::append_hex(&mut out, self.digest.as_ref());
+        out.push('\n');
+
+        for unit in &self.restarted_units {
+            out.push_str("RestartedUnit=");
+            out.push_str(unit);
+            out.push('\n');
+        }
+
+        out
+    }
+
+    /// Load the state file from a destination directory.
+    ///
+    /// If the state file does not exist, None is returned, rather than an Err.
+    pub fn load_local(destination: &Path) -> Result<Option<State>> {
+        let mut path = PathBuf::from(destination);
+        path.push("state");
+        match config::read_lines(&path) {
+            Ok(lines) => Ok(Some(State::parse(lines)?)),
+            Err(Error::IoError(ref e)) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Store the state file in a destination directory. Writes first and then
+    /// swaps the file.
+    pub fn store_local(&self, destination: &Path) -> Result<()> {
+        let mut path_tmp = PathBuf::from(destination);
+        let mut path_final = PathBuf::from(destination);
+        path_tmp.push("state.new");
+        path_final.push("state");
+
+        // Delete the file if the write fails.
+        let guard = util::FileGuard::new(&path_tmp);
+
+        fs::File::create(&path_tmp)?.write_all(self.serialize().as_bytes())?;
+
+        // Rename the state over the old state, mark it read-only.
+        guard.move_readonly(&path_final)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use util::Sha256;
+    use version::Version;
+    use super::State;
+
+    #[test]
+    fn serialize_then_parse_is_identity() {
+        let hex = b"9641a49d02e90cbb6213f202fb632da70cdc59073d42283cfcdc1d786454f17f";
+        let state = State {
+            version: Version::from("1.1.0"),
+            digest: Sha256::from_hex(&hex[..]).unwrap(),
+            restarted_units: vec!["foo.service".to_string(), "bar.service".to_string()],
+        };
+        let serialized = state.serialize();
+        assert_eq!(State::parse(serialized.lines()).unwrap(), state);
+    }
+
+    #[test]
+    fn parse_rejects_incomplete_state() {
+        let lines = ["Version=1.1.0", "RestartedUnit=foo.service"];
+        assert!(State::parse(&lines).is_err());
+    }
+}
@@ -7,53 +7,125 @@
 
 //! Contains the main store logic.
 
+use std::env;
 use std::fs;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
 use base64;
+use filebuffer::FileBuffer;
+use ring::digest;
 use ring::signature::Ed25519KeyPair;
 use untrusted::Input;
 
-use cli::Store;
-use config::PublicKey;
+use archive;
+use cli;
+use cli::{Canonicalize, RekeyAll, Seed, Store};
+use config;
+use config::{PublicKey, PublishConfig};
+use curl;
+use delta;
 use error::{Error, Result};
+use fetch;
 use manifest;
-use manifest::{Entry, Manifest};
+use manifest::{Delta, Entry, Index, IndexEntry, Manifest};
+use sftp::RemoteDir;
 use util;
+use util::Sha256;
+use version::Version;
 
-pub fn store(store: Store) -> Result<()> {
-    let secret_key_base64 = match (store.secret_key, store.secret_key_path) {
-        (Some(k), _) => k,
-        (None, Some(p)) => {
-            let mut s = String::new();
-            // Don't use a BufReader here, that would be pointless: we are
-            // already reading into a (string) buffer.
-            let mut f = fs::File::open(p)?;
-            f.read_to_string(&mut s)?;
-            // The base64-encoded secret key is 116 bytes long. There might be
-            // a trailing newline at the end of the file that we discard here.
-            // There might also be junk, then we find out later when parsing the
-            // base64.
-            s.truncate(116);
-            s
+/// Return the image path as a url, if it is an http or https url.
+fn as_url(image_path: &Path) -> Option<&str> {
+    match image_path.to_str() {
+        Some(s) if s.starts_with("http://") || s.starts_with("https://") => Some(s),
+        _ => None,
+    }
+}
+
+/// Read a base64-encoded secret key from a file, or from stdin if the path
+/// is `-`.
+fn read_secret_key(path: &Path) -> Result<String> {
+    let mut s = String::new();
+    if is_stdin(path) {
+        io::stdin().read_to_string(&mut s)?;
+        return Ok(s.trim().to_string())
+    }
+    // Don't use a BufReader here, that would be pointless: we are
+    // already reading into a (string) buffer.
+    let mut f = fs::File::open(path)?;
+    f.read_to_string(&mut s)?;
+    // The base64-encoded secret key is 116 bytes long. There might be
+    // a trailing newline at the end of the file that we discard here.
+    // There might also be junk, then we find out later when parsing the
+    // base64.
+    s.truncate(116);
+    Ok(s)
+}
+
+/// Return whether the key file path `-` stands for stdin.
+fn is_stdin(path: &Path) -> bool {
+    path.to_str() == Some("-")
+}
+
+/// Read a secret key file and parse the key. Errors name the file.
+fn load_key_pair(path: &Path) -> Result<Ed25519KeyPair> {
+    let what = if is_stdin(path) { "stdin".to_string() } else { path.display().to_string() };
+    read_secret_key(path)
+        .and_then(|key| parse_key_pair(&key))
+        .map_err(|e| Error::InvalidSecretKeyFile(what, Box::new(e)))
+}
+
+/// Read a secret key from an environment variable and parse the key. Errors
+/// name the variable.
+fn load_key_pair_env(var: &str) -> Result<Ed25519KeyPair> {
+    let what = format!("environment variable {}", var);
+    match env::var(var) {
+        Ok(key) => parse_key_pair(key.trim())
+            .map_err(|e| Error::InvalidSecretKeyFile(what, Box::new(e))),
+        Err(..) => {
+            let err = Error::OperationError("The variable is not set.");
+            Err(Error::InvalidSecretKeyFile(what, Box::new(err)))
         }
-        (None, None) => unreachable!("Should have been validated elsewhere."),
-    };
+    }
+}
 
-    let err = Err(Error::InvalidSecretKeyData);
-    let secret_key_bytes = base64::decode(&secret_key_base64).or(err)?;
+/// Return the paths of the key files in a directory, ordered by name.
+///
+/// Key files have no extension, or extension `.key`. Other files, such as
+/// public keys or a readme, are skipped, and so are hidden files, such as the
+/// `..data` link in a mounted Kubernetes secret.
+fn key_dir_paths(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for dir_entry in fs::read_dir(dir)? {
+        let path = dir_entry?.path();
+        let is_hidden = match path.file_name() {
+            Some(name) => name.to_string_lossy().starts_with('.'),
+            None => true,
+        };
+        let is_key = match path.extension() {
+            None => true,
+            Some(ext) => ext == "key",
+        };
+        if !is_hidden && is_key && path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
 
+fn parse_key_pair(secret_key_base64: &str) -> Result<Ed25519KeyPair> {
     let err = Err(Error::InvalidSecretKeyData);
-    let key_pair = Ed25519KeyPair::from_pkcs8(Input::from(&secret_key_bytes)).or(err)?;
-    let public_key = PublicKey::from_pair(&key_pair);
+    let secret_key_bytes = base64::decode(secret_key_base64).or(err)?;
 
-    let mut manifest = match Manifest::load_local(&store.output_path, &public_key)? {
-        Some(m) => m,
-        None => Manifest::new(),
-    };
+    let err = Err(Error::InvalidSecretKeyData);
+    Ed25519KeyPair::from_pkcs8(Input::from(&secret_key_bytes)).or(err)
+}
 
-    let mut store_dir = PathBuf::from(&store.output_path);
+/// Return the path of the store directory, create it if it does not exist.
+fn open_store_dir(output_path: &Path) -> Result<PathBuf> {
+    let mut store_dir = PathBuf::from(output_path);
     store_dir.push("store");
 
     // The server directory must exist, but we can create the store directory
@@ -63,19 +135,137 @@ pub fn store(store: Store) -> Result<()> {
         fs::create_dir(&store_dir)?;
     }
 
-    let digest = util::sha256sum(&store.image_path)?;
-    let mut digest_hex = String::new();
-    util::append_hex(&mut digest_hex, digest.as_ref());
+    Ok(store_dir)
+}
 
-    let mut target_fname = store_dir;
-    target_fname.push(&digest_hex);
+/// Return the path of the image with the given digest, `store/<hexdigest>`,
+/// followed by the image extension of the manifest, if it has one.
+fn image_fname(store_dir: &Path, digest: &Sha256, image_ext: &str) -> PathBuf {
+    let mut fname = String::new();
+    util::append_hex(&mut fname, digest.as_ref());
+    fname.push_str(image_ext);
+    store_dir.join(fname)
+}
 
-    // Copy the image into the store under its content-based name. If the target
-    // exists, verify the checksum instead.
-    if target_fname.is_file() {
-        // TODO: Verify SHA256.
-    } else {
-        fs::copy(&store.image_path, &target_fname)?;
+/// Return the path of the delta with the given digest, `store/<hexdigest>.delta`.
+fn delta_fname(store_dir: &Path, digest: &Sha256) -> PathBuf {
+    let mut fname = String::new();
+    util::append_hex(&mut fname, digest.as_ref());
+    fname.push_str(".delta");
+    store_dir.join(fname)
+}
+
+/// Store a delta from the image of the next older version in the manifest to
+/// the image with the given digest, and return it.
+///
+/// Returns None if there is no older version, or if the delta would not be
+/// smaller than the image, then fetchers are better off with the image.
+fn store_delta(
+    store_dir: &Path,
+    manifest: &Manifest,
+    version: &Version,
+    target_digest: &Sha256,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> Result<Option<Delta>> {
+    let base = match manifest.entries().iter().rev().find(|e| e.version < *version) {
+        Some(e) if e.digest != *target_digest => e,
+        Some(..) => return Ok(None),
+        None => {
+            println!("Not storing a delta, there is no older version.");
+            return Ok(None)
+        }
+    };
+
+    let base_bytes = FileBuffer::open(image_fname(store_dir, &base.digest, manifest.image_ext()))?;
+    let target_bytes = FileBuffer::open(image_fname(store_dir, target_digest, manifest.image_ext()))?;
+    let delta_bytes = delta::diff(&base_bytes, &target_bytes);
+    if delta_bytes.len() >= target_bytes.len() {
+        println!("Not storing a delta from {}, it would not be smaller than the image.", base.version.as_str());
+        return Ok(None)
+    }
+
+    let delta_digest = Sha256::copy_from_slice(digest::digest(&digest::SHA256, &delta_bytes).as_ref());
+    let path = delta_fname(store_dir, &delta_digest);
+    let tmp_path = path.with_extension("new");
+    let guard = util::FileGuard::new(&tmp_path);
+    fs::File::create(&tmp_path)?.write_all(&delta_bytes)?;
+    guard.move_readonly(&path)?;
+    chown_written(&path, uid, gid)?;
+
+    println!(
+        "{} -> delta from {}, {} of {} bytes",
+        version.as_str(), base.version.as_str(), delta_bytes.len(), target_bytes.len(),
+    );
+
+    let delta = Delta {
+        base: base.digest.clone(),
+        digest: delta_digest,
+    };
+    Ok(Some(delta))
+}
+
+/// Set the extension of the image files, unless the manifest has versions
+/// whose images are stored under a different extension.
+fn set_image_ext(manifest: &mut Manifest, image_ext: &str) -> Result<()> {
+    if !manifest.entries().is_empty() && manifest.image_ext() != image_ext {
+        let msg = "The manifest has versions whose images are stored with a \
+                   different extension. The image extension cannot change.";
+        return Err(Error::OperationError(msg))
+    }
+    manifest.set_image_ext(image_ext)
+}
+
+fn check_digest(digest: &Sha256, expected_digest: Option<&Sha256>) -> Result<()> {
+    match expected_digest {
+        Some(expected) if digest != expected => Err(Error::InvalidDigest),
+        _ => Ok(()),
+    }
+}
+
+/// Serialize and sign the manifest, and verify the result like a fetcher would.
+///
+/// Every signature must verify with its key, and the manifest must parse back
+/// to the one that was signed. A mismatch between serialization and parsing
+/// would break every fetcher, so we fail before anything is written.
+fn sign_manifest(manifest: &Manifest, key_pairs: &[Ed25519KeyPair]) -> Result<String> {
+    let manifest_string = manifest.serialize(key_pairs);
+    for key_pair in key_pairs {
+        let public_key = PublicKey::from_pair(key_pair);
+        match Manifest::parse(manifest_string.as_bytes(), &public_key) {
+            Ok(ref parsed) if parsed == manifest => continue,
+            Ok(..) => {
+                let msg = "The signed manifest parses differently than it was serialized.";
+                return Err(Error::SelfCheckFailed(Box::new(Error::InvalidManifest(msg))))
+            }
+            Err(e) => return Err(Error::SelfCheckFailed(Box::new(e))),
+        }
+    }
+    Ok(manifest_string)
+}
+
+/// Change the owner and group of a written file, if either was requested.
+fn chown_written(path: &Path, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+    if uid.is_some() || gid.is_some() {
+        util::chown_path(path, uid, gid)?;
+    }
+    Ok(())
+}
+
+/// Copy an image with known digest into the store, unless it is there already.
+fn import_image(store_dir: &Path, image_ext: &str, image_path: &Path, digest: &Sha256) -> Result<()> {
+    let target_fname = image_fname(store_dir, digest, image_ext);
+
+    // Copy the image into the store under its content-based name. Versions with
+    // identical images therefore share a file. If the target exists, verify
+    // its checksum instead, and replace it if it is damaged.
+    let is_intact = target_fname.is_file() && util::sha256sum(&target_fname)? == *digest;
+    if !is_intact {
+        if target_fname.is_file() {
+            eprintln!("Replacing damaged image {} in the store.", target_fname.display());
+            fs::remove_file(&target_fname)?;
+        }
+        fs::copy(image_path, &target_fname)?;
     }
 
     // The store should be immutable, make the file readonly.
@@ -83,19 +273,656 @@ pub fn store(store: Store) -> Result<()> {
     perms.set_readonly(true);
     fs::set_permissions(&target_fname, perms)?;
 
+    Ok(())
+}
+
+/// Set annotations on an existing version, after checking that its image in
+/// the store is unchanged. Return the digest of the image.
+fn annotate_entry(
+    manifest: &mut Manifest,
+    store_dir: &Path,
+    version: &Version,
+    annotations: &[(String, String)],
+    expected_digest: Option<&Sha256>,
+) -> Result<Sha256> {
+    for &(ref key, ref value) in annotations {
+        manifest.annotate(version, key, value)?;
+    }
+    let digest = manifest.entry(version).expect("Annotated entry exists.").digest.clone();
+    check_digest(&digest, expected_digest)?;
+
+    if util::sha256sum(&image_fname(store_dir, &digest, manifest.image_ext()))? != digest {
+        return Err(Error::InvalidDigest)
+    }
+
+    Ok(digest)
+}
+
+/// Download an image into the store, return its digest.
+fn download_image(
+    store_dir: &Path,
+    image_ext: &str,
+    uri: &str,
+    expected_digest: Option<&Sha256>,
+) -> Result<Sha256> {
+    // Download into the store directly, under a temporary name, because we
+    // only know the digest afterwards. The guard deletes the temporary file if
+    // we do not move it into place.
+    let tmp_fname = store_dir.join("image.new");
+    let guard = util::FileGuard::new(&tmp_fname);
+    let mut curl_handle = curl::Handle::new();
+    let digest = fetch::download_digest(uri, &tmp_fname, fetch::DEFAULT_WRITE_BUFFER_SIZE, &mut curl_handle)?;
+    check_digest(&digest, expected_digest)?;
+
+    let target_fname = image_fname(store_dir, &digest, image_ext);
+
+    if !target_fname.is_file() {
+        guard.move_readonly(&target_fname)?;
+    }
+
+    Ok(digest)
+}
+
+pub fn store(mut store: Store) -> Result<()> {
+    // Fill in what was not provided on the command line from the publish
+    // config, if there is one.
+    if let Some(ref config_path) = store.config_path {
+        let publish = PublishConfig::parse(config::read_lines(config_path)?)?;
+        if store.output_path.is_none() {
+            store.output_path = publish.output_path;
+        }
+        if store.secret_keys.is_empty() &&
+            store.secret_key_paths.is_empty() &&
+            store.secret_key_dirs.is_empty() &&
+            store.secret_key_envs.is_empty() {
+            store.secret_key_paths.extend(publish.secret_key_path);
+        }
+        if store.image_base.is_none() {
+            store.image_base = publish.image_base;
+        }
+        if store.name.is_none() {
+            store.name = publish.name;
+        }
+        if store.image_ext.is_none() {
+            store.image_ext = publish.image_ext;
+        }
+        if store.manifest_name.is_none() {
+            store.manifest_name = publish.manifest_name;
+        }
+        if store.max_versions.is_none() {
+            store.max_versions = publish.max_versions;
+        }
+    }
+
+    let output_path = match store.output_path.take() {
+        Some(p) => p,
+        None => return Err(Error::IncompleteConfig(
+            "Server directory not provided. Pass it via --output, \
+            or set 'Output=' in the publish config."
+        )),
+    };
+
+    let mut key_pairs = Vec::new();
+    for secret_key_base64 in &store.secret_keys {
+        key_pairs.push(parse_key_pair(secret_key_base64)?);
+    }
+    for var in &store.secret_key_envs {
+        key_pairs.push(load_key_pair_env(var)?);
+    }
+    for path in &store.secret_key_paths {
+        key_pairs.push(load_key_pair(path)?);
+    }
+    for dir in &store.secret_key_dirs {
+        let paths = key_dir_paths(dir)
+            .map_err(|e| Error::InvalidSecretKeyFile(dir.display().to_string(), Box::new(e)))?;
+        for path in paths {
+            key_pairs.push(load_key_pair(&path)?);
+        }
+    }
+    if key_pairs.is_empty() {
+        return Err(Error::IncompleteConfig(
+            "Secret key not provided. Pass it via --key, --key-file, --key-dir, \
+            --key-env, the TAKO_SECRET_KEY environment variable, \
+            or set 'KeyFile=' in the publish config."
+        ))
+    }
+
+    // Resolve the owner and group before writing anything, so a typo does not
+    // leave a half-published version behind.
+    let uid = match store.owner {
+        Some(ref user) => Some(util::lookup_user(user).ok_or_else(|| Error::UnknownUser(user.clone()))?),
+        None => None,
+    };
+    let gid = match store.group {
+        Some(ref group) => Some(util::lookup_group(group).ok_or_else(|| Error::UnknownGroup(group.clone()))?),
+        None => None,
+    };
+
+    if let Some(remote) = RemoteDir::from_output_path(&output_path) {
+        if uid.is_some() || gid.is_some() {
+            let msg = "Changing the owner or group is not supported for sftp urls.";
+            return Err(Error::OperationError(msg))
+        }
+        if store.max_versions.is_some() {
+            let msg = "Pruning versions is not supported for sftp urls.";
+            return Err(Error::OperationError(msg))
+        }
+        if store.manifest_name.is_some() {
+            let msg = "A manifest name other than 'manifest' is not supported for sftp urls.";
+            return Err(Error::OperationError(msg))
+        }
+        if store.delta {
+            let msg = "Storing deltas is not supported for sftp urls.";
+            return Err(Error::OperationError(msg))
+        }
+        let image_path = match store.image_path {
+            Some(ref p) => p,
+            None => {
+                let msg = "Annotating a version is not supported for sftp urls.";
+                return Err(Error::OperationError(msg))
+            }
+        };
+        return store_remote(&remote, &store, image_path, &key_pairs[..])
+    }
+
+    let output_path = util::resolve_dir(&output_path)?;
+
+    // During a key rotation, the existing manifest may be signed with only
+    // one of the keys.
+    let public_keys: Vec<_> = key_pairs.iter().map(PublicKey::from_pair).collect();
+
+    let manifest_path = match store.manifest_name {
+        Some(ref name) => output_path.join(name),
+        None => output_path.join(manifest::DEFAULT_NAME),
+    };
+    let mut manifest = match Manifest::load_file(&manifest_path, &public_keys[..])? {
+        Some(m) => m,
+        None => Manifest::new(),
+    };
+    if let Some(ref image_base) = store.image_base {
+        manifest.set_image_base(image_base)?;
+    }
+    if let Some(ref name) = store.name {
+        manifest.set_name(name)?;
+    }
+    if store.embed_public_key {
+        manifest.set_embed_public_keys();
+    }
+    if let Some(ref image_ext) = store.image_ext {
+        set_image_ext(&mut manifest, image_ext)?;
+    }
+
+    // Never prune the version that we store. Check before copying the image,
+    // so a refused store leaves nothing behind.
+    if let (Some(max_versions), true) = (store.max_versions, store.image_path.is_some()) {
+        let num_newer = manifest.entries().iter().filter(|e| e.version > store.version).count();
+        if num_newer >= max_versions {
+            let msg = "The version is older than the versions that --max-versions keeps. \
+                       Not storing it.";
+            return Err(Error::OperationError(msg))
+        }
+    }
+
+    let store_dir = open_store_dir(&output_path)?;
+
+    let digest = match store.image_path {
+        // Without an image, only annotate the existing entry.
+        None => annotate_entry(
+            &mut manifest,
+            &store_dir,
+            &store.version,
+            &store.annotations[..],
+            store.expect_digest.as_ref(),
+        )?,
+        Some(ref image_path) => {
+            let digest = match as_url(image_path) {
+                Some(uri) => download_image(&store_dir, manifest.image_ext(), uri, store.expect_digest.as_ref())?,
+                None => {
+                    let digest = util::sha256sum(image_path)?;
+                    check_digest(&digest, store.expect_digest.as_ref())?;
+                    import_image(&store_dir, manifest.image_ext(), image_path, &digest)?;
+                    digest
+                }
+            };
+            chown_written(&image_fname(&store_dir, &digest, manifest.image_ext()), uid, gid)?;
+            digest
+        }
+    };
+
+    let mut digest_hex = String::new();
+    util::append_hex(&mut digest_hex, digest.as_ref());
+
     println!("{} -> {}", store.version.as_str(), digest_hex);
 
-    // Add the new entry to the manifest.
-    let entry = Entry {
-        version: store.version,
+    // Add the new entry to the manifest. When annotating, the entry exists
+    // already and is identical, so this keeps the annotated entry.
+    let mut entry = Entry {
+        version: store.version.clone(),
         digest: digest,
+        annotations: Vec::new(),
+        deltas: Vec::new(),
     };
+    if store.delta {
+        let delta = store_delta(&store_dir, &manifest, &store.version, &entry.digest, uid, gid)?;
+        entry.deltas.extend(delta);
+    }
     manifest.insert(entry)?;
 
+    // With an image, only --notes can set annotations, on the stored version.
+    if store.image_path.is_some() {
+        for &(ref key, ref value) in &store.annotations {
+            manifest.annotate(&store.version, key, value)?;
+        }
+    }
+
+    // Prune only after storing an image, not when annotating.
+    let pruned = match store.max_versions {
+        Some(max_versions) if store.image_path.is_some() => manifest.prune(max_versions),
+        _ => Vec::new(),
+    };
+
     // And finally store the new manifest. Write to a temporary file, then swap
     // it into place.
-    let manifest_string = manifest.serialize(&key_pair);
-    manifest::store_local(&store.output_path, manifest_string.as_bytes())?;
+    let manifest_string = sign_manifest(&manifest, &key_pairs[..])?;
+    manifest::store_file(&manifest_path, manifest_string.as_bytes())?;
+    chown_written(&manifest_path, uid, gid)?;
+
+    // Delete images only once the manifest no longer references them.
+    for entry in &pruned {
+        println!("Pruned version {}.", entry.version.as_str());
+        for delta in &entry.deltas {
+            match fs::remove_file(delta_fname(&store_dir, &delta.digest)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                other => other?,
+            }
+        }
+        if !manifest.entries().iter().any(|e| e.digest == entry.digest) {
+            match fs::remove_file(image_fname(&store_dir, &entry.digest, manifest.image_ext())) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                other => other?,
+            }
+        }
+    }
 
     Ok(())
 }
+
+/// Store an image in a server directory on a remote host.
+///
+/// The image is uploaded before the manifest, such that the manifest never
+/// references an image that is not in the store.
+fn store_remote(
+    remote: &RemoteDir,
+    store: &Store,
+    image_path: &Path,
+    key_pairs: &[Ed25519KeyPair],
+) -> Result<()> {
+    if as_url(image_path).is_some() {
+        let msg = "Storing an image from a url in a remote server directory \
+            is not supported. Download the image first.";
+        return Err(Error::OperationError(msg))
+    }
+
+    let public_keys: Vec<_> = key_pairs.iter().map(PublicKey::from_pair).collect();
+
+    let mut manifest = match remote.load_manifest(&public_keys[..])? {
+        Some(m) => m,
+        None => Manifest::new(),
+    };
+    if let Some(ref image_base) = store.image_base {
+        manifest.set_image_base(image_base)?;
+    }
+    if let Some(ref name) = store.name {
+        manifest.set_name(name)?;
+    }
+    if store.embed_public_key {
+        manifest.set_embed_public_keys();
+    }
+    if let Some(ref image_ext) = store.image_ext {
+        set_image_ext(&mut manifest, image_ext)?;
+    }
+
+    let digest = util::sha256sum(image_path)?;
+    check_digest(&digest, store.expect_digest.as_ref())?;
+
+    let mut digest_hex = String::new();
+    util::append_hex(&mut digest_hex, digest.as_ref());
+
+    println!("{} -> {}", store.version.as_str(), digest_hex);
+
+    // Check for a duplicate before uploading anything.
+    let entry = Entry {
+        version: store.version.clone(),
+        digest: digest,
+        annotations: Vec::new(),
+        deltas: Vec::new(),
+    };
+    manifest.insert(entry)?;
+    for &(ref key, ref value) in &store.annotations {
+        manifest.annotate(&store.version, key, value)?;
+    }
+
+    // The remote only needs the file name in the store, so the extension
+    // goes along with the digest.
+    let mut image_name = digest_hex.clone();
+    image_name.push_str(manifest.image_ext());
+    if !remote.has_image(&image_name)? {
+        let mut f = fs::File::open(image_path)?;
+        remote.upload_image(&image_name, &mut f)?;
+    }
+
+    let manifest_string = sign_manifest(&manifest, key_pairs)?;
+    remote.upload_manifest(manifest_string.as_bytes())?;
+
+    Ok(())
+}
+
+/// Store every version in the source directory, and sign the manifest once.
+///
+/// The source directory contains one directory per version, named after the
+/// version, with the image file inside. Versions that conflict with an existing
+/// entry are reported and skipped.
+pub fn seed(seed: Seed) -> Result<()> {
+    let secret_key_base64 = match (seed.secret_key, seed.secret_key_path) {
+        (Some(k), _) => k,
+        (None, Some(p)) => read_secret_key(&p)?,
+        (None, None) => unreachable!("Should have been validated elsewhere."),
+    };
+
+    let key_pair = parse_key_pair(&secret_key_base64)?;
+    let public_key = PublicKey::from_pair(&key_pair);
+
+    let mut manifest = match Manifest::load_local(&seed.output_path, &[public_key])? {
+        Some(m) => m,
+        None => Manifest::new(),
+    };
+
+    let store_dir = open_store_dir(&seed.output_path)?;
+
+    // Process versions in a deterministic order, so the output is stable.
+    let mut version_dirs = Vec::new();
+    for dir_entry in fs::read_dir(&seed.source_path)? {
+        version_dirs.push(dir_entry?.path());
+    }
+    version_dirs.sort();
+
+    let mut num_skipped = 0;
+
+    for version_dir in &version_dirs {
+        let version = match version_dir.file_name().and_then(|n| n.to_str()) {
+            Some(v) if version_dir.is_dir() => Version::from(v),
+            _ => {
+                eprintln!("Skipping {}: not a version directory.", version_dir.display());
+                num_skipped += 1;
+                continue
+            }
+        };
+
+        let mut image_paths = Vec::new();
+        for dir_entry in fs::read_dir(version_dir)? {
+            let path = dir_entry?.path();
+            if path.is_file() {
+                image_paths.push(path);
+            }
+        }
+
+        if image_paths.len() != 1 {
+            eprintln!(
+                "Skipping {}: expected exactly one image file, found {}.",
+                version_dir.display(),
+                image_paths.len(),
+            );
+            num_skipped += 1;
+            continue
+        }
+
+        let digest = util::sha256sum(&image_paths[0])?;
+        let mut digest_hex = String::new();
+        util::append_hex(&mut digest_hex, digest.as_ref());
+
+        // Insert into the manifest before copying, so that we do not leave
+        // an unreferenced image in the store for skipped versions.
+        let entry = Entry {
+            version: version,
+            digest: digest.clone(),
+            annotations: Vec::new(),
+            deltas: Vec::new(),
+        };
+        match manifest.insert(entry) {
+            Ok(()) => {}
+            Err(Error::Duplicate(v)) => {
+                eprintln!(
+                    "Skipping {}: conflicts with version {} in the manifest.",
+                    version_dir.display(),
+                    v.as_str(),
+                );
+                num_skipped += 1;
+                continue
+            }
+            Err(e) => return Err(e),
+        }
+
+        import_image(&store_dir, manifest.image_ext(), &image_paths[0], &digest)?;
+        println!("{} -> {}", version_dir.display(), digest_hex);
+    }
+
+    // Write the manifest only once all images are in place, such that the
+    // manifest never references an image that is not in the store.
+    let manifest_string = sign_manifest(&manifest, &[key_pair])?;
+    manifest::store_local(&seed.output_path, manifest_string.as_bytes())?;
+
+    println!("Stored {} versions, skipped {}.", version_dirs.len() - num_skipped, num_skipped);
+
+    Ok(())
+}
+
+/// Collect the directories below `dir` that contain a manifest named
+/// `manifest_name`.
+///
+/// Does not follow symlinks, and skips the `store` directory of a server
+/// directory, which holds only images. The result is ordered by path.
+fn find_server_dirs(dir: &Path, manifest_name: &str, result: &mut Vec<PathBuf>) -> Result<()> {
+    let mut paths = Vec::new();
+    for dir_entry in fs::read_dir(dir)? {
+        paths.push(dir_entry?.path());
+    }
+    paths.sort();
+
+    let has_manifest = paths.iter().any(|p| {
+        p.file_name() == Some(manifest_name.as_ref()) &&
+        fs::symlink_metadata(p).map(|m| m.is_file()).unwrap_or(false)
+    });
+    if has_manifest {
+        result.push(PathBuf::from(dir));
+    }
+
+    for path in &paths {
+        if has_manifest && path.file_name() == Some("store".as_ref()) {
+            continue
+        }
+        if fs::symlink_metadata(path)?.is_dir() {
+            find_server_dirs(path, manifest_name, result)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Re-sign a manifest with only the new keys.
+///
+/// Returns false if the manifest was signed with the new keys already.
+fn rekey_manifest(path: &Path, old_key: &PublicKey, key_pairs: &[Ed25519KeyPair]) -> Result<bool> {
+    let bytes = match Manifest::load_file_bytes(path)? {
+        Some(bytes) => bytes,
+        None => return Err(Error::OperationError("The manifest disappeared.")),
+    };
+
+    let is_rekeyed = key_pairs
+        .iter()
+        .all(|pair| Manifest::parse(&bytes[..], &PublicKey::from_pair(pair)).is_ok());
+    if is_rekeyed {
+        return Ok(false)
+    }
+
+    let manifest = Manifest::parse(&bytes[..], old_key)?;
+    let manifest_string = sign_manifest(&manifest, key_pairs)?;
+    manifest::store_file(path, manifest_string.as_bytes())?;
+
+    Ok(true)
+}
+
+/// Sign every manifest below a root directory with a new key.
+///
+/// Continues after a manifest fails, and returns the number of failures.
+pub fn rekey_all(rekey: RekeyAll) -> Result<usize> {
+    let secret_key_base64 = match (rekey.secret_key, rekey.secret_key_path) {
+        (Some(k), _) => k,
+        (None, Some(p)) => read_secret_key(&p)?,
+        (None, None) => unreachable!("Should have been validated elsewhere."),
+    };
+    let key_pairs = [parse_key_pair(&secret_key_base64)?];
+    let manifest_name = match rekey.manifest_name {
+        Some(ref name) => &name[..],
+        None => manifest::DEFAULT_NAME,
+    };
+
+    // Find all manifests before changing any, so an unreadable directory does
+    // not leave the tree half rotated.
+    let mut server_dirs = Vec::new();
+    find_server_dirs(&rekey.root, manifest_name, &mut server_dirs)?;
+
+    if !server_dirs.is_empty() {
+        println!("Found {} manifests:", server_dirs.len());
+        for dir in &server_dirs {
+            println!("  {}", dir.join(manifest_name).display());
+        }
+        let question = "Re-sign the manifests that verify with the old key with only the new key?";
+        if !util::confirm(question, rekey.assume_yes)? {
+            println!("Nothing changed.");
+            return Ok(0)
+        }
+    }
+
+    let mut num_rekeyed = 0;
+    let mut num_skipped = 0;
+    let mut num_failed = 0;
+
+    for dir in &server_dirs {
+        let path = dir.join(manifest_name);
+        match rekey_manifest(&path, &rekey.old_key, &key_pairs[..]) {
+            Ok(true) => {
+                println!("Rekeyed {}.", path.display());
+                num_rekeyed += 1;
+            }
+            Ok(false) => {
+                println!("Skipped {}: signed with the new key already.", path.display());
+                num_skipped += 1;
+            }
+            Err(e) => {
+                eprintln!("Failed to rekey {}: {}", path.display(), e);
+                num_failed += 1;
+            }
+        }
+    }
+
+    println!("Rekeyed {} manifests, skipped {}, failed {}.", num_rekeyed, num_skipped, num_failed);
+
+    Ok(num_failed)
+}
+
+/// Return the message that the signatures of the manifest in a server
+/// directory cover, see `Manifest::serialize_unsigned`.
+///
+/// The signatures of the manifest are not checked, the message is the same
+/// whether they are valid or not.
+pub fn canonicalize(canonicalize: &Canonicalize) -> Result<String> {
+    let manifest_name = match canonicalize.manifest_name {
+        Some(ref name) => &name[..],
+        None => manifest::DEFAULT_NAME,
+    };
+    let path = canonicalize.output_path.join(manifest_name);
+    let bytes = match Manifest::load_file_bytes(&path)? {
+        Some(bytes) => bytes,
+        None => return Err(Error::OperationError("The server directory has no manifest.")),
+    };
+
+    let manifest = Manifest::parse_unverified(&bytes[..])?;
+    let public_keys = if canonicalize.public_keys.is_empty() {
+        Manifest::parse_public_keys(&bytes[..])?
+    } else {
+        canonicalize.public_keys.clone()
+    };
+    if public_keys.is_empty() {
+        let msg = "The manifest does not contain its public keys. Pass every key that will sign with --key.";
+        return Err(Error::OperationError(msg))
+    }
+
+    Ok(manifest.serialize_unsigned(&public_keys))
+}
+
+/// Sign a tar file of configs, and write it in the envelope that
+/// `fetch --config-archive` reads.
+///
+/// The tar file is checked first, so a compressed or damaged archive fails
+/// here rather than on the hosts.
+pub fn sign_archive(sign: &cli::SignArchive) -> Result<()> {
+    let secret_key_base64 = match (sign.secret_key.as_ref(), sign.secret_key_path.as_ref()) {
+        (Some(k), _) => k.clone(),
+        (None, Some(p)) => read_secret_key(p)?,
+        (None, None) => unreachable!("Should have been validated elsewhere."),
+    };
+    let key_pair = parse_key_pair(&secret_key_base64)?;
+
+    let mut tar = Vec::new();
+    fs::File::open(&sign.tar_path)?.read_to_end(&mut tar)?;
+    let members = archive::read_tar(&tar[..])?;
+    if !members.iter().any(|m| m.path.ends_with(".tako")) {
+        return Err(Error::InvalidArchive("The archive contains no config with extension '.tako'."))
+    }
+
+    manifest::store_file(&sign.output_path, &archive::sign(&tar[..], &[key_pair])[..])
+}
+
+/// Write a signed index that pins the current manifest of every image.
+///
+/// The manifests are not verified, fetch verifies them against the keys in the
+/// configs. The index only states which manifest and version belong together.
+pub fn index(index: &cli::Index) -> Result<()> {
+    let secret_key_base64 = match (index.secret_key.as_ref(), index.secret_key_path.as_ref()) {
+        (Some(k), _) => k.clone(),
+        (None, Some(p)) => read_secret_key(p)?,
+        (None, None) => unreachable!("Should have been validated elsewhere."),
+    };
+    let key_pair = parse_key_pair(&secret_key_base64)?;
+
+    let mut result = Index::new();
+    for &(ref dir, ref version) in &index.images {
+        let path = dir.join(manifest::DEFAULT_NAME);
+        let bytes = match Manifest::load_file_bytes(&path)? {
+            Some(bytes) => bytes,
+            None => return Err(Error::OperationError("The server directory has no manifest.")),
+        };
+        let manifest = Manifest::parse_unverified(&bytes[..])?;
+
+        let name = match manifest.name() {
+            Some(name) => name.to_string(),
+            None => {
+                let msg = "The manifest does not set 'Name=', which the index needs. See 'tako store --name'.";
+                return Err(Error::OperationError(msg))
+            }
+        };
+        if !manifest.entries().iter().any(|e| e.version == *version) {
+            let available = manifest.entries().iter().map(|e| e.version.clone()).collect();
+            return Err(Error::NoCandidate(version.clone(), available))
+        }
+
+        let digest = digest::digest(&digest::SHA256, &bytes[..]);
+        result.insert(IndexEntry {
+            name: name,
+            version: version.clone(),
+            manifest_digest: Sha256::copy_from_slice(digest.as_ref()),
+        })?;
+    }
+
+    manifest::store_file(&index.output_path, result.serialize(&[key_pair]).as_bytes())
+}
@@ -0,0 +1,95 @@
+// Tako -- Take container image.
+// Copyright 2018 Arian van Putten, Ruud van Asseldonk, Tako Marks.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! The transport that fetch downloads manifests and images with.
+//!
+//! Fetch downloads with Curl, see `curl::Handle`, which also does timeouts,
+//! certificate pinning, and transfer statistics. Those are set up on the handle
+//! beforehand. To download and verify a manifest or an image, fetch only needs
+//! to get the bytes at a uri, so those functions are generic over `Transport`,
+//! and tests can serve files from memory instead of from a server.
+
+use std::io;
+
+use curl;
+use curl::{Conditional, Validators};
+use error::Result;
+
+pub trait Transport {
+    /// Download the uri, and pass the data to `on_data` as it arrives.
+    ///
+    /// If `on_data` fails, the download fails with its error.
+    fn get<F>(&mut self, uri: &str, on_data: F) -> Result<()>
+    where F: FnMut(&[u8]) -> io::Result<()>;
+
+    /// Download the uri, unless it did not change since the response that the
+    /// validators were taken from. See `curl::Handle::download_conditional`.
+    fn get_conditional<F>(&mut self, uri: &str, validators: &Validators, on_data: F) -> Result<Conditional>
+    where F: FnMut(&[u8]);
+}
+
+impl Transport for curl::Handle {
+    fn get<F>(&mut self, uri: &str, on_data: F) -> Result<()>
+    where F: FnMut(&[u8]) -> io::Result<()> {
+        self.download_io(uri, on_data)
+    }
+
+    fn get_conditional<F>(&mut self, uri: &str, validators: &Validators, on_data: F) -> Result<Conditional>
+    where F: FnMut(&[u8]) {
+        self.download_conditional(uri, validators, on_data)
+    }
+}
+
+/// A transport that serves files from memory, for tests.
+#[cfg(test)]
+pub struct MemoryTransport {
+    /// The uri and contents of every file that exists.
+    pub files: Vec<(String, Vec<u8>)>,
+
+    /// The uris that were requested, in order.
+    pub requests: Vec<String>,
+}
+
+#[cfg(test)]
+impl MemoryTransport {
+    pub fn new() -> MemoryTransport {
+        MemoryTransport {
+            files: Vec::new(),
+            requests: Vec::new(),
+        }
+    }
+
+    /// Serve the contents at the uri.
+    pub fn insert(&mut self, uri: &str, contents: &[u8]) {
+        self.files.push((uri.to_string(), contents.to_vec()));
+    }
+}
+
+#[cfg(test)]
+impl Transport for MemoryTransport {
+    fn get<F>(&mut self, uri: &str, mut on_data: F) -> Result<()>
+    where F: FnMut(&[u8]) -> io::Result<()> {
+        use error::Error;
+        self.requests.push(uri.to_string());
+        match self.files.iter().find(|&&(ref u, _)| u == uri) {
+            // Deliver in small chunks, like a real download would.
+            Some(&(_, ref contents)) => {
+                for chunk in contents.chunks(7) {
+                    on_data(chunk)?;
+                }
+                Ok(())
+            }
+            None => Err(Error::DownloadError(format!("Remote file not found: {}", uri))),
+        }
+    }
+
+    fn get_conditional<F>(&mut self, uri: &str, _validators: &Validators, mut on_data: F) -> Result<Conditional>
+    where F: FnMut(&[u8]) {
+        self.get(uri, |chunk| { on_data(chunk); Ok(()) })?;
+        Ok(Conditional::Modified(Validators::default()))
+    }
+}
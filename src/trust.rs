@@ -0,0 +1,152 @@
+// Tako -- Take container image.
+// Copyright 2018 Arian van Putten, Ruud van Asseldonk, Tako Marks.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Trust on first use of the key that signs manifests.
+//!
+//! A config without `PublicKey=` can be fetched with `--tofu`. The first fetch
+//! trusts the key in the first `PublicKey` header field of the manifest, and
+//! records its fingerprint in `trusted-key` in the destination directory. Later
+//! fetches accept only manifests signed with that key. A manifest signed with
+//! multiple keys is accepted if any of them is the trusted key. To accept a new
+//! key after a deliberate key rotation, delete the file.
+
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use config;
+use config::{Config, PublicKey};
+use error::{Error, Result};
+use manifest::Manifest;
+use util;
+use util::Sha256;
+
+/// Parse the trust file, return the fingerprint of the trusted key.
+pub fn parse<I, S>(lines: I) -> Result<Sha256>
+where I: IntoIterator<Item = S>,
+      S: AsRef<str> {
+    let mut fingerprint = None;
+
+    config::for_each_pair(lines, |lineno, key, value| {
+        match key {
+            "Fingerprint" => {
+                let msg = "Invalid fingerprint. Expected 64 lowercase hexadecimal characters.";
+                let sha256 = Sha256::from_hex(value.as_bytes());
+                fingerprint = Some(sha256.ok_or(Error::InvalidConfig(lineno, msg))?);
+            }
+            _ => {
+                let msg = "Unknown key. Expected 'Fingerprint'.";
+                return Err(Error::InvalidConfig(lineno, msg))
+            }
+        }
+        Ok(())
+    })?;
+
+    match fingerprint {
+        Some(f) => Ok(f),
+        None => Err(Error::IncompleteConfig(
+            "Fingerprint not set in trust file. Expected 'Fingerprint='-line."
+        )),
+    }
+}
+
+/// Load the fingerprint of the trusted key from a destination directory.
+///
+/// If no key has been trusted yet, None is returned, rather than an Err.
+pub fn load_local(destination: &Path) -> Result<Option<Sha256>> {
+    let mut path = PathBuf::from(destination);
+    path.push("trusted-key");
+    match config::read_lines(&path) {
+        Ok(lines) => Ok(Some(parse(lines)?)),
+        Err(Error::IoError(ref e)) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Record the fingerprint of the trusted key in a destination directory.
+pub fn store_local(destination: &Path, fingerprint: &Sha256) -> Result<()> {
+    let mut path_tmp = PathBuf::from(destination);
+    let mut path_final = PathBuf::from(destination);
+    path_tmp.push("trusted-key.new");
+    path_final.push("trusted-key");
+
+    let mut contents = String::from("Fingerprint=");
+    util::append_hex(&mut contents, fingerprint.as_ref());
+    contents.push('\n');
+
+    // Delete the file if the write fails.
+    let guard = util::FileGuard::new(&path_tmp);
+    fs::File::create(&path_tmp)?.write_all(contents.as_bytes())?;
+    guard.move_readonly(&path_final)?;
+
+    Ok(())
+}
+
+/// Return the key to verify the manifest with.
+///
+/// This is the configured key if there is one. Otherwise, with `tofu`, it is
+/// the key in the manifest that matches the trusted key, or the first key if no
+/// key has been trusted yet. Call `trust` after the manifest has been verified.
+pub fn manifest_key(config: &Config, tofu: bool, manifest_bytes: &[u8]) -> Result<PublicKey> {
+    if let Some(ref key) = config.public_key {
+        return Ok(key.clone())
+    }
+
+    if !tofu {
+        return Err(Error::IncompleteConfig(
+            "Public key not set. Expected 'PublicKey='-line, or fetch with --tofu."
+        ))
+    }
+
+    let mut keys = Manifest::parse_public_keys(manifest_bytes)?;
+    if keys.is_empty() {
+        let msg = "Manifest does not contain a PublicKey field, which --tofu requires.";
+        return Err(Error::InvalidManifest(msg))
+    }
+
+    match load_local(&config.destination)? {
+        Some(trusted) => match keys.iter().position(|k| k.fingerprint() == trusted) {
+            Some(i) => Ok(keys.swap_remove(i)),
+            None => Err(Error::UntrustedKey(trusted, keys[0].fingerprint())),
+        },
+        None => Ok(keys.swap_remove(0)),
+    }
+}
+
+/// Trust the key of a verified manifest, if the config relies on trust on
+/// first use and no key has been trusted yet.
+pub fn trust(config: &Config, manifest_bytes: &[u8]) -> Result<()> {
+    if config.public_key.is_some() || load_local(&config.destination)?.is_some() {
+        return Ok(())
+    }
+    match Manifest::parse_public_keys(manifest_bytes)?.first() {
+        Some(key) => store_local(&config.destination, &key.fingerprint()),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse;
+
+    #[test]
+    fn parse_parses_fingerprint() {
+        let hex = "9641a49d02e90cbb6213f202fb632da70cdc59073d42283cfcdc1d786454f17f";
+        let line = format!("Fingerprint={}", hex);
+        let fingerprint = parse(&[line]).unwrap();
+        assert_eq!(fingerprint.as_ref()[..2], [0x96, 0x41]);
+    }
+
+    #[test]
+    fn parse_rejects_missing_or_invalid_fingerprint() {
+        assert!(parse(&["Origin=https://example.com"]).is_err());
+        assert!(parse(&["Fingerprint=9641"]).is_err());
+        let empty: [&str; 0] = [];
+        assert!(parse(&empty).is_err());
+    }
+}
@@ -7,14 +7,20 @@
 
 //! Utilities for formatting, parsing, digests, files, etc.
 
+use std::env;
+use std::ffi::CString;
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::io::{BufRead, Write};
+use std::os::raw;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use filebuffer::FileBuffer;
 use ring;
 
-use error::Result;
+use error::{Error, Result};
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Sha256(pub [u8; 32]);
@@ -25,6 +31,39 @@ impl Sha256 {
         sha256.copy_from_slice(bytes);
         Sha256(sha256)
     }
+
+    /// Parse 64 lowercase hexadecimal characters.
+    pub fn from_hex(hex: &[u8]) -> Option<Sha256> {
+        if hex.len() != 64 {
+            return None
+        }
+
+        let mut sha256 = [0_u8; 32];
+        for (dst, pair) in sha256.iter_mut().zip(hex.chunks(2)) {
+            // There is also u8::from_str_radix, but then we would need to do
+            // UTF-8 validation first, and all the error handling is just as
+            // messy as just doing it manually. As an additional benefit, we are
+            // stricter to only allow lowercase hexadecimal.
+            let high = parse_hex(pair[0])?;
+            let low = parse_hex(pair[1])?;
+            *dst = (high << 4) + low;
+        }
+
+        Some(Sha256(sha256))
+    }
+}
+
+/// Parse a lowercase ascii byte in [0, ..., f], return value as int.
+fn parse_hex(ch: u8) -> Option<u8> {
+    if ch < b'0' { return None }
+    if ch > b'f' { return None }
+    if ch <= b'9' {
+        Some(ch - b'0')
+    } else if ch >= b'a' {
+        Some(ch - b'a' + 10)
+    } else {
+        None
+    }
 }
 
 impl AsRef<[u8]> for Sha256 {
@@ -46,23 +85,280 @@ pub fn append_hex(string: &mut String, bytes: &[u8]) {
     }
 }
 
-/// Compute the SHA256 digest of a file. Mmaps the file.
-pub fn sha256sum(path: &Path) -> Result<Sha256> {
+/// Format a string as a quoted json string, append to the string.
+pub fn append_json_string(string: &mut String, value: &str) {
+    string.push('"');
+    for ch in value.chars() {
+        match ch {
+            '"' => string.push_str("\\\""),
+            '\\' => string.push_str("\\\\"),
+            '\n' => string.push_str("\\n"),
+            '\r' => string.push_str("\\r"),
+            '\t' => string.push_str("\\t"),
+            // Other control characters must be escaped as code points.
+            ch if (ch as u32) < 0x20 => {
+                string.push_str("\\u00");
+                append_hex(string, &[ch as u8]);
+            }
+            ch => string.push(ch),
+        }
+    }
+    string.push('"');
+}
+
+/// Format a string as a single-quoted shell word, append to the string.
+///
+/// Inside single quotes the shell interprets nothing, apart from the closing
+/// quote. A quote in the value is written as `'\''`: close the quoted part,
+/// add an escaped quote, and open a new quoted part.
+pub fn append_shell_string(string: &mut String, value: &str) {
+    string.push('\'');
+    for ch in value.chars() {
+        match ch {
+            '\'' => string.push_str("'\\''"),
+            ch => string.push(ch),
+        }
+    }
+    string.push('\'');
+}
+
+/// ANSI color codes for `append_colored`.
+pub const GREEN: &'static str = "32";
+pub const YELLOW: &'static str = "33";
+pub const RED: &'static str = "31";
+
+extern {
+    fn isatty(fd: raw::c_int) -> raw::c_int;
+    fn getpwnam(name: *const raw::c_char) -> *const Passwd;
+    fn getgrnam(name: *const raw::c_char) -> *const Group;
+    fn chown(path: *const raw::c_char, owner: u32, group: u32) -> raw::c_int;
+}
+
+/// Return whether output to the file descriptor should be colored.
+///
+/// This is the case if it is a terminal, and the `NO_COLOR` environment
+/// variable is not set to a non-empty value.
+pub fn is_color_terminal(fd: raw::c_int) -> bool {
+    let no_color = env::var_os("NO_COLOR").map(|v| !v.is_empty()).unwrap_or(false);
+    !no_color && is_terminal(fd)
+}
+
+/// Return whether the file descriptor is a terminal.
+pub fn is_terminal(fd: raw::c_int) -> bool {
+    unsafe { isatty(fd) == 1 }
+}
+
+/// Ask a yes/no question on stdout before a destructive change, read the
+/// answer from stdin.
+///
+/// With `assume_yes`, from `--assume-yes`, do not ask. Otherwise stdin must be
+/// a terminal, so a script that did not opt in fails rather than changing data.
+pub fn confirm(question: &str, assume_yes: bool) -> Result<bool> {
+    if assume_yes {
+        return Ok(true)
+    }
+    if !is_terminal(0) {
+        return Err(Error::ConfirmationRequired)
+    }
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    let stdin = io::stdin();
+    stdin.lock().read_line(&mut answer)?;
+    let answer = answer.trim();
+    Ok(answer == "y" || answer == "yes")
+}
+
+/// The leading fields of `struct passwd`, see getpwnam(3).
+#[repr(C)]
+struct Passwd {
+    pw_name: *const raw::c_char,
+    pw_passwd: *const raw::c_char,
+    pw_uid: u32,
+    pw_gid: u32,
+}
+
+/// The leading fields of `struct group`, see getgrnam(3).
+#[repr(C)]
+struct Group {
+    gr_name: *const raw::c_char,
+    gr_passwd: *const raw::c_char,
+    gr_gid: u32,
+}
+
+/// Return the uid of a user, given by name or as a number.
+///
+/// Returns None if there is no such user. A number is not checked against the
+/// user database, like chown(1) does.
+pub fn lookup_user(user: &str) -> Option<u32> {
+    if let Ok(uid) = user.parse() {
+        return Some(uid)
+    }
+    let name = CString::new(user).ok()?;
+    let passwd = unsafe { getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        None
+    } else {
+        Some(unsafe { (*passwd).pw_uid })
+    }
+}
+
+/// Return the gid of a group, given by name or as a number.
+///
+/// Returns None if there is no such group. A number is not checked against the
+/// group database, like chown(1) does.
+pub fn lookup_group(group: &str) -> Option<u32> {
+    if let Ok(gid) = group.parse() {
+        return Some(gid)
+    }
+    let name = CString::new(group).ok()?;
+    let group = unsafe { getgrnam(name.as_ptr()) };
+    if group.is_null() {
+        None
+    } else {
+        Some(unsafe { (*group).gr_gid })
+    }
+}
+
+/// Change the owner and group of a file. None leaves the id unchanged.
+pub fn chown_path(path: &Path, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+    // chown(2) leaves an id unchanged if it is -1.
+    let unchanged = !0_u32;
+    let path_c = CString::new(path.as_os_str().as_bytes())?;
+    let result = unsafe { chown(path_c.as_ptr(), uid.unwrap_or(unchanged), gid.unwrap_or(unchanged)) };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Append text to the string, wrapped in an ANSI color escape if enabled.
+pub fn append_colored(string: &mut String, text: &str, color: &str, enabled: bool) {
+    if enabled {
+        string.push_str("\x1b[");
+        string.push_str(color);
+        string.push('m');
+        string.push_str(text);
+        string.push_str("\x1b[0m");
+    } else {
+        string.push_str(text);
+    }
+}
+
+/// The longest duration that `parse_duration` accepts, in seconds.
+///
+/// This is more than a century, but short enough that adding it to the
+/// current time, or converting it to milliseconds, cannot overflow.
+pub const MAX_DURATION: u64 = 0xffff_ffff;
+
+/// Parse a duration like `30`, `30s`, `5m`, or `2h` into a number of seconds.
+///
+/// A number without suffix is in seconds. Returns `None` if the string is not
+/// a valid duration, or if it is longer than `MAX_DURATION`.
+pub fn parse_duration(duration: &str) -> Option<u64> {
+    let (digits, multiplier) = match duration.chars().last() {
+        Some('s') => (&duration[..duration.len() - 1], 1),
+        Some('m') => (&duration[..duration.len() - 1], 60),
+        Some('h') => (&duration[..duration.len() - 1], 3600),
+        _ => (duration, 1),
+    };
+
+    // Parse via `u64::from_str`, but reject signs, which it accepts.
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None
+    }
+
+    match digits.parse::<u64>().ok().and_then(|n| n.checked_mul(multiplier)) {
+        Some(seconds) if seconds <= MAX_DURATION => Some(seconds),
+        _ => None,
+    }
+}
+
+/// Parse a size like `4096`, `64K`, `1M`, or `1G` into a number of bytes.
+///
+/// The suffixes are powers of 1024. Returns `None` if the string is not a valid
+/// size, or if the size is zero.
+pub fn parse_size(size: &str) -> Option<usize> {
+    let (digits, multiplier) = match size.chars().last() {
+        Some('K') => (&size[..size.len() - 1], 1 << 10),
+        Some('M') => (&size[..size.len() - 1], 1 << 20),
+        Some('G') => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None
+    }
+
+    match digits.parse::<usize>().ok().and_then(|n| n.checked_mul(multiplier)) {
+        Some(0) => None,
+        other => other,
+    }
+}
+
+/// Compute the digest of a file with the given algorithm. Mmaps the file.
+pub fn digest_file(path: &Path, algorithm: &'static ring::digest::Algorithm) -> Result<ring::digest::Digest> {
     // Mmap the file when computing its digest. This way we can compute the
     // digest of files that don't fit in memory, without having to care about
     // streaming manually. Simple and fast.
     let fbuffer = FileBuffer::open(path)?;
-    let sha256_bytes = ring::digest::digest(&ring::digest::SHA256, &fbuffer);
+    Ok(ring::digest::digest(algorithm, &fbuffer))
+}
+
+/// Compute the SHA256 digest of a file. Mmaps the file.
+pub fn sha256sum(path: &Path) -> Result<Sha256> {
+    let sha256_bytes = digest_file(path, &ring::digest::SHA256)?;
     Ok(Sha256::copy_from_slice(sha256_bytes.as_ref()))
 }
 
+/// Whether to flush files to disk before and after renaming them into place.
+static FSYNC: AtomicBool = AtomicBool::new(true);
+
+/// Enable or disable the fsync calls for files written after this.
+///
+/// Renames are atomic either way. Without fsync, a crash cannot leave a
+/// half-written file under the final name while the system stays up, but after
+/// a power loss the file may be empty or partial, because the rename can reach
+/// the disk before the data does.
+pub fn set_fsync(enabled: bool) {
+    FSYNC.store(enabled, Ordering::SeqCst);
+}
+
+/// Flush a directory to disk, so a rename in it survives a power loss.
+///
+/// Does nothing if fsync is disabled.
+pub fn sync_dir(path: &Path) -> io::Result<()> {
+    if !FSYNC.load(Ordering::SeqCst) {
+        return Ok(())
+    }
+    // A bare file name has an empty parent, which is the working directory.
+    let dir = if path.as_os_str().is_empty() { Path::new(".") } else { path };
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Resolve the symlinks in the path of a directory, if it exists.
+///
+/// Files are written under a temporary name and renamed into place, which is
+/// only atomic within one directory. When the directory is a symlink, and a
+/// deploy repoints it while Tako runs, the temporary file and its final name
+/// could end up in different directories. Resolving the path once, and using
+/// the result for both, keeps them together.
+pub fn resolve_dir(path: &Path) -> io::Result<PathBuf> {
+    match fs::canonicalize(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(path.to_path_buf()),
+        other => other,
+    }
+}
+
 /// A file that is deleted on drop, unless explicitly renamed.
 ///
 /// This is used to write to a temporary file, which is cleaned up automatically
 /// on an error: construct a `FileGuard` with the file path. In case of an early
 /// return due to an error, the guard goes out of scope and deletes the file. If
 /// the full write was successful, call `move_readonly()` to mark the file
-/// read-only and move it into its final destination.
+/// read-only and move it into its final destination. Unless disabled with
+/// `set_fsync`, that also flushes the file and the directory to disk.
 pub struct FileGuard<'a> {
     path: &'a Path,
     delete: bool,
@@ -77,13 +373,20 @@ impl<'a> FileGuard<'a> {
     }
 
     pub fn move_readonly(mut self, dest: &Path) -> io::Result<()> {
+        // Flush the contents before the rename, otherwise the rename may reach
+        // the disk first, and a power loss leaves an empty file under the
+        // final name.
+        if FSYNC.load(Ordering::SeqCst) {
+            fs::File::open(self.path)?.sync_all()?;
+        }
+
         // Make the file readonly.
         let mut perms = fs::metadata(self.path)?.permissions();
         perms.set_readonly(true);
         fs::set_permissions(self.path, perms)?;
         fs::rename(self.path, dest)?;
         self.delete = false;
-        Ok(())
+        sync_dir(dest.parent().unwrap_or(Path::new(".")))
     }
 }
 
@@ -102,3 +405,92 @@ impl<'a> Drop for FileGuard<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{MAX_DURATION, Sha256, append_colored, append_json_string, append_shell_string, parse_duration, parse_size};
+    use super::{lookup_group, lookup_user};
+
+    #[test]
+    fn append_json_string_escapes_special_characters() {
+        let mut out = String::new();
+        append_json_string(&mut out, "a \"b\" \\ c\nd\u{1}");
+        assert_eq!(out, "\"a \\\"b\\\" \\\\ c\\nd\\u0001\"");
+    }
+
+    #[test]
+    fn append_shell_string_quotes_everything() {
+        let mut out = String::new();
+        append_shell_string(&mut out, "it's $(rm -rf /) `x` \"y\"\n");
+        assert_eq!(out, "'it'\\''s $(rm -rf /) `x` \"y\"\n'");
+    }
+
+    #[test]
+    fn append_colored_adds_escapes_only_if_enabled() {
+        let mut out = String::new();
+        append_colored(&mut out, "failed", "31", true);
+        append_colored(&mut out, "failed", "31", false);
+        assert_eq!(out, "\x1b[31mfailed\x1b[0mfailed");
+    }
+
+    #[test]
+    fn parse_duration_parses_units() {
+        assert_eq!(parse_duration("0"), Some(0));
+        assert_eq!(parse_duration("30"), Some(30));
+        assert_eq!(parse_duration("30s"), Some(30));
+        assert_eq!(parse_duration("5m"), Some(300));
+        assert_eq!(parse_duration("2h"), Some(7200));
+        assert_eq!(parse_duration("4294967295"), Some(MAX_DURATION));
+    }
+
+    #[test]
+    fn parse_duration_rejects_invalid_durations() {
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("s"), None);
+        assert_eq!(parse_duration("+5"), None);
+        assert_eq!(parse_duration("-5"), None);
+        assert_eq!(parse_duration("5d"), None);
+        assert_eq!(parse_duration("1.5h"), None);
+        assert_eq!(parse_duration("99999999999999999999h"), None);
+        assert_eq!(parse_duration("4294967296"), None);
+        assert_eq!(parse_duration("18446744073709551615"), None);
+        assert_eq!(parse_duration("18446744073709552s"), None);
+    }
+
+    #[test]
+    fn parse_size_parses_units() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("64K"), Some(64 * 1024));
+        assert_eq!(parse_size("1M"), Some(1024 * 1024));
+        assert_eq!(parse_size("1G"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_size("0"), None);
+        assert_eq!(parse_size("0M"), None);
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("1m"), None);
+        assert_eq!(parse_size("+1K"), None);
+        assert_eq!(parse_size("1.5M"), None);
+    }
+
+    #[test]
+    fn sha256_from_hex_parses_lowercase_hex() {
+        let hex = b"9641a49d02e90cbb6213f202fb632da70cdc59073d42283cfcdc1d786454f17f";
+        let sha256 = Sha256::from_hex(&hex[..]).unwrap();
+        assert_eq!(sha256.0[..4], [0x96, 0x41, 0xa4, 0x9d]);
+        assert_eq!(sha256.0[31], 0x7f);
+
+        // Wrong length, uppercase, and non-hex characters are rejected.
+        assert!(Sha256::from_hex(&hex[..62]).is_none());
+        assert!(Sha256::from_hex(b"9641A49D02E90CBB6213F202FB632DA70CDC59073D42283CFCDC1D786454F17F").is_none());
+        assert!(Sha256::from_hex(b"9641a49d02e90cbb6213f202fb632da70cdc59073d42283cfcdc1d786454f17g").is_none());
+    }
+
+    #[test]
+    fn lookup_user_and_group_accept_names_and_numbers() {
+        assert_eq!(lookup_user("root"), Some(0));
+        assert_eq!(lookup_user("1234"), Some(1234));
+        assert_eq!(lookup_user("no-such-user-tako"), None);
+        assert_eq!(lookup_group("root"), Some(0));
+        assert_eq!(lookup_group("1234"), Some(1234));
+        assert_eq!(lookup_group("no-such-group-tako"), None);
+    }
+}
@@ -0,0 +1,72 @@
+// Tako -- Take container image.
+// Copyright 2018 Arian van Putten, Ruud van Asseldonk, Tako Marks.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// A copy of the License has been included in the root of the repository.
+
+//! Verify images in a destination against the manifest stored next to them.
+//!
+//! This is what fetch checks after a download, without downloading anything,
+//! for instance after restoring a destination from a backup.
+
+use cli;
+use error::{Error, Result};
+use fetch;
+use manifest::{Entry, Manifest};
+use trust;
+use util;
+
+/// Verify the manifest in the destination, and the images of the versions.
+///
+/// Returns whether every image matched its digest. A manifest that does not
+/// verify is an error.
+pub fn verify(verify: &cli::Verify) -> Result<bool> {
+    let config = fetch::load_config(&verify.config_fname)?;
+
+    let bytes = match Manifest::load_local_bytes(&config.destination)? {
+        Some(bytes) => bytes,
+        None => {
+            let msg = "The destination has no manifest. Fetch the config first.";
+            return Err(Error::OperationError(msg))
+        }
+    };
+
+    // Without a key in the config, only a key that fetch trusted on first use
+    // will do. The key in the manifest itself proves nothing.
+    let is_trusted = trust::load_local(&config.destination)?.is_some();
+    let public_key = trust::manifest_key(&config, is_trusted, &bytes)?;
+    let manifest = Manifest::parse(&bytes, &public_key)?;
+
+    let entries: Vec<&Entry> = match verify.version {
+        Some(ref version) => match manifest.entry(version) {
+            Some(entry) => vec![entry],
+            None => {
+                let available = manifest.entries().iter().map(|e| e.version.clone()).collect();
+                return Err(Error::NoCandidate(version.clone(), available))
+            }
+        },
+        // Only the versions that were fetched have an image in the store.
+        None => manifest.entries().iter().filter(|entry| {
+            config.destination.join(fetch::store_path(entry)).is_file()
+        }).collect(),
+    };
+
+    if entries.is_empty() {
+        let msg = "The store of the destination has no image of any version in the manifest.";
+        return Err(Error::OperationError(msg))
+    }
+
+    let mut is_ok = true;
+    for entry in entries {
+        let image_path = config.destination.join(fetch::store_path(entry));
+        if util::sha256sum(&image_path)? == entry.digest {
+            println!("{}: OK", entry.version.as_str());
+        } else {
+            eprintln!("{}: FAILED, {} does not match its digest.", entry.version.as_str(), image_path.display());
+            is_ok = false;
+        }
+    }
+
+    Ok(is_ok)
+}
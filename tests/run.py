@@ -1,21 +1,30 @@
 #!/usr/bin/env python3
 
+import base64
+import gzip
+import hashlib
 import http.server
+import io
+import json
 import os
 import os.path
+import pwd
 import shutil
 import socketserver
 import subprocess
 import sys
+import tarfile
 import threading
 
 is_repo_root = os.path.exists(os.path.join(os.getcwd(), 'README.md'))
 assert is_repo_root, 'This script must be run from the root of the repository.'
 
 
-def exec(*args, expect=0):
-    """ Run a program with an expected exit code, print stdout on mismatch. """
-    p = subprocess.run(args, stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+def exec(*args, expect=0, input=None):
+    """ Run a program with an expected exit code, print stdout on mismatch.
+    Optionally feeds input to stdin. Returns stdout. """
+    p = subprocess.run(args, input=input,
+                       stdout=subprocess.PIPE, stderr=subprocess.PIPE)
     if p.returncode != expect:
         print('Process {} exited with unexpected '
               'exit code {}.'.format(args, p.returncode))
@@ -24,6 +33,7 @@ def exec(*args, expect=0):
         print('\nSTDERR\n------')
         sys.stdout.buffer.write(p.stderr)
         sys.exit(-1)
+    return p.stdout
 
 
 def run_server():
@@ -40,6 +50,18 @@ def run_server():
         httpd.serve_forever()
 
 
+class UnixHandler(http.server.SimpleHTTPRequestHandler):
+    # The default implementation logs the client address, which is empty for
+    # unix domain sockets, so logging would fail.
+    def log_message(self, format, *args):
+        pass
+
+
+def run_unix_server():
+    with socketserver.UnixStreamServer(unix_socket, UnixHandler) as httpd:
+        httpd.serve_forever()
+
+
 # Run the http server in a background thread. Don't wait for the
 # thread to finish if the main thread exits.
 httpd_thread = threading.Thread(target=run_server, daemon=True)
@@ -56,6 +78,65 @@ os.mkdir('tests/scratch')
 os.mkdir('tests/scratch/foo')
 os.mkdir('tests/scratch/bar')
 os.mkdir('tests/scratch/bar-origin')
+os.mkdir('tests/scratch/baz-origin')
+os.mkdir('tests/scratch/url-origin')
+os.mkdir('tests/scratch/seed-origin')
+os.mkdir('tests/scratch/unix')
+os.mkdir('tests/scratch/restart')
+os.mkdir('tests/scratch/group')
+os.mkdir('tests/scratch/split')
+os.mkdir('tests/scratch/split-origin')
+os.mkdir('tests/scratch/split-cdn')
+os.mkdir('tests/scratch/group-req')
+os.mkdir('tests/scratch/tofu')
+os.mkdir('tests/scratch/stop')
+os.mkdir('tests/scratch/cached')
+os.mkdir('tests/scratch/verify')
+os.mkdir('tests/scratch/wrong')
+os.mkdir('tests/scratch/rotate-origin')
+os.mkdir('tests/scratch/rotate')
+os.mkdir('tests/scratch/owner-origin')
+os.mkdir('tests/scratch/fallback-origin')
+os.mkdir('tests/scratch/fallback')
+os.mkdir('tests/scratch/deadline-a')
+os.mkdir('tests/scratch/deadline-b')
+os.mkdir('tests/scratch/named-origin')
+os.mkdir('tests/scratch/named')
+os.mkdir('tests/scratch/index-foo-origin')
+os.mkdir('tests/scratch/index-bar-origin')
+os.mkdir('tests/scratch/index-foo')
+os.mkdir('tests/scratch/index-bar')
+os.mkdir('tests/scratch/archive-app')
+os.mkdir('tests/scratch/archive-req')
+
+# Put a fake systemctl on the path, that logs the units it restarts, and that
+# fails to restart the units listed in the 'restart-fail' file. Units listed in
+# the 'missing' file do not exist. It reports the units listed in the 'crashed'
+# file as failed, and other units as active. If the 'offline' file exists, it
+# reports that systemd does not run.
+os.mkdir('tests/scratch/bin')
+with open('tests/scratch/bin/systemctl', 'w') as f:
+    f.write('#!/bin/sh\n'
+            'cd "$(dirname "$0")/.."\n'
+            'if [ "$1" = is-system-running ]; then\n'
+            '  if [ -f offline ]; then echo offline; exit 1; fi\n'
+            '  echo running; exit 0\n'
+            'fi\n'
+            'if [ "$1" = is-active ]; then\n'
+            '  if grep -qx "$2" crashed 2>/dev/null; then echo failed; exit 3; fi\n'
+            '  echo active; exit 0\n'
+            'fi\n'
+            'if grep -qx "$2" missing 2>/dev/null; then exit 5; fi\n'
+            'if grep -qx "$2" restart-fail 2>/dev/null; then exit 1; fi\n'
+            'echo "$1 $2" >> restart.log\n')
+os.chmod('tests/scratch/bin/systemctl', int('755', 8))
+os.environ['PATH'] = os.path.abspath('tests/scratch/bin') + ':' + os.environ['PATH']
+
+# Also serve over a unix domain socket, for 'http+unix://' origins. The socket
+# lives in the scratch directory, so start the server after creating that.
+unix_socket = 'tests/scratch/registry.sock'
+unix_httpd_thread = threading.Thread(target=run_unix_server, daemon=True)
+unix_httpd_thread.start()
 
 # Print a backtrace if the Rust program crashes.
 os.environ['RUST_BACKTRACE'] = '1'
@@ -71,6 +152,364 @@ exec('target/debug/tako', 'store',
      'tests/images/1.0.0.img', '1.0.0')
 assert os.path.exists('tests/scratch/bar-origin/manifest')
 assert os.path.exists('tests/scratch/bar-origin/store/' + img_v1_sha)
+# Without options that need a newer format, any version of Tako can read it.
+with open('tests/scratch/bar-origin/manifest', 'r') as f:
+    assert f.read().splitlines()[:2] == ['Tako Manifest 1', '']
+
+print(' * reads the output and key file from a publish config')
+with open('tests/scratch/secret.key', 'w') as f:
+    f.write(secret_key + '\n')
+with open('tests/scratch/publish.conf', 'w') as f:
+    f.write('Output=tests/scratch/baz-origin\n')
+    f.write('KeyFile=tests/scratch/secret.key\n')
+exec('target/debug/tako', 'store',
+     '--config', 'tests/scratch/publish.conf',
+     'tests/images/1.0.0.img', '1.0.0')
+assert os.path.exists('tests/scratch/baz-origin/manifest')
+assert os.path.exists('tests/scratch/baz-origin/store/' + img_v1_sha)
+
+print(' * produces identical manifests for identical inputs')
+with open('tests/scratch/bar-origin/manifest', 'rb') as f:
+    bar_manifest = f.read()
+with open('tests/scratch/baz-origin/manifest', 'rb') as f:
+    assert f.read() == bar_manifest
+
+print(' * shares one file between versions with identical images')
+os.mkdir('tests/scratch/dedup-origin')
+exec('target/debug/tako', 'store',
+     '--key', secret_key,
+     '--output', 'tests/scratch/dedup-origin',
+     'tests/images/1.0.0.img', '1.0.0')
+dedup_img = 'tests/scratch/dedup-origin/store/' + img_v1_sha
+os.chmod(dedup_img, int('644', 8))
+with open(dedup_img, 'w') as f:
+    f.write('bit rot')
+p = subprocess.run(['target/debug/tako', 'store', '--key', secret_key,
+                    '--output', 'tests/scratch/dedup-origin',
+                    'tests/images/1.0.0.img', '1.0.1'],
+                   stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+assert p.returncode == 0
+assert b'Replacing damaged image' in p.stderr
+assert os.listdir('tests/scratch/dedup-origin/store') == [img_v1_sha]
+assert exec('target/debug/tako', 'digest', dedup_img).strip() == ('sha256:' + img_v1_sha).encode('ascii')
+
+print(' * signs with every key given, for a key rotation')
+new_key_lines = exec('target/debug/tako', 'gen-key').decode('ascii').splitlines()
+new_secret_key, new_public_key = new_key_lines[1], new_key_lines[4]
+exec('target/debug/tako', 'store',
+     '--key', secret_key, '--embed-public-key',
+     '--output', 'tests/scratch/rotate-origin',
+     'tests/images/1.0.0.img', '1.0.0')
+with open('tests/scratch/rotate-origin/manifest', 'r') as f:
+    assert f.read().splitlines()[:3] == [
+        'Tako Manifest 1.1', 'PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=', '']
+# The manifest keeps the keys in later stores without the flag.
+exec('target/debug/tako', 'store',
+     '--key', secret_key, '--key', new_secret_key,
+     '--output', 'tests/scratch/rotate-origin',
+     'tests/images/1.1.0.img', '1.1.0')
+with open('tests/scratch/rotate-origin/manifest', 'r') as f:
+    rotate_manifest = f.read().splitlines()
+assert rotate_manifest[0] == 'Tako Manifest 1.2'
+assert rotate_manifest[2] == 'PublicKey=' + new_public_key
+assert rotate_manifest[-3] == ''
+
+print(' * signs with every key file in --key-dir')
+os.mkdir('tests/scratch/key-dir')
+key_files = [
+    ('a-old', secret_key + '\n'),
+    ('b-new.key', new_secret_key + '\n'),
+    ('README.md', 'Not a key.\n'),
+    ('b-new.pub', new_public_key + '\n'),
+    ('..data', 'Not a key either.\n'),
+]
+for fname, contents in key_files:
+    with open('tests/scratch/key-dir/' + fname, 'w') as f:
+        f.write(contents)
+exec('target/debug/tako', 'store',
+     '--key-dir', 'tests/scratch/key-dir',
+     '--output', 'tests/scratch/rotate-origin',
+     'tests/images/1.1.0.img', '1.1.1')
+with open('tests/scratch/rotate-origin/manifest', 'r') as f:
+    rotate_manifest = f.read().splitlines()
+assert rotate_manifest[1:3] == [
+    'PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=',
+    'PublicKey=' + new_public_key,
+]
+with open('tests/scratch/key-dir/c-broken', 'w') as f:
+    f.write('garbage\n')
+p = subprocess.run(['target/debug/tako', 'store',
+                    '--key-dir', 'tests/scratch/key-dir',
+                    '--output', 'tests/scratch/rotate-origin',
+                    'tests/images/1.1.0.img', '1.1.2'],
+                   stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+assert p.returncode == 4
+assert b'tests/scratch/key-dir/c-broken: Invalid secret key.' in p.stderr
+
+print(' * reads the secret key from the variable named by --key-env')
+os.mkdir('tests/scratch/key-env-origin')
+env = dict(os.environ, APP_SIGNING_KEY=new_secret_key, BROKEN_KEY='garbage')
+p = subprocess.run(['target/debug/tako', 'store', '--key-env', 'APP_SIGNING_KEY',
+                    '--embed-public-key', '--output', 'tests/scratch/key-env-origin',
+                    'tests/images/1.1.0.img', '1.1.3'], env=env)
+assert p.returncode == 0
+with open('tests/scratch/key-env-origin/manifest', 'r') as f:
+    key_env_manifest = f.read().splitlines()
+assert key_env_manifest[1:3] == ['PublicKey=' + new_public_key, '']
+for var, error in [('BROKEN_KEY', b'Invalid secret key.'), ('UNSET_KEY', b'The variable is not set.')]:
+    p = subprocess.run(['target/debug/tako', 'store', '--key-env', var,
+                        '--output', 'tests/scratch/key-env-origin',
+                        'tests/images/1.1.0.img', '1.1.4'],
+                       env=env, stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+    assert p.returncode == 4
+    assert b'environment variable ' + var.encode('ascii') + b': ' + error in p.stderr
+
+print(' * reads the secret key from stdin with --key-file -')
+os.mkdir('tests/scratch/key-arg-origin')
+os.mkdir('tests/scratch/key-stdin-origin')
+exec('target/debug/tako', 'store', '--key', secret_key,
+     '--output', 'tests/scratch/key-arg-origin', 'tests/images/1.1.0.img', '1.1.3')
+exec('target/debug/tako', 'store', '--key-file', '-',
+     '--output', 'tests/scratch/key-stdin-origin', 'tests/images/1.1.0.img', '1.1.3',
+     input=secret_key.encode('ascii') + b'\n')
+with open('tests/scratch/key-arg-origin/manifest', 'rb') as f:
+    key_arg_manifest = f.read()
+with open('tests/scratch/key-stdin-origin/manifest', 'rb') as f:
+    assert f.read() == key_arg_manifest
+p = subprocess.run(['target/debug/tako', 'store', '--key-file', '-',
+                    '--output', 'tests/scratch/key-stdin-origin',
+                    'tests/images/1.1.0.img', '1.1.4'],
+                   input=b'garbage\n', stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+assert p.returncode == 4
+assert b'stdin: Invalid secret key.' in p.stderr
+
+print(' * writes a generated key pair to files with gen-key')
+exec('target/debug/tako', 'gen-key',
+     '--secret-out', 'tests/scratch/gen.key', '--public-out', 'tests/scratch/gen.pub')
+assert os.stat('tests/scratch/gen.key').st_mode & 0o777 == 0o600
+with open('tests/scratch/gen.pub', 'r') as f:
+    gen_public_key = f.read().strip()
+os.mkdir('tests/scratch/gen-key-origin')
+exec('target/debug/tako', 'store', '--key-file', 'tests/scratch/gen.key', '--embed-public-key',
+     '--output', 'tests/scratch/gen-key-origin', 'tests/images/1.1.0.img', '1.1.3')
+with open('tests/scratch/gen-key-origin/manifest', 'r') as f:
+    assert f.read().splitlines()[1] == 'PublicKey=' + gen_public_key
+# An existing secret key is never replaced.
+exec('target/debug/tako', 'gen-key', '--secret-out', 'tests/scratch/gen.key', expect=7)
+# The key without a file is printed.
+gen_key_lines = exec('target/debug/tako', 'gen-key', '--public-out', 'tests/scratch/gen.pub').splitlines()
+assert gen_key_lines[0] == b'Secret key (save to an encrypted secret store):'
+assert len(gen_key_lines) == 2
+
+print(' * changes the owner and group of written files')
+uid, gid = os.getuid(), os.getgid()
+user = pwd.getpwuid(uid).pw_name
+exec('target/debug/tako', 'store',
+     '--key', secret_key,
+     '--output', 'tests/scratch/bar-origin',
+     '--owner', 'no-such-user-tako',
+     'tests/images/1.1.0.img', '1.1.0',
+     expect=4)
+with open('tests/scratch/bar-origin/manifest', 'rb') as f:
+    assert f.read() == bar_manifest
+exec('target/debug/tako', 'store',
+     '--key', secret_key,
+     '--output', 'tests/scratch/owner-origin',
+     '--owner', user, '--group', str(gid),
+     'tests/images/1.0.0.img', '1.0.0')
+for fname in ['manifest', 'store/' + img_v1_sha]:
+    st = os.stat('tests/scratch/owner-origin/' + fname)
+    assert (st.st_uid, st.st_gid) == (uid, gid)
+
+print(' * stores an image downloaded from a url')
+exec('target/debug/tako', 'store',
+     '--key', secret_key,
+     '--output', 'tests/scratch/url-origin',
+     '--expect-digest', img_v1_sha,
+     'http://127.0.0.1:8117/tests/images/1.0.0.img', '1.0.0')
+assert os.path.exists('tests/scratch/url-origin/store/' + img_v1_sha)
+assert not os.path.exists('tests/scratch/url-origin/store/image.new')
+
+print(' * rejects an image with an unexpected digest')
+exec('target/debug/tako', 'store',
+     '--key', secret_key,
+     '--output', 'tests/scratch/url-origin',
+     '--expect-digest', img_v1_sha,
+     'http://127.0.0.1:8117/tests/images/2.0.0.img', '2.0.0',
+     expect=6)
+assert os.listdir('tests/scratch/url-origin/store') == [img_v1_sha]
+
+print('tako seed')
+
+print(' * stores every version directory and skips conflicts')
+for version in ['1.0.0', '1.1.0', '2.0.0', '1-0-0']:
+    os.makedirs('tests/scratch/pile/' + version)
+    img = version.replace('-', '.')
+    shutil.copy('tests/images/{}.img'.format(img),
+                'tests/scratch/pile/{}/image'.format(version))
+out = exec('target/debug/tako', 'seed',
+           '--key', secret_key,
+           '--output', 'tests/scratch/seed-origin',
+           '--from', 'tests/scratch/pile')
+assert out.endswith(b'Stored 3 versions, skipped 1.\n')
+assert os.path.exists('tests/scratch/seed-origin/store/' + img_v1_sha)
+with open('tests/scratch/seed-origin/manifest', 'rb') as f:
+    seed_manifest = f.read()
+assert seed_manifest.count(b'\n') == 7
+
+print(' * can run again on the same directory')
+exec('target/debug/tako', 'seed',
+     '--key', secret_key,
+     '--output', 'tests/scratch/seed-origin',
+     '--from', 'tests/scratch/pile')
+with open('tests/scratch/seed-origin/manifest', 'rb') as f:
+    assert f.read() == seed_manifest
+
+print('tako --version')
+
+print(' * prints the version and the git commit as json')
+out = exec('target/debug/tako', '--version')
+assert out == b'0.0.0\n'
+info = json.loads(exec('target/debug/tako', '--version', '--format', 'json').decode('utf-8'))
+assert info['version'] == '0.0.0'
+head = subprocess.run(['git', 'rev-parse', 'HEAD'], stdout=subprocess.PIPE)
+if head.returncode == 0:
+    assert info['commit'] == head.stdout.decode('ascii').strip()
+assert info['features'] == []
+
+print('tako help')
+
+print(' * lists the exit codes')
+out = exec('target/debug/tako', 'help', 'exit-codes').decode('utf-8')
+assert [line[:5] for line in out.splitlines() if line[:5].strip().isdigit()] == ['  {}  '.format(i) for i in range(8)]
+exec('target/debug/tako', 'print-config', 'tests/scratch/does-not-exist.tako', expect=7)
+
+print(' * reports every mistake in a config at once')
+with open('tests/scratch/mistakes.tako', 'w') as f:
+    f.write('Origin=https://images.example.com/app-foo\n'
+            'ImageTimeout=later\n'
+            'Versoin=*\n')
+p = subprocess.run(['target/debug/tako', 'print-config', 'tests/scratch/mistakes.tako'],
+                   stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+assert p.returncode == 4
+assert p.stdout == b''
+errors = p.stderr.decode('utf-8').splitlines()
+assert [e.split(': ')[1] for e in errors] == [
+    'Invalid config on line 2',
+    'Invalid config on line 3',
+    'Incomplete config',
+    'Incomplete config',
+]
+
+print('tako import-key')
+
+print(' * converts a seed to a key pair')
+# Test vector 1 from RFC 8032.
+p = subprocess.run(['target/debug/tako', 'import-key', '--from', 'seed-hex', '-'],
+                   input=b'9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60\n',
+                   stdout=subprocess.PIPE)
+assert p.returncode == 0
+assert p.stdout.decode('ascii').splitlines()[4] == '11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo='
+exec('target/debug/tako', 'import-key', '--from', 'openssh', 'tests/images/1.0.0.img', expect=4)
+
+if shutil.which('ssh-keygen') is not None:
+    print(' * converts an OpenSSH key to a key pair')
+    subprocess.run(['ssh-keygen', '-q', '-t', 'ed25519', '-N', '', '-f', 'tests/scratch/id_ed25519'],
+                   check=True)
+    with open('tests/scratch/id_ed25519.pub', 'r') as f:
+        ssh_public_key = base64.b64decode(f.read().split()[1])[-32:]
+    lines = exec('target/debug/tako', 'import-key', '--from', 'openssh',
+                 'tests/scratch/id_ed25519').decode('ascii').splitlines()
+    assert base64.b64decode(lines[4]) == ssh_public_key
+
+print('tako digest')
+
+print(' * prints the digest that store records')
+out = exec('target/debug/tako', 'digest', 'tests/images/1.0.0.img')
+assert out == b'sha256:' + img_v1_sha.encode('ascii') + b'\n'
+out = exec('target/debug/tako', 'digest', '--algo', 'sha512', 'tests/images/1.0.0.img')
+assert out.startswith(b'sha512:') and len(out) == 7 + 128 + 1
+
+print('tako doctor')
+
+public_key = 'l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k='
+
+print(' * reports a healthy server directory')
+out = exec('target/debug/tako', 'doctor',
+           '--output', 'tests/scratch/baz-origin', '--key', public_key)
+assert b'signature is valid' in out
+assert b'Orphaned files: none.' in out
+
+print(' * removes orphaned files with --fix')
+orphan = 'tests/scratch/baz-origin/store/' + 'f' * 64
+with open(orphan, 'w') as f:
+    f.write('orphan')
+# Stdin is not a terminal here, so without --assume-yes, it must refuse.
+out = exec('target/debug/tako', 'doctor',
+           '--output', 'tests/scratch/baz-origin', '--fix', input=b'y\n', expect=1)
+assert b'Orphaned files (1):' in out
+assert os.path.exists(orphan)
+exec('target/debug/tako', 'doctor',
+     '--output', 'tests/scratch/baz-origin', '--fix', '--assume-yes')
+assert not os.path.exists(orphan)
+
+print(' * leaves non-files in the store alone')
+os.mkdir('tests/scratch/baz-origin/store/subdir')
+out = exec('target/debug/tako', 'doctor',
+           '--output', 'tests/scratch/baz-origin', '--fix', '--assume-yes')
+assert b'Orphaned files: none.' in out
+assert os.path.isdir('tests/scratch/baz-origin/store/subdir')
+os.rmdir('tests/scratch/baz-origin/store/subdir')
+
+print(' * refuses to fix without a valid manifest')
+with open(orphan, 'w') as f:
+    f.write('orphan')
+# The manifest does not verify with a key that did not sign it.
+out = exec('target/debug/tako', 'doctor',
+           '--output', 'tests/scratch/baz-origin', '--key', new_public_key,
+           '--fix', '--assume-yes', expect=1)
+assert b'signature is INVALID' in out
+assert os.path.exists(orphan)
+exec('target/debug/tako', 'doctor',
+     '--output', 'tests/scratch/baz-origin', '--manifest-name', 'missing',
+     '--fix', '--assume-yes', expect=1)
+assert os.path.exists('tests/scratch/baz-origin/store/' + img_v1_sha)
+os.remove(orphan)
+
+print(' * fails on a damaged image')
+baz_store_img_v1 = 'tests/scratch/baz-origin/store/' + img_v1_sha
+os.chmod(baz_store_img_v1, int('644', 8))
+with open(baz_store_img_v1, 'w') as f:
+    f.write('burrito')
+out = exec('target/debug/tako', 'doctor',
+           '--output', 'tests/scratch/baz-origin', expect=1)
+assert b'Damaged images (1):' in out
+
+print(' * honors the manifest name and the image extension')
+os.mkdir('tests/scratch/doctor-ext')
+exec('target/debug/tako', 'store', '--key', secret_key,
+     '--output', 'tests/scratch/doctor-ext', '--manifest-name', 'app.manifest',
+     '--image-ext', '.img', 'tests/images/1.0.0.img', '1.0.0')
+out = exec('target/debug/tako', 'doctor',
+           '--output', 'tests/scratch/doctor-ext', '--manifest-name', 'app.manifest',
+           '--key', public_key, '--fix', '--assume-yes')
+assert b'signature is valid' in out
+assert b'Missing images: none.' in out
+assert b'Orphaned files: none.' in out
+assert os.path.isfile('tests/scratch/doctor-ext/store/' + img_v1_sha + '.img')
+
+print('tako keys')
+
+print(' * prints the fingerprint and key of every config')
+key_sha = hashlib.sha256(base64.b64decode(public_key)).hexdigest()
+out = exec('target/debug/tako', 'keys', 'tests/config/bar.tako', 'tests/config/foo-none.tako')
+assert out.splitlines() == [
+    'tests/config/{}.tako: sha256:{} {}'.format(name, key_sha, public_key).encode('ascii')
+    for name in ['bar', 'foo-none']
+]
+
+print(' * fails on a config that cannot be read')
+exec('target/debug/tako', 'keys', 'tests/config/bar.tako', 'tests/scratch/missing.tako', expect=1)
 
 print('tako fetch')
 
@@ -82,6 +521,7 @@ print(' * fetches the manifest into an non-empty destination')
 exec('target/debug/tako', 'fetch', 'tests/config/foo-none.tako')
 assert os.path.exists('tests/scratch/foo/manifest')
 
+img_v1_1_sha = 'b48525f21afa1b3ed710063910c5d461ca1e11ed495a01168e040c4da09aad36'
 img_v2_sha = '64358f43b990c1473817773028ff27029f4d367bf06595b6948d746fece678cd'
 foo_store_img_v2 = 'tests/scratch/foo/store/' + img_v2_sha
 store_img_v2 = 'store/' + img_v2_sha
@@ -100,23 +540,1157 @@ exec('target/debug/tako', 'fetch', 'tests/config/foo-any.tako')
 # request for the image, only for the manifest.
 assert os.path.exists(foo_store_img_v2)
 assert os.readlink('tests/scratch/foo/latest') == store_img_v2
+out = exec('target/debug/tako', 'fetch', '--no-download-if-present', 'tests/config/foo-any.tako')
+assert b'The image of 2.0.0 is installed already, up to date.' in out
 
-print(' * deletes a damaged image')
-# Corrupt the file in the store. Running "tako fetch" again should detect this,
-# and delete the file (such that on a next run it would be redownloaded).
+print(' * reports a damaged installed image as drift, and repairs it with --repair')
+# Corrupt the installed file in the store. Running "tako fetch" again should
+# detect this, and leave the file for inspection, unless asked to repair it.
 os.chmod(foo_store_img_v2, int('755', 8))
 with open(foo_store_img_v2, 'w') as f:
     f.write('burrito')
 os.chmod(foo_store_img_v2, int('555', 8))
-# TODO: The expected exit code should be 1 for failure, not 101 (for panic).
-exec('target/debug/tako', 'fetch', 'tests/config/foo-any.tako', expect=101)
-assert not os.path.exists(foo_store_img_v2)
+# With --no-download-if-present, the installed image is not hashed again.
+exec('target/debug/tako', 'fetch', '--no-download-if-present', 'tests/config/foo-any.tako')
+# Verify checks the image without fetching.
+exec('target/debug/tako', 'verify', 'tests/config/foo-any.tako', '2.0.0', expect=6)
+p = subprocess.run(['target/debug/tako', 'fetch', 'tests/config/foo-any.tako'],
+                   stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+assert p.returncode == 6
+assert b'drifted from what Tako installed' in p.stderr
+with open(foo_store_img_v2, 'r') as f:
+    assert f.read() == 'burrito'
+exec('target/debug/tako', 'fetch', '--repair', 'tests/config/foo-any.tako')
+assert exec('sha256sum', foo_store_img_v2).startswith(img_v2_sha.encode('ascii'))
+assert not os.access(foo_store_img_v2, os.W_OK)
+
+print(' * verifies the images in a destination against the stored manifest')
+out = exec('target/debug/tako', 'verify', 'tests/config/foo-any.tako', '2.0.0')
+assert out == b'2.0.0: OK\n'
+out = exec('target/debug/tako', 'verify', '--all', 'tests/config/foo-any.tako')
+assert out.endswith(b'2.0.0: OK\n')
+exec('target/debug/tako', 'verify', 'tests/config/foo-any.tako', '9.9.9', expect=1)
+# A manifest that does not verify fails, whatever the images are.
+shutil.copy('tests/scratch/foo/manifest', 'tests/scratch/foo-manifest.orig')
+os.chmod('tests/scratch/foo/manifest', 0o644)
+with open('tests/scratch/foo/manifest', 'rb') as f:
+    manifest = f.read()
+with open('tests/scratch/foo/manifest', 'wb') as f:
+    f.write(manifest.replace(b'2.0.0 ', b'2.0.1 '))
+exec('target/debug/tako', 'verify', '--all', 'tests/config/foo-any.tako', expect=6)
+shutil.copy('tests/scratch/foo-manifest.orig', 'tests/scratch/foo/manifest')
+
+print(' * reports a latest that was changed by hand as drift')
+os.remove('tests/scratch/foo/latest')
+os.symlink('/tmp/some.img', 'tests/scratch/foo/latest')
+exec('target/debug/tako', 'fetch', 'tests/config/foo-any.tako', expect=6)
+assert os.readlink('tests/scratch/foo/latest') == '/tmp/some.img'
+exec('target/debug/tako', 'fetch', '--repair', 'tests/config/foo-any.tako')
+assert os.readlink('tests/scratch/foo/latest') == store_img_v2
+
+print(' * fails clearly when the CA bundle cannot be loaded')
+for ca_cert, reason in [('tests/scratch/no-such-ca.pem', b'No such file'),
+                        ('tests/config/foo-any.tako', b'Expected PEM certificates')]:
+    p = subprocess.run(['target/debug/tako', 'fetch', '--cacert', ca_cert, 'tests/config/foo-any.tako'],
+                       stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+    assert p.returncode == 4
+    assert b'Failed to load CA certificates from ' + ca_cert.encode('ascii') in p.stderr
+    assert reason in p.stderr
+exec('target/debug/tako', 'fetch', '--capath', 'tests/scratch/no-such-ca.d',
+     'tests/config/foo-any.tako', expect=4)
 
 print(' * fetches a previously stored manifest')
 exec('target/debug/tako', 'fetch', 'tests/config/bar.tako')
 assert os.path.exists('tests/scratch/bar/manifest')
 assert os.readlink('tests/scratch/bar/latest') == 'store/' + img_v1_sha
 
+print(' * continues after a failure and reports a summary')
+out = exec('target/debug/tako', 'fetch', '--format', 'json',
+           'tests/config/does-not-exist.tako',
+           'tests/config/foo-none.tako',
+           'tests/config/foo-any.tako',
+           expect=7)
+report = json.loads(out.decode('utf-8'))
+assert report['updated'] == 0
+assert report['unchanged'] == 2
+assert report['failed'] == 1
+statuses = [c['status'] for c in report['configs']]
+assert statuses == ['failed', 'no-candidate', 'unchanged']
+assert report['configs'][2]['version'] == '2.0.0'
+assert report['configs'][2]['digest'] == img_v2_sha
+assert report['configs'][0]['transfers'] == []
+manifest_transfer = report['configs'][2]['transfers'][0]
+assert manifest_transfer['uri'].endswith('/manifest')
+assert manifest_transfer['bytes'] == os.path.getsize('tests/origin/foo/manifest')
+assert manifest_transfer['seconds'] >= manifest_transfer['connect_seconds']
+# The test server speaks http/1.0, so it closes every connection.
+assert manifest_transfer['http_version'] == '1.0'
+assert manifest_transfer['new_connections'] == 1
+
+print(' * writes the installed digest with --digest-file, and removes it on failure')
+exec('target/debug/tako', 'fetch', '--digest-file', 'tests/scratch/foo.digest',
+     'tests/config/foo-any.tako')
+with open('tests/scratch/foo.digest', 'r') as f:
+    assert f.read() == 'sha256:' + img_v2_sha + '\n'
+exec('target/debug/tako', 'fetch', '--digest-file', 'tests/scratch/foo.digest',
+     'tests/config/does-not-exist.tako', expect=7)
+assert not os.path.exists('tests/scratch/foo.digest')
+
+print(' * prints shell variables with --format env')
+out = exec('target/debug/tako', 'fetch', '--format', 'env',
+           'tests/config/foo-any.tako')
+# Source the output in a shell, to check that it is valid shell syntax.
+script = out.decode('utf-8') + 'echo "$TAKO_UPDATED $TAKO_VERSION $TAKO_DIGEST"'
+out = subprocess.check_output(['sh', '-c', script])
+assert out.decode('utf-8') == '0 2.0.0 ' + img_v2_sha + '\n'
+exec('target/debug/tako', 'fetch', '--format', 'env',
+     'tests/config/foo-any.tako', 'tests/config/bar.tako', expect=4)
+
+print(' * fetches only the manifest with --manifest-only')
+with open('tests/origin/foo/manifest', 'rb') as f:
+    origin_manifest = f.read()
+out = exec('target/debug/tako', 'fetch', '--manifest-only',
+           'tests/config/foo-any.tako')
+assert out == origin_manifest
+exec('target/debug/tako', 'fetch', '--manifest-only',
+     '--out', 'tests/scratch/foo-manifest.txt',
+     'tests/config/foo-any.tako')
+with open('tests/scratch/foo-manifest.txt', 'rb') as f:
+    assert f.read() == origin_manifest
+assert not os.path.exists('tests/scratch/foo-manifest.txt.new')
+
+print(' * verifies only the manifest against a key from the command line')
+out = exec('target/debug/tako', 'fetch', '--manifest-only', '--force',
+           '--key', public_key, 'tests/config/foo-any.tako')
+assert out == origin_manifest
+exec('target/debug/tako', 'fetch', '--manifest-only',
+     '--key', public_key, 'tests/config/foo-any.tako', expect=1)
+exec('target/debug/tako', 'fetch', '--manifest-only', '--force',
+     '--key', new_public_key, 'tests/config/foo-any.tako', expect=6)
+with open('tests/scratch/foo.pub', 'w') as f:
+    f.write(public_key + '\n')
+out = exec('target/debug/tako', 'fetch', '--manifest-only', '--force',
+           '--key-file', 'tests/scratch/foo.pub', 'tests/config/foo-any.tako')
+assert out == origin_manifest
+
+print(' * fetches over a unix domain socket')
+exec('target/debug/tako', 'fetch', 'tests/config/foo-unix.tako')
+assert os.path.exists('tests/scratch/unix/store/' + img_v2_sha)
+assert os.readlink('tests/scratch/unix/latest') == store_img_v2
+
+print(' * fetches the newest version within VersionLowerBound and VersionUpperBound')
+os.mkdir('tests/scratch/bounds')
+bounds_args = [
+    '--set', 'Origin=http://127.0.0.1:8117/tests/origin/foo',
+    '--set', 'PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=',
+    '--set', 'Version=*',
+    '--set', 'Destination=tests/scratch/bounds',
+]
+exec('target/debug/tako', 'fetch', *bounds_args,
+     '--set', 'VersionLowerBound=1.1.0', '--set', 'VersionUpperBound=2.0.0')
+assert os.readlink('tests/scratch/bounds/latest') == 'store/' + img_v1_1_sha
+out = exec('target/debug/tako', 'fetch', *bounds_args,
+           '--set', 'VersionLowerBound=3.0.0', '--set', 'VersionUpperBound=4.0.0')
+assert b'The config only allows versions at least 3.0.0 and below 4.0.0. ' \
+       b'The highest available version is 2.0.0.' in out
+assert os.readlink('tests/scratch/bounds/latest') == 'store/' + img_v1_1_sha
+
+print(' * prints the urls that it would fetch with --print-url')
+out = exec('target/debug/tako', 'fetch', '--print-url',
+           '--set', 'Origin=http://[::1]:8117/app/',
+           '--set', 'PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=',
+           '--set', 'Version=*',
+           '--set', 'Destination=tests/scratch/print-url')
+assert out.decode('utf-8').splitlines() == [
+    'manifest http://[::1]:8117/app/manifest',
+    'images   http://[::1]:8117/app/store/<digest> (unresolved)',
+]
+assert not os.path.exists('tests/scratch/print-url')
+out = exec('target/debug/tako', 'fetch', '--print-url', 'tests/config/foo-unix.tako')
+assert out.decode('utf-8').splitlines()[0] == 'manifest http://localhost/tests/origin/foo/manifest'
+assert out.decode('utf-8').splitlines()[2].startswith('socket   ')
+
+def read_restart_log():
+    with open('tests/scratch/restart.log', 'r') as f:
+        return f.read().splitlines()
+
+print(' * completes pending restarts after a failed restart')
+with open('tests/scratch/restart-fail', 'w') as f:
+    f.write('b.service\n')
+exec('target/debug/tako', 'fetch', 'tests/config/foo-restart.tako', expect=1)
+assert os.readlink('tests/scratch/restart/latest') == 'store/' + img_v1_1_sha
+assert read_restart_log() == ['restart a.service']
+os.remove('tests/scratch/restart-fail')
+exec('target/debug/tako', 'fetch', 'tests/config/foo-restart.tako')
+assert read_restart_log() == ['restart a.service', 'restart b.service']
+
+print(' * does not restart units again when nothing changed')
+exec('target/debug/tako', 'fetch', 'tests/config/foo-restart.tako')
+assert read_restart_log() == ['restart a.service', 'restart b.service']
+
+print(' * replaces the latest symlink when a newer version is available')
+with open('tests/config/foo-restart.tako', 'r') as f:
+    config = f.read().replace('Version=1.*', 'Version=*')
+with open('tests/scratch/foo-restart-any.tako', 'w') as f:
+    f.write(config)
+exec('target/debug/tako', 'fetch', 'tests/scratch/foo-restart-any.tako')
+assert os.readlink('tests/scratch/restart/latest') == store_img_v2
+assert read_restart_log()[2:] == ['restart a.service', 'restart b.service']
+
+print(' * refuses to downgrade unless --allow-downgrade is passed')
+exec('target/debug/tako', 'fetch', 'tests/config/foo-restart.tako', expect=1)
+assert os.readlink('tests/scratch/restart/latest') == store_img_v2
+exec('target/debug/tako', 'fetch', '--allow-downgrade', 'tests/config/foo-restart.tako')
+assert os.readlink('tests/scratch/restart/latest') == 'store/' + img_v1_1_sha
+
+print(' * installs nothing unless the selected version is the expected version')
+restarts = read_restart_log()
+exec('target/debug/tako', 'fetch', '--expected-version', '1.1.0',
+     'tests/scratch/foo-restart-any.tako', expect=1)
+assert os.readlink('tests/scratch/restart/latest') == 'store/' + img_v1_1_sha
+assert read_restart_log() == restarts
+exec('target/debug/tako', 'fetch', '--expected-version', '1.1.0', 'tests/config/foo-restart.tako')
+
+print(' * stops units before changing latest and starts them in reverse order')
+with open('tests/scratch/stop.tako', 'w') as f:
+    f.write('Origin=http://127.0.0.1:8117/tests/origin/foo\n'
+            'PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=\n'
+            'Version=*\n'
+            'Destination=tests/scratch/stop\n'
+            'StopUnit=app.service\n'
+            'StopUnit=db.service\n'
+            'RestartUnit=c.service\n')
+with open('tests/scratch/restart-fail', 'w') as f:
+    f.write('db.service\n')
+n = len(read_restart_log())
+exec('target/debug/tako', 'fetch', 'tests/scratch/stop.tako', expect=1)
+assert not os.path.exists('tests/scratch/stop/latest')
+assert read_restart_log()[n:] == ['stop app.service']
+os.remove('tests/scratch/restart-fail')
+exec('target/debug/tako', 'fetch', 'tests/scratch/stop.tako')
+assert os.readlink('tests/scratch/stop/latest') == store_img_v2
+assert read_restart_log()[n + 1:] == [
+    'stop app.service',
+    'stop db.service',
+    'start db.service',
+    'start app.service',
+    'restart c.service',
+]
+
+print(' * refuses a config that acts on a unit that the policy does not allow')
+with open('tests/scratch/policy', 'w') as f:
+    f.write('AllowUnit=app.service\nAllowUnit=db.*\nAllowUnit=c.service\n')
+with open('tests/scratch/policy.tako', 'w') as f:
+    f.write('Origin=http://127.0.0.1:8117/tests/origin/foo\n'
+            'PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=\n'
+            'Version=*\n'
+            'Destination=tests/scratch/stop\n'
+            'StopUnit=app.service\n'
+            'RestartUnit=sshd.service\n')
+n = len(read_restart_log())
+p = subprocess.run(['target/debug/tako', 'fetch', '--policy', 'tests/scratch/policy',
+                    'tests/scratch/policy.tako'],
+                   stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+assert p.returncode == 1
+assert b'does not allow configs to act on unit sshd.service' in p.stderr
+assert read_restart_log()[n:] == []
+# The stop test above uses only allowed units.
+exec('target/debug/tako', 'fetch', '--policy', 'tests/scratch/policy', 'tests/scratch/stop.tako')
+
+print(' * fails with --verify-active if a unit does not stay active')
+with open('tests/scratch/verify.tako', 'w') as f:
+    f.write('Origin=http://127.0.0.1:8117/tests/origin/foo\n'
+            'PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=\n'
+            'Version=*\n'
+            'Destination=tests/scratch/verify\n'
+            'RestartUnit=v.service\n')
+with open('tests/scratch/crashed', 'w') as f:
+    f.write('v.service\n')
+n = len(read_restart_log())
+p = subprocess.run(['target/debug/tako', 'fetch', '--verify-active', '--verify-grace', '0',
+                    'tests/scratch/verify.tako'],
+                   stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+assert p.returncode == 1
+assert b'v.service is not active' in p.stderr
+os.remove('tests/scratch/crashed')
+exec('target/debug/tako', 'fetch', '--verify-active', '--verify-grace', '0',
+     'tests/scratch/verify.tako')
+assert read_restart_log()[n:] == ['restart v.service', 'restart v.service']
+
+def write_group_configs(required_version):
+    base = ('Origin=http://127.0.0.1:8117/tests/origin/foo\n'
+            'PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=\n')
+    with open('tests/scratch/group.tako', 'w') as f:
+        f.write(base + 'Version=*\nDestination=tests/scratch/group\n'
+                'Requires=group-req.tako\n')
+    with open('tests/scratch/group-req.tako', 'w') as f:
+        f.write(base + 'Version=' + required_version + '\n'
+                'Destination=tests/scratch/group-req\n')
+
+print(' * applies nothing if a required config cannot be fetched')
+write_group_configs('4.0')
+exec('target/debug/tako', 'fetch', 'tests/scratch/group.tako', expect=1)
+assert not os.path.exists('tests/scratch/group/latest')
+assert not os.path.exists('tests/scratch/group-req/latest')
+
+print(' * applies required configs together with the config')
+write_group_configs('1.*')
+exec('target/debug/tako', 'fetch', 'tests/scratch/group.tako')
+assert os.readlink('tests/scratch/group/latest') == store_img_v2
+assert os.readlink('tests/scratch/group-req/latest') == 'store/' + img_v1_1_sha
+
+print(' * installs the versions that a signed index pins')
+for name, img in [('foo', '1.0.0'), ('foo', '1.1.0'), ('bar', '1.0.0')]:
+    exec('target/debug/tako', 'store', '--key', secret_key,
+         '--output', 'tests/scratch/index-' + name + '-origin', '--name', 'app-' + name,
+         'tests/images/' + img + '.img', img)
+def write_index(foo_version):
+    exec('target/debug/tako', 'index', '--key', secret_key,
+         '--output', 'tests/scratch/index',
+         'tests/scratch/index-foo-origin', foo_version,
+         'tests/scratch/index-bar-origin', '1.0.0')
+write_index('1.0.0')
+for name, extra in [('foo', 'Requires=index-bar.tako\n'), ('bar', '')]:
+    with open('tests/scratch/index-' + name + '.tako', 'w') as f:
+        f.write('Origin=http://127.0.0.1:8117/tests/scratch/index-' + name + '-origin\n'
+                'PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=\n'
+                'Version=*\n'
+                'Destination=tests/scratch/index-' + name + '\n'
+                'Name=app-' + name + '\n'
+                'Index=http://127.0.0.1:8117/tests/scratch/index\n' + extra)
+# The index pins 1.0.0, even though 1.1.0 is newer.
+exec('target/debug/tako', 'fetch', 'tests/scratch/index-foo.tako')
+assert os.readlink('tests/scratch/index-foo/latest') == 'store/' + img_v1_sha
+assert os.readlink('tests/scratch/index-bar/latest') == 'store/' + img_v1_sha
+
+print(' * installs nothing if a manifest changed after the index was signed')
+write_index('1.1.0')
+exec('target/debug/tako', 'store', '--key', secret_key,
+     '--output', 'tests/scratch/index-bar-origin',
+     'tests/images/1.1.0.img', '1.1.0')
+p = subprocess.run(['target/debug/tako', 'fetch', 'tests/scratch/index-foo.tako'],
+                   stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+assert p.returncode == 6
+assert b'differs from the manifest that the index pins' in p.stderr
+assert os.readlink('tests/scratch/index-foo/latest') == 'store/' + img_v1_sha
+assert os.readlink('tests/scratch/index-bar/latest') == 'store/' + img_v1_sha
+
+write_index('1.1.0')
+exec('target/debug/tako', 'fetch', 'tests/scratch/index-foo.tako')
+assert os.readlink('tests/scratch/index-foo/latest') == 'store/' + img_v1_1_sha
+
+print(' * fetches the configs in a signed config archive')
+with tarfile.open('tests/scratch/configs.tar', 'w', format=tarfile.USTAR_FORMAT) as tar:
+    for name, version, extra in [('app', '*', 'Requires=req.tako\n'), ('req', '1.*', '')]:
+        config = ('Origin=http://127.0.0.1:8117/tests/origin/foo\n'
+                  'PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=\n'
+                  'Version=' + version + '\n'
+                  'Destination=tests/scratch/archive-' + name + '\n' + extra).encode('utf-8')
+        info = tarfile.TarInfo('etc/' + name + '.tako')
+        info.size = len(config)
+        tar.addfile(info, io.BytesIO(config))
+exec('target/debug/tako', 'sign-archive', '--key', secret_key,
+     '--output', 'tests/scratch/configs.signed', 'tests/scratch/configs.tar')
+out = exec('target/debug/tako', 'fetch',
+           '--config-archive', 'tests/scratch/configs.signed',
+           '--archive-key', public_key)
+assert b'tests/scratch/configs.signed/etc/app.tako' in out
+assert os.readlink('tests/scratch/archive-app/latest') == store_img_v2
+assert os.readlink('tests/scratch/archive-req/latest') == 'store/' + img_v1_1_sha
+
+print(' * refuses a config archive that was modified or signed with another key')
+with open('tests/scratch/configs.signed', 'rb') as f:
+    signed = f.read()
+with open('tests/scratch/configs.tampered', 'wb') as f:
+    f.write(signed.replace(b'Version=1.*', b'Version=2.*'))
+exec('target/debug/tako', 'fetch',
+     '--config-archive', 'tests/scratch/configs.tampered',
+     '--archive-key', public_key,
+     expect=6)
+exec('target/debug/tako', 'fetch',
+     '--config-archive', 'tests/scratch/configs.signed',
+     '--archive-key', new_public_key,
+     expect=6)
+with gzip.open('tests/scratch/configs.tar.gz', 'wb') as f:
+    f.write(open('tests/scratch/configs.tar', 'rb').read())
+exec('target/debug/tako', 'sign-archive', '--key', secret_key,
+     '--output', 'tests/scratch/configs.gz.signed', 'tests/scratch/configs.tar.gz',
+     expect=1)
+
+print(' * writes metrics and keeps counters across runs')
+metrics_file = 'tests/scratch/tako.prom'
+for _ in range(2):
+    exec('target/debug/tako', 'fetch', '--metrics-file', metrics_file,
+         'tests/config/does-not-exist.tako',
+         'tests/config/bar.tako',
+         expect=7)
+with open(metrics_file, 'r') as f:
+    metrics = f.read().splitlines()
+assert 'tako_fetch_errors_total{config="tests/config/does-not-exist.tako"} 2' in metrics
+assert 'tako_fetch_errors_total{config="tests/config/bar.tako"} 0' in metrics
+assert 'tako_installed_info{config="tests/config/bar.tako",version="1.0.0"} 1' in metrics
+assert any(m.startswith('tako_last_success_timestamp{config="tests/config/bar.tako"}') for m in metrics)
+assert not any(m.startswith('tako_last_success_timestamp{config="tests/config/does-not-exist.tako"}') for m in metrics)
+
+print(' * downloads images from the image base in the manifest')
+exec('target/debug/tako', 'store',
+     '--key', secret_key,
+     '--output', 'tests/scratch/split-origin',
+     '--image-base', 'http://127.0.0.1:8117/tests/scratch/split-cdn/',
+     'tests/images/1.0.0.img', '1.0.0')
+# Serve the images from elsewhere than the manifest.
+shutil.move('tests/scratch/split-origin/store', 'tests/scratch/split-cdn/store')
+with open('tests/scratch/split.tako', 'w') as f:
+    f.write('Origin=http://127.0.0.1:8117/tests/scratch/split-origin\n'
+            'PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=\n'
+            'Version=*\n'
+            'Destination=tests/scratch/split\n')
+exec('target/debug/tako', 'fetch', 'tests/scratch/split.tako')
+assert os.readlink('tests/scratch/split/latest') == 'store/' + img_v1_sha
+
+print(' * refuses a manifest that declares a different name than the config')
+exec('target/debug/tako', 'store',
+     '--key', secret_key,
+     '--output', 'tests/scratch/named-origin',
+     '--name', 'app-foo',
+     'tests/images/1.0.0.img', '1.0.0')
+# A later store without --name keeps the name.
+exec('target/debug/tako', 'store',
+     '--key', secret_key,
+     '--output', 'tests/scratch/named-origin',
+     'tests/images/1.1.0.img', '1.1.0')
+with open('tests/scratch/named-origin/manifest', 'r') as f:
+    assert '\nName=app-foo\n' in f.read()
+named_args = [
+    '--set', 'Origin=http://127.0.0.1:8117/tests/scratch/named-origin',
+    '--set', 'PublicKey=' + public_key,
+    '--set', 'Version=*',
+    '--set', 'Destination=tests/scratch/named',
+]
+p = subprocess.run(['target/debug/tako', 'fetch'] + named_args + ['--set', 'Name=app-bar'],
+                   stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+assert p.returncode == 6
+assert b'image app-bar, but the origin serves image app-foo' in p.stderr
+assert not os.path.exists('tests/scratch/named/latest')
+# A manifest without a name does not satisfy a config that expects one.
+p = subprocess.run(['target/debug/tako', 'fetch',
+                    '--set', 'Origin=http://127.0.0.1:8117/tests/scratch/split-origin',
+                    '--set', 'PublicKey=' + public_key,
+                    '--set', 'Version=*',
+                    '--set', 'Destination=tests/scratch/named',
+                    '--set', 'Name=app-foo'],
+                   stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+assert p.returncode == 6
+assert b'declares no name' in p.stderr
+exec('target/debug/tako', 'fetch', *(named_args + ['--set', 'Name=app-foo']))
+assert os.readlink('tests/scratch/named/latest') == 'store/' + img_v1_1_sha
+
+print(' * trusts the key on first use with --tofu, and rejects a changed key')
+with open('tests/scratch/tofu.tako', 'w') as f:
+    f.write('Origin=http://127.0.0.1:8117/tests/scratch/tofu-origin\n'
+            'Version=*\n'
+            'Destination=tests/scratch/tofu\n')
+os.mkdir('tests/scratch/tofu-origin')
+exec('target/debug/tako', 'store', '--key', secret_key,
+     '--output', 'tests/scratch/tofu-origin', 'tests/images/1.0.0.img', '1.0.0')
+# Without --embed-public-key, the manifest has no key to trust.
+p = subprocess.run(['target/debug/tako', 'fetch', '--tofu', 'tests/scratch/tofu.tako'],
+                   stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+assert p.returncode != 0
+assert b'does not contain a PublicKey field' in p.stderr
+exec('target/debug/tako', 'store', '--key', secret_key, '--embed-public-key',
+     '--output', 'tests/scratch/tofu-origin', 'tests/images/1.0.0.img', '1.0.0')
+exec('target/debug/tako', 'fetch', 'tests/scratch/tofu.tako', expect=4)
+assert not os.path.exists('tests/scratch/tofu/trusted-key')
+exec('target/debug/tako', 'fetch', '--tofu', 'tests/scratch/tofu.tako')
+assert os.readlink('tests/scratch/tofu/latest') == 'store/' + img_v1_sha
+with open('tests/scratch/tofu/trusted-key', 'r') as f:
+    assert f.read().startswith('Fingerprint=')
+exec('target/debug/tako', 'fetch', '--tofu', 'tests/scratch/tofu.tako')
+os.remove('tests/scratch/tofu/trusted-key')
+with open('tests/scratch/tofu/trusted-key', 'w') as f:
+    f.write('Fingerprint=' + 'f' * 64 + '\n')
+p = subprocess.run(['target/debug/tako', 'fetch', '--tofu', 'tests/scratch/tofu.tako'],
+                   stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+assert p.returncode == 6
+assert b'HAS CHANGED' in p.stderr
+
+print(' * reuses a cached manifest if the origin reports it unchanged')
+shutil.copytree('tests/scratch/bar-origin', 'tests/scratch/cached-origin')
+with open('tests/scratch/cached.tako', 'w') as f:
+    f.write('Origin=http://127.0.0.1:8117/tests/scratch/cached-origin\n'
+            'PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=\n'
+            'Version=*\n'
+            'Destination=tests/scratch/cached\n')
+exec('target/debug/tako', 'fetch', '--cache-dir', 'tests/scratch/cache',
+     'tests/scratch/cached.tako')
+assert len(os.listdir('tests/scratch/cache')) == 1
+# Damage the manifest, but keep its modification time, so the server still
+# reports it as not modified. Only a fetch that uses the cache succeeds.
+cached_origin_manifest = 'tests/scratch/cached-origin/manifest'
+mtime = os.stat(cached_origin_manifest).st_mtime
+os.chmod(cached_origin_manifest, int('644', 8))
+with open(cached_origin_manifest, 'w') as f:
+    f.write('garbage')
+os.utime(cached_origin_manifest, (mtime, mtime))
+exec('target/debug/tako', 'fetch', '--cache-dir', 'tests/scratch/cache',
+     'tests/scratch/cached.tako')
+exec('target/debug/tako', 'fetch', '--cache-dir', 'tests/scratch/cache', '--no-cache',
+     'tests/scratch/cached.tako', expect=1)
+
+print(' * fetches a config given with --set')
+os.mkdir('tests/scratch/args')
+exec('target/debug/tako', 'fetch',
+     '--set', 'Origin=http://127.0.0.1:8117/tests/origin/foo',
+     '--set', 'PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=',
+     '--set', 'Version=*',
+     '--set', 'Destination=tests/scratch/args')
+assert os.readlink('tests/scratch/args/latest') == store_img_v2
+exec('target/debug/tako', 'fetch', '--set', 'Origni=http://127.0.0.1:8117', expect=4)
+
+print(' * fetches without fsync when asked to')
+os.mkdir('tests/scratch/no-fsync')
+exec('target/debug/tako', 'fetch', '--no-fsync',
+     '--set', 'Origin=http://127.0.0.1:8117/tests/origin/foo',
+     '--set', 'PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=',
+     '--set', 'Version=*',
+     '--set', 'Destination=tests/scratch/no-fsync',
+     '--set', 'Fsync=false')
+assert os.readlink('tests/scratch/no-fsync/latest') == store_img_v2
+exec('target/debug/tako', 'fetch', '--set', 'Fsync=maybe', expect=4)
+
+print(' * fetches with a small --write-buffer-size')
+os.mkdir('tests/scratch/small-buffer')
+exec('target/debug/tako', 'fetch', '--write-buffer-size', '1K',
+     '--set', 'Origin=http://127.0.0.1:8117/tests/origin/foo',
+     '--set', 'PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=',
+     '--set', 'Version=*',
+     '--set', 'Destination=tests/scratch/small-buffer')
+assert os.readlink('tests/scratch/small-buffer/latest') == store_img_v2
+
+print(' * fetches from an OCI image reference origin with the tag as version')
+os.mkdir('tests/scratch/oci')
+exec('target/debug/tako', 'fetch',
+     '--set', 'Origin=oci://127.0.0.1:8117/tests/origin/foo:1.1.0',
+     '--set', 'PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=',
+     '--set', 'Destination=tests/scratch/oci')
+assert os.readlink('tests/scratch/oci/latest') == 'store/' + img_v1_1_sha
+out = exec('target/debug/tako', 'fetch', '--print-url',
+           '--set', 'Origin=oci://127.0.0.1:8117/tests/origin/foo:1.1.0',
+           '--set', 'PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=',
+           '--set', 'Destination=tests/scratch/oci')
+assert b'manifest http://127.0.0.1:8117/tests/origin/foo/manifest\n' in out
+exec('target/debug/tako', 'fetch',
+     '--set', 'Origin=oci://127.0.0.1:8117/tests/origin/foo:1.1.0',
+     '--set', 'Version=*',
+     '--set', 'Destination=tests/scratch/oci', expect=4)
+
+print(' * accepts a manifest signed with multiple keys with either key')
+for key in [public_key, new_public_key]:
+    exec('target/debug/tako', 'fetch',
+         '--set', 'Origin=http://127.0.0.1:8117/tests/scratch/rotate-origin',
+         '--set', 'PublicKey=' + key,
+         '--set', 'Version=*',
+         '--set', 'Destination=tests/scratch/rotate')
+    assert os.readlink('tests/scratch/rotate/latest') == 'store/' + img_v1_1_sha
+
+print(' * rekeys every manifest in a tree and reports failures')
+for server_dir in ['a', 'nested/b', 'c']:
+    os.makedirs('tests/scratch/rekey/' + server_dir)
+exec('target/debug/tako', 'store', '--key', secret_key, '--embed-public-key',
+     '--output', 'tests/scratch/rekey/a', 'tests/images/1.0.0.img', '1.0.0')
+exec('target/debug/tako', 'store', '--key', secret_key,
+     '--output', 'tests/scratch/rekey/nested/b', 'tests/images/1.1.0.img', '1.1.0')
+exec('target/debug/tako', 'store', '--key', new_secret_key,
+     '--output', 'tests/scratch/rekey/c', 'tests/images/1.0.0.img', '1.0.0')
+# A manifest that does not verify with the old key fails, the others are
+# rekeyed regardless.
+with open('tests/scratch/rekey/c/manifest', 'rb') as f:
+    manifest_c = f.read()
+with open('tests/scratch/rekey/c/manifest', 'wb') as f:
+    f.write(manifest_c.replace(b'1.0.0', b'1.0.1'))
+# Stdin is not a terminal here, so without --assume-yes, nothing changes.
+out = exec('target/debug/tako', 'rekey-all', '--root', 'tests/scratch/rekey',
+           '--old-key', public_key, '--new-key', new_secret_key, expect=1)
+assert b'  tests/scratch/rekey/nested/b/manifest\n' in out
+assert b'Rekeyed' not in out
+p = subprocess.run(['target/debug/tako', 'rekey-all', '--root', 'tests/scratch/rekey',
+                    '--old-key', public_key, '--new-key', new_secret_key, '--assume-yes'],
+                   stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+assert p.returncode == 1
+assert b'Rekeyed tests/scratch/rekey/a/manifest.' in p.stdout
+assert b'Rekeyed tests/scratch/rekey/nested/b/manifest.' in p.stdout
+assert b'Failed to rekey tests/scratch/rekey/c/manifest' in p.stderr
+# Once the damaged manifest is restored, running again finishes the job.
+os.chmod('tests/scratch/rekey/c/manifest', 0o644)
+with open('tests/scratch/rekey/c/manifest', 'wb') as f:
+    f.write(manifest_c)
+out = exec('target/debug/tako', 'rekey-all', '--root', 'tests/scratch/rekey',
+           '--old-key', public_key, '--new-key', new_secret_key, '-y')
+assert b'Rekeyed 0 manifests, skipped 3, failed 0.' in out
+# With --manifest-name, only manifests with that name count.
+exec('target/debug/tako', 'store', '--key', secret_key,
+     '--output', 'tests/scratch/rekey/nested', '--manifest-name', 'app.manifest',
+     'tests/images/1.0.0.img', '1.0.0')
+out = exec('target/debug/tako', 'rekey-all', '--root', 'tests/scratch/rekey',
+           '--manifest-name', 'app.manifest',
+           '--old-key', public_key, '--new-key', new_secret_key, '-y')
+assert b'  tests/scratch/rekey/nested/app.manifest\n' in out
+assert b'Rekeyed 1 manifests, skipped 0, failed 0.' in out
+os.mkdir('tests/scratch/rekey-fetch')
+exec('target/debug/tako', 'fetch',
+     '--set', 'Origin=http://127.0.0.1:8117/tests/scratch/rekey/nested/b',
+     '--set', 'PublicKey=' + new_public_key,
+     '--set', 'Version=*',
+     '--set', 'Destination=tests/scratch/rekey-fetch')
+assert os.readlink('tests/scratch/rekey-fetch/latest') == 'store/' + img_v1_1_sha
+
+print(' * prints the bytes that the signatures cover with canonicalize')
+with open('tests/scratch/rekey/a/manifest', 'rb') as f:
+    manifest_a = f.read()
+out = exec('target/debug/tako', 'canonicalize', '--output', 'tests/scratch/rekey/a')
+# The signature is the last line, it covers everything before it.
+assert manifest_a == out + manifest_a.splitlines(True)[-1]
+assert b'PublicKey=' + new_public_key.encode('ascii') + b'\n' in out
+out = exec('target/debug/tako', 'canonicalize', '--output', 'tests/scratch/rekey/a',
+           '--key', public_key, '--key', new_public_key)
+assert out.startswith(b'Tako Manifest 1.2\n')
+assert b'PublicKey=' + public_key.encode('ascii') + b'\n' in out
+exec('target/debug/tako', 'canonicalize', '--output', 'tests/scratch/rekey', expect=1)
+
+print(' * falls back to an older version with --fallback-to-older')
+exec('target/debug/tako', 'store',
+     '--key', secret_key,
+     '--output', 'tests/scratch/fallback-origin',
+     'tests/images/1.0.0.img', '1.0.0')
+exec('target/debug/tako', 'store',
+     '--key', secret_key,
+     '--output', 'tests/scratch/fallback-origin',
+     'tests/images/1.1.0.img', '1.1.0')
+fallback_img_v1_1 = 'tests/scratch/fallback-origin/store/' + img_v1_1_sha
+os.chmod(fallback_img_v1_1, int('644', 8))
+with open(fallback_img_v1_1, 'w') as f:
+    f.write('broken release')
+fallback_args = [
+    '--set', 'Origin=http://127.0.0.1:8117/tests/scratch/fallback-origin',
+    '--set', 'PublicKey=' + public_key,
+    '--set', 'Version=1.*',
+    '--set', 'Destination=tests/scratch/fallback',
+]
+exec('target/debug/tako', 'fetch', *fallback_args, expect=6)
+assert not os.path.exists('tests/scratch/fallback/latest')
+p = subprocess.run(['target/debug/tako', 'fetch', '--fallback-to-older'] + fallback_args,
+                   stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+assert p.returncode == 0
+assert b'Skipping version 1.1.0' in p.stderr
+assert os.readlink('tests/scratch/fallback/latest') == 'store/' + img_v1_sha
+
+print(' * annotates a stored version and reports annotations in json')
+os.mkdir('tests/scratch/annotate-origin')
+os.mkdir('tests/scratch/annotate')
+exec('target/debug/tako', 'store',
+     '--key', secret_key,
+     '--output', 'tests/scratch/annotate-origin',
+     'tests/images/1.0.0.img', '1.0.0')
+exec('target/debug/tako', 'store',
+     '--key', secret_key,
+     '--output', 'tests/scratch/annotate-origin',
+     '--annotate', 'label=stable', '--annotate', 'note=Fixes the thing.',
+     '1.0.0')
+with open('tests/scratch/annotate-origin/manifest', 'rb') as f:
+    annotated_manifest = f.read()
+assert b'\n  Annotation=label=stable\n  Annotation=note=Fixes the thing.\n' in annotated_manifest
+exec('target/debug/tako', 'store',
+     '--key', secret_key,
+     '--output', 'tests/scratch/annotate-origin',
+     '--annotate', 'label=stable',
+     '2.0.0', expect=1)
+with open('tests/scratch/annotate-origin/manifest', 'rb') as f:
+    assert f.read() == annotated_manifest
+out = exec('target/debug/tako', 'fetch', '--format', 'json',
+           '--set', 'Origin=http://127.0.0.1:8117/tests/scratch/annotate-origin',
+           '--set', 'PublicKey=' + public_key,
+           '--set', 'Version=*',
+           '--set', 'Destination=tests/scratch/annotate')
+result = json.loads(out.decode('utf-8'))['configs'][0]
+assert result['annotations'] == {'label': 'stable', 'note': 'Fixes the thing.'}
+
+print(' * stores release notes with a version and prints them when fetching')
+os.mkdir('tests/scratch/notes-origin')
+os.mkdir('tests/scratch/notes')
+exec('target/debug/tako', 'store',
+     '--key', secret_key,
+     '--output', 'tests/scratch/notes-origin',
+     '--notes', 'First release.',
+     'tests/images/1.0.0.img', '1.0.0')
+exec('target/debug/tako', 'store',
+     '--key', secret_key,
+     '--output', 'tests/scratch/notes-origin',
+     'tests/images/1.1.0.img', '1.1.0')
+exec('target/debug/tako', 'store',
+     '--key', secret_key,
+     '--output', 'tests/scratch/notes-origin',
+     '--notes', 'Fixes the thing.',
+     '1.1.0')
+with open('tests/scratch/notes-origin/manifest', 'rb') as f:
+    notes_manifest = f.read()
+assert b'\n  Annotation=notes=First release.\n1.1.0 ' in notes_manifest
+assert notes_manifest.endswith(b'\n  Annotation=notes=Fixes the thing.\n\n' + notes_manifest.split(b'\n')[-2] + b'\n')
+out = exec('target/debug/tako', 'fetch',
+           '--set', 'Origin=http://127.0.0.1:8117/tests/scratch/notes-origin',
+           '--set', 'PublicKey=' + public_key,
+           '--set', 'Version=*',
+           '--set', 'Destination=tests/scratch/notes')
+assert b'Notes for 1.1.0: Fixes the thing.\n' in out
+
+print(' * installs nothing if the verify command rejects the image')
+os.mkdir('tests/scratch/vetoed')
+vetoed_args = [
+    '--set', 'Origin=http://127.0.0.1:8117/tests/scratch/fallback-origin',
+    '--set', 'PublicKey=' + public_key,
+    '--set', 'Version=1.0.0',
+    '--set', 'Destination=tests/scratch/vetoed',
+]
+exec('target/debug/tako', 'fetch', *vetoed_args,
+     '--set', 'VerifyCmd=test "$TAKO_VERSION" != 1.0.0', expect=1)
+assert os.listdir('tests/scratch/vetoed/store') == []
+assert not os.path.exists('tests/scratch/vetoed/latest')
+verify_cmd = ('test "$TAKO_DIGEST" = {} && '
+              'test "$(sha256sum < "$1" | cut -d " " -f 1)" = "$TAKO_DIGEST"').format(img_v1_sha)
+exec('target/debug/tako', 'fetch', *vetoed_args, '--set', 'VerifyCmd=' + verify_cmd)
+assert os.readlink('tests/scratch/vetoed/latest') == 'store/' + img_v1_sha
+
+print(' * keeps rejected downloads with --preserve-temp')
+shutil.copytree('tests/scratch/bar-origin', 'tests/scratch/wrong-origin')
+wrong_origin_img_v1 = 'tests/scratch/wrong-origin/store/' + img_v1_sha
+os.chmod(wrong_origin_img_v1, int('644', 8))
+with open(wrong_origin_img_v1, 'w') as f:
+    f.write('<html>Not Found</html>')
+with open('tests/scratch/wrong.tako', 'w') as f:
+    f.write('Origin=http://127.0.0.1:8117/tests/scratch/wrong-origin\n'
+            'PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=\n'
+            'Version=*\n'
+            'Destination=tests/scratch/wrong\n')
+exec('target/debug/tako', 'fetch', 'tests/scratch/wrong.tako', expect=6)
+assert os.listdir('tests/scratch/wrong/store') == []
+exec('target/debug/tako', 'fetch', '--preserve-temp', 'tests/scratch/wrong.tako', expect=6)
+assert os.listdir('tests/scratch/wrong/store') == [img_v1_sha + '.rejected']
+with open('tests/scratch/wrong/store/' + img_v1_sha + '.rejected', 'r') as f:
+    assert f.read() == '<html>Not Found</html>'
+os.chmod('tests/scratch/wrong-origin/manifest', int('644', 8))
+with open('tests/scratch/wrong-origin/manifest', 'w') as f:
+    f.write('garbage')
+exec('target/debug/tako', 'fetch', '--preserve-temp', 'tests/scratch/wrong.tako', expect=1)
+with open('tests/scratch/wrong/manifest.rejected', 'r') as f:
+    assert f.read() == 'garbage'
+
+print(' * rejects a downloaded image with one corrupted byte, and deletes it')
+shutil.copytree('tests/scratch/bar-origin', 'tests/scratch/flip-origin')
+flip_origin_img_v1 = 'tests/scratch/flip-origin/store/' + img_v1_sha
+os.chmod(flip_origin_img_v1, int('644', 8))
+with open(flip_origin_img_v1, 'rb') as f:
+    flipped = bytearray(f.read())
+flipped[len(flipped) // 2] ^= 0x01
+with open(flip_origin_img_v1, 'wb') as f:
+    f.write(flipped)
+os.mkdir('tests/scratch/flip')
+p = subprocess.run(['target/debug/tako', 'fetch',
+                    '--set', 'Origin=http://127.0.0.1:8117/tests/scratch/flip-origin',
+                    '--set', 'PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=',
+                    '--set', 'Version=*',
+                    '--set', 'Destination=tests/scratch/flip'],
+                   stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+assert p.returncode == 6
+expected_msg = 'Downloaded image does not match the manifest. Expected digest {}, got {}.'.format(
+    img_v1_sha, hashlib.sha256(flipped).hexdigest())
+assert expected_msg.encode('ascii') in p.stderr
+assert os.listdir('tests/scratch/flip/store') == []
+assert not os.path.exists('tests/scratch/flip/latest')
+
+print(' * keeps the installed version when a later download fails')
+os.mkdir('tests/scratch/keep-origin')
+os.mkdir('tests/scratch/keep')
+keep_args = [
+    '--set', 'Origin=http://127.0.0.1:8117/tests/scratch/keep-origin',
+    '--set', 'PublicKey=' + public_key,
+    '--set', 'Version=*',
+    '--set', 'Destination=tests/scratch/keep',
+]
+def list_leftovers(destination):
+    names = os.listdir(destination) + os.listdir(os.path.join(destination, 'store'))
+    return [name for name in names if name.endswith('.new')]
+exec('target/debug/tako', 'store', '--key', secret_key,
+     '--output', 'tests/scratch/keep-origin', 'tests/images/1.0.0.img', '1.0.0')
+exec('target/debug/tako', 'fetch', *keep_args)
+assert os.readlink('tests/scratch/keep/latest') == 'store/' + img_v1_sha
+assert list_leftovers('tests/scratch/keep') == []
+# Truncate the new image at the origin, so its download fails the digest check
+# after it was written to the store under a temporary name.
+exec('target/debug/tako', 'store', '--key', secret_key,
+     '--output', 'tests/scratch/keep-origin', 'tests/images/2.0.0.img', '2.0.0')
+keep_origin_img_v2 = 'tests/scratch/keep-origin/store/' + img_v2_sha
+os.chmod(keep_origin_img_v2, int('644', 8))
+with open(keep_origin_img_v2, 'r+b') as f:
+    f.truncate(10)
+exec('target/debug/tako', 'fetch', *keep_args, expect=6)
+assert os.readlink('tests/scratch/keep/latest') == 'store/' + img_v1_sha
+with open('tests/scratch/keep/store/' + img_v1_sha, 'rb') as f:
+    assert hashlib.sha256(f.read()).hexdigest() == img_v1_sha
+assert os.listdir('tests/scratch/keep/store') == [img_v1_sha]
+assert list_leftovers('tests/scratch/keep') == []
+# Once the origin is repaired, the next run installs the new version.
+os.chmod(keep_origin_img_v2, int('644', 8))
+shutil.copyfile('tests/images/2.0.0.img', keep_origin_img_v2)
+exec('target/debug/tako', 'fetch', *keep_args)
+assert os.readlink('tests/scratch/keep/latest') == 'store/' + img_v2_sha
+assert list_leftovers('tests/scratch/keep') == []
+
+print(' * does not restart units for a re-tag unless --force-restart')
+os.mkdir('tests/scratch/retag-origin')
+os.mkdir('tests/scratch/retag')
+retag_args = [
+    '--set', 'Origin=http://127.0.0.1:8117/tests/scratch/retag-origin',
+    '--set', 'PublicKey=' + public_key,
+    '--set', 'Version=1.*',
+    '--set', 'Destination=tests/scratch/retag',
+    '--set', 'RestartUnit=retag.service',
+]
+for version in ['1.0.0', '1.0.1', '1.0.2']:
+    exec('target/debug/tako', 'store',
+         '--key', secret_key,
+         '--output', 'tests/scratch/retag-origin',
+         'tests/images/1.0.0.img', version)
+    n = len(read_restart_log())
+    if version == '1.0.2':
+        exec('target/debug/tako', 'fetch', '--force-restart', *retag_args)
+        assert read_restart_log()[n:] == ['restart retag.service']
+    else:
+        out = exec('target/debug/tako', 'fetch', *retag_args)
+        assert read_restart_log()[n:] == (['restart retag.service'] if version == '1.0.0' else [])
+        assert (b'Content of 1.0.1 is unchanged from 1.0.0, skipping restart.' in out) == (version == '1.0.1')
+    with open('tests/scratch/retag/state', 'r') as f:
+        assert f.read().startswith('Version={}\n'.format(version))
+
+print(' * does not restart units on a first install with --no-restart-on-first-install')
+os.mkdir('tests/scratch/first')
+first_args = [
+    '--set', 'Origin=http://127.0.0.1:8117/tests/scratch/retag-origin',
+    '--set', 'PublicKey=' + public_key,
+    '--set', 'Version=1.0.0',
+    '--set', 'Destination=tests/scratch/first',
+    '--set', 'RestartUnit=first.service',
+]
+n = len(read_restart_log())
+exec('target/debug/tako', 'fetch', '--no-restart-on-first-install', *first_args)
+assert os.readlink('tests/scratch/first/latest') == 'store/' + img_v1_sha
+assert read_restart_log()[n:] == []
+# The units count as up to date, so a later run does not restart them either.
+exec('target/debug/tako', 'fetch', *first_args)
+assert read_restart_log()[n:] == []
+exec('target/debug/tako', 'store',
+     '--key', secret_key,
+     '--output', 'tests/scratch/retag-origin',
+     'tests/images/1.1.0.img', '1.1.0')
+first_args[5] = 'Version=1.1.0'
+exec('target/debug/tako', 'fetch', '--no-restart-on-first-install', *first_args)
+assert read_restart_log()[n:] == ['restart first.service']
+
+print(' * skips restart units that do not exist with --allow-missing-restart-unit')
+os.mkdir('tests/scratch/missing-unit')
+with open('tests/scratch/missing', 'w') as f:
+    f.write('gone.service\n')
+missing_args = [
+    '--set', 'Origin=http://127.0.0.1:8117/tests/scratch/retag-origin',
+    '--set', 'PublicKey=' + public_key,
+    '--set', 'Version=1.0.0',
+    '--set', 'Destination=tests/scratch/missing-unit',
+    '--set', 'RestartUnit=gone.service',
+    '--set', 'RestartUnit=here.service',
+]
+n = len(read_restart_log())
+exec('target/debug/tako', 'fetch', *missing_args, expect=1)
+assert read_restart_log()[n:] == []
+exec('target/debug/tako', 'fetch', '--allow-missing-restart-unit', *missing_args)
+assert read_restart_log()[n:] == ['restart here.service']
+# A unit that exists and fails still fails.
+with open('tests/scratch/restart-fail', 'w') as f:
+    f.write('here.service\n')
+missing_args[5] = 'Version=1.1.0'
+exec('target/debug/tako', 'fetch', '--allow-missing-restart-unit', *missing_args, expect=1)
+os.remove('tests/scratch/restart-fail')
+os.remove('tests/scratch/missing')
+
+print(' * fails without systemd, unless --ignore-restart-units-without-systemd')
+os.mkdir('tests/scratch/no-systemd')
+with open('tests/scratch/offline', 'w') as f:
+    f.write('')
+no_systemd_args = [
+    '--set', 'Origin=http://127.0.0.1:8117/tests/scratch/retag-origin',
+    '--set', 'PublicKey=' + public_key,
+    '--set', 'Version=1.0.0',
+    '--set', 'Destination=tests/scratch/no-systemd',
+    '--set', 'RestartUnit=app.service',
+]
+n = len(read_restart_log())
+exec('target/debug/tako', 'fetch', *no_systemd_args, expect=1)
+assert not os.path.exists('tests/scratch/no-systemd/latest')
+exec('target/debug/tako', 'fetch', '--ignore-restart-units-without-systemd', *no_systemd_args)
+assert os.path.exists('tests/scratch/no-systemd/latest')
+assert read_restart_log()[n:] == []
+os.remove('tests/scratch/offline')
+
+print(' * restarts units with the command from RestartCommand=')
+os.mkdir('tests/scratch/restart-command')
+n = len(read_restart_log())
+exec('target/debug/tako', 'fetch',
+     '--set', 'Origin=http://127.0.0.1:8117/tests/scratch/retag-origin',
+     '--set', 'PublicKey=' + public_key,
+     '--set', 'Version=1.0.0',
+     '--set', 'Destination=tests/scratch/restart-command',
+     '--set', 'RestartUnit=app.service',
+     '--set', 'RestartBackend=command',
+     # The fake systemctl logs its arguments, so it serves as a command too.
+     '--set', 'RestartCommand=systemctl %action-via-command %unit')
+assert read_restart_log()[n:] == ['restart-via-command app.service']
+
+print(' * detects an origin that stops listing versions with --detect-rollback')
+os.mkdir('tests/scratch/rollback-origin')
+os.mkdir('tests/scratch/rollback')
+exec('target/debug/tako', 'store',
+     '--key', secret_key,
+     '--output', 'tests/scratch/rollback-origin',
+     'tests/images/1.0.0.img', '1.0.0')
+shutil.copy('tests/scratch/rollback-origin/manifest', 'tests/scratch/rollback-manifest-old')
+exec('target/debug/tako', 'store',
+     '--key', secret_key,
+     '--output', 'tests/scratch/rollback-origin',
+     'tests/images/1.1.0.img', '1.1.0')
+rollback_args = [
+    '--set', 'Origin=http://127.0.0.1:8117/tests/scratch/rollback-origin',
+    '--set', 'PublicKey=' + public_key,
+    '--set', 'Version=1.*',
+    '--set', 'Destination=tests/scratch/rollback',
+]
+exec('target/debug/tako', 'fetch', '--detect-rollback', 'refuse', *rollback_args)
+with open('tests/scratch/rollback/highest-version', 'r') as f:
+    assert 'Version=1.1.0\n' in f.read()
+os.chmod('tests/scratch/rollback-origin/manifest', int('644', 8))
+shutil.copy('tests/scratch/rollback-manifest-old', 'tests/scratch/rollback-origin/manifest')
+p = subprocess.run(['target/debug/tako', 'fetch', '--detect-rollback', 'refuse'] + rollback_args,
+                   stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+assert p.returncode == 6
+assert b'listed version 1.1.0 before' in p.stderr
+# Deleting the local manifest bypasses the subset check, but not the record.
+os.remove('tests/scratch/rollback/manifest')
+p = subprocess.run(['target/debug/tako', 'fetch', '--detect-rollback', 'refuse'] + rollback_args,
+                   stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+assert p.returncode == 6
+assert b'listed version 1.1.0 before' in p.stderr
+p = subprocess.run(['target/debug/tako', 'fetch', '--detect-rollback', 'warn',
+                    '--allow-downgrade'] + rollback_args,
+                   stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+assert p.returncode == 0
+assert b'Warning: The origin listed version 1.1.0 before' in p.stderr
+assert os.readlink('tests/scratch/rollback/latest') == 'store/' + img_v1_sha
+with open('tests/scratch/rollback/highest-version', 'r') as f:
+    assert 'Version=1.1.0\n' in f.read()
+
+print(' * prunes the oldest versions with --max-versions, and fetchers accept that')
+os.mkdir('tests/scratch/prune-origin')
+os.mkdir('tests/scratch/prune')
+for version in ['1.0.0', '1.1.0']:
+    exec('target/debug/tako', 'store', '--key', secret_key,
+         '--output', 'tests/scratch/prune-origin',
+         'tests/images/{}.img'.format(version), version)
+prune_args = [
+    '--set', 'Origin=http://127.0.0.1:8117/tests/scratch/prune-origin',
+    '--set', 'PublicKey=' + public_key,
+    '--set', 'Version=*',
+    '--set', 'Destination=tests/scratch/prune',
+]
+exec('target/debug/tako', 'fetch', *prune_args)
+out = exec('target/debug/tako', 'store', '--key', secret_key, '--max-versions', '2',
+           '--output', 'tests/scratch/prune-origin', 'tests/images/2.0.0.img', '2.0.0')
+assert b'Pruned version 1.0.0.' in out
+assert not os.path.exists('tests/scratch/prune-origin/store/' + img_v1_sha)
+with open('tests/scratch/prune-origin/manifest', 'r') as f:
+    assert '\n1.0.0 ' not in f.read()
+exec('target/debug/tako', 'fetch', *prune_args)
+assert os.readlink('tests/scratch/prune/latest') == store_img_v2
+# Storing a version that would be pruned right away is refused.
+exec('target/debug/tako', 'store', '--key', secret_key, '--max-versions', '2',
+     '--output', 'tests/scratch/prune-origin', 'tests/images/1.0.0.img', '1.0.0', expect=1)
+assert not os.path.exists('tests/scratch/prune-origin/store/' + img_v1_sha)
+
+print(' * fails configs that are not done at the --deadline')
+for name, verify_cmd in [('a', 'sleep 2'), ('b', 'true')]:
+    with open('tests/scratch/deadline-{}.tako'.format(name), 'w') as f:
+        f.write('Origin=http://127.0.0.1:8117/tests/origin/foo\n'
+                'PublicKey=l0D28J2fiIXvWPbeZP7wkaq+dB55Gl2ysigl9mQH29k=\n'
+                'Version=*\n'
+                'Destination=tests/scratch/deadline-{}\n'
+                'VerifyCmd={}\n'.format(name, verify_cmd))
+p = subprocess.run(['target/debug/tako', 'fetch', '--deadline', '1s', '--format', 'json',
+                    'tests/scratch/deadline-a.tako', 'tests/scratch/deadline-b.tako'],
+                   stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+assert p.returncode == 2
+assert p.stderr.count(b'--deadline passed') == 2
+report = json.loads(p.stdout.decode('utf-8'))
+assert [c['transfers'] == [] for c in report['configs']] == [False, True]
+assert not os.path.exists('tests/scratch/deadline-a/latest')
+assert not os.path.exists('tests/scratch/deadline-b/latest')
+exec('target/debug/tako', 'fetch', '--deadline', '1m', 'tests/scratch/deadline-b.tako')
+assert os.path.exists('tests/scratch/deadline-b/latest')
+
+print(' * exports a bundle and imports it into another destination')
+os.mkdir('tests/scratch/imported')
+exec('target/debug/tako', 'export', '--output', 'tests/scratch/foo.bundle',
+     'tests/config/foo-any.tako')
+assert not os.access('tests/scratch/foo.bundle', os.W_OK)
+with open('tests/scratch/foo.bundle', 'rb') as f:
+    bundle = f.read()
+assert bundle.startswith(b'Tako Bundle 1\nVersion=2.0.0\n')
+# A bundle signed with a different key, or with a damaged image, is rejected.
+exec('target/debug/tako', 'import', '--destination', 'tests/scratch/imported',
+     '--key', new_public_key, 'tests/scratch/foo.bundle', expect=6)
+with open('tests/scratch/foo-damaged.bundle', 'wb') as f:
+    f.write(bundle[:-1] + b'!')
+exec('target/debug/tako', 'import', '--destination', 'tests/scratch/imported',
+     '--key', public_key, 'tests/scratch/foo-damaged.bundle', expect=6)
+assert not os.path.exists('tests/scratch/imported/latest')
+assert not os.path.exists('tests/scratch/imported/store/' + img_v2_sha)
+exec('target/debug/tako', 'import', '--destination', 'tests/scratch/imported',
+     '--key', public_key, 'tests/scratch/foo.bundle')
+assert os.readlink('tests/scratch/imported/latest') == store_img_v2
+assert os.path.exists('tests/scratch/imported/manifest')
+
+print(' * stores images with an extension and a renamed manifest, and fetches them')
+os.makedirs('tests/scratch/ext-origin')
+os.mkdir('tests/scratch/ext')
+exec('target/debug/tako', 'store', '--key', secret_key, '--output', 'tests/scratch/ext-origin',
+     '--image-ext', '.img', '--manifest-name', 'manifest.txt', 'tests/images/1.0.0.img', '1.0.0')
+assert os.path.isfile('tests/scratch/ext-origin/store/' + img_v1_sha + '.img')
+assert not os.path.exists('tests/scratch/ext-origin/manifest')
+with open('tests/scratch/ext-origin/manifest.txt', 'r') as f:
+    assert '\nImageExt=.img\n' in f.read()
+# The extension cannot change once the manifest has versions.
+exec('target/debug/tako', 'store', '--key', secret_key, '--output', 'tests/scratch/ext-origin',
+     '--image-ext', '.raw', '--manifest-name', 'manifest.txt', 'tests/images/2.0.0.img', '2.0.0',
+     expect=1)
+exec('target/debug/tako', 'store', '--key', secret_key, '--output', 'tests/scratch/ext-origin',
+     '--manifest-name', 'manifest.txt', 'tests/images/2.0.0.img', '2.0.0')
+assert os.path.isfile('tests/scratch/ext-origin/store/' + img_v2_sha + '.img')
+with open('tests/scratch/ext.tako', 'w') as f:
+    f.write('Origin=http://127.0.0.1:8117/tests/scratch/ext-origin\n'
+            'PublicKey={}\n'
+            'Version=*\n'
+            'Destination=tests/scratch/ext\n'
+            'ManifestName=manifest.txt\n'.format(public_key))
+out = exec('target/debug/tako', 'fetch', '--print-url', 'tests/scratch/ext.tako')
+assert b'/tests/scratch/ext-origin/manifest.txt\n' in out
+out = exec('target/debug/tako', 'fetch', '--print-url', '--expected-version', '2.0.0',
+           'tests/scratch/ext.tako')
+assert out.decode('utf-8').splitlines()[1] == \
+    'image    http://127.0.0.1:8117/tests/scratch/ext-origin/' + store_img_v2 + '.img'
+exec('target/debug/tako', 'fetch', '--print-url', '--expected-version', '1.0.0',
+     'tests/scratch/ext.tako', expect=1)
+exec('target/debug/tako', 'fetch', 'tests/scratch/ext.tako')
+assert os.readlink('tests/scratch/ext/latest') == store_img_v2
+
+print(' * reports available updates without installing them with --report-only')
+os.mkdir('tests/scratch/report-origin')
+os.mkdir('tests/scratch/report')
+exec('target/debug/tako', 'store', '--key', secret_key, '--output', 'tests/scratch/report-origin',
+     'tests/images/1.0.0.img', '1.0.0')
+with open('tests/scratch/report.tako', 'w') as f:
+    f.write('Origin=http://127.0.0.1:8117/tests/scratch/report-origin\n'
+            'PublicKey={}\n'
+            'Version=*\n'
+            'Destination=tests/scratch/report\n'.format(public_key))
+out = exec('target/debug/tako', 'fetch', '--report-only', 'tests/scratch/report.tako', expect=3)
+assert out == b'tests/scratch/report.tako: update available: none -> 1.0.0\n'
+assert os.listdir('tests/scratch/report') == []
+exec('target/debug/tako', 'fetch', 'tests/scratch/report.tako')
+out = exec('target/debug/tako', 'fetch', '--report-only', 'tests/scratch/report.tako')
+assert out == b'tests/scratch/report.tako: up to date: 1.0.0\n'
+exec('target/debug/tako', 'store', '--key', secret_key, '--output', 'tests/scratch/report-origin',
+     'tests/images/1.1.0.img', '1.1.0')
+out = exec('target/debug/tako', 'fetch', '--report-only', 'tests/scratch/report.tako', expect=3)
+assert out == b'tests/scratch/report.tako: update available: 1.0.0 -> 1.1.0\n'
+assert not os.path.exists('tests/scratch/report/store/' + img_v1_1_sha)
+assert os.readlink('tests/scratch/report/latest') == 'store/' + img_v1_sha
+
+print(' * stores and fetches through a symlinked directory')
+os.mkdir('tests/scratch/link-origin-1')
+os.symlink('link-origin-1', 'tests/scratch/link-origin')
+os.mkdir('tests/scratch/link-dest-1')
+os.symlink('link-dest-1', 'tests/scratch/link-dest')
+exec('target/debug/tako', 'store', '--key', secret_key, '--output', 'tests/scratch/link-origin',
+     'tests/images/1.0.0.img', '1.0.0')
+assert os.path.isfile('tests/scratch/link-origin-1/manifest')
+assert os.path.isfile('tests/scratch/link-origin-1/store/' + img_v1_sha)
+assert os.path.islink('tests/scratch/link-origin')
+exec('target/debug/tako', 'fetch',
+     '--set', 'Origin=http://127.0.0.1:8117/tests/scratch/link-origin',
+     '--set', 'PublicKey=' + public_key,
+     '--set', 'Version=*',
+     '--set', 'Destination=tests/scratch/link-dest')
+assert os.readlink('tests/scratch/link-dest-1/latest') == 'store/' + img_v1_sha
+assert os.path.isfile('tests/scratch/link-dest-1/state')
+assert [f for f in os.listdir('tests/scratch/link-dest-1/store') if f.endswith('.new')] == []
+assert os.path.islink('tests/scratch/link-dest')
+
+print(' * stores a delta with --delta, and fetches it instead of the image')
+os.mkdir('tests/scratch/delta-origin')
+os.mkdir('tests/scratch/delta')
+# The test images are too small for a delta to pay off, so make larger ones.
+delta_v1 = hashlib.sha256(b'delta').digest() * 4096
+delta_v2 = delta_v1[:50000] + b'changed' + delta_v1[50007:]
+for version, image in [('1.0.0', delta_v1), ('1.1.0', delta_v2)]:
+    with open('tests/scratch/delta-{}.img'.format(version), 'wb') as f:
+        f.write(image)
+exec('target/debug/tako', 'store', '--key', secret_key, '--output', 'tests/scratch/delta-origin',
+     'tests/scratch/delta-1.0.0.img', '1.0.0')
+out = exec('target/debug/tako', 'store', '--key', secret_key, '--output', 'tests/scratch/delta-origin',
+           '--delta', 'tests/scratch/delta-1.1.0.img', '1.1.0')
+assert b'1.1.0 -> delta from 1.0.0' in out
+with open('tests/scratch/delta-origin/manifest', 'r') as f:
+    manifest = f.read()
+assert manifest.startswith('Tako Manifest 1.6\n')
+assert '\n  Delta=' + hashlib.sha256(delta_v1).hexdigest() + ' ' in manifest
+delta_fnames = [f for f in os.listdir('tests/scratch/delta-origin/store') if f.endswith('.delta')]
+assert len(delta_fnames) == 1
+assert os.path.getsize('tests/scratch/delta-origin/store/' + delta_fnames[0]) < 1000
+delta_args = [
+    '--set', 'Origin=http://127.0.0.1:8117/tests/scratch/delta-origin',
+    '--set', 'PublicKey=' + public_key,
+    '--set', 'Destination=tests/scratch/delta',
+]
+exec('target/debug/tako', 'fetch', '--set', 'Version=1.0.*', *delta_args)
+out = exec('target/debug/tako', 'fetch', '--set', 'Version=*', *delta_args)
+assert b'Fetching delta for 1.1.0' in out
+v2_path = 'tests/scratch/delta/store/' + hashlib.sha256(delta_v2).hexdigest()
+with open(v2_path, 'rb') as f:
+    assert f.read() == delta_v2
+assert os.readlink('tests/scratch/delta/latest') == 'store/' + hashlib.sha256(delta_v2).hexdigest()
+assert [f for f in os.listdir('tests/scratch/delta/store') if not f.isalnum()] == []
+# A damaged delta is not fatal, fetch downloads the full image instead.
+os.chmod('tests/scratch/delta-origin/store/' + delta_fnames[0], 0o644)
+with open('tests/scratch/delta-origin/store/' + delta_fnames[0], 'ab') as f:
+    f.write(b'!')
+os.chmod(v2_path, 0o644)
+os.remove(v2_path)
+p = subprocess.run(['target/debug/tako', 'fetch', '--set', 'Version=*', *delta_args],
+                   stdout=subprocess.DEVNULL, stderr=subprocess.PIPE)
+assert p.returncode == 0
+assert b'downloading the full image instead' in p.stderr
+with open(v2_path, 'rb') as f:
+    assert f.read() == delta_v2
+# Pruning the version also deletes its delta.
+exec('target/debug/tako', 'store', '--key', secret_key, '--output', 'tests/scratch/delta-origin',
+     '--max-versions', '1', 'tests/scratch/delta-1.0.0.img', '2.0.0')
+assert [f for f in os.listdir('tests/scratch/delta-origin/store') if f.endswith('.delta')] == []
+
 # TODO: Test that Tako follows redirects.
 # TODO: Test that Tako handles file-not-found correctly (whatever that means).
 